@@ -0,0 +1,232 @@
+use std::fmt;
+
+use crate::{Expr, ExprKind};
+
+// Binding power of the operators, as in `parser::create_binary_op_precedence`.
+const OR_PRECEDENCE: i32 = 4;
+const AND_PRECEDENCE: i32 = 6;
+const COMPARISON_PRECEDENCE: i32 = 10;
+const ADDITIVE_PRECEDENCE: i32 = 20;
+const MULTIPLICATIVE_PRECEDENCE: i32 = 40;
+const POWER_PRECEDENCE: i32 = 50;
+const PRIMARY_PRECEDENCE: i32 = 100;
+
+/// Precedence of an operator that can follow an expression, or none when it is followed by
+/// a delimiter like `)` or `,`, or by the end of input.
+const NO_OPERATOR: i32 = -1;
+
+/// Prints the expression with the fewest parentheses the parser needs to read it back,
+/// e.g. `x ^ 2 + 1`.
+///
+/// Parsing the output with `parser::parse_top_level_expression` yields an equal tree for any
+/// AST the parser produces. Folded literals are the exception: a negative literal reads back
+/// as a negation, and non-finite ones can't be written at all.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut result = String::new();
+        match &self.kind {
+            ExprKind::System(equations) => {
+                for (i, equation) in equations.iter().enumerate() {
+                    if i > 0 {
+                        result.push_str(", ");
+                    }
+                    operand_to_pretty_string(equation, 0, NO_OPERATOR, &mut result);
+                }
+            },
+            _ => operand_to_pretty_string(self, 0, NO_OPERATOR, &mut result),
+        }
+        f.write_str(&result)
+    }
+}
+
+/// How tightly `expr` binds as an operand.
+///
+/// The prefix operators `-`, `not` and `let` start a primary, so they fit in any operand
+/// position; their own operand is what limits them, see `prefix_operand_precedence`.
+fn precedence(expr: &Expr) -> i32 {
+    match &expr.kind {
+        ExprKind::Or(..) => OR_PRECEDENCE,
+        ExprKind::And(..) => AND_PRECEDENCE,
+        ExprKind::Not(operand) if matches!(operand.kind, ExprKind::Eq(..)) => COMPARISON_PRECEDENCE,
+        ExprKind::Eq(..) | ExprKind::Lt(..) | ExprKind::Gt(..) | ExprKind::Le(..) | ExprKind::Ge(..) => {
+            COMPARISON_PRECEDENCE
+        },
+        ExprKind::Add(..) | ExprKind::Sub(..) => ADDITIVE_PRECEDENCE,
+        ExprKind::Mul(..) | ExprKind::Div(..) | ExprKind::Mod(..) => MULTIPLICATIVE_PRECEDENCE,
+        ExprKind::Pow(..) => POWER_PRECEDENCE,
+        ExprKind::System(..) => 0,
+        _ => PRIMARY_PRECEDENCE,
+    }
+}
+
+/// The lowest precedence of the operators that the operand of a prefix operator takes with it,
+/// e.g. `-x ^ 2` is `-(x ^ 2)` and `not x = y` is `not (x = y)`.
+fn prefix_operand_precedence(expr: &Expr) -> Option<i32> {
+    match &expr.kind {
+        ExprKind::Unary(..) => Some(POWER_PRECEDENCE),
+        ExprKind::Not(operand) if !matches!(operand.kind, ExprKind::Eq(..)) => Some(COMPARISON_PRECEDENCE),
+        ExprKind::Not(..) => None,
+        ExprKind::Let(..) | ExprKind::LetFn(..) => Some(0),
+        ExprKind::Literal(literal) if literal.is_sign_negative() => Some(POWER_PRECEDENCE),
+        _ => None,
+    }
+}
+
+/// Writes `expr` in a position requiring at least `min_precedence`, followed by an operator of
+/// precedence `next_precedence`; it is parenthesized if it would otherwise parse differently.
+fn operand_to_pretty_string(expr: &Expr, min_precedence: i32, next_precedence: i32, result: &mut String) {
+    let takes_next_operator = prefix_operand_precedence(expr)
+        .is_some_and(|operand_precedence| next_precedence >= operand_precedence);
+
+    if precedence(expr) < min_precedence || takes_next_operator {
+        parenthesized_to_pretty_string(expr, result);
+    } else {
+        ast_to_pretty_string_internal(expr, next_precedence, result);
+    }
+}
+
+fn parenthesized_to_pretty_string(expr: &Expr, result: &mut String) {
+    result.push('(');
+    ast_to_pretty_string_internal(expr, NO_OPERATOR, result);
+    result.push(')');
+}
+
+/// Writes a left-associative binary operation of the given precedence.
+fn binary_to_pretty_string(
+    lhs: &Expr,
+    operator: &str,
+    rhs: &Expr,
+    precedence: i32,
+    next_precedence: i32,
+    result: &mut String,
+) {
+    operand_to_pretty_string(lhs, precedence, precedence, result);
+    result.push_str(operator);
+    operand_to_pretty_string(rhs, precedence + 1, next_precedence, result);
+}
+
+/// Writes a comparison; its operands can't be comparisons, which would read as a chain.
+fn comparison_to_pretty_string(lhs: &Expr, operator: &str, rhs: &Expr, next_precedence: i32, result: &mut String) {
+    operand_to_pretty_string(lhs, COMPARISON_PRECEDENCE + 1, COMPARISON_PRECEDENCE, result);
+    result.push_str(operator);
+    operand_to_pretty_string(rhs, COMPARISON_PRECEDENCE + 1, next_precedence, result);
+}
+
+fn arguments_to_pretty_string<'a>(args: impl IntoIterator<Item = &'a Expr>, result: &mut String) {
+    result.push('(');
+    for (i, arg) in args.into_iter().enumerate() {
+        if i > 0 {
+            result.push_str(", ");
+        }
+        operand_to_pretty_string(arg, 0, NO_OPERATOR, result);
+    }
+    result.push(')');
+}
+
+fn ast_to_pretty_string_internal(
+    ast: &Expr,
+    next_precedence: i32,
+    result: &mut String,
+) {
+    match &ast.kind {
+        ExprKind::Id(id) => result.push_str(id),
+        ExprKind::Call(id, args) if id == "factorial" && args.len() == 1 => {
+            // The postfix '!' applies to the primary right before it.
+            let arg = &args[0];
+            if precedence(arg) == PRIMARY_PRECEDENCE && prefix_operand_precedence(arg).is_none() {
+                ast_to_pretty_string_internal(arg, NO_OPERATOR, result);
+            } else {
+                parenthesized_to_pretty_string(arg, result);
+            }
+            result.push('!');
+        },
+        ExprKind::Call(id, args) => {
+            result.push_str(id);
+            arguments_to_pretty_string(args.iter().map(|arg| &**arg), result);
+        },
+        ExprKind::Eq(lhs, rhs) => comparison_to_pretty_string(lhs, " = ", rhs, next_precedence, result),
+        ExprKind::Lt(lhs, rhs) => comparison_to_pretty_string(lhs, " < ", rhs, next_precedence, result),
+        ExprKind::Gt(lhs, rhs) => comparison_to_pretty_string(lhs, " > ", rhs, next_precedence, result),
+        ExprKind::Le(lhs, rhs) => comparison_to_pretty_string(lhs, " <= ", rhs, next_precedence, result),
+        ExprKind::Ge(lhs, rhs) => comparison_to_pretty_string(lhs, " >= ", rhs, next_precedence, result),
+        ExprKind::And(lhs, rhs) => binary_to_pretty_string(lhs, " and ", rhs, AND_PRECEDENCE, next_precedence, result),
+        ExprKind::Or(lhs, rhs) => binary_to_pretty_string(lhs, " or ", rhs, OR_PRECEDENCE, next_precedence, result),
+        ExprKind::Not(expr) => match &expr.kind {
+            ExprKind::Eq(lhs, rhs) => comparison_to_pretty_string(lhs, " != ", rhs, next_precedence, result),
+            _ => {
+                result.push_str("not ");
+                operand_to_pretty_string(expr, COMPARISON_PRECEDENCE, next_precedence, result);
+            },
+        },
+        ExprKind::Let(name, value, body) => {
+            result.push_str("let ");
+            result.push_str(name);
+            result.push_str(" = ");
+            operand_to_pretty_string(value, 0, NO_OPERATOR, result);
+            result.push_str(" in ");
+            operand_to_pretty_string(body, 0, next_precedence, result);
+        },
+        ExprKind::LetFn(name, parameters, value, body) => {
+            result.push_str("let ");
+            result.push_str(name);
+            result.push('(');
+            result.push_str(&parameters.join(", "));
+            result.push_str(") = ");
+            operand_to_pretty_string(value, 0, NO_OPERATOR, result);
+            result.push_str(" in ");
+            operand_to_pretty_string(body, 0, next_precedence, result);
+        },
+        ExprKind::Sum(index, from, to, term) | ExprKind::Prod(index, from, to, term) => {
+            result.push_str(ast.to_str());
+            result.push('(');
+            result.push_str(index);
+            for arg in [from, to, term] {
+                result.push_str(", ");
+                operand_to_pretty_string(arg, 0, NO_OPERATOR, result);
+            }
+            result.push(')');
+        },
+        ExprKind::System(equations) => {
+            // Only valid at the top level, which the `Display` impl handles.
+            arguments_to_pretty_string(equations.iter().map(|equation| &**equation), result);
+        },
+        ExprKind::If(condition, then, otherwise) => {
+            result.push_str("if");
+            arguments_to_pretty_string([&**condition, &**then, &**otherwise], result);
+        },
+        ExprKind::Add(lhs, rhs) => binary_to_pretty_string(lhs, " + ", rhs, ADDITIVE_PRECEDENCE, next_precedence, result),
+        ExprKind::Sub(lhs, rhs) => binary_to_pretty_string(lhs, " - ", rhs, ADDITIVE_PRECEDENCE, next_precedence, result),
+        ExprKind::Mul(lhs, rhs) => {
+            binary_to_pretty_string(lhs, " * ", rhs, MULTIPLICATIVE_PRECEDENCE, next_precedence, result)
+        },
+        ExprKind::Div(lhs, rhs) => {
+            binary_to_pretty_string(lhs, " / ", rhs, MULTIPLICATIVE_PRECEDENCE, next_precedence, result)
+        },
+        ExprKind::Mod(lhs, rhs) => {
+            binary_to_pretty_string(lhs, " % ", rhs, MULTIPLICATIVE_PRECEDENCE, next_precedence, result)
+        },
+        ExprKind::Pow(lhs, rhs) => {
+            // '^' is right-associative.
+            operand_to_pretty_string(lhs, POWER_PRECEDENCE + 1, POWER_PRECEDENCE, result);
+            result.push_str(" ^ ");
+            operand_to_pretty_string(rhs, POWER_PRECEDENCE, next_precedence, result);
+        },
+        ExprKind::Unary(expr) => {
+            result.push('-');
+            // The parser rejects a negation right after another one, as in '- -x'.
+            if matches!(expr.kind, ExprKind::Unary(..))
+                || matches!(expr.kind, ExprKind::Literal(literal) if literal.is_sign_negative())
+            {
+                parenthesized_to_pretty_string(expr, result);
+            } else {
+                operand_to_pretty_string(expr, POWER_PRECEDENCE, next_precedence, result);
+            }
+        },
+        ExprKind::Literal(literal) => {
+            result.push_str(&literal.to_string());
+        },
+        ExprKind::Error => {
+            result.push_str("<error>");
+        }
+    }
+}
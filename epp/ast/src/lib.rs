@@ -1,10 +1,73 @@
-#[derive(Debug, Clone)]
-pub enum Expr {
+mod display;
+mod pool;
+mod visit;
+
+use std::hash::{Hash, Hasher};
+use std::mem;
+
+use serde::{Deserialize, Serialize};
+use span::Span;
+
+pub use pool::{ExprId, ExprNode, ExprPool};
+pub use visit::{fold_children, walk_expr, walk_expr_mut, Folder, Visitor, VisitorMut};
+
+/// Maximum number of terms of an `ExprKind::Sum` or factors of an `ExprKind::Prod`.
+pub const MAX_AGGREGATE_TERM_COUNT: f64 = 1_000_000.0;
+
+/// Largest magnitude of the bounds of an `ExprKind::Sum` or `ExprKind::Prod`, 2^53. Beyond it,
+/// adding 1 to an index may not change its value.
+pub const MAX_AGGREGATE_BOUND: f64 = 9_007_199_254_740_992.0;
+
+/// An expression with the span of the input it was parsed from.
+///
+/// Two expressions are equal if they have the same structure, whatever their spans; see
+/// `ExprKind` for how literals compare.
+///
+/// As JSON, an expression is `{"kind": ..., "span": {"low": 0, "high": 5}}`, where the kind
+/// is an object like `{"type": "add", "args": [lhs, rhs]}`: `type` is the snake case name of
+/// the variant and `args` its fields, a single value for one field and an array for several.
+/// `error` has no `args`. Literals are JSON numbers, or one of the strings `"NaN"`, `"inf"`
+/// and `"-inf"` when not finite.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+
+    pub fn boxed(kind: ExprKind, span: Span) -> Box<Self> {
+        Box::new(Self::new(kind, span))
+    }
+
+    pub fn to_str(&self) -> &'static str {
+        self.kind.to_str()
+    }
+}
+
+/// Arguments of a call or equations of a system, in order.
+pub type ExprList = Vec<Box<Expr>>;
+
+/// The structure of an expression.
+///
+/// Equality and hashing are structural and treat literals as values, except that `-0.0`
+/// equals `0.0` and all NaNs are equal to each other, so that `Eq` and `Hash` agree.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "args", rename_all = "snake_case")]
+pub enum ExprKind {
     Eq(Box<Expr>, Box<Expr>),
     Lt(Box<Expr>, Box<Expr>),
     Gt(Box<Expr>, Box<Expr>),
     Le(Box<Expr>, Box<Expr>),
     Ge(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    /// `if(condition, then, otherwise)`: a number chosen by a boolean condition.
+    If(Box<Expr>, Box<Expr>, Box<Expr>),
     Unary(Box<Expr>),
     Add(Box<Expr>, Box<Expr>),
     Sub(Box<Expr>, Box<Expr>),
@@ -12,29 +75,205 @@ pub enum Expr {
     Div(Box<Expr>, Box<Expr>),
     Mod(Box<Expr>, Box<Expr>),
     Pow(Box<Expr>, Box<Expr>),
-    Call(String, Vec<Box<Expr>>),
+    Call(String, ExprList),
+    /// `sum(index, from, to, term)`: the sum of `term` for `index` = `from`, `from + 1`, ... up to `to`.
+    ///
+    /// Evaluates to NaN, or fails to evaluate in the evaluator, if a bound is not finite or larger
+    /// in magnitude than `MAX_AGGREGATE_BOUND`, or there are more than `MAX_AGGREGATE_TERM_COUNT`
+    /// terms.
+    Sum(String, Box<Expr>, Box<Expr>, Box<Expr>),
+    /// `prod(index, from, to, factor)`: the product counterpart of `Sum`.
+    Prod(String, Box<Expr>, Box<Expr>, Box<Expr>),
+    /// `let name = value in body`: `name` stands for `value` inside `body`.
+    Let(String, Box<Expr>, Box<Expr>),
+    /// `let name(parameters) = value in body`: a function callable inside `body`.
+    LetFn(String, Vec<String>, Box<Expr>, Box<Expr>),
     Id(String),
-    Literal(f64),
+    Literal(#[serde(with = "literal_serde")] f64),
+    /// Equations that must hold simultaneously, written `a = b, c < d`.
+    System(ExprList),
+    /// Placeholder for a part of the input that failed to parse.
+    Error,
 }
 
-impl Expr {
+impl PartialEq for Expr {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl Eq for Expr {}
+
+impl Hash for Expr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.kind.hash(state);
+    }
+}
+
+impl PartialEq for ExprKind {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ExprKind::Eq(a, b), ExprKind::Eq(c, d))
+            | (ExprKind::Lt(a, b), ExprKind::Lt(c, d))
+            | (ExprKind::Gt(a, b), ExprKind::Gt(c, d))
+            | (ExprKind::Le(a, b), ExprKind::Le(c, d))
+            | (ExprKind::Ge(a, b), ExprKind::Ge(c, d))
+            | (ExprKind::And(a, b), ExprKind::And(c, d))
+            | (ExprKind::Or(a, b), ExprKind::Or(c, d))
+            | (ExprKind::Add(a, b), ExprKind::Add(c, d))
+            | (ExprKind::Sub(a, b), ExprKind::Sub(c, d))
+            | (ExprKind::Mul(a, b), ExprKind::Mul(c, d))
+            | (ExprKind::Div(a, b), ExprKind::Div(c, d))
+            | (ExprKind::Mod(a, b), ExprKind::Mod(c, d))
+            | (ExprKind::Pow(a, b), ExprKind::Pow(c, d)) => a == c && b == d,
+            (ExprKind::Not(a), ExprKind::Not(b)) | (ExprKind::Unary(a), ExprKind::Unary(b)) => a == b,
+            (ExprKind::If(a, b, c), ExprKind::If(d, e, f)) => a == d && b == e && c == f,
+            (ExprKind::Call(a, b), ExprKind::Call(c, d)) => a == c && b == d,
+            (ExprKind::Sum(a, b, c, d), ExprKind::Sum(e, f, g, h))
+            | (ExprKind::Prod(a, b, c, d), ExprKind::Prod(e, f, g, h)) => a == e && b == f && c == g && d == h,
+            (ExprKind::Let(a, b, c), ExprKind::Let(d, e, f)) => a == d && b == e && c == f,
+            (ExprKind::LetFn(a, b, c, d), ExprKind::LetFn(e, f, g, h)) => a == e && b == f && c == g && d == h,
+            (ExprKind::Id(a), ExprKind::Id(b)) => a == b,
+            (ExprKind::Literal(a), ExprKind::Literal(b)) => literal_bits(*a) == literal_bits(*b),
+            (ExprKind::System(a), ExprKind::System(b)) => a == b,
+            (ExprKind::Error, ExprKind::Error) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for ExprKind {}
+
+impl Hash for ExprKind {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            ExprKind::Eq(lhs, rhs)
+            | ExprKind::Lt(lhs, rhs)
+            | ExprKind::Gt(lhs, rhs)
+            | ExprKind::Le(lhs, rhs)
+            | ExprKind::Ge(lhs, rhs)
+            | ExprKind::And(lhs, rhs)
+            | ExprKind::Or(lhs, rhs)
+            | ExprKind::Add(lhs, rhs)
+            | ExprKind::Sub(lhs, rhs)
+            | ExprKind::Mul(lhs, rhs)
+            | ExprKind::Div(lhs, rhs)
+            | ExprKind::Mod(lhs, rhs)
+            | ExprKind::Pow(lhs, rhs) => {
+                lhs.hash(state);
+                rhs.hash(state);
+            },
+            ExprKind::Not(expr) | ExprKind::Unary(expr) => expr.hash(state),
+            ExprKind::If(condition, then, otherwise) => {
+                condition.hash(state);
+                then.hash(state);
+                otherwise.hash(state);
+            },
+            ExprKind::Call(name, args) => {
+                name.hash(state);
+                args.hash(state);
+            },
+            ExprKind::Sum(index, from, to, term) | ExprKind::Prod(index, from, to, term) => {
+                index.hash(state);
+                from.hash(state);
+                to.hash(state);
+                term.hash(state);
+            },
+            ExprKind::Let(name, value, body) => {
+                name.hash(state);
+                value.hash(state);
+                body.hash(state);
+            },
+            ExprKind::LetFn(name, parameters, value, body) => {
+                name.hash(state);
+                parameters.hash(state);
+                value.hash(state);
+                body.hash(state);
+            },
+            ExprKind::Id(id) => id.hash(state),
+            ExprKind::Literal(literal) => literal_bits(*literal).hash(state),
+            ExprKind::System(equations) => equations.hash(state),
+            ExprKind::Error => {},
+        }
+    }
+}
+
+/// Bits of a literal under the equality of `ExprKind`, with a single zero and a single NaN.
+fn literal_bits(literal: f64) -> u64 {
+    if literal.is_nan() {
+        f64::NAN.to_bits()
+    } else if literal == 0.0 {
+        0
+    } else {
+        literal.to_bits()
+    }
+}
+
+/// Literals as JSON numbers, with the non-finite ones, which JSON can't represent, as strings.
+///
+/// For use with `#[serde(with = "ast::literal_serde")]` on other serialized numbers.
+pub mod literal_serde {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Literal {
+        Number(f64),
+        Name(String),
+    }
+
+    pub fn serialize<S: Serializer>(literal: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        if literal.is_nan() {
+            serializer.serialize_str("NaN")
+        } else if literal.is_infinite() {
+            serializer.serialize_str(if *literal < 0.0 { "-inf" } else { "inf" })
+        } else {
+            serializer.serialize_f64(*literal)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        match Literal::deserialize(deserializer)? {
+            Literal::Number(number) => Ok(number),
+            Literal::Name(name) => match name.as_str() {
+                "NaN" => Ok(f64::NAN),
+                "inf" => Ok(f64::INFINITY),
+                "-inf" => Ok(f64::NEG_INFINITY),
+                _ => Err(de::Error::custom(format!("invalid literal `{}`", name))),
+            },
+        }
+    }
+}
+
+impl ExprKind {
     pub fn to_str(&self) -> &'static str {
         match self {
-            Expr::Eq(..) => Expr::eq_str(),
-            Expr::Lt(..) => Expr::lt_str(),
-            Expr::Gt(..) => Expr::gt_str(),
-            Expr::Le(..) => Expr::le_str(),
-            Expr::Ge(..) => Expr::ge_str(),
-            Expr::Unary(..) => Expr::unary_str(),
-            Expr::Add(..) => Expr::add_str(),
-            Expr::Sub(..) => Expr::sub_str(),
-            Expr::Mul(..) => Expr::mul_str(),
-            Expr::Div(..) => Expr::div_str(),
-            Expr::Mod(..) => Expr::mod_str(),
-            Expr::Pow(..) => Expr::pow_str(),
-            Expr::Call(..) => Expr::call_str(),
-            Expr::Id(..) => Expr::id_str(),
-            Expr::Literal(..) => Expr::literal_str(),
+            ExprKind::Eq(..) => ExprKind::eq_str(),
+            ExprKind::Lt(..) => ExprKind::lt_str(),
+            ExprKind::Gt(..) => ExprKind::gt_str(),
+            ExprKind::Le(..) => ExprKind::le_str(),
+            ExprKind::Ge(..) => ExprKind::ge_str(),
+            ExprKind::And(..) => ExprKind::and_str(),
+            ExprKind::Or(..) => ExprKind::or_str(),
+            ExprKind::Not(..) => ExprKind::not_str(),
+            ExprKind::If(..) => ExprKind::if_str(),
+            ExprKind::Unary(..) => ExprKind::unary_str(),
+            ExprKind::Add(..) => ExprKind::add_str(),
+            ExprKind::Sub(..) => ExprKind::sub_str(),
+            ExprKind::Mul(..) => ExprKind::mul_str(),
+            ExprKind::Div(..) => ExprKind::div_str(),
+            ExprKind::Mod(..) => ExprKind::mod_str(),
+            ExprKind::Pow(..) => ExprKind::pow_str(),
+            ExprKind::Call(..) => ExprKind::call_str(),
+            ExprKind::Sum(..) => ExprKind::sum_str(),
+            ExprKind::Prod(..) => ExprKind::prod_str(),
+            ExprKind::Let(..) => ExprKind::let_str(),
+            ExprKind::LetFn(..) => ExprKind::let_fn_str(),
+            ExprKind::Id(..) => ExprKind::id_str(),
+            ExprKind::Literal(..) => ExprKind::literal_str(),
+            ExprKind::System(..) => ExprKind::system_str(),
+            ExprKind::Error => ExprKind::error_str(),
         }
     }
 
@@ -58,6 +297,22 @@ impl Expr {
         ">="
     }
 
+    pub fn and_str() -> &'static str {
+        "and"
+    }
+
+    pub fn or_str() -> &'static str {
+        "or"
+    }
+
+    pub fn not_str() -> &'static str {
+        "not"
+    }
+
+    pub fn if_str() -> &'static str {
+        "if"
+    }
+
     pub fn unary_str() -> &'static str {
         "unary"
     }
@@ -90,6 +345,22 @@ impl Expr {
         "call"
     }
 
+    pub fn sum_str() -> &'static str {
+        "sum"
+    }
+
+    pub fn prod_str() -> &'static str {
+        "prod"
+    }
+
+    pub fn let_str() -> &'static str {
+        "let"
+    }
+
+    pub fn let_fn_str() -> &'static str {
+        "let_fn"
+    }
+
     pub fn id_str() -> &'static str {
         "id"
     }
@@ -97,4 +368,70 @@ impl Expr {
     pub fn literal_str() -> &'static str {
         "literal"
     }
+
+    pub fn system_str() -> &'static str {
+        ","
+    }
+
+    pub fn error_str() -> &'static str {
+        "error"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn expr(kind: ExprKind, low: usize, high: usize) -> Box<Expr> {
+        Expr::boxed(kind, Span::new(low, high))
+    }
+
+    #[test]
+    fn json_schema_is_stable() {
+        let ast = expr(
+            ExprKind::Add(
+                expr(ExprKind::Call("f".to_string(), vec![expr(ExprKind::Id("x".to_string()), 2, 3)]), 0, 4),
+                expr(ExprKind::Literal(f64::NAN), 7, 10),
+            ),
+            0,
+            10,
+        );
+        let json = r#"{"kind":{"type":"add","args":[{"kind":{"type":"call","args":["f",[{"kind":{"type":"id","args":"x"},"span":{"low":2,"high":3}}]]},"span":{"low":0,"high":4}},{"kind":{"type":"literal","args":"NaN"},"span":{"low":7,"high":10}}]},"span":{"low":0,"high":10}}"#;
+
+        assert_eq!(serde_json::to_string(&ast).unwrap(), json);
+        let parsed: Box<Expr> = serde_json::from_str(json).unwrap();
+        assert_eq!(parsed, ast);
+        assert_eq!(parsed.span, Span::new(0, 10));
+
+        assert_eq!(serde_json::to_string(&ExprKind::Error).unwrap(), r#"{"type":"error"}"#);
+        assert_eq!(serde_json::to_string(&ExprKind::Literal(-f64::INFINITY)).unwrap(), r#"{"type":"literal","args":"-inf"}"#);
+        assert_eq!(serde_json::to_string(&ExprKind::Literal(1.5)).unwrap(), r#"{"type":"literal","args":1.5}"#);
+    }
+
+    #[test]
+    fn malformed_json_is_rejected() {
+        for json in [
+            r#"{"kind":{"type":"literal","args":"nan"},"span":{"low":0,"high":1}}"#,
+            r#"{"kind":{"type":"modulo","args":[]},"span":{"low":0,"high":1}}"#,
+            r#"{"kind":{"type":"id","args":"x"},"span":{"low":3,"high":1}}"#,
+        ] {
+            assert!(serde_json::from_str::<Expr>(json).is_err(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn equality_ignores_spans_and_signs_of_zero_and_nan() {
+        let literal = |value| expr(ExprKind::Literal(value), 0, 1);
+        assert_eq!(expr(ExprKind::Id("x".to_string()), 0, 1), expr(ExprKind::Id("x".to_string()), 4, 9));
+        assert_eq!(literal(-0.0), literal(0.0));
+        assert_eq!(literal(f64::NAN), literal(-f64::NAN));
+        assert_ne!(literal(1.0), literal(-1.0));
+        assert_ne!(literal(f64::INFINITY), literal(f64::NEG_INFINITY));
+
+        let set = HashSet::from([literal(0.0), literal(f64::NAN)]);
+        assert!(set.contains(&literal(-0.0)));
+        assert!(set.contains(&expr(ExprKind::Literal(f64::from_bits(0x7ff8_0000_0000_0001)), 5, 6)));
+        assert!(!set.contains(&literal(1.0)));
+    }
 }
@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::mem;
+
+use span::Span;
+
+use crate::{Expr, ExprKind};
+
+/// Handle of an expression in an `ExprPool`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ExprId(u32);
+
+impl ExprId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// An `ExprKind` whose children are expressions of the same `ExprPool`.
+///
+/// Literals are the same node if they have the same bits, so `-0.0` and `0.0` stay apart,
+/// except that all NaNs are one node.
+#[derive(Debug, Clone)]
+pub enum ExprNode {
+    Eq(ExprId, ExprId),
+    Lt(ExprId, ExprId),
+    Gt(ExprId, ExprId),
+    Le(ExprId, ExprId),
+    Ge(ExprId, ExprId),
+    And(ExprId, ExprId),
+    Or(ExprId, ExprId),
+    Not(ExprId),
+    If(ExprId, ExprId, ExprId),
+    Unary(ExprId),
+    Add(ExprId, ExprId),
+    Sub(ExprId, ExprId),
+    Mul(ExprId, ExprId),
+    Div(ExprId, ExprId),
+    Mod(ExprId, ExprId),
+    Pow(ExprId, ExprId),
+    Call(String, Vec<ExprId>),
+    Sum(String, ExprId, ExprId, ExprId),
+    Prod(String, ExprId, ExprId, ExprId),
+    Let(String, ExprId, ExprId),
+    LetFn(String, Vec<String>, ExprId, ExprId),
+    Id(String),
+    Literal(f64),
+    System(Vec<ExprId>),
+    Error,
+}
+
+impl ExprNode {
+    /// Ids of the children, from left to right as written.
+    pub fn children(&self) -> Vec<ExprId> {
+        match self {
+            ExprNode::Eq(lhs, rhs)
+            | ExprNode::Lt(lhs, rhs)
+            | ExprNode::Gt(lhs, rhs)
+            | ExprNode::Le(lhs, rhs)
+            | ExprNode::Ge(lhs, rhs)
+            | ExprNode::And(lhs, rhs)
+            | ExprNode::Or(lhs, rhs)
+            | ExprNode::Add(lhs, rhs)
+            | ExprNode::Sub(lhs, rhs)
+            | ExprNode::Mul(lhs, rhs)
+            | ExprNode::Div(lhs, rhs)
+            | ExprNode::Mod(lhs, rhs)
+            | ExprNode::Pow(lhs, rhs) => vec![*lhs, *rhs],
+            ExprNode::Not(expr) | ExprNode::Unary(expr) => vec![*expr],
+            ExprNode::If(condition, then, otherwise) => vec![*condition, *then, *otherwise],
+            ExprNode::Sum(_, from, to, term) | ExprNode::Prod(_, from, to, term) => vec![*from, *to, *term],
+            ExprNode::Let(_, value, body) | ExprNode::LetFn(_, _, value, body) => vec![*value, *body],
+            ExprNode::Call(_, children) | ExprNode::System(children) => children.clone(),
+            ExprNode::Id(_) | ExprNode::Literal(_) | ExprNode::Error => Vec::new(),
+        }
+    }
+}
+
+impl PartialEq for ExprNode {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ExprNode::Eq(a, b), ExprNode::Eq(c, d))
+            | (ExprNode::Lt(a, b), ExprNode::Lt(c, d))
+            | (ExprNode::Gt(a, b), ExprNode::Gt(c, d))
+            | (ExprNode::Le(a, b), ExprNode::Le(c, d))
+            | (ExprNode::Ge(a, b), ExprNode::Ge(c, d))
+            | (ExprNode::And(a, b), ExprNode::And(c, d))
+            | (ExprNode::Or(a, b), ExprNode::Or(c, d))
+            | (ExprNode::Add(a, b), ExprNode::Add(c, d))
+            | (ExprNode::Sub(a, b), ExprNode::Sub(c, d))
+            | (ExprNode::Mul(a, b), ExprNode::Mul(c, d))
+            | (ExprNode::Div(a, b), ExprNode::Div(c, d))
+            | (ExprNode::Mod(a, b), ExprNode::Mod(c, d))
+            | (ExprNode::Pow(a, b), ExprNode::Pow(c, d)) => a == c && b == d,
+            (ExprNode::Not(a), ExprNode::Not(b)) | (ExprNode::Unary(a), ExprNode::Unary(b)) => a == b,
+            (ExprNode::If(a, b, c), ExprNode::If(d, e, f)) => a == d && b == e && c == f,
+            (ExprNode::Call(a, b), ExprNode::Call(c, d)) => a == c && b == d,
+            (ExprNode::Sum(a, b, c, d), ExprNode::Sum(e, f, g, h))
+            | (ExprNode::Prod(a, b, c, d), ExprNode::Prod(e, f, g, h)) => a == e && b == f && c == g && d == h,
+            (ExprNode::Let(a, b, c), ExprNode::Let(d, e, f)) => a == d && b == e && c == f,
+            (ExprNode::LetFn(a, b, c, d), ExprNode::LetFn(e, f, g, h)) => a == e && b == f && c == g && d == h,
+            (ExprNode::Id(a), ExprNode::Id(b)) => a == b,
+            (ExprNode::Literal(a), ExprNode::Literal(b)) => literal_bits(*a) == literal_bits(*b),
+            (ExprNode::System(a), ExprNode::System(b)) => a == b,
+            (ExprNode::Error, ExprNode::Error) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for ExprNode {}
+
+impl Hash for ExprNode {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            ExprNode::Eq(lhs, rhs)
+            | ExprNode::Lt(lhs, rhs)
+            | ExprNode::Gt(lhs, rhs)
+            | ExprNode::Le(lhs, rhs)
+            | ExprNode::Ge(lhs, rhs)
+            | ExprNode::And(lhs, rhs)
+            | ExprNode::Or(lhs, rhs)
+            | ExprNode::Add(lhs, rhs)
+            | ExprNode::Sub(lhs, rhs)
+            | ExprNode::Mul(lhs, rhs)
+            | ExprNode::Div(lhs, rhs)
+            | ExprNode::Mod(lhs, rhs)
+            | ExprNode::Pow(lhs, rhs) => {
+                lhs.hash(state);
+                rhs.hash(state);
+            },
+            ExprNode::Not(expr) | ExprNode::Unary(expr) => expr.hash(state),
+            ExprNode::If(condition, then, otherwise) => {
+                condition.hash(state);
+                then.hash(state);
+                otherwise.hash(state);
+            },
+            ExprNode::Call(name, args) => {
+                name.hash(state);
+                args.hash(state);
+            },
+            ExprNode::Sum(index, from, to, term) | ExprNode::Prod(index, from, to, term) => {
+                index.hash(state);
+                from.hash(state);
+                to.hash(state);
+                term.hash(state);
+            },
+            ExprNode::Let(name, value, body) => {
+                name.hash(state);
+                value.hash(state);
+                body.hash(state);
+            },
+            ExprNode::LetFn(name, parameters, value, body) => {
+                name.hash(state);
+                parameters.hash(state);
+                value.hash(state);
+                body.hash(state);
+            },
+            ExprNode::Id(id) => id.hash(state),
+            ExprNode::Literal(literal) => literal_bits(*literal).hash(state),
+            ExprNode::System(equations) => equations.hash(state),
+            ExprNode::Error => {},
+        }
+    }
+}
+
+/// Bits of a literal under the equality of `ExprNode`, with a single NaN.
+fn literal_bits(literal: f64) -> u64 {
+    if literal.is_nan() {
+        f64::NAN.to_bits()
+    } else {
+        literal.to_bits()
+    }
+}
+
+/// Arena of hash-consed expressions: adding a node equal to an existing one returns the
+/// existing id, so equal subexpressions are stored once and compared by id.
+///
+/// Nodes have no span of their own; each id keeps the span it was first added with.
+#[derive(Debug, Default, Clone)]
+pub struct ExprPool {
+    nodes: Vec<ExprNode>,
+    spans: Vec<Span>,
+    ids: HashMap<ExprNode, ExprId>,
+}
+
+impl ExprPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of distinct expressions in the pool.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn add(&mut self, node: ExprNode, span: Span) -> ExprId {
+        if let Some(id) = self.ids.get(&node) {
+            return *id;
+        }
+
+        let id = ExprId(self.nodes.len() as u32);
+        self.nodes.push(node.clone());
+        self.spans.push(span);
+        self.ids.insert(node, id);
+        id
+    }
+
+    pub fn node(&self, id: ExprId) -> &ExprNode {
+        &self.nodes[id.index()]
+    }
+
+    pub fn span(&self, id: ExprId) -> Span {
+        self.spans[id.index()]
+    }
+
+    /// Adds an AST, sharing the subexpressions it has in common with the pool.
+    pub fn add_expr(&mut self, expr: &Expr) -> ExprId {
+        let node = match &expr.kind {
+            ExprKind::Eq(lhs, rhs) => ExprNode::Eq(self.add_expr(lhs), self.add_expr(rhs)),
+            ExprKind::Lt(lhs, rhs) => ExprNode::Lt(self.add_expr(lhs), self.add_expr(rhs)),
+            ExprKind::Gt(lhs, rhs) => ExprNode::Gt(self.add_expr(lhs), self.add_expr(rhs)),
+            ExprKind::Le(lhs, rhs) => ExprNode::Le(self.add_expr(lhs), self.add_expr(rhs)),
+            ExprKind::Ge(lhs, rhs) => ExprNode::Ge(self.add_expr(lhs), self.add_expr(rhs)),
+            ExprKind::And(lhs, rhs) => ExprNode::And(self.add_expr(lhs), self.add_expr(rhs)),
+            ExprKind::Or(lhs, rhs) => ExprNode::Or(self.add_expr(lhs), self.add_expr(rhs)),
+            ExprKind::Not(expr) => ExprNode::Not(self.add_expr(expr)),
+            ExprKind::If(condition, then, otherwise) => {
+                ExprNode::If(self.add_expr(condition), self.add_expr(then), self.add_expr(otherwise))
+            },
+            ExprKind::Unary(expr) => ExprNode::Unary(self.add_expr(expr)),
+            ExprKind::Add(lhs, rhs) => ExprNode::Add(self.add_expr(lhs), self.add_expr(rhs)),
+            ExprKind::Sub(lhs, rhs) => ExprNode::Sub(self.add_expr(lhs), self.add_expr(rhs)),
+            ExprKind::Mul(lhs, rhs) => ExprNode::Mul(self.add_expr(lhs), self.add_expr(rhs)),
+            ExprKind::Div(lhs, rhs) => ExprNode::Div(self.add_expr(lhs), self.add_expr(rhs)),
+            ExprKind::Mod(lhs, rhs) => ExprNode::Mod(self.add_expr(lhs), self.add_expr(rhs)),
+            ExprKind::Pow(lhs, rhs) => ExprNode::Pow(self.add_expr(lhs), self.add_expr(rhs)),
+            ExprKind::Call(name, args) => {
+                ExprNode::Call(name.to_owned(), args.iter().map(|arg| self.add_expr(arg)).collect())
+            },
+            ExprKind::Sum(index, from, to, term) => {
+                ExprNode::Sum(index.to_owned(), self.add_expr(from), self.add_expr(to), self.add_expr(term))
+            },
+            ExprKind::Prod(index, from, to, factor) => {
+                ExprNode::Prod(index.to_owned(), self.add_expr(from), self.add_expr(to), self.add_expr(factor))
+            },
+            ExprKind::Let(name, value, body) => ExprNode::Let(name.to_owned(), self.add_expr(value), self.add_expr(body)),
+            ExprKind::LetFn(name, parameters, value, body) => {
+                ExprNode::LetFn(name.to_owned(), parameters.clone(), self.add_expr(value), self.add_expr(body))
+            },
+            ExprKind::Id(name) => ExprNode::Id(name.to_owned()),
+            ExprKind::Literal(literal) => ExprNode::Literal(*literal),
+            ExprKind::System(equations) => {
+                ExprNode::System(equations.iter().map(|equation| self.add_expr(equation)).collect())
+            },
+            ExprKind::Error => ExprNode::Error,
+        };
+
+        self.add(node, expr.span)
+    }
+
+    /// Builds the AST of an expression, copying each shared subexpression where it is used.
+    ///
+    /// Each distinct subexpression is converted once; its other uses clone the result.
+    pub fn to_expr(&self, id: ExprId) -> Box<Expr> {
+        self.to_expr_cached(id, &mut HashMap::new())
+    }
+
+    fn to_expr_cached(&self, id: ExprId, exprs: &mut HashMap<ExprId, Box<Expr>>) -> Box<Expr> {
+        if let Some(expr) = exprs.get(&id) {
+            return expr.clone();
+        }
+
+        let mut expr = |id: &ExprId| self.to_expr_cached(*id, exprs);
+
+        let kind = match self.node(id) {
+            ExprNode::Eq(lhs, rhs) => ExprKind::Eq(expr(lhs), expr(rhs)),
+            ExprNode::Lt(lhs, rhs) => ExprKind::Lt(expr(lhs), expr(rhs)),
+            ExprNode::Gt(lhs, rhs) => ExprKind::Gt(expr(lhs), expr(rhs)),
+            ExprNode::Le(lhs, rhs) => ExprKind::Le(expr(lhs), expr(rhs)),
+            ExprNode::Ge(lhs, rhs) => ExprKind::Ge(expr(lhs), expr(rhs)),
+            ExprNode::And(lhs, rhs) => ExprKind::And(expr(lhs), expr(rhs)),
+            ExprNode::Or(lhs, rhs) => ExprKind::Or(expr(lhs), expr(rhs)),
+            ExprNode::Not(operand) => ExprKind::Not(expr(operand)),
+            ExprNode::If(condition, then, otherwise) => ExprKind::If(expr(condition), expr(then), expr(otherwise)),
+            ExprNode::Unary(operand) => ExprKind::Unary(expr(operand)),
+            ExprNode::Add(lhs, rhs) => ExprKind::Add(expr(lhs), expr(rhs)),
+            ExprNode::Sub(lhs, rhs) => ExprKind::Sub(expr(lhs), expr(rhs)),
+            ExprNode::Mul(lhs, rhs) => ExprKind::Mul(expr(lhs), expr(rhs)),
+            ExprNode::Div(lhs, rhs) => ExprKind::Div(expr(lhs), expr(rhs)),
+            ExprNode::Mod(lhs, rhs) => ExprKind::Mod(expr(lhs), expr(rhs)),
+            ExprNode::Pow(lhs, rhs) => ExprKind::Pow(expr(lhs), expr(rhs)),
+            ExprNode::Call(name, args) => ExprKind::Call(name.to_owned(), args.iter().map(&mut expr).collect()),
+            ExprNode::Sum(index, from, to, term) => ExprKind::Sum(index.to_owned(), expr(from), expr(to), expr(term)),
+            ExprNode::Prod(index, from, to, factor) => {
+                ExprKind::Prod(index.to_owned(), expr(from), expr(to), expr(factor))
+            },
+            ExprNode::Let(name, value, body) => ExprKind::Let(name.to_owned(), expr(value), expr(body)),
+            ExprNode::LetFn(name, parameters, value, body) => {
+                ExprKind::LetFn(name.to_owned(), parameters.clone(), expr(value), expr(body))
+            },
+            ExprNode::Id(name) => ExprKind::Id(name.to_owned()),
+            ExprNode::Literal(literal) => ExprKind::Literal(*literal),
+            ExprNode::System(equations) => ExprKind::System(equations.iter().map(&mut expr).collect()),
+            ExprNode::Error => ExprKind::Error,
+        };
+
+        let expr = Expr::boxed(kind, self.span(id));
+        exprs.insert(id, expr.clone());
+        expr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expr(kind: ExprKind, low: usize, high: usize) -> Box<Expr> {
+        Expr::boxed(kind, Span::new(low, high))
+    }
+
+    #[test]
+    fn equal_subexpressions_are_stored_once() {
+        // x * x + x * x
+        let x = |low| expr(ExprKind::Id("x".to_string()), low, low + 1);
+        let square = |low| expr(ExprKind::Mul(x(low), x(low + 4)), low, low + 5);
+        let ast = expr(ExprKind::Add(square(0), square(8)), 0, 13);
+
+        let mut pool = ExprPool::new();
+        let id = pool.add_expr(&ast);
+        assert_eq!(pool.len(), 3);
+        assert_eq!(pool.add_expr(&square(20)), pool.node(id).children()[0]);
+        assert_eq!(pool.len(), 3);
+
+        // Spans are those of the first occurrence.
+        let rebuilt = pool.to_expr(id);
+        assert_eq!(rebuilt, ast);
+        match &rebuilt.kind {
+            ExprKind::Add(lhs, rhs) => assert_eq!((lhs.span, rhs.span), (Span::new(0, 5), Span::new(0, 5))),
+            kind => panic!("expected an addition, found {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn literals_are_told_apart_by_bits() {
+        let mut pool = ExprPool::new();
+        let zero = pool.add(ExprNode::Literal(0.0), Span::default());
+        assert_ne!(pool.add(ExprNode::Literal(-0.0), Span::default()), zero);
+        let nan = pool.add(ExprNode::Literal(f64::NAN), Span::default());
+        assert_eq!(pool.add(ExprNode::Literal(-f64::NAN), Span::default()), nan);
+    }
+
+    #[test]
+    fn shared_nodes_expand_into_a_tree() {
+        // e_0 = x, e_n = e_{n-1} + e_{n-1}: n + 1 nodes in the pool, 2^(n+1) - 1 in the AST.
+        let mut pool = ExprPool::new();
+        let mut id = pool.add(ExprNode::Id("x".to_string()), Span::default());
+        for _ in 0..12 {
+            id = pool.add(ExprNode::Add(id, id), Span::default());
+        }
+        assert_eq!(pool.len(), 13);
+
+        fn count(expr: &Expr) -> usize {
+            match &expr.kind {
+                ExprKind::Add(lhs, rhs) => 1 + count(lhs) + count(rhs),
+                _ => 1,
+            }
+        }
+        assert_eq!(count(&pool.to_expr(id)), (1 << 13) - 1);
+    }
+}
@@ -0,0 +1,266 @@
+use crate::{Expr, ExprKind};
+
+/// A pass reading an AST.
+///
+/// `visit_expr` is called for the root; the default implementation visits every child in
+/// order with `walk_expr`. A pass overrides it to handle the variants it cares about and
+/// calls `walk_expr` for the rest.
+pub trait Visitor {
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
+    }
+}
+
+/// A pass modifying an AST in place, the mutable counterpart of `Visitor`.
+pub trait VisitorMut {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        walk_expr_mut(self, expr);
+    }
+}
+
+/// A pass building a new AST from an existing one.
+///
+/// The default `fold_expr` copies the expression with each child replaced by its fold, see
+/// `fold_children`.
+pub trait Folder {
+    fn fold_expr(&mut self, expr: &Expr) -> Box<Expr> {
+        fold_children(self, expr)
+    }
+}
+
+/// Visits the children of `expr`, from left to right as written.
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match &expr.kind {
+        ExprKind::Eq(lhs, rhs)
+        | ExprKind::Lt(lhs, rhs)
+        | ExprKind::Gt(lhs, rhs)
+        | ExprKind::Le(lhs, rhs)
+        | ExprKind::Ge(lhs, rhs)
+        | ExprKind::And(lhs, rhs)
+        | ExprKind::Or(lhs, rhs)
+        | ExprKind::Add(lhs, rhs)
+        | ExprKind::Sub(lhs, rhs)
+        | ExprKind::Mul(lhs, rhs)
+        | ExprKind::Div(lhs, rhs)
+        | ExprKind::Mod(lhs, rhs)
+        | ExprKind::Pow(lhs, rhs) => {
+            visitor.visit_expr(lhs);
+            visitor.visit_expr(rhs);
+        },
+        ExprKind::Not(expr) | ExprKind::Unary(expr) => visitor.visit_expr(expr),
+        ExprKind::If(condition, then, otherwise) => {
+            visitor.visit_expr(condition);
+            visitor.visit_expr(then);
+            visitor.visit_expr(otherwise);
+        },
+        ExprKind::Call(_, args) => {
+            for arg in args {
+                visitor.visit_expr(arg);
+            }
+        },
+        ExprKind::Sum(_, from, to, term) | ExprKind::Prod(_, from, to, term) => {
+            visitor.visit_expr(from);
+            visitor.visit_expr(to);
+            visitor.visit_expr(term);
+        },
+        ExprKind::Let(_, value, body) | ExprKind::LetFn(_, _, value, body) => {
+            visitor.visit_expr(value);
+            visitor.visit_expr(body);
+        },
+        ExprKind::System(equations) => {
+            for equation in equations {
+                visitor.visit_expr(equation);
+            }
+        },
+        ExprKind::Id(_) | ExprKind::Literal(_) | ExprKind::Error => { },
+    }
+}
+
+/// Visits the children of `expr`, from left to right as written.
+pub fn walk_expr_mut<V: VisitorMut + ?Sized>(visitor: &mut V, expr: &mut Expr) {
+    match &mut expr.kind {
+        ExprKind::Eq(lhs, rhs)
+        | ExprKind::Lt(lhs, rhs)
+        | ExprKind::Gt(lhs, rhs)
+        | ExprKind::Le(lhs, rhs)
+        | ExprKind::Ge(lhs, rhs)
+        | ExprKind::And(lhs, rhs)
+        | ExprKind::Or(lhs, rhs)
+        | ExprKind::Add(lhs, rhs)
+        | ExprKind::Sub(lhs, rhs)
+        | ExprKind::Mul(lhs, rhs)
+        | ExprKind::Div(lhs, rhs)
+        | ExprKind::Mod(lhs, rhs)
+        | ExprKind::Pow(lhs, rhs) => {
+            visitor.visit_expr_mut(lhs);
+            visitor.visit_expr_mut(rhs);
+        },
+        ExprKind::Not(expr) | ExprKind::Unary(expr) => visitor.visit_expr_mut(expr),
+        ExprKind::If(condition, then, otherwise) => {
+            visitor.visit_expr_mut(condition);
+            visitor.visit_expr_mut(then);
+            visitor.visit_expr_mut(otherwise);
+        },
+        ExprKind::Call(_, args) => {
+            for arg in args {
+                visitor.visit_expr_mut(arg);
+            }
+        },
+        ExprKind::Sum(_, from, to, term) | ExprKind::Prod(_, from, to, term) => {
+            visitor.visit_expr_mut(from);
+            visitor.visit_expr_mut(to);
+            visitor.visit_expr_mut(term);
+        },
+        ExprKind::Let(_, value, body) | ExprKind::LetFn(_, _, value, body) => {
+            visitor.visit_expr_mut(value);
+            visitor.visit_expr_mut(body);
+        },
+        ExprKind::System(equations) => {
+            for equation in equations {
+                visitor.visit_expr_mut(equation);
+            }
+        },
+        ExprKind::Id(_) | ExprKind::Literal(_) | ExprKind::Error => { },
+    }
+}
+
+/// Copies `expr`, keeping its span, with each child replaced by its fold.
+pub fn fold_children<F: Folder + ?Sized>(folder: &mut F, expr: &Expr) -> Box<Expr> {
+    let mut fold = |expr: &Expr| folder.fold_expr(expr);
+
+    let kind = match &expr.kind {
+        ExprKind::Eq(lhs, rhs) => ExprKind::Eq(fold(lhs), fold(rhs)),
+        ExprKind::Lt(lhs, rhs) => ExprKind::Lt(fold(lhs), fold(rhs)),
+        ExprKind::Gt(lhs, rhs) => ExprKind::Gt(fold(lhs), fold(rhs)),
+        ExprKind::Le(lhs, rhs) => ExprKind::Le(fold(lhs), fold(rhs)),
+        ExprKind::Ge(lhs, rhs) => ExprKind::Ge(fold(lhs), fold(rhs)),
+        ExprKind::And(lhs, rhs) => ExprKind::And(fold(lhs), fold(rhs)),
+        ExprKind::Or(lhs, rhs) => ExprKind::Or(fold(lhs), fold(rhs)),
+        ExprKind::Not(expr) => ExprKind::Not(fold(expr)),
+        ExprKind::If(condition, then, otherwise) => ExprKind::If(fold(condition), fold(then), fold(otherwise)),
+        ExprKind::Unary(expr) => ExprKind::Unary(fold(expr)),
+        ExprKind::Add(lhs, rhs) => ExprKind::Add(fold(lhs), fold(rhs)),
+        ExprKind::Sub(lhs, rhs) => ExprKind::Sub(fold(lhs), fold(rhs)),
+        ExprKind::Mul(lhs, rhs) => ExprKind::Mul(fold(lhs), fold(rhs)),
+        ExprKind::Div(lhs, rhs) => ExprKind::Div(fold(lhs), fold(rhs)),
+        ExprKind::Mod(lhs, rhs) => ExprKind::Mod(fold(lhs), fold(rhs)),
+        ExprKind::Pow(lhs, rhs) => ExprKind::Pow(fold(lhs), fold(rhs)),
+        ExprKind::Call(name, args) => ExprKind::Call(name.to_owned(), args.iter().map(|arg| fold(arg)).collect()),
+        ExprKind::Sum(index, from, to, term) => ExprKind::Sum(index.to_owned(), fold(from), fold(to), fold(term)),
+        ExprKind::Prod(index, from, to, factor) => ExprKind::Prod(index.to_owned(), fold(from), fold(to), fold(factor)),
+        ExprKind::Let(name, value, body) => ExprKind::Let(name.to_owned(), fold(value), fold(body)),
+        ExprKind::LetFn(name, parameters, value, body) => {
+            ExprKind::LetFn(name.to_owned(), parameters.clone(), fold(value), fold(body))
+        },
+        ExprKind::Id(name) => ExprKind::Id(name.to_owned()),
+        ExprKind::Literal(literal) => ExprKind::Literal(*literal),
+        ExprKind::System(equations) => ExprKind::System(equations.iter().map(|equation| fold(equation)).collect()),
+        ExprKind::Error => ExprKind::Error,
+    };
+
+    Expr::boxed(kind, expr.span)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use span::Span;
+
+    fn expr(kind: ExprKind) -> Box<Expr> {
+        Expr::boxed(kind, Span::default())
+    }
+
+    fn id(name: &str) -> Box<Expr> {
+        expr(ExprKind::Id(name.to_string()))
+    }
+
+    /// Names of the identifiers, in visiting order.
+    #[derive(Default)]
+    struct IdLister {
+        names: Vec<String>,
+    }
+
+    impl Visitor for IdLister {
+        fn visit_expr(&mut self, expr: &Expr) {
+            if let ExprKind::Id(name) = &expr.kind {
+                self.names.push(name.clone());
+            }
+            walk_expr(self, expr);
+        }
+    }
+
+    /// Doubles every literal.
+    struct Doubler;
+
+    impl VisitorMut for Doubler {
+        fn visit_expr_mut(&mut self, expr: &mut Expr) {
+            if let ExprKind::Literal(literal) = &mut expr.kind {
+                *literal *= 2.0;
+            }
+            walk_expr_mut(self, expr);
+        }
+    }
+
+    /// Renames every identifier `x` to `y`.
+    struct Renamer;
+
+    impl Folder for Renamer {
+        fn fold_expr(&mut self, expr: &Expr) -> Box<Expr> {
+            match &expr.kind {
+                ExprKind::Id(name) if name == "x" => Expr::boxed(ExprKind::Id("y".to_string()), expr.span),
+                _ => fold_children(self, expr),
+            }
+        }
+    }
+
+    #[test]
+    fn walk_visits_children_as_written() {
+        let ast = expr(ExprKind::LetFn(
+            "f".to_string(),
+            vec!["t".to_string()],
+            id("a"),
+            expr(ExprKind::Sum(
+                "k".to_string(),
+                id("b"),
+                id("c"),
+                expr(ExprKind::Call("f".to_string(), vec![id("d"), id("e")])),
+            )),
+        ));
+
+        let mut lister = IdLister::default();
+        lister.visit_expr(&ast);
+        assert_eq!(lister.names, ["a", "b", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn walk_mut_modifies_in_place() {
+        let literal = |literal: f64| expr(ExprKind::Literal(literal));
+        let mut ast = expr(ExprKind::If(
+            expr(ExprKind::Lt(id("x"), literal(1.0))),
+            expr(ExprKind::Call("f".to_string(), vec![literal(2.0), id("y")])),
+            expr(ExprKind::Sum("k".to_string(), literal(3.0), literal(4.0), literal(5.0))),
+        ));
+        Doubler.visit_expr_mut(&mut ast);
+
+        let expected = expr(ExprKind::If(
+            expr(ExprKind::Lt(id("x"), literal(2.0))),
+            expr(ExprKind::Call("f".to_string(), vec![literal(4.0), id("y")])),
+            expr(ExprKind::Sum("k".to_string(), literal(6.0), literal(8.0), literal(10.0))),
+        ));
+        assert_eq!(ast, expected);
+    }
+
+    #[test]
+    fn fold_rebuilds_with_spans() {
+        let one = Expr::boxed(ExprKind::Literal(1.0), Span::new(4, 5));
+        let ast = Expr::boxed(ExprKind::Add(id("x"), one), Span::new(0, 5));
+        let folded = Renamer.fold_expr(&ast);
+
+        assert_eq!(folded, expr(ExprKind::Add(id("y"), expr(ExprKind::Literal(1.0)))));
+        assert_eq!(folded.span, Span::new(0, 5));
+        match &folded.kind {
+            ExprKind::Add(_, rhs) => assert_eq!(rhs.span, Span::new(4, 5)),
+            kind => panic!("expected an addition, found {:?}", kind),
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use serde::Serialize;
+
+/// Stable identifier of a diagnostic, independent from its message wording.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum DiagnosticCode {
+    #[serde(rename = "E0001")]
+    UndefinedIdentifier,
+    #[serde(rename = "E0002")]
+    UndefinedFunction,
+    #[serde(rename = "E0003")]
+    ArgumentCountMismatch,
+    #[serde(rename = "E0004")]
+    TypeMismatch,
+    #[serde(rename = "E0005")]
+    UnexpectedToken,
+    #[serde(rename = "E0006")]
+    UnclosedDelimiter,
+    #[serde(rename = "E0007")]
+    UnexpectedEndOfInput,
+    #[serde(rename = "E0008")]
+    NotDifferentiable,
+    #[serde(rename = "E0009")]
+    MalformedNumberLiteral,
+    #[serde(rename = "E0010")]
+    InvalidDefinition,
+    #[serde(rename = "E0011")]
+    MalformedAst,
+    #[serde(rename = "W0001")]
+    UnusedVariable,
+}
+
+impl DiagnosticCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiagnosticCode::UndefinedIdentifier => "E0001",
+            DiagnosticCode::UndefinedFunction => "E0002",
+            DiagnosticCode::ArgumentCountMismatch => "E0003",
+            DiagnosticCode::TypeMismatch => "E0004",
+            DiagnosticCode::UnexpectedToken => "E0005",
+            DiagnosticCode::UnclosedDelimiter => "E0006",
+            DiagnosticCode::UnexpectedEndOfInput => "E0007",
+            DiagnosticCode::NotDifferentiable => "E0008",
+            DiagnosticCode::MalformedNumberLiteral => "E0009",
+            DiagnosticCode::InvalidDefinition => "E0010",
+            DiagnosticCode::MalformedAst => "E0011",
+            DiagnosticCode::UnusedVariable => "W0001",
+        }
+    }
+}
@@ -0,0 +1,14 @@
+use std::fmt;
+
+/// Returned instead of a result when the reason it could not be produced has already been
+/// reported as an error to a `DiagnosticSink`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ErrorReported;
+
+impl fmt::Display for ErrorReported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("errors were reported to the diagnostic sink")
+    }
+}
+
+impl std::error::Error for ErrorReported {}
@@ -0,0 +1,23 @@
+use serde::Serialize;
+use span::Span;
+
+/// Secondary span attached to a diagnostic, e.g. pointing at a related token.
+#[derive(Debug, Clone, Hash, Serialize)]
+pub struct Label {
+    span: Span,
+    message: String,
+}
+
+impl Label {
+    pub fn new(span: Span, message: String) -> Self {
+        Self { span, message }
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
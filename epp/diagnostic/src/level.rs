@@ -5,4 +5,5 @@ pub enum Level {
     Error,
     Warning,
     Note,
+    Help,
 }
@@ -1,37 +1,118 @@
+mod code;
+mod error_reported;
 #[cfg(feature = "global_instance")]
 pub(crate) mod global_instance;
+mod label;
 mod level;
+mod render;
+mod sink;
+mod sub_diagnostic;
+mod suggestion;
 
+pub use code::*;
+pub use error_reported::*;
+pub use label::*;
 pub use level::*;
+pub use render::*;
+pub use sink::*;
+pub use sub_diagnostic::*;
+pub use suggestion::*;
 
 #[cfg(feature = "global_instance")]
 use crate::global_instance::DIAGNOSTICS;
 #[cfg(feature = "global_instance")]
 use parking_lot::MutexGuard;
 use serde::Serialize;
+use span::Span;
 
 #[derive(Debug, Clone, Hash, Serialize)]
 pub struct Diagnostic {
     level: Level,
+    code: Option<DiagnosticCode>,
     message: String,
+    span: Option<Span>,
+    labels: Vec<Label>,
+    children: Vec<SubDiagnostic>,
+    suggestions: Vec<Suggestion>,
 }
 
 impl Diagnostic {
     pub fn new(level: Level, message: String) -> Self {
         Self {
             level,
+            code: None,
             message,
+            span: None,
+            labels: Vec::new(),
+            children: Vec::new(),
+            suggestions: Vec::new(),
         }
     }
 
+    pub fn with_code(mut self, code: DiagnosticCode) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn with_label(mut self, span: Span, message: String) -> Self {
+        self.labels.push(Label::new(span, message));
+        self
+    }
+
+    pub fn with_note(mut self, message: String) -> Self {
+        self.children.push(SubDiagnostic::new(Level::Note, message, None));
+        self
+    }
+
+    pub fn with_help(mut self, message: String) -> Self {
+        self.children.push(SubDiagnostic::new(Level::Help, message, None));
+        self
+    }
+
+    pub fn with_suggestion(
+        mut self,
+        span: Span,
+        replacement: String,
+        message: String,
+        applicability: Applicability,
+    ) -> Self {
+        self.suggestions.push(Suggestion::new(span, replacement, message, applicability));
+        self
+    }
+
     pub fn level(&self) -> Level {
         self.level
     }
 
+    pub fn code(&self) -> Option<DiagnosticCode> {
+        self.code
+    }
+
     pub fn message(&self) -> &str {
         &self.message
     }
 
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    pub fn labels(&self) -> &[Label] {
+        &self.labels
+    }
+
+    pub fn children(&self) -> &[SubDiagnostic] {
+        &self.children
+    }
+
+    pub fn suggestions(&self) -> &[Suggestion] {
+        &self.suggestions
+    }
+
     #[cfg(feature = "global_instance")]
     pub fn push_new(diagnostic: Diagnostic) {
         DIAGNOSTICS.lock().push(diagnostic);
@@ -47,3 +128,76 @@ impl Diagnostic {
         DIAGNOSTICS.lock().clear();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_keeps_every_part() {
+        let diagnostic = Diagnostic::new(Level::Error, "unclosed '('".to_string())
+            .with_code(DiagnosticCode::UnclosedDelimiter)
+            .with_span(Span::new(3, 4))
+            .with_label(Span::new(0, 1), "opened here".to_string())
+            .with_note("parentheses must be balanced".to_string())
+            .with_help("add ')'".to_string())
+            .with_suggestion(Span::new(4, 4), ")".to_string(), "close it".to_string(), Applicability::MaybeIncorrect);
+
+        assert_eq!(diagnostic.code(), Some(DiagnosticCode::UnclosedDelimiter));
+        assert_eq!(diagnostic.span(), Some(Span::new(3, 4)));
+        assert_eq!(diagnostic.labels()[0].message(), "opened here");
+        assert_eq!(
+            diagnostic.children().iter().map(|child| child.level()).collect::<Vec<_>>(),
+            [Level::Note, Level::Help]
+        );
+        assert_eq!(diagnostic.suggestions()[0].replacement(), ")");
+    }
+
+    #[test]
+    fn serializes_codes_as_their_identifiers() {
+        let codes = [
+            DiagnosticCode::UndefinedIdentifier,
+            DiagnosticCode::UndefinedFunction,
+            DiagnosticCode::ArgumentCountMismatch,
+            DiagnosticCode::TypeMismatch,
+            DiagnosticCode::UnexpectedToken,
+            DiagnosticCode::UnclosedDelimiter,
+            DiagnosticCode::UnexpectedEndOfInput,
+            DiagnosticCode::NotDifferentiable,
+            DiagnosticCode::MalformedNumberLiteral,
+            DiagnosticCode::InvalidDefinition,
+            DiagnosticCode::MalformedAst,
+            DiagnosticCode::UnusedVariable,
+        ];
+
+        for code in codes {
+            assert_eq!(serde_json::to_value(code).unwrap(), code.as_str());
+        }
+    }
+
+    #[test]
+    fn serializes_to_json() {
+        let diagnostic = Diagnostic::new(Level::Warning, "unused variable `y`".to_string())
+            .with_code(DiagnosticCode::UnusedVariable)
+            .with_span(Span::new(4, 5))
+            .with_suggestion(Span::new(4, 5), "_y".to_string(), "rename it".to_string(), Applicability::MachineApplicable);
+
+        assert_eq!(
+            serde_json::to_value(&diagnostic).unwrap(),
+            serde_json::json!({
+                "level": "Warning",
+                "code": "W0001",
+                "message": "unused variable `y`",
+                "span": {"low": 4, "high": 5},
+                "labels": [],
+                "children": [],
+                "suggestions": [{
+                    "span": {"low": 4, "high": 5},
+                    "replacement": "_y",
+                    "message": "rename it",
+                    "applicability": "MachineApplicable",
+                }],
+            })
+        );
+    }
+}
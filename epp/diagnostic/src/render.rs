@@ -0,0 +1,323 @@
+use crate::{Diagnostic, Level};
+use span::Span;
+use std::fmt::Write;
+use std::io::IsTerminal;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const GREEN: &str = "\x1b[1;32m";
+const CYAN: &str = "\x1b[1;36m";
+const BLUE: &str = "\x1b[1;34m";
+
+/// Renders diagnostics as rustc-style reports with the offending source line underlined.
+#[derive(Debug, Clone, Copy)]
+pub struct Renderer {
+    color: bool,
+}
+
+impl Renderer {
+    /// Renderer that emits plain text, suitable for logs and text snapshots.
+    pub fn plain() -> Self {
+        Self { color: false }
+    }
+
+    /// Renderer that emits ANSI color escapes.
+    pub fn colored() -> Self {
+        Self { color: true }
+    }
+
+    /// Uses colors only if stdout is a terminal.
+    pub fn for_stdout() -> Self {
+        Self {
+            color: std::io::stdout().is_terminal(),
+        }
+    }
+
+    pub fn render_all(&self, diagnostics: &[Diagnostic], source: &str) -> String {
+        let mut result = String::new();
+
+        for diagnostic in diagnostics {
+            result.push_str(&self.render(diagnostic, source));
+            result.push('\n');
+        }
+
+        result
+    }
+
+    pub fn render(&self, diagnostic: &Diagnostic, source: &str) -> String {
+        let lines = SourceLines::new(source);
+        let mut result = String::new();
+
+        let style = level_style(diagnostic.level());
+        let header = match diagnostic.code() {
+            Some(code) => format!("{}[{}]", level_name(diagnostic.level()), code.as_str()),
+            None => level_name(diagnostic.level()).to_string(),
+        };
+        writeln!(
+            result,
+            "{}{}",
+            self.paint(&header, style),
+            self.paint(&format!(": {}", diagnostic.message()), BOLD),
+        ).unwrap();
+
+        let mut annotations = Vec::new();
+        if let Some(span) = diagnostic.span() {
+            // A label on exactly the primary span is shown as the primary annotation's message.
+            let message = diagnostic
+                .labels()
+                .iter()
+                .find(|label| label.span() == span)
+                .map(|label| label.message());
+            annotations.push(Annotation { span, message, primary: true });
+        }
+        for label in diagnostic.labels() {
+            if Some(label.span()) != diagnostic.span() {
+                annotations.push(Annotation { span: label.span(), message: Some(label.message()), primary: false });
+            }
+        }
+
+        let gutter_width = annotations
+            .iter()
+            .map(|annotation| lines.line_index(annotation.span.low()) + 1)
+            .chain(diagnostic.suggestions().iter().map(|suggestion| lines.line_index(suggestion.span().low()) + 1))
+            .max()
+            .map_or(0, |line_number| line_number.to_string().len());
+        let gutter = " ".repeat(gutter_width);
+
+        if let Some(span) = diagnostic.span() {
+            let line_index = lines.line_index(span.low());
+            let column = lines.column(line_index, span.low());
+            writeln!(result, "{}{} {}:{}", gutter, self.paint("-->", BLUE), line_index + 1, column + 1).unwrap();
+        }
+
+        if !annotations.is_empty() {
+            writeln!(result, "{} {}", gutter, self.paint("|", BLUE)).unwrap();
+
+            let mut line_indices = annotations
+                .iter()
+                .map(|annotation| lines.line_index(annotation.span.low()))
+                .collect::<Vec<_>>();
+            line_indices.sort_unstable();
+            line_indices.dedup();
+
+            for line_index in line_indices {
+                self.write_source_line(&mut result, &lines, line_index, gutter_width);
+
+                for annotation in annotations.iter().filter(|annotation| lines.line_index(annotation.span.low()) == line_index) {
+                    let column = lines.column(line_index, annotation.span.low());
+                    let width = lines.underline_width(line_index, annotation.span);
+                    let (marker, style) = if annotation.primary { ('^', style) } else { ('-', BLUE) };
+
+                    let mut underline = marker.to_string().repeat(width);
+                    if let Some(message) = annotation.message {
+                        underline.push(' ');
+                        underline.push_str(message);
+                    }
+
+                    writeln!(
+                        result,
+                        "{} {} {}{}",
+                        gutter,
+                        self.paint("|", BLUE),
+                        " ".repeat(column),
+                        self.paint(&underline, style),
+                    ).unwrap();
+                }
+            }
+        }
+
+        for child in diagnostic.children() {
+            writeln!(
+                result,
+                "{} {} {}: {}",
+                gutter,
+                self.paint("=", BLUE),
+                self.paint(level_name(child.level()), BOLD),
+                child.message(),
+            ).unwrap();
+        }
+
+        for suggestion in diagnostic.suggestions() {
+            writeln!(
+                result,
+                "{}: {}",
+                self.paint(level_name(Level::Help), level_style(Level::Help)),
+                suggestion.message(),
+            ).unwrap();
+
+            let line_index = lines.line_index(suggestion.span().low());
+            let (line_start, line_end) = lines.bounds(line_index);
+            let low = suggestion.span().low().clamp(line_start, line_end);
+            let high = suggestion.span().high().clamp(low, line_end);
+            let patched = format!(
+                "{}{}{}",
+                &source[line_start..low],
+                suggestion.replacement(),
+                &source[high..line_end],
+            );
+
+            writeln!(result, "{} {}", gutter, self.paint("|", BLUE)).unwrap();
+            writeln!(result, "{} {} {}", self.paint(&format!("{:>width$}", line_index + 1, width = gutter_width), BLUE), self.paint("|", BLUE), patched).unwrap();
+        }
+
+        result
+    }
+
+    fn write_source_line(&self, result: &mut String, lines: &SourceLines, line_index: usize, gutter_width: usize) {
+        writeln!(
+            result,
+            "{} {} {}",
+            self.paint(&format!("{:>width$}", line_index + 1, width = gutter_width), BLUE),
+            self.paint("|", BLUE),
+            lines.text(line_index),
+        ).unwrap();
+    }
+
+    fn paint(&self, text: &str, style: &str) -> String {
+        if self.color {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+struct Annotation<'a> {
+    span: Span,
+    message: Option<&'a str>,
+    primary: bool,
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "error",
+        Level::Warning => "warning",
+        Level::Note => "note",
+        Level::Help => "help",
+    }
+}
+
+fn level_style(level: Level) -> &'static str {
+    match level {
+        Level::Error => RED,
+        Level::Warning => YELLOW,
+        Level::Note => GREEN,
+        Level::Help => CYAN,
+    }
+}
+
+/// Byte ranges of the lines of a source text, excluding line terminators.
+struct SourceLines<'s> {
+    source: &'s str,
+    bounds: Vec<(usize, usize)>,
+}
+
+impl<'s> SourceLines<'s> {
+    fn new(source: &'s str) -> Self {
+        let mut bounds = Vec::new();
+        let mut start = 0;
+
+        for line in source.split('\n') {
+            let end = start + line.trim_end_matches('\r').len();
+            bounds.push((start, end));
+            start += line.len() + 1;
+        }
+
+        Self { source, bounds }
+    }
+
+    fn line_index(&self, offset: usize) -> usize {
+        self.bounds
+            .iter()
+            .position(|(_, end)| offset <= *end)
+            .unwrap_or(self.bounds.len() - 1)
+    }
+
+    fn bounds(&self, line_index: usize) -> (usize, usize) {
+        self.bounds[line_index]
+    }
+
+    fn text(&self, line_index: usize) -> &'s str {
+        let (start, end) = self.bounds[line_index];
+        &self.source[start..end]
+    }
+
+    /// Column of `offset` in characters.
+    fn column(&self, line_index: usize, offset: usize) -> usize {
+        let (start, end) = self.bounds[line_index];
+        self.source[start..offset.clamp(start, end)].chars().count()
+    }
+
+    /// Width in characters of the part of `span` on the given line; at least one.
+    fn underline_width(&self, line_index: usize, span: Span) -> usize {
+        let (start, end) = self.bounds[line_index];
+        let low = span.low().clamp(start, end);
+        let high = span.high().clamp(low, end);
+        usize::max(1, self.source[low..high].chars().count())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Applicability, DiagnosticCode};
+
+    #[test]
+    fn renders_labels_children_and_suggestions() {
+        let diagnostic = Diagnostic::new(Level::Error, "expected ')'".to_string())
+            .with_code(DiagnosticCode::UnclosedDelimiter)
+            .with_span(Span::new(6, 6))
+            .with_label(Span::new(0, 1), "unclosed delimiter".to_string())
+            .with_help("add ')'".to_string())
+            .with_suggestion(Span::new(6, 6), ")".to_string(), "close the parenthesis".to_string(), Applicability::MachineApplicable);
+
+        assert_eq!(
+            Renderer::plain().render(&diagnostic, "(1 + 2"),
+            "\
+error[E0006]: expected ')'
+ --> 1:7
+  |
+1 | (1 + 2
+  |       ^
+  | - unclosed delimiter
+  = help: add ')'
+help: close the parenthesis
+  |
+1 | (1 + 2)
+"
+        );
+    }
+
+    #[test]
+    fn renders_the_line_of_the_span() {
+        let diagnostic = Diagnostic::new(Level::Warning, "unused variable `y`".to_string())
+            .with_span(Span::new(13, 14))
+            .with_label(Span::new(13, 14), "defined here".to_string())
+            .with_note("it is never used".to_string());
+
+        assert_eq!(
+            Renderer::plain().render(&diagnostic, "x = 1,\nlet y = 2; x"),
+            "\
+warning: unused variable `y`
+ --> 2:7
+  |
+2 | let y = 2; x
+  |       ^ defined here
+  = note: it is never used
+"
+        );
+    }
+
+    #[test]
+    fn colored_renderer_wraps_parts_in_escapes() {
+        let diagnostic = Diagnostic::new(Level::Error, "bad".to_string()).with_span(Span::new(0, 1));
+
+        assert_eq!(
+            Renderer::colored().render(&diagnostic, "x"),
+            "\u{1b}[1;31merror\u{1b}[0m\u{1b}[1m: bad\u{1b}[0m\n \u{1b}[1;34m-->\u{1b}[0m 1:1\n  \u{1b}[1;34m|\u{1b}[0m\n\
+            \u{1b}[1;34m1\u{1b}[0m \u{1b}[1;34m|\u{1b}[0m x\n  \u{1b}[1;34m|\u{1b}[0m \u{1b}[1;31m^\u{1b}[0m\n"
+        );
+    }
+}
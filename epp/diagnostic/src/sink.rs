@@ -0,0 +1,90 @@
+use crate::{Diagnostic, Level};
+
+/// Collects the diagnostics reported during a single compilation.
+#[derive(Debug, Default, Clone)]
+pub struct DiagnosticSink {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl DiagnosticSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, diagnostic: Diagnostic) {
+        self.diagnostics.push(diagnostic);
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    pub fn into_diagnostics(self) -> Vec<Diagnostic> {
+        self.diagnostics
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.level() == Level::Error)
+            .count()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.error_count() != 0
+    }
+
+    pub fn clear(&mut self) {
+        self.diagnostics.clear();
+    }
+
+    /// Moves the collected diagnostics into the process-wide diagnostic list.
+    #[cfg(feature = "global_instance")]
+    pub fn flush_to_global(&mut self) {
+        crate::global_instance::DIAGNOSTICS.lock().append(&mut self.diagnostics);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_only_errors() {
+        let mut sink = DiagnosticSink::new();
+        sink.push(Diagnostic::new(Level::Warning, "unused".to_string()));
+        assert!(!sink.has_errors());
+
+        sink.push(Diagnostic::new(Level::Error, "unexpected token".to_string()));
+        sink.push(Diagnostic::new(Level::Error, "unclosed '('".to_string()));
+        assert_eq!(sink.error_count(), 2);
+        assert_eq!(sink.diagnostics().len(), 3);
+
+        sink.clear();
+        assert!(sink.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn sinks_are_independent() {
+        let mut first = DiagnosticSink::new();
+        let second = DiagnosticSink::new();
+        first.push(Diagnostic::new(Level::Error, "unexpected token".to_string()));
+
+        assert!(first.has_errors());
+        assert!(!second.has_errors());
+        assert_eq!(first.into_diagnostics()[0].message(), "unexpected token");
+    }
+
+    #[cfg(feature = "global_instance")]
+    #[test]
+    fn flush_moves_diagnostics_to_the_global_list() {
+        Diagnostic::clear();
+        let mut sink = DiagnosticSink::new();
+        sink.push(Diagnostic::new(Level::Error, "unexpected token".to_string()));
+
+        sink.flush_to_global();
+        assert!(sink.diagnostics().is_empty());
+        assert_eq!(Diagnostic::diagnostics().len(), 1);
+        Diagnostic::clear();
+    }
+}
@@ -0,0 +1,33 @@
+use crate::Level;
+use serde::Serialize;
+use span::Span;
+
+/// Note or help message attached to a parent diagnostic.
+#[derive(Debug, Clone, Hash, Serialize)]
+pub struct SubDiagnostic {
+    level: Level,
+    message: String,
+    span: Option<Span>,
+}
+
+impl SubDiagnostic {
+    pub fn new(level: Level, message: String, span: Option<Span>) -> Self {
+        Self {
+            level,
+            message,
+            span,
+        }
+    }
+
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+}
@@ -0,0 +1,46 @@
+use serde::Serialize;
+use span::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub enum Applicability {
+    /// The replacement is known to be what the user intended and can be applied automatically.
+    MachineApplicable,
+    /// The replacement is likely, but not certainly, what the user intended.
+    MaybeIncorrect,
+}
+
+/// Fix that replaces the source text covered by `span` with `replacement`.
+#[derive(Debug, Clone, Hash, Serialize)]
+pub struct Suggestion {
+    span: Span,
+    replacement: String,
+    message: String,
+    applicability: Applicability,
+}
+
+impl Suggestion {
+    pub fn new(span: Span, replacement: String, message: String, applicability: Applicability) -> Self {
+        Self {
+            span,
+            replacement,
+            message,
+            applicability,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    pub fn replacement(&self) -> &str {
+        &self.replacement
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn applicability(&self) -> Applicability {
+        self.applicability
+    }
+}
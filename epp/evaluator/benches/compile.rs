@@ -0,0 +1,115 @@
+//! Compares `CompiledExpr::eval`, `Program::run_batch` and `eval_number_batch` with
+//! `eval_number` on expressions sampled like a plot.
+//!
+//! Run with `cargo bench` from the evaluator directory.
+
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const SAMPLE_COUNT: usize = 20_000;
+
+const EXPRESSIONS: &[&str] = &[
+    "x",
+    "sin(x) * x^2 + 3 * x - 1",
+    "if(x < 0, -x, sqrt(x)) + abs(cos(x * pi))",
+    "let f(t) = t^2 + 1 in f(x) / f(x + 1)",
+    "let r = x * x in r * r - 2 * r + 1",
+    "sum(k, 1, 10, sin(k * x) / k)",
+];
+
+fn parse(expr: &str) -> Box<ast::Expr> {
+    let mut diagnostics = diagnostic::DiagnosticSink::new();
+
+    let ctx = parser::ParserContext::new(
+        Box::new(lexer::token_iter(expr)),
+        parser::create_binary_op_precedence(),
+        &mut diagnostics,
+    );
+
+    parser::parse_top_level_expression(ctx).unwrap_or_else(|_| panic!("failed to parse {}", expr))
+}
+
+fn sample(i: usize) -> f64 {
+    -10.0 + 20.0 * i as f64 / SAMPLE_COUNT as f64
+}
+
+fn time(mut f: impl FnMut() -> f64) -> (Duration, f64) {
+    let start = Instant::now();
+    let checksum = f();
+    (start.elapsed(), checksum)
+}
+
+fn per_eval(duration: Duration) -> f64 {
+    duration.as_nanos() as f64 / SAMPLE_COUNT as f64
+}
+
+fn assert_same(expr: &str, backend: &str, expected: f64, found: f64) {
+    assert!(
+        expected.to_bits() == found.to_bits() || (expected.is_nan() && found.is_nan()),
+        "{}: tree walker and {} disagree",
+        expr,
+        backend
+    );
+}
+
+fn main() {
+    let constants = HashMap::from([("pi".to_string(), std::f64::consts::PI)]);
+    let inputs = (0..SAMPLE_COUNT).map(sample).collect::<Vec<_>>();
+    let mut outputs = vec![0.0; SAMPLE_COUNT];
+
+    println!(
+        "{:<45} {:>12} {:>12} {:>12} {:>12} {:>12} {:>12} {:>12}",
+        "expression", "tree ns", "closures ns", "speedup", "bytecode ns", "speedup", "batch ns", "speedup"
+    );
+
+    for expr in EXPRESSIONS {
+        let ast = parse(expr);
+        let compiled = evaluator::compile_number_expr(&ast, &["x"], &constants, 0.0).unwrap();
+        let program = evaluator::compile_number_bytecode(&ast, &["x"], &constants, 0.0).unwrap();
+
+        let (tree_time, tree_checksum) = time(|| {
+            let mut variables = constants.clone();
+            let mut checksum = 0.0;
+            for x in &inputs {
+                variables.insert("x".to_string(), *x);
+                checksum += evaluator::eval_number(black_box(&ast), &evaluator::Env::new(&variables)).unwrap_or(f64::NAN);
+            }
+            checksum
+        });
+
+        let (compiled_time, compiled_checksum) = time(|| {
+            let mut checksum = 0.0;
+            for x in &inputs {
+                checksum += black_box(&compiled).eval(&[*x]);
+            }
+            checksum
+        });
+
+        let (program_time, program_checksum) = time(|| {
+            black_box(&program).run_batch(&inputs, &mut outputs);
+            outputs.iter().sum()
+        });
+
+        let (batch_time, batch_checksum) = time(|| {
+            evaluator::eval_number_batch(black_box(&ast), &[("x", &inputs)], &constants, 0.0, &mut outputs).unwrap();
+            outputs.iter().sum()
+        });
+
+        assert_same(expr, "closures", tree_checksum, compiled_checksum);
+        assert_same(expr, "bytecode", tree_checksum, program_checksum);
+        assert_same(expr, "batch", tree_checksum, batch_checksum);
+
+        println!(
+            "{:<45} {:>12.1} {:>12.1} {:>11.1}x {:>12.1} {:>11.1}x {:>12.1} {:>11.1}x",
+            expr,
+            per_eval(tree_time),
+            per_eval(compiled_time),
+            tree_time.as_secs_f64() / compiled_time.as_secs_f64(),
+            per_eval(program_time),
+            tree_time.as_secs_f64() / program_time.as_secs_f64(),
+            per_eval(batch_time),
+            tree_time.as_secs_f64() / batch_time.as_secs_f64(),
+        );
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+
+use ast::{Expr, ExprKind};
+
+use crate::compile::{Scope, Variable};
+use crate::{aggregate_term_count, arity_mismatch, builtin, Builtin, EvalError};
+
+/// Evaluates a number expression for a batch of values of its variables, given as one column
+/// per variable, writing the result for each row to `output`.
+///
+/// The expression is evaluated one node at a time over the whole batch, in loops over columns
+/// which the compiler can vectorize. As both branches of an `if` are evaluated, errors are
+/// reported like by `compile_number_expr`, whatever the values. A `sum` or `prod` whose bounds
+/// are out of range evaluates to NaN in its row.
+///
+/// # Panics
+///
+/// Panics if a column is not as long as `output`.
+pub fn eval_number_batch(
+    ast: &Expr,
+    columns: &[(&str, &[f64])],
+    constants: &HashMap<String, f64>,
+    equality_approximate_threshold: f64,
+    output: &mut [f64],
+) -> Result<(), EvalError> {
+    for (name, column) in columns {
+        assert_eq!(column.len(), output.len(), "column `{}` is not as long as the output", name);
+    }
+
+    let names = columns.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+    let mut batch = Batch {
+        inputs: columns.iter().map(|(_, column)| *column).collect(),
+        locals: Vec::new(),
+        len: output.len(),
+        equality_approximate_threshold,
+    };
+
+    let result = batch.number(ast, &Scope::new(&names, constants))?;
+    output.copy_from_slice(&result);
+    Ok(())
+}
+
+/// Columns in scope while evaluating a batch: the inputs in the first slots, followed by the
+/// values of definitions, parameters and indices, which are dropped when they go out of scope.
+struct Batch<'c> {
+    inputs: Vec<&'c [f64]>,
+    locals: Vec<Vec<f64>>,
+    len: usize,
+    equality_approximate_threshold: f64,
+}
+
+impl<'c> Batch<'c> {
+    fn column(&self, slot: usize) -> &[f64] {
+        match self.inputs.get(slot) {
+            Some(column) => column,
+            None => &self.locals[slot - self.inputs.len()],
+        }
+    }
+
+    /// Puts a column in a new slot, which stays until `unbind` is called with it.
+    fn bind(&mut self, column: Vec<f64>) -> usize {
+        self.locals.push(column);
+        self.inputs.len() + self.locals.len() - 1
+    }
+
+    /// Drops the column in the slot and all columns bound after it.
+    fn unbind(&mut self, slot: usize) {
+        self.locals.truncate(slot - self.inputs.len());
+    }
+
+    fn binary<'a>(
+        &mut self,
+        lhs: &'a Expr,
+        rhs: &'a Expr,
+        operator: impl Fn(f64, f64) -> f64,
+        scope: &Scope<'a>,
+    ) -> Result<Vec<f64>, EvalError> {
+        let mut lhs = self.number(lhs, scope)?;
+        let rhs = self.number(rhs, scope)?;
+
+        for (lhs, rhs) in lhs.iter_mut().zip(&rhs) {
+            *lhs = operator(*lhs, *rhs);
+        }
+
+        Ok(lhs)
+    }
+
+    fn unary<'a>(&mut self, expr: &'a Expr, operator: impl Fn(f64) -> f64, scope: &Scope<'a>) -> Result<Vec<f64>, EvalError> {
+        let mut column = self.number(expr, scope)?;
+
+        for value in column.iter_mut() {
+            *value = operator(*value);
+        }
+
+        Ok(column)
+    }
+
+    fn number<'a>(&mut self, ast: &'a Expr, scope: &Scope<'a>) -> Result<Vec<f64>, EvalError> {
+        let column = match &ast.kind {
+            ExprKind::Literal(value) => vec![*value; self.len],
+            ExprKind::Add(lhs, rhs) => self.binary(lhs, rhs, |lhs, rhs| lhs + rhs, scope)?,
+            ExprKind::Sub(lhs, rhs) => self.binary(lhs, rhs, |lhs, rhs| lhs - rhs, scope)?,
+            ExprKind::Mul(lhs, rhs) => self.binary(lhs, rhs, |lhs, rhs| lhs * rhs, scope)?,
+            ExprKind::Div(lhs, rhs) => self.binary(lhs, rhs, |lhs, rhs| lhs / rhs, scope)?,
+            ExprKind::Mod(lhs, rhs) => self.binary(lhs, rhs, |lhs, rhs| lhs % rhs, scope)?,
+            ExprKind::Pow(lhs, rhs) => self.binary(lhs, rhs, f64::powf, scope)?,
+            ExprKind::Unary(expr) => self.unary(expr, |value| -value, scope)?,
+            ExprKind::If(condition, then, otherwise) => {
+                let condition = self.condition(condition, scope)?;
+                let mut then = self.number(then, scope)?;
+                let otherwise = self.number(otherwise, scope)?;
+
+                for ((then, otherwise), condition) in then.iter_mut().zip(&otherwise).zip(&condition) {
+                    if !condition {
+                        *then = *otherwise;
+                    }
+                }
+
+                then
+            },
+            ExprKind::Id(name) => match scope.variables.get(name) {
+                Some(Variable::Slot(slot)) => self.column(*slot).to_vec(),
+                Some(Variable::Constant(value)) => vec![*value; self.len],
+                None => return Err(EvalError::UnknownVariable { name: name.to_owned(), span: ast.span }),
+            },
+            ExprKind::Eq(..)
+            | ExprKind::Lt(..)
+            | ExprKind::Gt(..)
+            | ExprKind::Le(..)
+            | ExprKind::Ge(..)
+            | ExprKind::And(..)
+            | ExprKind::Or(..)
+            | ExprKind::Not(..)
+            | ExprKind::System(..) => return Err(EvalError::RelationInNumericContext { span: ast.span }),
+            ExprKind::Error => return Err(EvalError::Erroneous { span: ast.span }),
+            ExprKind::Sum(index, from, to, term) | ExprKind::Prod(index, from, to, term) => {
+                let from = self.number(from, scope)?;
+                let to = self.number(to, scope)?;
+
+                let slot = self.bind(from);
+                let result = self.aggregate(ast, index, slot, &to, term, scope);
+                self.unbind(slot);
+                result?
+            },
+            ExprKind::Let(name, value, body) => {
+                let value = self.number(value, scope)?;
+
+                let slot = self.bind(value);
+                let result = self.number(body, &scope.with_variable(name, Variable::Slot(slot)));
+                self.unbind(slot);
+                result?
+            },
+            ExprKind::LetFn(name, parameters, value, body) => {
+                self.number(body, &scope.with_function(name, parameters, value))?
+            },
+            ExprKind::Call(name, args) => {
+                let mut args = args.iter().map(|arg| self.number(arg, scope)).collect::<Result<Vec<_>, _>>()?;
+
+                if let Some(function) = scope.functions.get(name) {
+                    if args.len() != function.parameters.len() {
+                        return Err(arity_mismatch(name, function.parameters.len(), args.len(), ast.span));
+                    }
+
+                    let first_slot = self.inputs.len() + self.locals.len();
+                    let mut function_scope = function.scope.clone();
+                    for (parameter, arg) in function.parameters.iter().zip(args) {
+                        let slot = self.bind(arg);
+                        function_scope.variables.insert(parameter.to_owned(), Variable::Slot(slot));
+                    }
+
+                    let result = self.number(function.body, &function_scope);
+                    self.unbind(first_slot);
+                    return result;
+                }
+
+                match (builtin(name), args.len()) {
+                    (Some(Builtin::Unary(function)), 1) => {
+                        let mut column = args.remove(0);
+                        for value in column.iter_mut() {
+                            *value = function(*value);
+                        }
+                        column
+                    },
+                    (Some(Builtin::Binary(function)), 2) => {
+                        let rhs = args.remove(1);
+                        let mut lhs = args.remove(0);
+                        for (lhs, rhs) in lhs.iter_mut().zip(&rhs) {
+                            *lhs = function(*lhs, *rhs);
+                        }
+                        lhs
+                    },
+                    (Some(builtin), found) => return Err(arity_mismatch(name, builtin.arity(), found, ast.span)),
+                    (None, _) => return Err(EvalError::UnknownFunction { name: name.to_owned(), span: ast.span }),
+                }
+            },
+        };
+
+        Ok(column)
+    }
+
+    /// Evaluates a `Sum` or `Prod` whose index starts in `index_slot`, stepping every row at
+    /// once until the row with the most terms is done.
+    ///
+    /// The term is evaluated at least once, so that errors in it don't depend on the bounds.
+    fn aggregate<'a>(
+        &mut self,
+        ast: &'a Expr,
+        index: &str,
+        index_slot: usize,
+        to: &[f64],
+        term: &'a Expr,
+        scope: &Scope<'a>,
+    ) -> Result<Vec<f64>, EvalError> {
+        let (identity, combine): (f64, fn(f64, f64) -> f64) = match &ast.kind {
+            ExprKind::Sum(..) => (0.0, |lhs, rhs| lhs + rhs),
+            _ => (1.0, |lhs, rhs| lhs * rhs),
+        };
+
+        let from = self.column(index_slot).to_vec();
+        let term_counts = from
+            .iter()
+            .zip(to)
+            .map(|(from, to)| aggregate_term_count(*from, *to).ok())
+            .collect::<Vec<_>>();
+        let mut result = term_counts
+            .iter()
+            .map(|term_count| if term_count.is_some() { identity } else { f64::NAN })
+            .collect::<Vec<_>>();
+        let term_counts = term_counts.into_iter().map(|term_count| term_count.unwrap_or(0)).collect::<Vec<_>>();
+        let step_count = term_counts.iter().copied().max().unwrap_or(0);
+
+        let term_scope = scope.with_variable(index, Variable::Slot(index_slot));
+
+        for step in 0..step_count.max(1) {
+            // Done rows see a NaN index, which keeps any aggregate in their term empty.
+            let index_column = &mut self.locals[index_slot - self.inputs.len()];
+            for ((value, from), term_count) in index_column.iter_mut().zip(&from).zip(&term_counts) {
+                *value = if step < *term_count { from + step as f64 } else { f64::NAN };
+            }
+
+            let term = self.number(term, &term_scope)?;
+
+            for ((result, term), term_count) in result.iter_mut().zip(&term).zip(&term_counts) {
+                if step < *term_count {
+                    *result = combine(*result, *term);
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn compare<'a>(
+        &mut self,
+        lhs: &'a Expr,
+        rhs: &'a Expr,
+        relation: impl Fn(f64, f64) -> bool,
+        scope: &Scope<'a>,
+    ) -> Result<Vec<bool>, EvalError> {
+        let lhs = self.number(lhs, scope)?;
+        let rhs = self.number(rhs, scope)?;
+
+        Ok(lhs.iter().zip(&rhs).map(|(lhs, rhs)| relation(*lhs, *rhs)).collect())
+    }
+
+    fn condition<'a>(&mut self, ast: &'a Expr, scope: &Scope<'a>) -> Result<Vec<bool>, EvalError> {
+        let column = match &ast.kind {
+            ExprKind::Eq(lhs, rhs) => {
+                let threshold = self.equality_approximate_threshold;
+                self.compare(lhs, rhs, |lhs, rhs| f64::abs(lhs - rhs) < threshold, scope)?
+            },
+            ExprKind::Lt(lhs, rhs) => self.compare(lhs, rhs, |lhs, rhs| lhs < rhs, scope)?,
+            ExprKind::Gt(lhs, rhs) => self.compare(lhs, rhs, |lhs, rhs| lhs > rhs, scope)?,
+            ExprKind::Le(lhs, rhs) => self.compare(lhs, rhs, |lhs, rhs| lhs <= rhs, scope)?,
+            ExprKind::Ge(lhs, rhs) => self.compare(lhs, rhs, |lhs, rhs| lhs >= rhs, scope)?,
+            ExprKind::And(lhs, rhs) => {
+                let mut lhs = self.condition(lhs, scope)?;
+                let rhs = self.condition(rhs, scope)?;
+                for (lhs, rhs) in lhs.iter_mut().zip(&rhs) {
+                    *lhs &= *rhs;
+                }
+                lhs
+            },
+            ExprKind::Or(lhs, rhs) => {
+                let mut lhs = self.condition(lhs, scope)?;
+                let rhs = self.condition(rhs, scope)?;
+                for (lhs, rhs) in lhs.iter_mut().zip(&rhs) {
+                    *lhs |= *rhs;
+                }
+                lhs
+            },
+            ExprKind::Not(expr) => {
+                let mut column = self.condition(expr, scope)?;
+                for value in column.iter_mut() {
+                    *value = !*value;
+                }
+                column
+            },
+            ExprKind::System(equations) => {
+                let mut column = vec![true; self.len];
+                for equation in equations {
+                    let equation = self.condition(equation, scope)?;
+                    for (value, equation) in column.iter_mut().zip(&equation) {
+                        *value &= *equation;
+                    }
+                }
+                column
+            },
+            ExprKind::Let(name, value, body) => {
+                let value = self.number(value, scope)?;
+
+                let slot = self.bind(value);
+                let result = self.condition(body, &scope.with_variable(name, Variable::Slot(slot)));
+                self.unbind(slot);
+                result?
+            },
+            ExprKind::LetFn(name, parameters, value, body) => {
+                self.condition(body, &scope.with_function(name, parameters, value))?
+            },
+            ExprKind::Error => return Err(EvalError::Erroneous { span: ast.span }),
+            _ => return Err(EvalError::NumberInBooleanContext { span: ast.span }),
+        };
+
+        Ok(column)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{constants, eval_at, parse, same, EXPRESSIONS, INPUTS, OUT_OF_RANGE_BOUNDS};
+
+    fn eval_batch(input: &str, xs: &[f64]) -> Vec<f64> {
+        let mut output = vec![0.0; xs.len()];
+        eval_number_batch(&parse(input), &[("x", xs)], &constants(), 0.0, &mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn batch_agrees_with_eval_number() {
+        for input in EXPRESSIONS {
+            let ast = parse(input);
+
+            for (x, found) in INPUTS.iter().zip(eval_batch(input, INPUTS)) {
+                let expected = eval_at(&ast, *x);
+                assert!(same(expected, found), "{} at {}: expected {}, found {}", input, x, expected, found);
+            }
+        }
+    }
+
+    #[test]
+    fn aggregate_rows_have_their_own_term_counts() {
+        assert_eq!(eval_batch("sum(k, 1, x, k)", &[0.0, 1.0, 4.0, 2.5]), [0.0, 1.0, 10.0, 3.0]);
+
+        let found = eval_batch("prod(k, x, 3, k)", &[1e17, 1.0, f64::NAN, 4.0]);
+        assert!(found[0].is_nan() && found[2].is_nan());
+        assert_eq!(found[1], 6.0);
+        assert_eq!(found[3], 1.0);
+    }
+
+    #[test]
+    fn aggregate_with_out_of_range_bounds_is_nan() {
+        for (input, x, expected) in OUT_OF_RANGE_BOUNDS {
+            let found = eval_batch(input, &[*x])[0];
+            assert!(same(*expected, found), "{} at {}: expected {}, found {}", input, x, expected, found);
+        }
+    }
+
+    #[test]
+    fn term_errors_do_not_depend_on_the_bounds() {
+        let mut output = [0.0];
+        let error = eval_number_batch(&parse("sum(k, 1, 0, y)"), &[("x", &[1.0])], &constants(), 0.0, &mut output);
+        assert!(matches!(error, Err(EvalError::UnknownVariable { name, .. }) if name == "y"));
+    }
+
+    #[test]
+    fn columns_bind_variables() {
+        let mut output = [0.0; 2];
+        eval_number_batch(&parse("x - y"), &[("y", &[1.0, 2.0]), ("x", &[10.0, 20.0])], &constants(), 0.0, &mut output)
+            .unwrap();
+        assert_eq!(output, [9.0, 18.0]);
+    }
+}
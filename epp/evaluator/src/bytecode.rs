@@ -0,0 +1,711 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use ast::{Expr, ExprKind};
+use serde::{Deserialize, Serialize};
+
+use crate::compile::{Scope, Variable};
+use crate::{aggregate_term_count, arity_mismatch, builtin, Builtin, EvalError};
+
+/// An instruction of a `Program`, which works on a stack of numbers and the slots of a frame.
+///
+/// Relations and logical operators push 1 for true and 0 for false, and conditional jumps
+/// treat any number but 0 as true.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", content = "args", rename_all = "snake_case")]
+pub enum Instruction {
+    /// Pushes a number.
+    Constant(#[serde(with = "ast::literal_serde")] f64),
+    /// Pushes the value of a slot.
+    Load(u32),
+    /// Pops a value into a slot.
+    Store(u32),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Neg,
+    /// Calls the built-in function of the given index in `Program::functions`, popping as many
+    /// arguments as it takes.
+    Call(u32),
+    /// Whether two numbers differ by less than the equality threshold of the program.
+    Eq,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    And,
+    Or,
+    Not,
+    /// Pushes the number of terms of a `sum` or `prod` whose bounds are in the two slots, or NaN
+    /// if the evaluator rejects the bounds.
+    TermCount(u32, u32),
+    /// Continues at the given instruction.
+    Jump(u32),
+    /// Pops a condition and continues at the given instruction if it is false.
+    JumpIfFalse(u32),
+}
+
+/// A number expression lowered to stack-based bytecode.
+///
+/// Programs are serializable, so they can be cached and shipped; a deserialized program is
+/// checked to keep every jump, slot, function and stack access in bounds, though a program
+/// which was not lowered by `compile_number_bytecode` may loop forever. Its `Display` is a
+/// disassembly, one instruction per line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "ProgramData", into = "ProgramData")]
+pub struct Program {
+    instructions: Vec<Instruction>,
+    /// Names of the slots, the variables first.
+    slots: Vec<String>,
+    variable_count: usize,
+    functions: Vec<String>,
+    equality_approximate_threshold: f64,
+    /// Resolved `functions`.
+    builtins: Vec<Builtin>,
+    /// Deepest the stack gets.
+    stack_size: usize,
+}
+
+/// The serialized form of a `Program`.
+#[derive(Serialize, Deserialize)]
+struct ProgramData {
+    variable_count: usize,
+    slots: Vec<String>,
+    functions: Vec<String>,
+    #[serde(with = "ast::literal_serde")]
+    equality_approximate_threshold: f64,
+    instructions: Vec<Instruction>,
+}
+
+impl From<Program> for ProgramData {
+    fn from(program: Program) -> Self {
+        ProgramData {
+            variable_count: program.variable_count,
+            slots: program.slots,
+            functions: program.functions,
+            equality_approximate_threshold: program.equality_approximate_threshold,
+            instructions: program.instructions,
+        }
+    }
+}
+
+impl TryFrom<ProgramData> for Program {
+    type Error = String;
+
+    fn try_from(data: ProgramData) -> Result<Self, String> {
+        if data.variable_count > data.slots.len() {
+            return Err(format!("{} variables but only {} slots", data.variable_count, data.slots.len()));
+        }
+
+        let builtins = data
+            .functions
+            .iter()
+            .map(|name| builtin(name).ok_or_else(|| format!("unknown function `{}`", name)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let stack_size = stack_size(&data.instructions, data.slots.len(), &builtins)?;
+
+        Ok(Program {
+            instructions: data.instructions,
+            slots: data.slots,
+            variable_count: data.variable_count,
+            functions: data.functions,
+            equality_approximate_threshold: data.equality_approximate_threshold,
+            builtins,
+            stack_size,
+        })
+    }
+}
+
+/// Follows every path through the instructions to find how deep the stack gets, failing if a
+/// path pops more than it pushed, reaches an instruction with two stack depths or ends with
+/// anything but the result on the stack.
+fn stack_size(instructions: &[Instruction], slot_count: usize, builtins: &[Builtin]) -> Result<usize, String> {
+    let mut depths: Vec<Option<usize>> = vec![None; instructions.len() + 1];
+    let mut pending = vec![(0, 0)];
+    let mut stack_size = 0;
+
+    while let Some((at, depth)) = pending.pop() {
+        match depths[at] {
+            Some(known) if known == depth => continue,
+            Some(known) => return Err(format!("stack depth at {:04} is both {} and {}", at, known, depth)),
+            None => depths[at] = Some(depth),
+        }
+
+        let instruction = match instructions.get(at) {
+            Some(instruction) => *instruction,
+            None if depth == 1 => continue,
+            None => return Err(format!("program ends with {} values on the stack", depth)),
+        };
+
+        let slot = |slot: u32| {
+            if (slot as usize) < slot_count {
+                Ok(())
+            } else {
+                Err(format!("slot {} at {:04} is out of bounds", slot, at))
+            }
+        };
+        let target = |target: u32| {
+            if (target as usize) <= instructions.len() {
+                Ok(target as usize)
+            } else {
+                Err(format!("jump to {:04} at {:04} is out of bounds", target, at))
+            }
+        };
+
+        let (pops, pushes) = match instruction {
+            Instruction::Constant(_) => (0, 1),
+            Instruction::Load(index) => {
+                slot(index)?;
+                (0, 1)
+            },
+            Instruction::Store(index) => {
+                slot(index)?;
+                (1, 0)
+            },
+            Instruction::Call(function) => match builtins.get(function as usize) {
+                Some(builtin) => (builtin.arity(), 1),
+                None => return Err(format!("function {} at {:04} is out of bounds", function, at)),
+            },
+            Instruction::TermCount(from, to) => {
+                slot(from)?;
+                slot(to)?;
+                (0, 1)
+            },
+            Instruction::Neg | Instruction::Not => (1, 1),
+            Instruction::Jump(_) | Instruction::JumpIfFalse(_) => (0, 0),
+            _ => (2, 1),
+        };
+
+        let depth = match instruction {
+            Instruction::JumpIfFalse(_) if depth == 0 => None,
+            Instruction::JumpIfFalse(_) => Some(depth - 1),
+            _ if depth < pops => None,
+            _ => Some(depth - pops + pushes),
+        };
+        let depth = depth.ok_or_else(|| format!("stack underflow at {:04}", at))?;
+        stack_size = stack_size.max(depth);
+
+        match instruction {
+            Instruction::Jump(to) => pending.push((target(to)?, depth)),
+            Instruction::JumpIfFalse(to) => {
+                pending.push((target(to)?, depth));
+                pending.push((at + 1, depth));
+            },
+            _ => pending.push((at + 1, depth)),
+        }
+    }
+
+    Ok(stack_size)
+}
+
+impl Program {
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    /// Names of the built-in functions, indexed by `Instruction::Call`.
+    pub fn functions(&self) -> &[String] {
+        &self.functions
+    }
+
+    pub fn variable_count(&self) -> usize {
+        self.variable_count
+    }
+
+    /// Runs the program with the values of the variables, in the order they were given to
+    /// `compile_number_bytecode`.
+    ///
+    /// Like in the emitted JS, a `sum` or `prod` whose bounds are out of range evaluates to NaN.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of values is not the number of variables.
+    pub fn run(&self, variables: &[f64]) -> f64 {
+        let mut output = [0.0];
+        self.run_batch(variables, &mut output);
+        output[0]
+    }
+
+    /// Runs the program once for each output, with the values of the variables for the n-th
+    /// output at `inputs[n * variable_count..(n + 1) * variable_count]`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of inputs is not the number of outputs times the number of variables.
+    pub fn run_batch(&self, inputs: &[f64], outputs: &mut [f64]) {
+        assert_eq!(inputs.len(), outputs.len() * self.variable_count, "wrong number of variable values");
+
+        let mut stack = Vec::with_capacity(self.stack_size);
+        let mut frame = vec![0.0; self.slots.len()];
+
+        for (n, output) in outputs.iter_mut().enumerate() {
+            frame[..self.variable_count].copy_from_slice(&inputs[n * self.variable_count..(n + 1) * self.variable_count]);
+            *output = self.execute(&mut stack, &mut frame);
+        }
+    }
+
+    fn execute(&self, stack: &mut Vec<f64>, frame: &mut [f64]) -> f64 {
+        fn pop(stack: &mut Vec<f64>) -> f64 {
+            stack.pop().expect("stack underflow in a checked program")
+        }
+
+        fn binary(stack: &mut Vec<f64>, operator: impl Fn(f64, f64) -> f64) {
+            let rhs = pop(stack);
+            let lhs = pop(stack);
+            stack.push(operator(lhs, rhs));
+        }
+
+        fn truth(condition: bool) -> f64 {
+            if condition { 1.0 } else { 0.0 }
+        }
+
+        stack.clear();
+        let mut at = 0;
+
+        while let Some(instruction) = self.instructions.get(at) {
+            at += 1;
+
+            match *instruction {
+                Instruction::Constant(value) => stack.push(value),
+                Instruction::Load(slot) => stack.push(frame[slot as usize]),
+                Instruction::Store(slot) => frame[slot as usize] = pop(stack),
+                Instruction::Add => binary(stack, |lhs, rhs| lhs + rhs),
+                Instruction::Sub => binary(stack, |lhs, rhs| lhs - rhs),
+                Instruction::Mul => binary(stack, |lhs, rhs| lhs * rhs),
+                Instruction::Div => binary(stack, |lhs, rhs| lhs / rhs),
+                Instruction::Mod => binary(stack, |lhs, rhs| lhs % rhs),
+                Instruction::Pow => binary(stack, f64::powf),
+                Instruction::Neg => {
+                    let value = pop(stack);
+                    stack.push(-value);
+                },
+                Instruction::Call(function) => match self.builtins[function as usize] {
+                    Builtin::Unary(function) => {
+                        let arg = pop(stack);
+                        stack.push(function(arg));
+                    },
+                    Builtin::Binary(function) => binary(stack, function),
+                },
+                Instruction::Eq => {
+                    let threshold = self.equality_approximate_threshold;
+                    binary(stack, |lhs, rhs| truth(f64::abs(lhs - rhs) < threshold));
+                },
+                Instruction::Lt => binary(stack, |lhs, rhs| truth(lhs < rhs)),
+                Instruction::Gt => binary(stack, |lhs, rhs| truth(lhs > rhs)),
+                Instruction::Le => binary(stack, |lhs, rhs| truth(lhs <= rhs)),
+                Instruction::Ge => binary(stack, |lhs, rhs| truth(lhs >= rhs)),
+                Instruction::And => binary(stack, |lhs, rhs| truth(lhs != 0.0 && rhs != 0.0)),
+                Instruction::Or => binary(stack, |lhs, rhs| truth(lhs != 0.0 || rhs != 0.0)),
+                Instruction::Not => {
+                    let value = pop(stack);
+                    stack.push(truth(value == 0.0));
+                },
+                Instruction::TermCount(from, to) => {
+                    let term_count = aggregate_term_count(frame[from as usize], frame[to as usize]);
+                    stack.push(term_count.map_or(f64::NAN, |term_count| term_count as f64));
+                },
+                Instruction::Jump(target) => at = target as usize,
+                Instruction::JumpIfFalse(target) => {
+                    if pop(stack) == 0.0 {
+                        at = target as usize;
+                    }
+                },
+            }
+        }
+
+        pop(stack)
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "; {} variable(s), {} slot(s), stack size {}, equality threshold {}",
+            self.variable_count,
+            self.slots.len(),
+            self.stack_size,
+            self.equality_approximate_threshold,
+        )?;
+
+        for (at, instruction) in self.instructions.iter().enumerate() {
+            write!(f, "{:04}  ", at)?;
+
+            let slot = |slot: u32| &self.slots[slot as usize];
+
+            match *instruction {
+                Instruction::Constant(value) => writeln!(f, "constant {}", value)?,
+                Instruction::Load(index) => writeln!(f, "load {} ({})", index, slot(index))?,
+                Instruction::Store(index) => writeln!(f, "store {} ({})", index, slot(index))?,
+                Instruction::Call(function) => writeln!(f, "call {} ({})", function, self.functions[function as usize])?,
+                Instruction::TermCount(from, to) => {
+                    writeln!(f, "term_count {} {} ({}, {})", from, to, slot(from), slot(to))?
+                },
+                Instruction::Jump(target) => writeln!(f, "jump {:04}", target)?,
+                Instruction::JumpIfFalse(target) => writeln!(f, "jump_if_false {:04}", target)?,
+                instruction => writeln!(f, "{}", format!("{:?}", instruction).to_lowercase())?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Lowers a number expression of the given variables to bytecode, with the constants
+/// substituted.
+///
+/// Like `compile_number_expr`, this reports unknown names, arity mismatches and misplaced
+/// relations anywhere in the expression, and inlines defined functions at each call.
+pub fn compile_number_bytecode(
+    ast: &Expr,
+    variables: &[&str],
+    constants: &HashMap<String, f64>,
+    equality_approximate_threshold: f64,
+) -> Result<Program, EvalError> {
+    let mut assembler = Assembler {
+        instructions: Vec::new(),
+        slots: variables.iter().map(|name| name.to_string()).collect(),
+        functions: Vec::new(),
+    };
+    assembler.number(ast, &Scope::new(variables, constants))?;
+
+    let data = ProgramData {
+        variable_count: variables.len(),
+        slots: assembler.slots,
+        functions: assembler.functions,
+        equality_approximate_threshold,
+        instructions: assembler.instructions,
+    };
+
+    Program::try_from(data).map_err(|error| EvalError::DomainError {
+        message: format!("expression cannot be lowered to bytecode: {}", error),
+        span: ast.span,
+    })
+}
+
+struct Assembler {
+    instructions: Vec<Instruction>,
+    slots: Vec<String>,
+    functions: Vec<String>,
+}
+
+impl Assembler {
+    /// Appends an instruction, returning its index.
+    fn emit(&mut self, instruction: Instruction) -> u32 {
+        self.instructions.push(instruction);
+        self.here() - 1
+    }
+
+    fn here(&self) -> u32 {
+        self.instructions.len() as u32
+    }
+
+    /// Points the jump at `at` to the next instruction to be emitted.
+    fn patch(&mut self, at: u32) {
+        let here = self.here();
+        match &mut self.instructions[at as usize] {
+            Instruction::Jump(target) | Instruction::JumpIfFalse(target) => *target = here,
+            _ => unreachable!("only jumps are patched"),
+        }
+    }
+
+    fn new_slot(&mut self, name: String) -> u32 {
+        self.slots.push(name);
+        self.slots.len() as u32 - 1
+    }
+
+    fn function(&mut self, name: &str) -> u32 {
+        match self.functions.iter().position(|function| function == name) {
+            Some(index) => index as u32,
+            None => {
+                self.functions.push(name.to_owned());
+                self.functions.len() as u32 - 1
+            },
+        }
+    }
+
+    fn binary<'a>(&mut self, lhs: &'a Expr, rhs: &'a Expr, operator: Instruction, scope: &Scope<'a>) -> Result<(), EvalError> {
+        self.number(lhs, scope)?;
+        self.number(rhs, scope)?;
+        self.emit(operator);
+        Ok(())
+    }
+
+    fn number<'a>(&mut self, ast: &'a Expr, scope: &Scope<'a>) -> Result<(), EvalError> {
+        match &ast.kind {
+            ExprKind::Literal(value) => {
+                self.emit(Instruction::Constant(*value));
+            },
+            ExprKind::Add(lhs, rhs) => self.binary(lhs, rhs, Instruction::Add, scope)?,
+            ExprKind::Sub(lhs, rhs) => self.binary(lhs, rhs, Instruction::Sub, scope)?,
+            ExprKind::Mul(lhs, rhs) => self.binary(lhs, rhs, Instruction::Mul, scope)?,
+            ExprKind::Div(lhs, rhs) => self.binary(lhs, rhs, Instruction::Div, scope)?,
+            ExprKind::Mod(lhs, rhs) => self.binary(lhs, rhs, Instruction::Mod, scope)?,
+            ExprKind::Pow(lhs, rhs) => self.binary(lhs, rhs, Instruction::Pow, scope)?,
+            ExprKind::Unary(expr) => {
+                self.number(expr, scope)?;
+                self.emit(Instruction::Neg);
+            },
+            ExprKind::If(condition, then, otherwise) => {
+                self.condition(condition, scope)?;
+                let to_otherwise = self.emit(Instruction::JumpIfFalse(0));
+                self.number(then, scope)?;
+                let to_end = self.emit(Instruction::Jump(0));
+                self.patch(to_otherwise);
+                self.number(otherwise, scope)?;
+                self.patch(to_end);
+            },
+            ExprKind::Id(name) => match scope.variables.get(name) {
+                Some(Variable::Slot(slot)) => {
+                    self.emit(Instruction::Load(*slot as u32));
+                },
+                Some(Variable::Constant(value)) => {
+                    self.emit(Instruction::Constant(*value));
+                },
+                None => return Err(EvalError::UnknownVariable { name: name.to_owned(), span: ast.span }),
+            },
+            ExprKind::Eq(..)
+            | ExprKind::Lt(..)
+            | ExprKind::Gt(..)
+            | ExprKind::Le(..)
+            | ExprKind::Ge(..)
+            | ExprKind::And(..)
+            | ExprKind::Or(..)
+            | ExprKind::Not(..)
+            | ExprKind::System(..) => return Err(EvalError::RelationInNumericContext { span: ast.span }),
+            ExprKind::Error => return Err(EvalError::Erroneous { span: ast.span }),
+            ExprKind::Sum(index, from, to, term) | ExprKind::Prod(index, from, to, term) => {
+                let (identity, combine, result) = match &ast.kind {
+                    ExprKind::Sum(..) => (0.0, Instruction::Add, "sum"),
+                    _ => (1.0, Instruction::Mul, "prod"),
+                };
+
+                // The index of the `i`-th term is `from + i`, as in the evaluator.
+                let index_slot = self.new_slot(index.to_owned());
+                let from_slot = self.new_slot(format!("{}.from", index));
+                let to_slot = self.new_slot(format!("{}.to", index));
+                let count_slot = self.new_slot(format!("{}.count", index));
+                let i_slot = self.new_slot(format!("{}.i", index));
+                let result_slot = self.new_slot(format!("{}.{}", index, result));
+
+                self.number(from, scope)?;
+                self.emit(Instruction::Store(from_slot));
+                self.number(to, scope)?;
+                self.emit(Instruction::Store(to_slot));
+                self.emit(Instruction::TermCount(from_slot, to_slot));
+                self.emit(Instruction::Store(count_slot));
+                self.emit(Instruction::Load(count_slot));
+                self.emit(Instruction::Constant(0.0));
+                self.emit(Instruction::Ge);
+                let to_out_of_range = self.emit(Instruction::JumpIfFalse(0));
+                self.emit(Instruction::Constant(0.0));
+                self.emit(Instruction::Store(i_slot));
+                self.emit(Instruction::Constant(identity));
+                self.emit(Instruction::Store(result_slot));
+
+                let start = self.here();
+                self.emit(Instruction::Load(i_slot));
+                self.emit(Instruction::Load(count_slot));
+                self.emit(Instruction::Lt);
+                let to_done = self.emit(Instruction::JumpIfFalse(0));
+                self.emit(Instruction::Load(from_slot));
+                self.emit(Instruction::Load(i_slot));
+                self.emit(Instruction::Add);
+                self.emit(Instruction::Store(index_slot));
+                self.emit(Instruction::Load(result_slot));
+                self.number(term, &scope.with_variable(index, Variable::Slot(index_slot as usize)))?;
+                self.emit(combine);
+                self.emit(Instruction::Store(result_slot));
+                self.emit(Instruction::Load(i_slot));
+                self.emit(Instruction::Constant(1.0));
+                self.emit(Instruction::Add);
+                self.emit(Instruction::Store(i_slot));
+                self.emit(Instruction::Jump(start));
+
+                self.patch(to_done);
+                self.emit(Instruction::Load(result_slot));
+                let to_end = self.emit(Instruction::Jump(0));
+                self.patch(to_out_of_range);
+                self.emit(Instruction::Constant(f64::NAN));
+                self.patch(to_end);
+            },
+            ExprKind::Let(name, value, body) => {
+                self.number(value, scope)?;
+                let slot = self.new_slot(name.to_owned());
+                self.emit(Instruction::Store(slot));
+                self.number(body, &scope.with_variable(name, Variable::Slot(slot as usize)))?;
+            },
+            ExprKind::LetFn(name, parameters, value, body) => {
+                self.number(body, &scope.with_function(name, parameters, value))?;
+            },
+            ExprKind::Call(name, args) => {
+                for arg in args {
+                    self.number(arg, scope)?;
+                }
+
+                if let Some(function) = scope.functions.get(name) {
+                    if args.len() != function.parameters.len() {
+                        return Err(arity_mismatch(name, function.parameters.len(), args.len(), ast.span));
+                    }
+
+                    // The arguments are on the stack in order, so the last one is stored first.
+                    let mut function_scope = function.scope.clone();
+                    let slots = function
+                        .parameters
+                        .iter()
+                        .map(|parameter| {
+                            let slot = self.new_slot(parameter.to_owned());
+                            function_scope.variables.insert(parameter.to_owned(), Variable::Slot(slot as usize));
+                            slot
+                        })
+                        .collect::<Vec<_>>();
+                    for slot in slots.into_iter().rev() {
+                        self.emit(Instruction::Store(slot));
+                    }
+
+                    return self.number(function.body, &function_scope);
+                }
+
+                match builtin(name) {
+                    Some(builtin) if builtin.arity() == args.len() => {
+                        let function = self.function(name);
+                        self.emit(Instruction::Call(function));
+                    },
+                    Some(builtin) => return Err(arity_mismatch(name, builtin.arity(), args.len(), ast.span)),
+                    None => return Err(EvalError::UnknownFunction { name: name.to_owned(), span: ast.span }),
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    fn condition<'a>(&mut self, ast: &'a Expr, scope: &Scope<'a>) -> Result<(), EvalError> {
+        match &ast.kind {
+            ExprKind::Eq(lhs, rhs) => self.binary(lhs, rhs, Instruction::Eq, scope)?,
+            ExprKind::Lt(lhs, rhs) => self.binary(lhs, rhs, Instruction::Lt, scope)?,
+            ExprKind::Gt(lhs, rhs) => self.binary(lhs, rhs, Instruction::Gt, scope)?,
+            ExprKind::Le(lhs, rhs) => self.binary(lhs, rhs, Instruction::Le, scope)?,
+            ExprKind::Ge(lhs, rhs) => self.binary(lhs, rhs, Instruction::Ge, scope)?,
+            ExprKind::And(lhs, rhs) => {
+                self.condition(lhs, scope)?;
+                self.condition(rhs, scope)?;
+                self.emit(Instruction::And);
+            },
+            ExprKind::Or(lhs, rhs) => {
+                self.condition(lhs, scope)?;
+                self.condition(rhs, scope)?;
+                self.emit(Instruction::Or);
+            },
+            ExprKind::Not(expr) => {
+                self.condition(expr, scope)?;
+                self.emit(Instruction::Not);
+            },
+            ExprKind::System(equations) => {
+                self.emit(Instruction::Constant(1.0));
+                for equation in equations {
+                    self.condition(equation, scope)?;
+                    self.emit(Instruction::And);
+                }
+            },
+            ExprKind::Let(name, value, body) => {
+                self.number(value, scope)?;
+                let slot = self.new_slot(name.to_owned());
+                self.emit(Instruction::Store(slot));
+                self.condition(body, &scope.with_variable(name, Variable::Slot(slot as usize)))?;
+            },
+            ExprKind::LetFn(name, parameters, value, body) => {
+                self.condition(body, &scope.with_function(name, parameters, value))?;
+            },
+            ExprKind::Error => return Err(EvalError::Erroneous { span: ast.span }),
+            _ => return Err(EvalError::NumberInBooleanContext { span: ast.span }),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{constants, eval_at, parse, same, EXPRESSIONS, INPUTS, OUT_OF_RANGE_BOUNDS};
+
+    fn program(input: &str) -> Program {
+        compile_number_bytecode(&parse(input), &["x"], &constants(), 0.0).unwrap()
+    }
+
+    #[test]
+    fn program_agrees_with_eval_number() {
+        for input in EXPRESSIONS {
+            let ast = parse(input);
+            let program = program(input);
+            let mut outputs = vec![0.0; INPUTS.len()];
+            program.run_batch(INPUTS, &mut outputs);
+
+            for (x, found) in INPUTS.iter().zip(outputs) {
+                let expected = eval_at(&ast, *x);
+                assert!(same(expected, found), "{} at {}: expected {}, found {}", input, x, expected, found);
+                assert!(same(expected, program.run(&[*x])), "{} at {}", input, x);
+            }
+        }
+    }
+
+    #[test]
+    fn aggregate_with_out_of_range_bounds_is_nan() {
+        for (input, x, expected) in OUT_OF_RANGE_BOUNDS {
+            let found = program(input).run(&[*x]);
+            assert!(same(*expected, found), "{} at {}: expected {}, found {}", input, x, expected, found);
+        }
+    }
+
+    #[test]
+    fn program_round_trips_through_json() {
+        for input in EXPRESSIONS {
+            let program = program(input);
+            let json = serde_json::to_string(&program).unwrap();
+            let deserialized: Program = serde_json::from_str(&json).unwrap();
+
+            // Instructions are compared through JSON, since `Constant(NaN)` is not equal to itself.
+            assert_eq!(serde_json::to_string(&deserialized).unwrap(), json);
+            assert_eq!(deserialized.to_string(), program.to_string());
+        }
+    }
+
+    #[test]
+    fn deserialization_checks_the_program() {
+        let program = |instructions: &str| {
+            let json = format!(
+                r#"{{"variable_count": 1, "slots": ["x"], "functions": ["sin"],
+                "equality_approximate_threshold": 0, "instructions": {}}}"#,
+                instructions
+            );
+            serde_json::from_str::<Program>(&json).map_err(|error| error.to_string())
+        };
+
+        assert!(program(r#"[{"op": "load", "args": 0}, {"op": "call", "args": 0}]"#).is_ok());
+        assert!(program(r#"[{"op": "load", "args": 1}]"#).unwrap_err().contains("slot 1 at 0000 is out of bounds"));
+        assert!(program(r#"[{"op": "call", "args": 1}]"#).unwrap_err().contains("function 1"));
+        assert!(program(r#"[{"op": "add"}]"#).unwrap_err().contains("stack underflow at 0000"));
+        assert!(program(r#"[{"op": "jump", "args": 9}]"#).unwrap_err().contains("out of bounds"));
+        assert!(program(r#"[{"op": "load", "args": 0}, {"op": "load", "args": 0}]"#)
+            .unwrap_err()
+            .contains("program ends with 2 values on the stack"));
+    }
+
+    #[test]
+    fn disassembly() {
+        assert_eq!(
+            program("sin(x) + 2").to_string(),
+            "; 1 variable(s), 1 slot(s), stack size 2, equality threshold 0\n\
+            0000  load 0 (x)\n\
+            0001  call 0 (sin)\n\
+            0002  constant 2\n\
+            0003  add\n"
+        );
+    }
+}
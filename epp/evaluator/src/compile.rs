@@ -0,0 +1,390 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use ast::{Expr, ExprKind};
+
+use crate::{aggregate_term_count, arity_mismatch, builtin, Builtin, EvalError};
+
+type Number = Box<dyn Fn(&mut [f64]) -> f64>;
+type Condition = Box<dyn Fn(&mut [f64]) -> bool>;
+
+/// A number expression compiled for repeated evaluation.
+///
+/// Names are resolved once by `compile_number_expr`: variables to slots of a frame, constants
+/// to their values and built-in functions to function pointers, so `eval` neither allocates
+/// nor looks anything up.
+pub struct CompiledExpr {
+    root: Number,
+    variable_count: usize,
+    /// Values of the variables, followed by those of the definitions, parameters and indices.
+    frame: RefCell<Vec<f64>>,
+}
+
+impl CompiledExpr {
+    /// Evaluates the expression with the values of the variables, in the order they were given
+    /// to `compile_number_expr`.
+    ///
+    /// Like in the emitted JS, a `sum` or `prod` whose bounds are out of range evaluates to NaN.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of values is not the number of variables.
+    pub fn eval(&self, variables: &[f64]) -> f64 {
+        assert_eq!(variables.len(), self.variable_count, "wrong number of variable values");
+
+        let mut frame = self.frame.borrow_mut();
+        frame[..self.variable_count].copy_from_slice(variables);
+        (self.root)(&mut frame)
+    }
+}
+
+/// Compiles a number expression of the given variables, with the constants substituted.
+///
+/// Unknown names, arity mismatches and misplaced relations are reported here, including in
+/// branches of an `if` which `eval_number` would skip.
+pub fn compile_number_expr(
+    ast: &Expr,
+    variables: &[&str],
+    constants: &HashMap<String, f64>,
+    equality_approximate_threshold: f64,
+) -> Result<CompiledExpr, EvalError> {
+    let scope = Scope::new(variables, constants);
+
+    let mut compiler = Compiler {
+        slot_count: variables.len(),
+        equality_approximate_threshold,
+    };
+    let root = compiler.number(ast, &scope)?;
+
+    Ok(CompiledExpr {
+        root,
+        variable_count: variables.len(),
+        frame: RefCell::new(vec![0.0; compiler.slot_count]),
+    })
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Variable {
+    Slot(usize),
+    Constant(f64),
+}
+
+/// Names in scope while compiling, like `Environment` while evaluating.
+#[derive(Clone)]
+pub(crate) struct Scope<'a> {
+    pub(crate) variables: HashMap<String, Variable>,
+    pub(crate) functions: HashMap<String, Rc<Function<'a>>>,
+}
+
+/// A function defined by `ExprKind::LetFn`, compiled again at each call.
+pub(crate) struct Function<'a> {
+    pub(crate) parameters: &'a [String],
+    pub(crate) body: &'a Expr,
+    pub(crate) scope: Scope<'a>,
+}
+
+impl<'a> Scope<'a> {
+    /// The constants, shadowed by the variables in slots numbered in order.
+    pub(crate) fn new(variables: &[&str], constants: &HashMap<String, f64>) -> Self {
+        let mut scope = Scope {
+            variables: constants.iter().map(|(name, value)| (name.to_owned(), Variable::Constant(*value))).collect(),
+            functions: HashMap::new(),
+        };
+        for (slot, name) in variables.iter().enumerate() {
+            scope.variables.insert(name.to_string(), Variable::Slot(slot));
+        }
+        scope
+    }
+
+    pub(crate) fn with_variable(&self, name: &str, variable: Variable) -> Self {
+        let mut scope = self.clone();
+        scope.variables.insert(name.to_string(), variable);
+        scope
+    }
+
+    pub(crate) fn with_function(&self, name: &str, parameters: &'a [String], body: &'a Expr) -> Self {
+        let function = Function {
+            parameters,
+            body,
+            scope: self.clone(),
+        };
+
+        let mut scope = self.clone();
+        scope.functions.insert(name.to_string(), Rc::new(function));
+        scope
+    }
+}
+
+struct Compiler {
+    slot_count: usize,
+    equality_approximate_threshold: f64,
+}
+
+impl Compiler {
+    fn new_slot(&mut self) -> usize {
+        self.slot_count += 1;
+        self.slot_count - 1
+    }
+
+    fn number<'a>(&mut self, ast: &'a Expr, scope: &Scope<'a>) -> Result<Number, EvalError> {
+        let number: Number = match &ast.kind {
+            ExprKind::Literal(value) => {
+                let value = *value;
+                Box::new(move |_| value)
+            },
+            ExprKind::Add(lhs, rhs) => {
+                let (lhs, rhs) = (self.number(lhs, scope)?, self.number(rhs, scope)?);
+                Box::new(move |frame| lhs(frame) + rhs(frame))
+            },
+            ExprKind::Sub(lhs, rhs) => {
+                let (lhs, rhs) = (self.number(lhs, scope)?, self.number(rhs, scope)?);
+                Box::new(move |frame| lhs(frame) - rhs(frame))
+            },
+            ExprKind::Mul(lhs, rhs) => {
+                let (lhs, rhs) = (self.number(lhs, scope)?, self.number(rhs, scope)?);
+                Box::new(move |frame| lhs(frame) * rhs(frame))
+            },
+            ExprKind::Div(lhs, rhs) => {
+                let (lhs, rhs) = (self.number(lhs, scope)?, self.number(rhs, scope)?);
+                Box::new(move |frame| lhs(frame) / rhs(frame))
+            },
+            ExprKind::Mod(lhs, rhs) => {
+                let (lhs, rhs) = (self.number(lhs, scope)?, self.number(rhs, scope)?);
+                Box::new(move |frame| lhs(frame) % rhs(frame))
+            },
+            ExprKind::Pow(lhs, rhs) => {
+                let (lhs, rhs) = (self.number(lhs, scope)?, self.number(rhs, scope)?);
+                Box::new(move |frame| lhs(frame).powf(rhs(frame)))
+            },
+            ExprKind::Unary(expr) => {
+                let expr = self.number(expr, scope)?;
+                Box::new(move |frame| -expr(frame))
+            },
+            ExprKind::If(condition, then, otherwise) => {
+                let condition = self.condition(condition, scope)?;
+                let then = self.number(then, scope)?;
+                let otherwise = self.number(otherwise, scope)?;
+                Box::new(move |frame| if condition(frame) { then(frame) } else { otherwise(frame) })
+            },
+            ExprKind::Id(name) => match scope.variables.get(name) {
+                Some(Variable::Slot(slot)) => {
+                    let slot = *slot;
+                    Box::new(move |frame| frame[slot])
+                },
+                Some(Variable::Constant(value)) => {
+                    let value = *value;
+                    Box::new(move |_| value)
+                },
+                None => return Err(EvalError::UnknownVariable { name: name.to_owned(), span: ast.span }),
+            },
+            ExprKind::Eq(..)
+            | ExprKind::Lt(..)
+            | ExprKind::Gt(..)
+            | ExprKind::Le(..)
+            | ExprKind::Ge(..)
+            | ExprKind::And(..)
+            | ExprKind::Or(..)
+            | ExprKind::Not(..)
+            | ExprKind::System(..) => return Err(EvalError::RelationInNumericContext { span: ast.span }),
+            ExprKind::Error => return Err(EvalError::Erroneous { span: ast.span }),
+            ExprKind::Sum(index, from, to, term) | ExprKind::Prod(index, from, to, term) => {
+                let (identity, combine): (f64, fn(f64, f64) -> f64) = match &ast.kind {
+                    ExprKind::Sum(..) => (0.0, |lhs, rhs| lhs + rhs),
+                    _ => (1.0, |lhs, rhs| lhs * rhs),
+                };
+
+                let from = self.number(from, scope)?;
+                let to = self.number(to, scope)?;
+                let slot = self.new_slot();
+                let term = self.number(term, &scope.with_variable(index, Variable::Slot(slot)))?;
+
+                Box::new(move |frame| {
+                    let from = from(frame);
+                    let to = to(frame);
+
+                    let term_count = match aggregate_term_count(from, to) {
+                        Ok(term_count) => term_count,
+                        Err(_) => return f64::NAN,
+                    };
+
+                    let mut result = identity;
+
+                    for i in 0..term_count {
+                        frame[slot] = from + i as f64;
+                        result = combine(result, term(frame));
+                    }
+
+                    result
+                })
+            },
+            ExprKind::Let(name, value, body) => {
+                let value = self.number(value, scope)?;
+                let slot = self.new_slot();
+                let body = self.number(body, &scope.with_variable(name, Variable::Slot(slot)))?;
+
+                Box::new(move |frame| {
+                    frame[slot] = value(frame);
+                    body(frame)
+                })
+            },
+            ExprKind::LetFn(name, parameters, value, body) => {
+                self.number(body, &scope.with_function(name, parameters, value))?
+            },
+            ExprKind::Call(name, args) => {
+                let mut args = args.iter().map(|arg| self.number(arg, scope)).collect::<Result<Vec<_>, _>>()?;
+
+                if let Some(function) = scope.functions.get(name) {
+                    return self.call(name, function, args, ast);
+                }
+
+                match (builtin(name), args.len()) {
+                    (Some(Builtin::Unary(function)), 1) => {
+                        let arg = args.remove(0);
+                        Box::new(move |frame| function(arg(frame)))
+                    },
+                    (Some(Builtin::Binary(function)), 2) => {
+                        let rhs = args.remove(1);
+                        let lhs = args.remove(0);
+                        Box::new(move |frame| function(lhs(frame), rhs(frame)))
+                    },
+                    (Some(builtin), found) => return Err(arity_mismatch(name, builtin.arity(), found, ast.span)),
+                    (None, _) => return Err(EvalError::UnknownFunction { name: name.to_owned(), span: ast.span }),
+                }
+            },
+        };
+
+        Ok(number)
+    }
+
+    /// Inlines a call of a defined function, with its parameters in new slots.
+    fn call<'a>(&mut self, name: &str, function: &Function<'a>, args: Vec<Number>, ast: &Expr) -> Result<Number, EvalError> {
+        if args.len() != function.parameters.len() {
+            return Err(arity_mismatch(name, function.parameters.len(), args.len(), ast.span));
+        }
+
+        let mut scope = function.scope.clone();
+        let mut slots = Vec::with_capacity(args.len());
+        for parameter in function.parameters {
+            let slot = self.new_slot();
+            scope.variables.insert(parameter.to_owned(), Variable::Slot(slot));
+            slots.push(slot);
+        }
+        let body = self.number(function.body, &scope)?;
+
+        Ok(Box::new(move |frame| {
+            for (slot, arg) in slots.iter().zip(&args) {
+                frame[*slot] = arg(frame);
+            }
+            body(frame)
+        }))
+    }
+
+    fn condition<'a>(&mut self, ast: &'a Expr, scope: &Scope<'a>) -> Result<Condition, EvalError> {
+        let condition: Condition = match &ast.kind {
+            ExprKind::Eq(lhs, rhs) => {
+                let (lhs, rhs) = (self.number(lhs, scope)?, self.number(rhs, scope)?);
+                let threshold = self.equality_approximate_threshold;
+                Box::new(move |frame| f64::abs(lhs(frame) - rhs(frame)) < threshold)
+            },
+            ExprKind::Lt(lhs, rhs) => {
+                let (lhs, rhs) = (self.number(lhs, scope)?, self.number(rhs, scope)?);
+                Box::new(move |frame| lhs(frame) < rhs(frame))
+            },
+            ExprKind::Gt(lhs, rhs) => {
+                let (lhs, rhs) = (self.number(lhs, scope)?, self.number(rhs, scope)?);
+                Box::new(move |frame| lhs(frame) > rhs(frame))
+            },
+            ExprKind::Le(lhs, rhs) => {
+                let (lhs, rhs) = (self.number(lhs, scope)?, self.number(rhs, scope)?);
+                Box::new(move |frame| lhs(frame) <= rhs(frame))
+            },
+            ExprKind::Ge(lhs, rhs) => {
+                let (lhs, rhs) = (self.number(lhs, scope)?, self.number(rhs, scope)?);
+                Box::new(move |frame| lhs(frame) >= rhs(frame))
+            },
+            ExprKind::And(lhs, rhs) => {
+                let (lhs, rhs) = (self.condition(lhs, scope)?, self.condition(rhs, scope)?);
+                Box::new(move |frame| lhs(frame) && rhs(frame))
+            },
+            ExprKind::Or(lhs, rhs) => {
+                let (lhs, rhs) = (self.condition(lhs, scope)?, self.condition(rhs, scope)?);
+                Box::new(move |frame| lhs(frame) || rhs(frame))
+            },
+            ExprKind::Not(expr) => {
+                let expr = self.condition(expr, scope)?;
+                Box::new(move |frame| !expr(frame))
+            },
+            ExprKind::System(equations) => {
+                let equations = equations
+                    .iter()
+                    .map(|equation| self.condition(equation, scope))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Box::new(move |frame| equations.iter().all(|equation| equation(frame)))
+            },
+            ExprKind::Let(name, value, body) => {
+                let value = self.number(value, scope)?;
+                let slot = self.new_slot();
+                let body = self.condition(body, &scope.with_variable(name, Variable::Slot(slot)))?;
+
+                Box::new(move |frame| {
+                    frame[slot] = value(frame);
+                    body(frame)
+                })
+            },
+            ExprKind::LetFn(name, parameters, value, body) => {
+                self.condition(body, &scope.with_function(name, parameters, value))?
+            },
+            ExprKind::Error => return Err(EvalError::Erroneous { span: ast.span }),
+            _ => return Err(EvalError::NumberInBooleanContext { span: ast.span }),
+        };
+
+        Ok(condition)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{constants, eval_at, parse, same, EXPRESSIONS, INPUTS, OUT_OF_RANGE_BOUNDS};
+
+    #[test]
+    fn compiled_expr_agrees_with_eval_number() {
+        for input in EXPRESSIONS {
+            let ast = parse(input);
+            let compiled = compile_number_expr(&ast, &["x"], &constants(), 0.0).unwrap();
+
+            for x in INPUTS {
+                let expected = eval_at(&ast, *x);
+                let found = compiled.eval(&[*x]);
+                assert!(same(expected, found), "{} at {}: expected {}, found {}", input, x, expected, found);
+            }
+        }
+    }
+
+    #[test]
+    fn aggregate_with_out_of_range_bounds_is_nan() {
+        for (input, x, expected) in OUT_OF_RANGE_BOUNDS {
+            let found = compile_number_expr(&parse(input), &["x"], &constants(), 0.0).unwrap().eval(&[*x]);
+            assert!(same(*expected, found), "{} at {}: expected {}, found {}", input, x, expected, found);
+        }
+    }
+
+    #[test]
+    fn names_are_resolved_in_every_branch() {
+        let error = compile_number_expr(&parse("if(x < 0, x, y)"), &["x"], &constants(), 0.0).err();
+        assert!(matches!(error, Some(EvalError::UnknownVariable { name, .. }) if name == "y"));
+
+        let error = compile_number_expr(&parse("sin(x, 1)"), &["x"], &constants(), 0.0).err();
+        assert!(matches!(error, Some(EvalError::ArityMismatch { expected: 1, found: 2, .. })));
+
+        let error = compile_number_expr(&parse("(x < 1) + 1"), &["x"], &constants(), 0.0).err();
+        assert!(matches!(error, Some(EvalError::RelationInNumericContext { .. })));
+    }
+
+    #[test]
+    fn variables_are_given_in_order() {
+        let compiled = compile_number_expr(&parse("x - 2 * y"), &["y", "x"], &constants(), 0.0).unwrap();
+        assert_eq!(compiled.eval(&[1.0, 5.0]), 3.0);
+    }
+}
@@ -1,5 +1,18 @@
+mod batch;
+mod bytecode;
+mod compile;
+
 use std::collections::HashMap;
-use ast::Expr;
+use std::fmt;
+use std::rc::Rc;
+use ast::{
+    fold_children, Expr, ExprId, ExprKind, ExprNode, ExprPool, Folder, MAX_AGGREGATE_BOUND, MAX_AGGREGATE_TERM_COUNT,
+};
+use span::Span;
+
+pub use batch::eval_number_batch;
+pub use bytecode::{compile_number_bytecode, Instruction, Program};
+pub use compile::{compile_number_expr, CompiledExpr};
 
 #[derive(Debug)]
 pub struct EvalResult {
@@ -27,304 +40,959 @@ impl EvalResult {
     }
 }
 
+/// Why an expression can't be evaluated.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    /// A variable that is neither given a value nor defined.
+    UnknownVariable { name: String, span: Span },
+    /// A function that is neither built in nor defined.
+    UnknownFunction { name: String, span: Span },
+    ArityMismatch { name: String, expected: usize, found: usize, span: Span },
+    /// A comparison or logical operation where a number is expected, like `(x < 1) + 1`.
+    RelationInNumericContext { span: Span },
+    /// A number where a comparison or logical operation is expected, like `x + 1` as an equation.
+    NumberInBooleanContext { span: Span },
+    /// An operation outside of what can be evaluated, like a `sum` with infinitely many terms.
+    DomainError { message: String, span: Span },
+    /// A part of the input that failed to parse.
+    Erroneous { span: Span },
+}
+
+impl EvalError {
+    pub fn span(&self) -> Span {
+        match self {
+            EvalError::UnknownVariable { span, .. }
+            | EvalError::UnknownFunction { span, .. }
+            | EvalError::ArityMismatch { span, .. }
+            | EvalError::RelationInNumericContext { span }
+            | EvalError::NumberInBooleanContext { span }
+            | EvalError::DomainError { span, .. }
+            | EvalError::Erroneous { span } => *span,
+        }
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalError::UnknownVariable { name, .. } => write!(f, "unknown variable `{}`", name),
+            EvalError::UnknownFunction { name, .. } => write!(f, "unknown function `{}`", name),
+            EvalError::ArityMismatch { name, expected, found, .. } => {
+                write!(f, "function `{}` expects {} argument(s), found {}", name, expected, found)
+            },
+            EvalError::RelationInNumericContext { .. } => write!(f, "expected a number, found a relation"),
+            EvalError::NumberInBooleanContext { .. } => write!(f, "expected a relation, found a number"),
+            EvalError::DomainError { message, .. } => f.write_str(message),
+            EvalError::Erroneous { .. } => write!(f, "erroneous expression cannot be evaluated"),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+/// Values of the variables and the functions in scope.
+#[derive(Clone)]
+struct Environment<'a> {
+    globals: &'a HashMap<String, f64>,
+    /// Variables bound by definitions and function parameters, shadowing the globals.
+    locals: HashMap<String, f64>,
+    functions: HashMap<String, Rc<Function<'a>>>,
+}
+
+/// A function defined by `ExprKind::LetFn`, closed over the environment of its definition.
+struct Function<'a> {
+    parameters: &'a [String],
+    body: &'a Expr,
+    environment: Environment<'a>,
+}
+
+impl<'a> Environment<'a> {
+    fn new(globals: &'a HashMap<String, f64>) -> Self {
+        Self {
+            globals,
+            locals: HashMap::new(),
+            functions: HashMap::new(),
+        }
+    }
+
+    fn variable(&self, name: &str) -> Option<f64> {
+        self.locals.get(name).or_else(|| self.globals.get(name)).copied()
+    }
+
+    fn with_variable(&self, name: &str, value: f64) -> Self {
+        let mut environment = self.clone();
+        environment.locals.insert(name.to_string(), value);
+        environment
+    }
+
+    fn with_function(&self, name: &str, parameters: &'a [String], body: &'a Expr) -> Self {
+        let function = Function {
+            parameters,
+            body,
+            environment: self.clone(),
+        };
+
+        let mut environment = self.clone();
+        environment.functions.insert(name.to_string(), Rc::new(function));
+        environment
+    }
+}
+
 pub fn eval_equation(
     ast: &Expr,
     variables: &HashMap<String, f64>,
     equality_approximate_threshold: f64,
-) -> Result<EvalResult, ()> {
-    match ast {
-        Expr::Eq(lhs, rhs) => {
-            let lhs = fold_const_expr(lhs, variables);
-            let rhs = fold_const_expr(rhs, variables);
-            return Ok(
+) -> Result<EvalResult, EvalError> {
+    eval_equation_internal(ast, &Environment::new(variables), equality_approximate_threshold)
+}
+
+fn eval_equation_internal<'a>(
+    ast: &'a Expr,
+    environment: &Environment<'a>,
+    equality_approximate_threshold: f64,
+) -> Result<EvalResult, EvalError> {
+    match &ast.kind {
+        ExprKind::Eq(lhs, rhs) => {
+            let lhs = fold_const_expr(lhs, environment, equality_approximate_threshold)?;
+            let rhs = fold_const_expr(rhs, environment, equality_approximate_threshold)?;
+            Ok(
                 EvalResult {
                     rhs, op: ast.to_str(), lhs,
                     eval_result: f64::abs(lhs - rhs) < equality_approximate_threshold
                 }
-            );
+            )
         },
-        Expr::Lt(lhs, rhs) => {
-            let lhs = fold_const_expr(lhs, variables);
-            let rhs = fold_const_expr(rhs, variables);
-            return Ok(
+        ExprKind::Lt(lhs, rhs) => {
+            let lhs = fold_const_expr(lhs, environment, equality_approximate_threshold)?;
+            let rhs = fold_const_expr(rhs, environment, equality_approximate_threshold)?;
+            Ok(
                 EvalResult {
                     rhs, op: ast.to_str(), lhs,
                     eval_result: lhs < rhs
                 }
-            );
+            )
         },
-        Expr::Gt(lhs, rhs) => {
-            let lhs = fold_const_expr(lhs, variables);
-            let rhs = fold_const_expr(rhs, variables);
-            return Ok(
+        ExprKind::Gt(lhs, rhs) => {
+            let lhs = fold_const_expr(lhs, environment, equality_approximate_threshold)?;
+            let rhs = fold_const_expr(rhs, environment, equality_approximate_threshold)?;
+            Ok(
                 EvalResult {
                     rhs, op: ast.to_str(), lhs,
                     eval_result: lhs > rhs
                 }
-            );
+            )
         },
-        Expr::Le(lhs, rhs) => {
-            let lhs = fold_const_expr(lhs, variables);
-            let rhs = fold_const_expr(rhs, variables);
-            return Ok(
+        ExprKind::Le(lhs, rhs) => {
+            let lhs = fold_const_expr(lhs, environment, equality_approximate_threshold)?;
+            let rhs = fold_const_expr(rhs, environment, equality_approximate_threshold)?;
+            Ok(
                 EvalResult {
                     rhs, op: ast.to_str(), lhs,
                     eval_result: lhs <= rhs
                 }
-            );
+            )
         },
-        Expr::Ge(lhs, rhs) => {
-            let lhs = fold_const_expr(lhs, variables);
-            let rhs = fold_const_expr(rhs, variables);
-            return Ok(
+        ExprKind::Ge(lhs, rhs) => {
+            let lhs = fold_const_expr(lhs, environment, equality_approximate_threshold)?;
+            let rhs = fold_const_expr(rhs, environment, equality_approximate_threshold)?;
+            Ok(
                 EvalResult {
                     rhs, op: ast.to_str(), lhs,
                     eval_result: lhs >= rhs
                 }
-            );
+            )
         },
-        _ => unreachable!(),
-    }
-}
-
-fn fold_const_expr(ast: &Expr, variables: &HashMap<String, f64>) -> f64 {
-    match ast {
-        Expr::Literal(value) => value.clone(),
-        Expr::Add(lhs, rhs) => fold_const_expr(lhs, variables) + fold_const_expr(rhs, variables),
-        Expr::Sub(lhs, rhs) => fold_const_expr(lhs, variables) - fold_const_expr(rhs, variables),
-        Expr::Mul(lhs, rhs) => fold_const_expr(lhs, variables) * fold_const_expr(rhs, variables),
-        Expr::Div(lhs, rhs) => fold_const_expr(lhs, variables) / fold_const_expr(rhs, variables),
-        Expr::Mod(lhs, rhs) => fold_const_expr(lhs, variables) % fold_const_expr(rhs, variables),
-        Expr::Pow(lhs, rhs) => fold_const_expr(lhs, variables).powf(fold_const_expr(rhs, variables)),
-        Expr::Unary(expr) => -fold_const_expr(expr, variables),
-        Expr::Id(id) => {
-            if let Some(value) = variables.get(id) {
-                value.clone()
-            } else {
-                panic!("variable not found");
-            }
+        ExprKind::And(lhs, rhs) => {
+            let lhs = eval_equation_internal(lhs, environment, equality_approximate_threshold)?.eval_result;
+            let rhs = eval_equation_internal(rhs, environment, equality_approximate_threshold)?.eval_result;
+            Ok(
+                EvalResult {
+                    rhs: truth_value(rhs), op: ast.to_str(), lhs: truth_value(lhs),
+                    eval_result: lhs && rhs
+                }
+            )
         },
-        Expr::Eq(..)
-        | Expr::Lt(..)
-        | Expr::Gt(..)
-        | Expr::Le(..)
-        | Expr::Ge(..) => panic!("constant expression expected"),
-        Expr::Call(func_name, params) => {
-            let params = params.iter().map(|param| fold_const_expr(param, variables)).collect::<Vec<f64>>();
-            match func_name.as_str() {
-                "abs" => f64::abs(params[0]),
-                "acos" => f64::acos(params[0]),
-                "acosh" => f64::acosh(params[0]),
-                "asin" => f64::asin(params[0]),
-                "asinh" => f64::asinh(params[0]),
-                "atan" => f64::atan(params[0]),
-                "atan2" => f64::atan2(params[0], params[1]),
-                "atanh" => f64::atanh(params[0]),
-                "cbrt" => f64::cbrt(params[0]),
-                "ceil" => f64::ceil(params[0]),
-                "cos" => f64::cos(params[0]),
-                "cosh" => f64::cosh(params[0]),
-                "exp" => f64::exp(params[0]),
-                "exp_m1" => f64::exp_m1(params[0]),
-                "floor" => f64::floor(params[0]),
-                "hypot" => f64::hypot(params[0], params[1]),
-                "ln" => f64::ln(params[0]),
-                "ln_1p" => f64::ln_1p(params[0]),
-                "log" => f64::log(params[0], params[1]),
-                "log10" => f64::log10(params[0]),
-                "log2" => f64::log2(params[0]),
-                "max" => f64::max(params[0], params[1]),
-                "min" => f64::min(params[0], params[1]),
-                "pow" => f64::powf(params[0], params[1]),
-                "round" => f64::round(params[0]),
-                "sin" => f64::sin(params[0]),
-                "sinh" => f64::sinh(params[0]),
-                "sqrt" => f64::sqrt(params[0]),
-                "tan" => f64::tan(params[0]),
-                "tanh" => f64::tanh(params[0]),
-                _ => panic!("function not found"),
-            }
-        }
-    }
-}
-
-pub fn fold_expr(ast: &Expr) -> Box<Expr> {
-    match ast {
-        Expr::Eq(lhs, rhs) => {
-            let lhs = fold_expr(lhs);
-            let rhs = fold_expr(rhs);
-            return Box::new(Expr::Eq(lhs, rhs));
+        ExprKind::Or(lhs, rhs) => {
+            let lhs = eval_equation_internal(lhs, environment, equality_approximate_threshold)?.eval_result;
+            let rhs = eval_equation_internal(rhs, environment, equality_approximate_threshold)?.eval_result;
+            Ok(
+                EvalResult {
+                    rhs: truth_value(rhs), op: ast.to_str(), lhs: truth_value(lhs),
+                    eval_result: lhs || rhs
+                }
+            )
         },
-        Expr::Lt(lhs, rhs) => {
-            let lhs = fold_expr(lhs);
-            let rhs = fold_expr(rhs);
-            return Box::new(Expr::Lt(lhs, rhs));
+        ExprKind::Not(expr) => {
+            let operand = eval_equation_internal(expr, environment, equality_approximate_threshold)?.eval_result;
+            Ok(
+                EvalResult {
+                    rhs: truth_value(operand), op: ast.to_str(), lhs: f64::NAN,
+                    eval_result: !operand
+                }
+            )
         },
-        Expr::Gt(lhs, rhs) => {
-            let lhs = fold_expr(lhs);
-            let rhs = fold_expr(rhs);
-            return Box::new(Expr::Gt(lhs, rhs));
+        ExprKind::Let(name, value, body) => {
+            let value = fold_const_expr(value, environment, equality_approximate_threshold)?;
+            eval_equation_internal(body, &environment.with_variable(name, value), equality_approximate_threshold)
         },
-        Expr::Le(lhs, rhs) => {
-            let lhs = fold_expr(lhs);
-            let rhs = fold_expr(rhs);
-            return Box::new(Expr::Le(lhs, rhs));
+        ExprKind::LetFn(name, parameters, value, body) => {
+            let environment = environment.with_function(name, parameters, value);
+            eval_equation_internal(body, &environment, equality_approximate_threshold)
         },
-        Expr::Ge(lhs, rhs) => {
-            let lhs = fold_expr(lhs);
-            let rhs = fold_expr(rhs);
-            return Box::new(Expr::Ge(lhs, rhs));
+        ExprKind::System(_) => {
+            let results = eval_system_internal(ast, environment, equality_approximate_threshold)?;
+            let satisfied_count = results.iter().filter(|result| result.eval_result).count();
+            Ok(
+                EvalResult {
+                    rhs: results.len() as f64, op: ast.to_str(), lhs: satisfied_count as f64,
+                    eval_result: satisfied_count == results.len()
+                }
+            )
         },
-        Expr::Unary(expr) => {
-            let expr = fold_expr(expr);
+        ExprKind::Error => Err(EvalError::Erroneous { span: ast.span }),
+        _ => Err(EvalError::NumberInBooleanContext { span: ast.span }),
+    }
+}
 
-            match expr.as_ref() {
-                Expr::Literal(expr) => return Box::new(Expr::Literal(-expr)),
-                Expr::Unary(expr) => return expr.clone(),
-                _ => { }
-            }
-            return Box::new(Expr::Unary(expr));
+/// Evaluates each equation of a system separately; any other equation is a system of one.
+///
+/// `eval_equation` evaluates a system as the conjunction of its equations, reporting the
+/// number of satisfied equations as `lhs` and the number of equations as `rhs`.
+pub fn eval_system(
+    ast: &Expr,
+    variables: &HashMap<String, f64>,
+    equality_approximate_threshold: f64,
+) -> Result<Vec<EvalResult>, EvalError> {
+    eval_system_internal(ast, &Environment::new(variables), equality_approximate_threshold)
+}
+
+fn eval_system_internal<'a>(
+    ast: &'a Expr,
+    environment: &Environment<'a>,
+    equality_approximate_threshold: f64,
+) -> Result<Vec<EvalResult>, EvalError> {
+    match &ast.kind {
+        ExprKind::Let(name, value, body) => {
+            let value = fold_const_expr(value, environment, equality_approximate_threshold)?;
+            eval_system_internal(body, &environment.with_variable(name, value), equality_approximate_threshold)
         },
-        Expr::Add(lhs, rhs) => {
-            let lhs = fold_expr(lhs);
-            let rhs = fold_expr(rhs);
+        ExprKind::LetFn(name, parameters, value, body) => {
+            let environment = environment.with_function(name, parameters, value);
+            eval_system_internal(body, &environment, equality_approximate_threshold)
+        },
+        ExprKind::System(equations) => {
+            equations
+                .iter()
+                .map(|equation| eval_equation_internal(equation, environment, equality_approximate_threshold))
+                .collect()
+        },
+        _ => Ok(vec![eval_equation_internal(ast, environment, equality_approximate_threshold)?]),
+    }
+}
 
-            if let Expr::Literal(lhs) = lhs.as_ref() {
-                if *lhs == 0.0 {
-                    return rhs;
-                }
-            }
+/// What a number expression is evaluated with: the values of its variables, and how close two
+/// numbers must be for an `=` in a condition to hold.
+#[derive(Debug, Clone, Copy)]
+pub struct Env<'a> {
+    variables: &'a HashMap<String, f64>,
+    equality_approximate_threshold: f64,
+}
 
-            if let Expr::Literal(rhs) = rhs.as_ref() {
-                if *rhs == 0.0 {
-                    return lhs;
-                }
+impl<'a> Env<'a> {
+    /// An environment where `=` holds only for equal numbers.
+    pub fn new(variables: &'a HashMap<String, f64>) -> Self {
+        Self {
+            variables,
+            equality_approximate_threshold: 0.0,
+        }
+    }
+
+    pub fn with_equality_approximate_threshold(mut self, equality_approximate_threshold: f64) -> Self {
+        self.equality_approximate_threshold = equality_approximate_threshold;
+        self
+    }
+
+    pub fn variables(&self) -> &'a HashMap<String, f64> {
+        self.variables
+    }
+
+    pub fn equality_approximate_threshold(&self) -> f64 {
+        self.equality_approximate_threshold
+    }
+}
+
+/// Evaluates a number expression, such as an operand of the relations of `eval_equation`.
+pub fn eval_number(ast: &Expr, env: &Env) -> Result<f64, EvalError> {
+    fold_const_expr(ast, &Environment::new(env.variables), env.equality_approximate_threshold)
+}
+
+/// Operands of logical connectives are reported in `EvalResult` as `1.0` (true) or `0.0` (false).
+/// `not` has no left operand, so its `lhs` is NaN.
+fn truth_value(value: bool) -> f64 {
+    if value { 1.0 } else { 0.0 }
+}
+
+fn fold_const_expr<'a>(
+    ast: &'a Expr,
+    environment: &Environment<'a>,
+    equality_approximate_threshold: f64,
+) -> Result<f64, EvalError> {
+    let fold = |expr: &'a Expr| fold_const_expr(expr, environment, equality_approximate_threshold);
+
+    let value = match &ast.kind {
+        ExprKind::Literal(value) => *value,
+        ExprKind::Add(lhs, rhs) => fold(lhs)? + fold(rhs)?,
+        ExprKind::Sub(lhs, rhs) => fold(lhs)? - fold(rhs)?,
+        ExprKind::Mul(lhs, rhs) => fold(lhs)? * fold(rhs)?,
+        ExprKind::Div(lhs, rhs) => fold(lhs)? / fold(rhs)?,
+        ExprKind::Mod(lhs, rhs) => fold(lhs)? % fold(rhs)?,
+        ExprKind::Pow(lhs, rhs) => fold(lhs)?.powf(fold(rhs)?),
+        ExprKind::Unary(expr) => -fold(expr)?,
+        ExprKind::If(condition, then, otherwise) => {
+            // Only the selected branch is evaluated.
+            if eval_equation_internal(condition, environment, equality_approximate_threshold)?.eval_result {
+                fold(then)?
+            } else {
+                fold(otherwise)?
             }
-            
-            if let Expr::Literal(lhs) = lhs.as_ref() {
-                if let Expr::Literal(rhs) = rhs.as_ref() {
-                    return Box::new(Expr::Literal(lhs + rhs));
-                }
+        },
+        ExprKind::Id(id) => {
+            match environment.variable(id) {
+                Some(value) => value,
+                None => return Err(EvalError::UnknownVariable { name: id.to_owned(), span: ast.span }),
             }
-            return Box::new(Expr::Add(lhs, rhs));
         },
-        Expr::Sub(lhs, rhs) => {
-            let lhs = fold_expr(lhs);
-            let rhs = fold_expr(rhs);
+        ExprKind::Eq(..)
+        | ExprKind::Lt(..)
+        | ExprKind::Gt(..)
+        | ExprKind::Le(..)
+        | ExprKind::Ge(..)
+        | ExprKind::And(..)
+        | ExprKind::Or(..)
+        | ExprKind::Not(..)
+        | ExprKind::System(..) => return Err(EvalError::RelationInNumericContext { span: ast.span }),
+        ExprKind::Error => return Err(EvalError::Erroneous { span: ast.span }),
+        ExprKind::Sum(index, from, to, term) => {
+            fold_aggregate(ast, index, from, to, term, environment, equality_approximate_threshold)?
+        },
+        ExprKind::Prod(index, from, to, factor) => {
+            fold_aggregate(ast, index, from, to, factor, environment, equality_approximate_threshold)?
+        },
+        ExprKind::Let(name, value, body) => {
+            let value = fold(value)?;
+            fold_const_expr(body, &environment.with_variable(name, value), equality_approximate_threshold)?
+        },
+        ExprKind::LetFn(name, parameters, value, body) => {
+            let environment = environment.with_function(name, parameters, value);
+            fold_const_expr(body, &environment, equality_approximate_threshold)?
+        },
+        ExprKind::Call(func_name, params) => {
+            let params = params.iter().map(|param| fold(param)).collect::<Result<Vec<f64>, _>>()?;
 
-            if let Expr::Literal(lhs) = lhs.as_ref() {
-                if *lhs == 0.0 {
-                    return Box::new(Expr::Unary(rhs));
+            if let Some(function) = environment.functions.get(func_name) {
+                if params.len() != function.parameters.len() {
+                    return Err(arity_mismatch(func_name, function.parameters.len(), params.len(), ast.span));
                 }
-            }
 
-            if let Expr::Literal(rhs) = rhs.as_ref() {
-                if *rhs == 0.0 {
-                    return lhs;
+                let mut function_environment = function.environment.clone();
+                for (parameter, value) in function.parameters.iter().zip(params) {
+                    function_environment.locals.insert(parameter.to_owned(), value);
                 }
+                return fold_const_expr(function.body, &function_environment, equality_approximate_threshold);
             }
 
-            if let Expr::Literal(lhs) = lhs.as_ref() {
-                if let Expr::Literal(rhs) = rhs.as_ref() {
-                    return Box::new(Expr::Literal(lhs - rhs));
-                }
-            }
-            return Box::new(Expr::Sub(lhs, rhs));
-        },
-        Expr::Mul(lhs, rhs) => {
-            let lhs = fold_expr(lhs);
-            let rhs = fold_expr(rhs);
+            let builtin = match builtin(func_name) {
+                Some(builtin) => builtin,
+                None => return Err(EvalError::UnknownFunction { name: func_name.to_owned(), span: ast.span }),
+            };
 
-            if let Expr::Literal(lhs) = lhs.as_ref() {
-                if *lhs == 0.0 {
-                    return Box::new(Expr::Literal(0.0));
-                }
-                if *lhs == 1.0 {
-                    return rhs;
-                }
+            match (builtin, params.as_slice()) {
+                (Builtin::Unary(function), [arg]) => function(*arg),
+                (Builtin::Binary(function), [lhs, rhs]) => function(*lhs, *rhs),
+                (builtin, params) => return Err(arity_mismatch(func_name, builtin.arity(), params.len(), ast.span)),
             }
+        }
+    };
 
-            if let Expr::Literal(rhs) = rhs.as_ref() {
-                if *rhs == 0.0 {
-                    return Box::new(Expr::Literal(0.0));
-                }
-                if *rhs == 1.0 {
-                    return lhs;
-                }
-            }
+    Ok(value)
+}
 
-            if let Expr::Literal(lhs) = lhs.as_ref() {
-                if let Expr::Literal(rhs) = rhs.as_ref() {
-                    return Box::new(Expr::Literal(lhs * rhs));
-                }
-            }
-            return Box::new(Expr::Mul(lhs, rhs));
-        },
-        Expr::Div(lhs, rhs) => {
-            let lhs = fold_expr(lhs);
-            let rhs = fold_expr(rhs);
+#[derive(Debug, Clone, Copy)]
+enum Builtin {
+    Unary(fn(f64) -> f64),
+    Binary(fn(f64, f64) -> f64),
+}
 
-            if let Expr::Literal(lhs) = lhs.as_ref() {
-                if *lhs == 0.0 {
-                    return Box::new(Expr::Literal(0.0));
-                }
-            }
+impl Builtin {
+    fn arity(self) -> usize {
+        match self {
+            Builtin::Unary(_) => 1,
+            Builtin::Binary(_) => 2,
+        }
+    }
+}
 
-            if let Expr::Literal(lhs) = lhs.as_ref() {
-                if let Expr::Literal(rhs) = rhs.as_ref() {
-                    return Box::new(Expr::Literal(lhs / rhs));
-                }
-            }
-            return Box::new(Expr::Div(lhs, rhs));
-        },
-        Expr::Mod(lhs, rhs) => {
-            let lhs = fold_expr(lhs);
-            let rhs = fold_expr(rhs);
-            if let Expr::Literal(lhs) = lhs.as_ref() {
-                if let Expr::Literal(rhs) = rhs.as_ref() {
-                    return Box::new(Expr::Literal(lhs % rhs));
-                }
-            }
-            return Box::new(Expr::Mod(lhs, rhs));
+fn builtin(name: &str) -> Option<Builtin> {
+    let builtin = match name {
+        "abs" => Builtin::Unary(f64::abs),
+        "acos" => Builtin::Unary(f64::acos),
+        "acosh" => Builtin::Unary(f64::acosh),
+        "asin" => Builtin::Unary(f64::asin),
+        "asinh" => Builtin::Unary(f64::asinh),
+        "atan" => Builtin::Unary(f64::atan),
+        "atan2" => Builtin::Binary(f64::atan2),
+        "atanh" => Builtin::Unary(f64::atanh),
+        "cbrt" => Builtin::Unary(f64::cbrt),
+        "ceil" => Builtin::Unary(f64::ceil),
+        "cos" => Builtin::Unary(f64::cos),
+        "cosh" => Builtin::Unary(f64::cosh),
+        "exp" => Builtin::Unary(f64::exp),
+        "exp_m1" => Builtin::Unary(f64::exp_m1),
+        "factorial" => Builtin::Unary(factorial),
+        "floor" => Builtin::Unary(f64::floor),
+        "hypot" => Builtin::Binary(f64::hypot),
+        "ln" => Builtin::Unary(f64::ln),
+        "ln_1p" => Builtin::Unary(f64::ln_1p),
+        "log" => Builtin::Binary(f64::log),
+        "log10" => Builtin::Unary(f64::log10),
+        "log2" => Builtin::Unary(f64::log2),
+        "max" => Builtin::Binary(f64::max),
+        "min" => Builtin::Binary(f64::min),
+        "pow" => Builtin::Binary(f64::powf),
+        "round" => Builtin::Unary(f64::round),
+        "sin" => Builtin::Unary(f64::sin),
+        "sinh" => Builtin::Unary(f64::sinh),
+        "sqrt" => Builtin::Unary(f64::sqrt),
+        "tan" => Builtin::Unary(f64::tan),
+        "tanh" => Builtin::Unary(f64::tanh),
+        _ => return None,
+    };
+    Some(builtin)
+}
+
+fn arity_mismatch(name: &str, expected: usize, found: usize, span: Span) -> EvalError {
+    EvalError::ArityMismatch { name: name.to_owned(), expected, found, span }
+}
+
+/// Evaluates a `Sum` or `Prod` by combining the values of its term for each index value.
+fn fold_aggregate<'a>(
+    ast: &'a Expr,
+    index: &str,
+    from: &'a Expr,
+    to: &'a Expr,
+    term: &'a Expr,
+    environment: &Environment<'a>,
+    equality_approximate_threshold: f64,
+) -> Result<f64, EvalError> {
+    let (identity, combine): (f64, fn(f64, f64) -> f64) = match &ast.kind {
+        ExprKind::Sum(..) => (0.0, |lhs, rhs| lhs + rhs),
+        _ => (1.0, |lhs, rhs| lhs * rhs),
+    };
+
+    let from = fold_const_expr(from, environment, equality_approximate_threshold)?;
+    let to = fold_const_expr(to, environment, equality_approximate_threshold)?;
+
+    let term_count = aggregate_term_count(from, to).map_err(|error| {
+        let message = match error {
+            AggregateBoundsError::NotFinite => {
+                format!("bounds of {} must be finite, found {} to {}", ast.to_str(), from, to)
+            },
+            AggregateBoundsError::TooLarge => {
+                format!(
+                    "bounds of {} must be at most {} in magnitude, found {} to {}",
+                    ast.to_str(), MAX_AGGREGATE_BOUND, from, to
+                )
+            },
+            AggregateBoundsError::TooManyTerms => {
+                format!("{} has more than {} terms", ast.to_str(), MAX_AGGREGATE_TERM_COUNT)
+            },
+        };
+        EvalError::DomainError { message, span: ast.span }
+    })?;
+
+    let mut environment = environment.clone();
+    let mut result = identity;
+
+    for i in 0..term_count {
+        environment.locals.insert(index.to_owned(), from + i as f64);
+        result = combine(result, fold_const_expr(term, &environment, equality_approximate_threshold)?);
+    }
+
+    Ok(result)
+}
+
+/// Why the index values of a `Sum` or `Prod` can't be enumerated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum AggregateBoundsError {
+    NotFinite,
+    TooLarge,
+    TooManyTerms,
+}
+
+/// Number of index values `from`, `from + 1`, ... up to `to` of a `Sum` or `Prod`; the index
+/// value of the `i`-th term is `from + i`. Every backend enumerates the terms this way, so that
+/// they agree on the result.
+pub(crate) fn aggregate_term_count(from: f64, to: f64) -> Result<u64, AggregateBoundsError> {
+    if !from.is_finite() || !to.is_finite() {
+        return Err(AggregateBoundsError::NotFinite);
+    }
+
+    if from.abs() > MAX_AGGREGATE_BOUND || to.abs() > MAX_AGGREGATE_BOUND {
+        return Err(AggregateBoundsError::TooLarge);
+    }
+
+    if to < from {
+        return Ok(0);
+    }
+
+    if to - from >= MAX_AGGREGATE_TERM_COUNT {
+        return Err(AggregateBoundsError::TooManyTerms);
+    }
+
+    Ok((to - from).floor() as u64 + 1)
+}
+
+/// `n!` for non-negative integers, `gamma(n + 1)` for other numbers.
+fn factorial(n: f64) -> f64 {
+    if n.fract() == 0.0 {
+        if n < 0.0 {
+            return f64::NAN;
+        }
+        if n > 170.0 {
+            return f64::INFINITY;
+        }
+        return (2..=n as u32).map(f64::from).product();
+    }
+
+    gamma(n + 1.0)
+}
+
+/// Lanczos approximation of the gamma function (g = 7, n = 9).
+fn gamma(z: f64) -> f64 {
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+
+    if z < 0.5 {
+        // Reflection formula.
+        return std::f64::consts::PI / (f64::sin(std::f64::consts::PI * z) * gamma(1.0 - z));
+    }
+
+    let z = z - 1.0;
+    let t = z + 7.5;
+    let series = COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFICIENTS[0], |series, (i, coefficient)| series + coefficient / (z + (i + 1) as f64));
+
+    f64::sqrt(2.0 * std::f64::consts::PI) * t.powf(z + 0.5) * f64::exp(-t) * series
+}
+
+#[derive(Clone, Copy)]
+enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+}
+
+/// What an arithmetic operation on folded operands simplifies to.
+enum Simplified {
+    Lhs,
+    Rhs,
+    NegatedRhs,
+    Literal(f64),
+    Unchanged,
+}
+
+/// Simplifies an operation from the values of its operands that are literals.
+fn simplify(operator: Operator, lhs: Option<f64>, rhs: Option<f64>) -> Simplified {
+    match (operator, lhs, rhs) {
+        (Operator::Add, Some(0.0), _) => Simplified::Rhs,
+        (Operator::Add, _, Some(0.0)) => Simplified::Lhs,
+        (Operator::Add, Some(lhs), Some(rhs)) => Simplified::Literal(lhs + rhs),
+        (Operator::Sub, Some(0.0), _) => Simplified::NegatedRhs,
+        (Operator::Sub, _, Some(0.0)) => Simplified::Lhs,
+        (Operator::Sub, Some(lhs), Some(rhs)) => Simplified::Literal(lhs - rhs),
+        (Operator::Mul, Some(0.0), _) => Simplified::Literal(0.0),
+        (Operator::Mul, Some(1.0), _) => Simplified::Rhs,
+        (Operator::Mul, _, Some(0.0)) => Simplified::Literal(0.0),
+        (Operator::Mul, _, Some(1.0)) => Simplified::Lhs,
+        (Operator::Mul, Some(lhs), Some(rhs)) => Simplified::Literal(lhs * rhs),
+        (Operator::Div, Some(0.0), _) => Simplified::Literal(0.0),
+        (Operator::Div, Some(lhs), Some(rhs)) => Simplified::Literal(lhs / rhs),
+        (Operator::Mod, Some(lhs), Some(rhs)) => Simplified::Literal(lhs % rhs),
+        (Operator::Pow, Some(0.0), _) => Simplified::Literal(0.0),
+        (Operator::Pow, Some(1.0), _) => Simplified::Literal(1.0),
+        (Operator::Pow, _, Some(0.0)) => Simplified::Literal(1.0),
+        (Operator::Pow, _, Some(1.0)) => Simplified::Lhs,
+        (Operator::Pow, Some(lhs), Some(rhs)) => Simplified::Literal(lhs.powf(rhs)),
+        _ => Simplified::Unchanged,
+    }
+}
+
+/// Evaluates the parts of an AST that don't depend on any variable, and drops the operations
+/// that have no effect, like `+ 0` and `* 1`.
+struct ConstantFolder;
+
+impl Folder for ConstantFolder {
+    fn fold_expr(&mut self, expr: &Expr) -> Box<Expr> {
+        let folded = fold_children(self, expr);
+        let span = folded.span;
+
+        let (operator, lhs, rhs) = match folded.kind {
+            ExprKind::Unary(expr) => {
+                return match expr.kind {
+                    ExprKind::Literal(value) => Expr::boxed(ExprKind::Literal(-value), span),
+                    ExprKind::Unary(expr) => expr,
+                    _ => Expr::boxed(ExprKind::Unary(expr), span),
+                };
+            },
+            ExprKind::Add(lhs, rhs) => (Operator::Add, lhs, rhs),
+            ExprKind::Sub(lhs, rhs) => (Operator::Sub, lhs, rhs),
+            ExprKind::Mul(lhs, rhs) => (Operator::Mul, lhs, rhs),
+            ExprKind::Div(lhs, rhs) => (Operator::Div, lhs, rhs),
+            ExprKind::Mod(lhs, rhs) => (Operator::Mod, lhs, rhs),
+            ExprKind::Pow(lhs, rhs) => (Operator::Pow, lhs, rhs),
+            kind => return Expr::boxed(kind, span),
+        };
+
+        match simplify(operator, literal_value(&lhs), literal_value(&rhs)) {
+            Simplified::Lhs => lhs,
+            Simplified::Rhs => rhs,
+            Simplified::NegatedRhs => Expr::boxed(ExprKind::Unary(rhs), span),
+            Simplified::Literal(value) => Expr::boxed(ExprKind::Literal(value), span),
+            Simplified::Unchanged => {
+                let kind = match operator {
+                    Operator::Add => ExprKind::Add(lhs, rhs),
+                    Operator::Sub => ExprKind::Sub(lhs, rhs),
+                    Operator::Mul => ExprKind::Mul(lhs, rhs),
+                    Operator::Div => ExprKind::Div(lhs, rhs),
+                    Operator::Mod => ExprKind::Mod(lhs, rhs),
+                    Operator::Pow => ExprKind::Pow(lhs, rhs),
+                };
+                Expr::boxed(kind, span)
+            },
+        }
+    }
+}
+
+fn literal_value(expr: &Expr) -> Option<f64> {
+    match expr.kind {
+        ExprKind::Literal(value) => Some(value),
+        _ => None,
+    }
+}
+
+pub fn fold_expr(ast: &Expr) -> Box<Expr> {
+    ConstantFolder.fold_expr(ast)
+}
+
+/// Same as `fold_expr`, for an expression of `pool`. Each shared subexpression is folded once.
+pub fn fold_pooled_expr(pool: &mut ExprPool, id: ExprId) -> ExprId {
+    fold_pooled_expr_internal(pool, id, &mut HashMap::new())
+}
+
+fn fold_pooled_expr_internal(pool: &mut ExprPool, id: ExprId, folded: &mut HashMap<ExprId, ExprId>) -> ExprId {
+    if let Some(result) = folded.get(&id) {
+        return *result;
+    }
+
+    let span = pool.span(id);
+    let node = pool.node(id).clone();
+    let mut fold = |child: ExprId| fold_pooled_expr_internal(pool, child, folded);
+
+    let node = match node {
+        ExprNode::Eq(lhs, rhs) => ExprNode::Eq(fold(lhs), fold(rhs)),
+        ExprNode::Lt(lhs, rhs) => ExprNode::Lt(fold(lhs), fold(rhs)),
+        ExprNode::Gt(lhs, rhs) => ExprNode::Gt(fold(lhs), fold(rhs)),
+        ExprNode::Le(lhs, rhs) => ExprNode::Le(fold(lhs), fold(rhs)),
+        ExprNode::Ge(lhs, rhs) => ExprNode::Ge(fold(lhs), fold(rhs)),
+        ExprNode::And(lhs, rhs) => ExprNode::And(fold(lhs), fold(rhs)),
+        ExprNode::Or(lhs, rhs) => ExprNode::Or(fold(lhs), fold(rhs)),
+        ExprNode::Not(expr) => ExprNode::Not(fold(expr)),
+        ExprNode::If(condition, then, otherwise) => ExprNode::If(fold(condition), fold(then), fold(otherwise)),
+        ExprNode::Unary(expr) => ExprNode::Unary(fold(expr)),
+        ExprNode::Add(lhs, rhs) => ExprNode::Add(fold(lhs), fold(rhs)),
+        ExprNode::Sub(lhs, rhs) => ExprNode::Sub(fold(lhs), fold(rhs)),
+        ExprNode::Mul(lhs, rhs) => ExprNode::Mul(fold(lhs), fold(rhs)),
+        ExprNode::Div(lhs, rhs) => ExprNode::Div(fold(lhs), fold(rhs)),
+        ExprNode::Mod(lhs, rhs) => ExprNode::Mod(fold(lhs), fold(rhs)),
+        ExprNode::Pow(lhs, rhs) => ExprNode::Pow(fold(lhs), fold(rhs)),
+        ExprNode::Call(name, args) => ExprNode::Call(name, args.into_iter().map(&mut fold).collect()),
+        ExprNode::Sum(index, from, to, term) => ExprNode::Sum(index, fold(from), fold(to), fold(term)),
+        ExprNode::Prod(index, from, to, factor) => ExprNode::Prod(index, fold(from), fold(to), fold(factor)),
+        ExprNode::Let(name, value, body) => ExprNode::Let(name, fold(value), fold(body)),
+        ExprNode::LetFn(name, parameters, value, body) => ExprNode::LetFn(name, parameters, fold(value), fold(body)),
+        ExprNode::System(equations) => ExprNode::System(equations.into_iter().map(&mut fold).collect()),
+        node @ (ExprNode::Id(_) | ExprNode::Literal(_) | ExprNode::Error) => node,
+    };
+
+    let result = match node {
+        ExprNode::Unary(expr) => match pool.node(expr).clone() {
+            ExprNode::Literal(value) => pool.add(ExprNode::Literal(-value), span),
+            ExprNode::Unary(expr) => expr,
+            _ => pool.add(ExprNode::Unary(expr), span),
         },
-        Expr::Pow(lhs, rhs) => {
-            let lhs = fold_expr(lhs);
-            let rhs = fold_expr(rhs);
+        ExprNode::Add(lhs, rhs) => simplify_pooled(pool, Operator::Add, lhs, rhs, span),
+        ExprNode::Sub(lhs, rhs) => simplify_pooled(pool, Operator::Sub, lhs, rhs, span),
+        ExprNode::Mul(lhs, rhs) => simplify_pooled(pool, Operator::Mul, lhs, rhs, span),
+        ExprNode::Div(lhs, rhs) => simplify_pooled(pool, Operator::Div, lhs, rhs, span),
+        ExprNode::Mod(lhs, rhs) => simplify_pooled(pool, Operator::Mod, lhs, rhs, span),
+        ExprNode::Pow(lhs, rhs) => simplify_pooled(pool, Operator::Pow, lhs, rhs, span),
+        node => pool.add(node, span),
+    };
 
-            if let Expr::Literal(lhs) = lhs.as_ref() {
-                if *lhs == 0.0 {
-                    return Box::new(Expr::Literal(0.0));
-                }
-                if *lhs == 1.0 {
-                    return Box::new(Expr::Literal(1.0));
-                }
-            }
+    folded.insert(id, result);
+    result
+}
 
-            if let Expr::Literal(rhs) = rhs.as_ref() {
-                if *rhs == 0.0 {
-                    return Box::new(Expr::Literal(1.0));
-                }
-                if *rhs == 1.0 {
-                    return lhs;
-                }
-            }
+fn simplify_pooled(pool: &mut ExprPool, operator: Operator, lhs: ExprId, rhs: ExprId, span: Span) -> ExprId {
+    let literal_value = |id: ExprId| match pool.node(id) {
+        ExprNode::Literal(value) => Some(*value),
+        _ => None,
+    };
 
-            if let Expr::Literal(lhs) = lhs.as_ref() {
-                if let Expr::Literal(rhs) = rhs.as_ref() {
-                    return Box::new(Expr::Literal(lhs.powf(*rhs)));
-                }
-            }
-            return Box::new(Expr::Pow(lhs, rhs));
+    match simplify(operator, literal_value(lhs), literal_value(rhs)) {
+        Simplified::Lhs => lhs,
+        Simplified::Rhs => rhs,
+        Simplified::NegatedRhs => pool.add(ExprNode::Unary(rhs), span),
+        Simplified::Literal(value) => pool.add(ExprNode::Literal(value), span),
+        Simplified::Unchanged => {
+            let node = match operator {
+                Operator::Add => ExprNode::Add(lhs, rhs),
+                Operator::Sub => ExprNode::Sub(lhs, rhs),
+                Operator::Mul => ExprNode::Mul(lhs, rhs),
+                Operator::Div => ExprNode::Div(lhs, rhs),
+                Operator::Mod => ExprNode::Mod(lhs, rhs),
+                Operator::Pow => ExprNode::Pow(lhs, rhs),
+            };
+            pool.add(node, span)
         },
-        Expr::Call(name, params) => {
-            let params = params.iter().map(|x| fold_expr(x)).collect();
-            return Box::new(Expr::Call(name.to_string(), params));
-        }
-        Expr::Id(name) => {
-            return Box::new(Expr::Id(name.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(crate) fn parse(input: &str) -> Box<Expr> {
+        let mut diagnostics = diagnostic::DiagnosticSink::new();
+        let ctx = parser::ParserContext::new(
+            Box::new(lexer::token_iter(input)),
+            parser::create_binary_op_precedence(),
+            &mut diagnostics,
+        );
+        parser::parse_top_level_expression(ctx).unwrap_or_else(|_| panic!("failed to parse {}", input))
+    }
+
+    fn eval(input: &str) -> Result<f64, EvalError> {
+        eval_number(&parse(input), &Env::new(&HashMap::new()))
+    }
+
+    /// Number expressions of `x` on which the other backends must agree with `eval_number`.
+    pub(crate) const EXPRESSIONS: &[&str] = &[
+        "x",
+        "sin(x) * x^2 + 3 * x - 1",
+        "-x^2 + 2^-x",
+        "x % 3 + atan2(x, 2) - log(x, 2)",
+        "if(x < 0, -x, sqrt(x)) + abs(cos(x * pi))",
+        "if(x >= 1 and not x = 2 or x < -5, 1, 0)",
+        "let f(t) = t^2 + 1 in f(x) / f(x + 1)",
+        "let r = x * x in r * r - 2 * r + 1",
+        "let f(t) = t * x in let x = 2 in f(x)",
+        "sum(k, 1, 10, sin(k * x) / k)",
+        "prod(k, x, x + 3, k)",
+        "sum(k, 1, x, sum(j, k, x, j * k))",
+        "sum(k, x, 1e17, k)",
+        "factorial(x) + factorial(abs(x) / 2)",
+    ];
+
+    pub(crate) const INPUTS: &[f64] = &[0.0, -0.0, 1.0, 2.0, -2.5, 3.7, 12.0, -40.0, 1e15, f64::NAN, f64::INFINITY];
+
+    pub(crate) fn constants() -> HashMap<String, f64> {
+        HashMap::from([("pi".to_string(), std::f64::consts::PI)])
+    }
+
+    /// Aggregates with bounds of `x`, the value of `x`, and the result every backend must give:
+    /// NaN when the bounds are out of range.
+    pub(crate) const OUT_OF_RANGE_BOUNDS: &[(&str, f64, f64)] = &[
+        ("sum(k, x, x, k)", 1e17, f64::NAN),
+        ("sum(k, x, x, k)", f64::INFINITY, f64::NAN),
+        ("sum(k, x, x, k)", 1e15, 1e15),
+        ("prod(k, x, x, k)", 1e17, f64::NAN),
+        ("prod(k, x, x, k)", f64::NAN, f64::NAN),
+        ("prod(k, x, x, k)", -3.0, -3.0),
+    ];
+
+    /// Whether two results are the same number, or both NaN.
+    pub(crate) fn same(lhs: f64, rhs: f64) -> bool {
+        lhs.to_bits() == rhs.to_bits() || (lhs.is_nan() && rhs.is_nan())
+    }
+
+    /// Result of `eval_number` for `x`, with a failed aggregate evaluating to NaN like in the
+    /// other backends.
+    pub(crate) fn eval_at(ast: &Expr, x: f64) -> f64 {
+        let variables = HashMap::from([("x".to_string(), x), ("pi".to_string(), std::f64::consts::PI)]);
+        match eval_number(ast, &Env::new(&variables)) {
+            Ok(value) => value,
+            Err(EvalError::DomainError { .. }) => f64::NAN,
+            Err(error) => panic!("{}", error),
         }
-        Expr::Literal(literal) => {
-            return Box::new(Expr::Literal(*literal));
+    }
+
+    #[test]
+    fn aggregate_enumerates_from_the_lower_bound() {
+        assert_eq!(eval("sum(k, 1, 4, k)"), Ok(10.0));
+        assert_eq!(eval("sum(k, 0.5, 2.7, k)"), Ok(4.5));
+        assert_eq!(eval("prod(k, 1, 5, k)"), Ok(120.0));
+        assert_eq!(eval("sum(k, 3, 1, k)"), Ok(0.0));
+        assert_eq!(eval("prod(k, 3, 1, k)"), Ok(1.0));
+    }
+
+    #[test]
+    fn aggregate_with_out_of_range_bounds_is_nan() {
+        for (input, x, expected) in OUT_OF_RANGE_BOUNDS {
+            let found = eval_at(&parse(input), *x);
+            assert!(same(*expected, found), "{} at {}: expected {}, found {}", input, x, expected, found);
         }
     }
+
+    #[test]
+    fn aggregate_term_count_of_bounds() {
+        assert_eq!(aggregate_term_count(1.0, 1.0), Ok(1));
+        assert_eq!(aggregate_term_count(-2.5, 2.0), Ok(5));
+        assert_eq!(aggregate_term_count(2.0, 1.0), Ok(0));
+        assert_eq!(aggregate_term_count(0.0, f64::NAN), Err(AggregateBoundsError::NotFinite));
+        assert_eq!(aggregate_term_count(f64::NEG_INFINITY, 0.0), Err(AggregateBoundsError::NotFinite));
+        assert_eq!(aggregate_term_count(1e17, 1e17), Err(AggregateBoundsError::TooLarge));
+        assert_eq!(aggregate_term_count(0.0, MAX_AGGREGATE_TERM_COUNT), Err(AggregateBoundsError::TooManyTerms));
+        assert_eq!(aggregate_term_count(MAX_AGGREGATE_BOUND, MAX_AGGREGATE_BOUND), Ok(1));
+    }
+
+    #[test]
+    fn aggregate_rejects_bounds_beyond_consecutive_integers() {
+        assert!(matches!(eval("sum(k, 1e17, 1e17, k)"), Err(EvalError::DomainError { .. })));
+        assert!(matches!(eval("prod(k, -1e17, 0, k)"), Err(EvalError::DomainError { .. })));
+    }
+
+    #[test]
+    fn aggregate_rejects_too_many_terms() {
+        assert!(matches!(eval("sum(k, 0, 1e6, k)"), Err(EvalError::DomainError { .. })));
+        assert_eq!(eval("sum(k, 1, 999999, 1)"), Ok(999999.0));
+    }
+
+    #[test]
+    fn env_carries_the_equality_threshold() {
+        let variables = HashMap::from([("x".to_string(), 1.000001)]);
+        let ast = parse("if(x = 1, 2, 3)");
+
+        assert_eq!(eval_number(&ast, &Env::new(&variables)), Ok(3.0));
+        let env = Env::new(&variables).with_equality_approximate_threshold(0.00001);
+        assert_eq!(env.equality_approximate_threshold(), 0.00001);
+        assert_eq!(eval_number(&ast, &env), Ok(2.0));
+    }
+
+    fn holds(input: &str, x: f64, y: f64) -> bool {
+        let variables = HashMap::from([("x".to_string(), x), ("y".to_string(), y)]);
+        eval_equation(&parse(input), &variables, 1e-9).unwrap().eval_result()
+    }
+
+    #[test]
+    fn connectives_combine_relations() {
+        assert!(holds("0 < x < 2", 1.0, 0.0));
+        assert!(!holds("0 < x < 2", 2.0, 0.0));
+        assert!(holds("x < 0 or y > 0", 1.0, 1.0));
+        assert!(!holds("x < 0 or y > 0 and x > y", 1.0, 1.0));
+        assert!(holds("not x = y", 1.0, 2.0));
+        assert!(holds("x != y", 1.0, 2.0));
+    }
+
+    #[test]
+    fn connectives_report_truth_values_as_operands() {
+        let variables = HashMap::from([("x".to_string(), 1.0)]);
+        let result = eval_equation(&parse("x > 0 and x > 2"), &variables, 0.0).unwrap();
+        assert_eq!((result.lhs(), result.op(), result.rhs(), result.eval_result()), (1.0, "and", 0.0, false));
+
+        let result = eval_equation(&parse("not x > 0"), &variables, 0.0).unwrap();
+        assert!(result.lhs().is_nan());
+        assert_eq!(result.rhs(), 1.0);
+    }
+
+    #[test]
+    fn conditional_evaluates_only_the_selected_branch() {
+        let variables = HashMap::from([("x".to_string(), 2.0)]);
+        assert_eq!(eval_number(&parse("if(x > 0, x, y)"), &Env::new(&variables)), Ok(2.0));
+        assert!(matches!(
+            eval_number(&parse("if(x < 0, x, y)"), &Env::new(&variables)),
+            Err(EvalError::UnknownVariable { name, .. }) if name == "y"
+        ));
+    }
+
+    #[test]
+    fn functions_close_over_their_definition() {
+        assert_eq!(eval("let r = 3 in let f(t) = t * r in let r = 10 in f(2) + r"), Ok(16.0));
+        assert_eq!(eval("let f(a, b) = a - b in f(5, 2)"), Ok(3.0));
+        assert!(matches!(eval("let f(t) = t in f(1, 2)"), Err(EvalError::ArityMismatch { expected: 1, found: 2, .. })));
+        assert!(matches!(eval("sin(1, 2)"), Err(EvalError::ArityMismatch { expected: 1, found: 2, .. })));
+    }
+
+    #[test]
+    fn system_equations_are_evaluated_separately() {
+        let variables = HashMap::from([("x".to_string(), 1.0), ("y".to_string(), 2.0)]);
+        let ast = parse("r = 2; x < r, y > r, x + y = 3");
+
+        let results = eval_system(&ast, &variables, 1e-9).unwrap();
+        assert_eq!(results.iter().map(|result| result.eval_result()).collect::<Vec<_>>(), [true, false, true]);
+
+        let result = eval_equation(&ast, &variables, 1e-9).unwrap();
+        assert_eq!((result.lhs(), result.rhs(), result.eval_result()), (2.0, 3.0, false));
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_expression() {
+        assert_eq!(
+            eval("1 + y * 2"),
+            Err(EvalError::UnknownVariable { name: "y".to_string(), span: Span::new(4, 5) })
+        );
+        assert_eq!(
+            eval("2 * g(1)"),
+            Err(EvalError::UnknownFunction { name: "g".to_string(), span: Span::new(4, 8) })
+        );
+        assert_eq!(eval("(1 < 2) + 1"), Err(EvalError::RelationInNumericContext { span: Span::new(0, 7) }));
+        assert_eq!(
+            eval_equation(&parse("1 + 2"), &HashMap::new(), 0.0).map(|result| result.eval_result()),
+            Err(EvalError::NumberInBooleanContext { span: Span::new(0, 5) })
+        );
+
+        let error = Expr::boxed(ExprKind::Error, Span::new(0, 2));
+        let erroneous = Expr::boxed(ExprKind::Add(error, parse("1")), Span::new(0, 6));
+        assert_eq!(eval_number(&erroneous, &Env::new(&HashMap::new())), Err(EvalError::Erroneous { span: Span::new(0, 2) }));
+    }
+
+    #[test]
+    fn errors_display_as_messages() {
+        let messages = ["y", "g(1)", "f(t) = t; f(1, 2)", "1 < 2", "sum(k, 0, 1e9, k)"]
+            .map(|input| eval(input).unwrap_err().to_string());
+        assert_eq!(
+            messages,
+            [
+                "unknown variable `y`",
+                "unknown function `g`",
+                "function `f` expects 1 argument(s), found 2",
+                "expected a number, found a relation",
+                "sum has more than 1000000 terms",
+            ]
+        );
+    }
 }
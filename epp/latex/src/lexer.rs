@@ -0,0 +1,100 @@
+use span::Span;
+
+use crate::token::{LatexToken, LatexTokenKind};
+
+/// Splits LaTeX math input into tokens.
+///
+/// Whitespace and spacing commands like `\,` or `\quad` are dropped, and `\left`/`\right`
+/// are merged into the delimiter that follows them.
+pub fn tokenize(input: &str) -> Vec<LatexToken> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some((start, char)) = chars.next() {
+        let kind = match char {
+            char if char.is_whitespace() => continue,
+            '0'..='9' | '.' => {
+                let mut number = char.to_string();
+                while let Some((_, char)) = chars.peek().filter(|(_, char)| char.is_ascii_digit() || *char == '.') {
+                    number.push(*char);
+                    chars.next();
+                }
+                LatexTokenKind::Number(number)
+            },
+            char if char.is_alphabetic() => LatexTokenKind::Letter(char),
+            '\\' => {
+                let mut name = String::new();
+                while let Some((_, char)) = chars.peek().filter(|(_, char)| char.is_ascii_alphabetic()) {
+                    name.push(*char);
+                    chars.next();
+                }
+                if name.is_empty() {
+                    if let Some((_, char)) = chars.next() {
+                        name.push(char);
+                    }
+                }
+
+                match name.as_str() {
+                    "," | ";" | ":" | "!" | " " | "quad" | "qquad" => continue,
+                    "left" | "right" => {
+                        while chars.peek().is_some_and(|(_, char)| char.is_whitespace()) {
+                            chars.next();
+                        }
+
+                        let is_left = name == "left";
+                        let delimiter = match chars.peek().map(|(_, char)| *char) {
+                            Some('(') if is_left => Some(LatexTokenKind::OpenParen),
+                            Some(')') if !is_left => Some(LatexTokenKind::CloseParen),
+                            Some('[') if is_left => Some(LatexTokenKind::OpenBracket),
+                            Some(']') if !is_left => Some(LatexTokenKind::CloseBracket),
+                            Some('|') if is_left => Some(LatexTokenKind::OpenBar),
+                            Some('|') => Some(LatexTokenKind::CloseBar),
+                            Some('.') => {
+                                // An invisible delimiter.
+                                chars.next();
+                                continue;
+                            },
+                            _ => None,
+                        };
+
+                        match delimiter {
+                            Some(delimiter) => {
+                                chars.next();
+                                delimiter
+                            },
+                            None => LatexTokenKind::Command(name),
+                        }
+                    },
+                    "lvert" => LatexTokenKind::OpenBar,
+                    "rvert" => LatexTokenKind::CloseBar,
+                    "vert" => LatexTokenKind::Bar,
+                    _ => LatexTokenKind::Command(name),
+                }
+            },
+            '{' => LatexTokenKind::OpenBrace,
+            '}' => LatexTokenKind::CloseBrace,
+            '(' => LatexTokenKind::OpenParen,
+            ')' => LatexTokenKind::CloseParen,
+            '[' => LatexTokenKind::OpenBracket,
+            ']' => LatexTokenKind::CloseBracket,
+            '|' => LatexTokenKind::Bar,
+            '^' => LatexTokenKind::Caret,
+            '_' => LatexTokenKind::Underscore,
+            '+' => LatexTokenKind::Plus,
+            '-' => LatexTokenKind::Minus,
+            '*' => LatexTokenKind::Star,
+            '/' => LatexTokenKind::Slash,
+            '=' => LatexTokenKind::Eq,
+            '<' => LatexTokenKind::Lt,
+            '>' => LatexTokenKind::Gt,
+            ',' => LatexTokenKind::Comma,
+            '!' => LatexTokenKind::Bang,
+            char => LatexTokenKind::Unknown(char),
+        };
+
+        let end = chars.peek().map_or(input.len(), |(offset, _)| *offset);
+        tokens.push(LatexToken::new(kind, Span::new(start, end)));
+    }
+
+    tokens
+}
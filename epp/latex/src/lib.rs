@@ -0,0 +1,845 @@
+mod lexer;
+mod parser_context;
+mod token;
+
+pub use lexer::tokenize;
+pub use parser_context::*;
+pub use token::*;
+
+use ast::{Expr, ExprKind, ExprList};
+use diagnostic::{Diagnostic, DiagnosticCode, ErrorReported, Level};
+use span::Span;
+
+/// Commands standing for a variable or a constant of the same name, e.g. `\theta` or `\pi`.
+const GREEK_LETTERS: &[&str] = &[
+    "alpha", "beta", "gamma", "delta", "epsilon", "varepsilon", "zeta", "eta", "theta",
+    "vartheta", "iota", "kappa", "lambda", "mu", "nu", "xi", "pi", "rho", "varrho", "sigma",
+    "tau", "upsilon", "phi", "varphi", "chi", "psi", "omega", "Gamma", "Delta", "Theta",
+    "Lambda", "Xi", "Pi", "Sigma", "Upsilon", "Phi", "Psi", "Omega",
+];
+
+/// Function commands taking one argument, with the built-in function they call.
+const FUNCTION_COMMANDS: &[(&str, &str)] = &[
+    ("sin", "sin"), ("cos", "cos"), ("tan", "tan"),
+    ("arcsin", "asin"), ("arccos", "acos"), ("arctan", "atan"),
+    ("sinh", "sinh"), ("cosh", "cosh"), ("tanh", "tanh"),
+    ("exp", "exp"), ("ln", "ln"), ("lg", "log10"),
+];
+
+/// Function commands whose power `^{-1}` is their inverse, with the built-in inverse function.
+const INVERTIBLE_FUNCTION_COMMANDS: &[(&str, &str)] = &[
+    ("sin", "asin"), ("cos", "acos"), ("tan", "atan"),
+    ("sinh", "asinh"), ("cosh", "acosh"), ("tanh", "atanh"),
+];
+
+/// Function commands taking one argument, which are the reciprocal of a built-in function.
+const RECIPROCAL_FUNCTION_COMMANDS: &[(&str, &str)] = &[
+    ("sec", "cos"), ("csc", "sin"), ("cot", "tan"),
+];
+
+fn error_expr(span: Span) -> Box<Expr> {
+    Expr::boxed(ExprKind::Error, span)
+}
+
+fn is_function_command(name: &str) -> bool {
+    FUNCTION_COMMANDS.iter().chain(RECIPROCAL_FUNCTION_COMMANDS).any(|(command, _)| *command == name)
+        || matches!(name, "log" | "max" | "min" | "operatorname")
+}
+
+/// Whether the command starts an operand, as opposed to an operator like `\cdot` or `\le`.
+fn is_operand_command(name: &str) -> bool {
+    GREEK_LETTERS.contains(&name)
+        || is_function_command(name)
+        || matches!(name, "frac" | "dfrac" | "tfrac" | "sqrt" | "mathrm")
+}
+
+/// Whether the current token starts an operand that is implicitly multiplied with the previous one.
+fn is_at_operand(ctx: &LatexParserContext) -> bool {
+    match ctx.current_kind() {
+        Some(LatexTokenKind::Number(_))
+        | Some(LatexTokenKind::Letter(_))
+        | Some(LatexTokenKind::OpenParen)
+        | Some(LatexTokenKind::OpenBracket)
+        | Some(LatexTokenKind::OpenBrace)
+        | Some(LatexTokenKind::OpenBar) => true,
+        Some(LatexTokenKind::Bar) => !ctx.is_in_bar(),
+        Some(LatexTokenKind::Command(name)) => is_operand_command(name),
+        _ => false,
+    }
+}
+
+/// Whether the exponent is `-1`.
+fn is_minus_one(exponent: &Expr) -> bool {
+    match &exponent.kind {
+        ExprKind::Unary(expr) => matches!(expr.kind, ExprKind::Literal(literal) if literal == 1.0),
+        ExprKind::Literal(literal) => *literal == -1.0,
+        _ => false,
+    }
+}
+
+fn is_command(ctx: &LatexParserContext, names: &[&str]) -> bool {
+    matches!(ctx.current_kind(), Some(LatexTokenKind::Command(name)) if names.contains(&name.as_str()))
+}
+
+/// Consumes the closing delimiter of a group, or reports it missing.
+fn expect_close(ctx: &mut LatexParserContext, close: LatexTokenKind, close_str: &str, open_span: Span) {
+    if ctx.current_kind() == Some(&close) {
+        ctx.next_token();
+        return;
+    }
+
+    let span = ctx.current_span();
+    ctx.push_diagnostic(Diagnostic::new(
+        Level::Error,
+        format!("expected '{}'", close_str),
+    )
+    .with_code(DiagnosticCode::UnclosedDelimiter)
+    .with_span(span)
+    .with_label(open_span, "unclosed delimiter".to_string()));
+}
+
+/// Reports the current token as unexpected, consuming it unless it ends an enclosing group.
+fn report_unexpected(ctx: &mut LatexParserContext, expected: &str) -> Box<Expr> {
+    let span = ctx.current_span();
+    let message = match ctx.current_kind() {
+        Some(LatexTokenKind::Command(name)) if !is_operand_command(name) && !is_operator_command(name) => {
+            format!("unknown LaTeX command '\\{}'", name)
+        },
+        Some(_) => "unexpected token when parsing primary".to_string(),
+        None => "unexpected end of input".to_string(),
+    };
+    let code = if ctx.current_token().is_some() {
+        DiagnosticCode::UnexpectedToken
+    } else {
+        DiagnosticCode::UnexpectedEndOfInput
+    };
+
+    ctx.push_diagnostic(Diagnostic::new(Level::Error, message)
+        .with_code(code)
+        .with_span(span)
+        .with_label(span, format!("expected {}", expected)));
+
+    match ctx.current_kind() {
+        None
+        | Some(LatexTokenKind::CloseParen)
+        | Some(LatexTokenKind::CloseBracket)
+        | Some(LatexTokenKind::CloseBrace)
+        | Some(LatexTokenKind::CloseBar)
+        | Some(LatexTokenKind::Comma) => { },
+        Some(LatexTokenKind::Bar) if ctx.is_in_bar() => { },
+        Some(_) => { ctx.next_token(); },
+    }
+
+    error_expr(span)
+}
+
+fn is_operator_command(name: &str) -> bool {
+    matches!(name,
+        "cdot" | "times" | "ast" | "div"
+        | "le" | "leq" | "leqslant" | "ge" | "geq" | "geqslant" | "ne" | "neq" | "lt" | "gt"
+        | "land" | "wedge" | "lor" | "vee" | "lnot" | "neg"
+    )
+}
+
+/// Converts the text of a number token into its value.
+fn parse_number(ctx: &mut LatexParserContext, number: String) -> Box<Expr> {
+    let span = ctx.current_span();
+    ctx.next_token();
+
+    match number.parse::<f64>() {
+        Ok(value) => Expr::boxed(ExprKind::Literal(value), span),
+        Err(_) => {
+            ctx.push_diagnostic(Diagnostic::new(
+                Level::Error,
+                format!("malformed number literal '{}'", number),
+            )
+            .with_code(DiagnosticCode::MalformedNumberLiteral)
+            .with_span(span));
+
+            error_expr(span)
+        },
+    }
+}
+
+/// subscript ::= '_' (digit | letter | '{' (letter | number)* '}')
+///
+/// A subscript is part of the name it is attached to, so `x_{1}` is the variable `x_1`.
+fn parse_subscript(ctx: &mut LatexParserContext, name: &mut String) {
+    if ctx.current_kind() != Some(&LatexTokenKind::Underscore) {
+        return;
+    }
+    ctx.next_token(); // eat _.
+    name.push('_');
+
+    let open_span = ctx.current_span();
+    match ctx.current_kind() {
+        Some(LatexTokenKind::OpenBrace) => {
+            ctx.next_token();
+            loop {
+                match ctx.current_kind() {
+                    Some(LatexTokenKind::Letter(letter)) => name.push(*letter),
+                    Some(LatexTokenKind::Number(number)) if !number.contains('.') => name.push_str(number),
+                    _ => break,
+                }
+                ctx.next_token();
+            }
+            expect_close(ctx, LatexTokenKind::CloseBrace, "}", open_span);
+        },
+        Some(LatexTokenKind::Number(_)) => {
+            ctx.split_current_number();
+            if let Some(LatexTokenKind::Number(digit)) = ctx.current_kind() {
+                name.push_str(digit);
+            }
+            ctx.next_token();
+        },
+        Some(LatexTokenKind::Letter(letter)) => {
+            name.push(*letter);
+            ctx.next_token();
+        },
+        _ => {
+            ctx.push_diagnostic(Diagnostic::new(
+                Level::Error,
+                "expected a subscript of letters and digits".to_string(),
+            )
+            .with_code(DiagnosticCode::UnexpectedToken)
+            .with_span(open_span));
+        },
+    }
+}
+
+/// name_group ::= '{' letter* '}'
+fn parse_name_group(ctx: &mut LatexParserContext, command: &str) -> Option<String> {
+    let open_span = ctx.current_span();
+    if ctx.current_kind() != Some(&LatexTokenKind::OpenBrace) {
+        ctx.push_diagnostic(Diagnostic::new(
+            Level::Error,
+            format!("expected '{{' after '\\{}'", command),
+        )
+        .with_code(DiagnosticCode::UnexpectedToken)
+        .with_span(open_span)
+        .with_help(format!("write it as '\\{}{{name}}'", command)));
+        return None;
+    }
+    ctx.next_token();
+
+    let mut name = String::new();
+    while let Some(LatexTokenKind::Letter(letter)) = ctx.current_kind() {
+        name.push(*letter);
+        ctx.next_token();
+    }
+    expect_close(ctx, LatexTokenKind::CloseBrace, "}", open_span);
+
+    if name.is_empty() {
+        ctx.push_diagnostic(Diagnostic::new(
+            Level::Error,
+            "expected a name".to_string(),
+        )
+        .with_code(DiagnosticCode::UnexpectedToken)
+        .with_span(open_span.to(ctx.last_span())));
+        return None;
+    }
+
+    Some(name)
+}
+
+/// group_argument
+///   ::= '{' expression '}'
+///   ::= digit
+///   ::= atom
+///
+/// The argument of `^`, `\frac` and `\sqrt`, which is a single character unless braced.
+fn parse_group_argument(ctx: &mut LatexParserContext) -> Box<Expr> {
+    match ctx.current_kind() {
+        Some(LatexTokenKind::OpenBrace) => parse_group(ctx, LatexTokenKind::CloseBrace, "}"),
+        Some(LatexTokenKind::Number(_)) => {
+            ctx.split_current_number();
+            parse_atom(ctx)
+        },
+        Some(LatexTokenKind::Letter(_)) | Some(LatexTokenKind::Command(_)) => parse_atom(ctx),
+        _ => report_unexpected(ctx, "'{'"),
+    }
+}
+
+/// group ::= open expression close
+fn parse_group(ctx: &mut LatexParserContext, close: LatexTokenKind, close_str: &str) -> Box<Expr> {
+    let open_span = ctx.current_span();
+    ctx.next_token(); // eat the opening delimiter.
+
+    let mut expr = parse_expression(ctx);
+    expect_close(ctx, close, close_str, open_span);
+
+    expr.span = open_span.to(ctx.last_span());
+    expr
+}
+
+/// abs ::= '|' expression '|'
+fn parse_abs(ctx: &mut LatexParserContext) -> Box<Expr> {
+    let open_span = ctx.current_span();
+    let explicit = ctx.current_kind() == Some(&LatexTokenKind::OpenBar);
+
+    let expr = if explicit {
+        parse_group(ctx, LatexTokenKind::CloseBar, "\\right|")
+    } else {
+        ctx.open_bar();
+        let expr = parse_group(ctx, LatexTokenKind::Bar, "|");
+        ctx.close_bar();
+        expr
+    };
+
+    let span = open_span.to(ctx.last_span());
+    Expr::boxed(ExprKind::Call("abs".to_string(), vec![expr]), span)
+}
+
+/// frac ::= '\frac' group_argument group_argument
+fn parse_frac(ctx: &mut LatexParserContext) -> Box<Expr> {
+    let span = ctx.current_span();
+    ctx.next_token(); // eat \frac.
+
+    let numerator = parse_group_argument(ctx);
+    let denominator = parse_group_argument(ctx);
+
+    let span = span.to(ctx.last_span());
+    Expr::boxed(ExprKind::Div(numerator, denominator), span)
+}
+
+/// sqrt ::= '\sqrt' ('[' expression ']')? group_argument
+///
+/// `\sqrt[3]{x}` is `cbrt(x)` and other indices `n` are the power `x^(1/n)`.
+fn parse_sqrt(ctx: &mut LatexParserContext) -> Box<Expr> {
+    let span = ctx.current_span();
+    ctx.next_token(); // eat \sqrt.
+
+    let index = if ctx.current_kind() == Some(&LatexTokenKind::OpenBracket) {
+        Some(parse_group(ctx, LatexTokenKind::CloseBracket, "]"))
+    } else {
+        None
+    };
+    let radicand = parse_group_argument(ctx);
+    let span = span.to(ctx.last_span());
+
+    let kind = match index {
+        None => ExprKind::Call("sqrt".to_string(), vec![radicand]),
+        Some(index) => match index.kind {
+            ExprKind::Literal(2.0) => ExprKind::Call("sqrt".to_string(), vec![radicand]),
+            ExprKind::Literal(3.0) => ExprKind::Call("cbrt".to_string(), vec![radicand]),
+            _ => {
+                let index_span = index.span;
+                let one = Expr::boxed(ExprKind::Literal(1.0), index_span);
+                ExprKind::Pow(radicand, Expr::boxed(ExprKind::Div(one, index), index_span))
+            },
+        },
+    };
+    Expr::boxed(kind, span)
+}
+
+/// function_arguments
+///   ::= '(' expression (',' expression)* ')'
+///   ::= implicit_product
+///
+/// Without parentheses the argument extends over the juxtaposed operands that follow, so
+/// `\sin 2x` is `sin(2x)`, but stops at the next function: `\sin x \cos x` is `sin(x) cos(x)`.
+fn parse_function_arguments(ctx: &mut LatexParserContext) -> ExprList {
+    if ctx.current_kind() != Some(&LatexTokenKind::OpenParen) {
+        return vec![parse_implicit_product(ctx)];
+    }
+
+    let open_span = ctx.current_span();
+    ctx.next_token(); // eat (.
+
+    let mut arguments = vec![parse_expression(ctx)];
+    while ctx.current_kind() == Some(&LatexTokenKind::Comma) {
+        ctx.next_token();
+        arguments.push(parse_expression(ctx));
+    }
+    expect_close(ctx, LatexTokenKind::CloseParen, ")", open_span);
+
+    arguments
+}
+
+/// function
+///   ::= function_command ('^' group_argument)? function_arguments
+///   ::= '\log' ('_' group_argument)? function_arguments
+///   ::= '\operatorname' name_group function_arguments
+///
+/// `\sin^2 x` is `sin(x)^2`, and `\log_b x` is `log(x, b)`; `\log x` alone is the common logarithm.
+/// `\sin^{-1} x` is the inverse `asin(x)`, as for the other trigonometric and hyperbolic functions
+/// in `INVERTIBLE_FUNCTION_COMMANDS`; on any other function `^{-1}` is ambiguous and reported.
+fn parse_function(ctx: &mut LatexParserContext, command: String) -> Box<Expr> {
+    let span = ctx.current_span();
+    ctx.next_token(); // eat the command.
+
+    let mut base = None;
+    let name = match command.as_str() {
+        "operatorname" => match parse_name_group(ctx, &command) {
+            Some(name) => name,
+            None => return error_expr(span.to(ctx.last_span())),
+        },
+        "log" => {
+            if ctx.current_kind() == Some(&LatexTokenKind::Underscore) {
+                ctx.next_token();
+                base = Some(parse_group_argument(ctx));
+                "log".to_string()
+            } else {
+                "log10".to_string()
+            }
+        },
+        _ => command.clone(),
+    };
+
+    let mut exponent = if ctx.current_kind() == Some(&LatexTokenKind::Caret) {
+        ctx.next_token();
+        Some(parse_group_argument(ctx))
+    } else {
+        None
+    };
+
+    let mut inverse = None;
+    if let Some(minus_one) = exponent.as_ref().filter(|exponent| is_minus_one(exponent)) {
+        match INVERTIBLE_FUNCTION_COMMANDS.iter().find(|(name, _)| *name == command) {
+            Some((_, function)) => {
+                inverse = Some(*function);
+                exponent = None;
+            },
+            None => {
+                let minus_one_span = minus_one.span;
+                ctx.push_diagnostic(Diagnostic::new(
+                    Level::Error,
+                    format!("'\\{}^{{-1}}' is ambiguous", command),
+                )
+                .with_code(DiagnosticCode::UnexpectedToken)
+                .with_span(minus_one_span)
+                .with_help(format!("write '(\\{} x)^{{-1}}' for the reciprocal", command)));
+            },
+        }
+    }
+
+    let mut arguments = parse_function_arguments(ctx);
+    arguments.extend(base);
+    let call_span = span.to(ctx.last_span());
+
+    let call = if let Some(function) = inverse {
+        Expr::boxed(ExprKind::Call(function.to_string(), arguments), call_span)
+    } else if let Some((_, function)) = FUNCTION_COMMANDS.iter().find(|(name, _)| *name == command) {
+        Expr::boxed(ExprKind::Call(function.to_string(), arguments), call_span)
+    } else if let Some((_, function)) = RECIPROCAL_FUNCTION_COMMANDS.iter().find(|(name, _)| *name == command) {
+        let call = Expr::boxed(ExprKind::Call(function.to_string(), arguments), call_span);
+        Expr::boxed(ExprKind::Div(Expr::boxed(ExprKind::Literal(1.0), span), call), call_span)
+    } else {
+        Expr::boxed(ExprKind::Call(name, arguments), call_span)
+    };
+
+    match exponent {
+        Some(exponent) => Expr::boxed(ExprKind::Pow(call, exponent), call_span),
+        None => call,
+    }
+}
+
+/// atom
+///   ::= number
+///   ::= (letter | greek_letter | '\mathrm' name_group) subscript?
+///   ::= frac | sqrt | function | abs
+///   ::= '(' expression ')' | '[' expression ']' | '{' expression '}'
+fn parse_atom(ctx: &mut LatexParserContext) -> Box<Expr> {
+    let span = ctx.current_span();
+
+    match ctx.current_kind().cloned() {
+        Some(LatexTokenKind::Number(number)) => parse_number(ctx, number),
+        Some(LatexTokenKind::Letter(letter)) => {
+            ctx.next_token();
+            let mut name = letter.to_string();
+            parse_subscript(ctx, &mut name);
+            Expr::boxed(ExprKind::Id(name), span.to(ctx.last_span()))
+        },
+        Some(LatexTokenKind::Command(command)) if GREEK_LETTERS.contains(&command.as_str()) => {
+            ctx.next_token();
+            let mut name = command;
+            parse_subscript(ctx, &mut name);
+            Expr::boxed(ExprKind::Id(name), span.to(ctx.last_span()))
+        },
+        Some(LatexTokenKind::Command(command)) if command == "mathrm" => {
+            ctx.next_token();
+            match parse_name_group(ctx, &command) {
+                Some(mut name) => {
+                    parse_subscript(ctx, &mut name);
+                    Expr::boxed(ExprKind::Id(name), span.to(ctx.last_span()))
+                },
+                None => error_expr(span.to(ctx.last_span())),
+            }
+        },
+        Some(LatexTokenKind::Command(command)) => match command.as_str() {
+            "frac" | "dfrac" | "tfrac" => parse_frac(ctx),
+            "sqrt" => parse_sqrt(ctx),
+            _ if is_function_command(&command) => parse_function(ctx, command),
+            _ => report_unexpected(ctx, "number, variable, or '('"),
+        },
+        Some(LatexTokenKind::OpenParen) => parse_group(ctx, LatexTokenKind::CloseParen, ")"),
+        Some(LatexTokenKind::OpenBracket) => parse_group(ctx, LatexTokenKind::CloseBracket, "]"),
+        Some(LatexTokenKind::OpenBrace) => parse_group(ctx, LatexTokenKind::CloseBrace, "}"),
+        Some(LatexTokenKind::OpenBar) => parse_abs(ctx),
+        // Where an operand is expected a bar opens, so that `||x| - 3|` nests.
+        Some(LatexTokenKind::Bar) => parse_abs(ctx),
+        _ => report_unexpected(ctx, "number, variable, or '('"),
+    }
+}
+
+/// postfix ::= atom '!'*
+fn parse_postfix(ctx: &mut LatexParserContext) -> Box<Expr> {
+    let mut expr = parse_atom(ctx);
+
+    while ctx.current_kind() == Some(&LatexTokenKind::Bang) {
+        ctx.next_token();
+        let span = expr.span.to(ctx.last_span());
+        expr = Expr::boxed(ExprKind::Call("factorial".to_string(), vec![expr]), span);
+    }
+
+    expr
+}
+
+/// power ::= postfix ('^' group_argument)?
+fn parse_power(ctx: &mut LatexParserContext) -> Box<Expr> {
+    let base = parse_postfix(ctx);
+
+    if ctx.current_kind() != Some(&LatexTokenKind::Caret) {
+        return base;
+    }
+    ctx.next_token(); // eat ^.
+
+    let exponent = parse_group_argument(ctx);
+    let span = base.span.to(exponent.span);
+
+    if ctx.current_kind() == Some(&LatexTokenKind::Caret) {
+        ctx.push_diagnostic(Diagnostic::new(
+            Level::Error,
+            "double superscript".to_string(),
+        )
+        .with_code(DiagnosticCode::UnexpectedToken)
+        .with_span(ctx.current_span())
+        .with_help("group the exponent in braces, e.g. 'a^{b^c}'".to_string()));
+        ctx.next_token();
+        parse_group_argument(ctx);
+    }
+
+    Expr::boxed(ExprKind::Pow(base, exponent), span)
+}
+
+/// signed ::= ('-' | '+') signed | power
+fn parse_signed(ctx: &mut LatexParserContext) -> Box<Expr> {
+    let span = ctx.current_span();
+
+    match ctx.current_kind() {
+        Some(LatexTokenKind::Minus) => {
+            ctx.next_token();
+            let expr = parse_signed(ctx);
+            let span = span.to(expr.span);
+            Expr::boxed(ExprKind::Unary(expr), span)
+        },
+        Some(LatexTokenKind::Plus) => {
+            ctx.next_token();
+            parse_signed(ctx)
+        },
+        _ => parse_power(ctx),
+    }
+}
+
+/// implicit_product ::= signed power*
+///
+/// The operands stop before a function command, which starts an operand of its own.
+fn parse_implicit_product(ctx: &mut LatexParserContext) -> Box<Expr> {
+    let mut lhs = parse_signed(ctx);
+
+    while is_at_operand(ctx)
+        && !matches!(ctx.current_kind(), Some(LatexTokenKind::Command(name)) if is_function_command(name))
+    {
+        let rhs = parse_power(ctx);
+        let span = lhs.span.to(rhs.span);
+        lhs = Expr::boxed(ExprKind::Mul(lhs, rhs), span);
+    }
+
+    lhs
+}
+
+/// term ::= signed (('\cdot' | '\times' | '*' | '/' | '\div') signed | power)*
+fn parse_term(ctx: &mut LatexParserContext) -> Box<Expr> {
+    let mut lhs = parse_signed(ctx);
+
+    loop {
+        let is_division = if is_command(ctx, &["cdot", "times", "ast"])
+            || ctx.current_kind() == Some(&LatexTokenKind::Star)
+        {
+            false
+        } else if is_command(ctx, &["div"]) || ctx.current_kind() == Some(&LatexTokenKind::Slash) {
+            true
+        } else if is_at_operand(ctx) {
+            let rhs = parse_power(ctx);
+            let span = lhs.span.to(rhs.span);
+            lhs = Expr::boxed(ExprKind::Mul(lhs, rhs), span);
+            continue;
+        } else {
+            return lhs;
+        };
+        ctx.next_token(); // eat the operator.
+
+        let rhs = parse_signed(ctx);
+        let span = lhs.span.to(rhs.span);
+        lhs = if is_division {
+            Expr::boxed(ExprKind::Div(lhs, rhs), span)
+        } else {
+            Expr::boxed(ExprKind::Mul(lhs, rhs), span)
+        };
+    }
+}
+
+/// additive ::= term (('+' | '-') term)*
+fn parse_additive(ctx: &mut LatexParserContext) -> Box<Expr> {
+    let mut lhs = parse_term(ctx);
+
+    loop {
+        let is_subtraction = match ctx.current_kind() {
+            Some(LatexTokenKind::Plus) => false,
+            Some(LatexTokenKind::Minus) => true,
+            _ => return lhs,
+        };
+        ctx.next_token();
+
+        let rhs = parse_term(ctx);
+        let span = lhs.span.to(rhs.span);
+        lhs = if is_subtraction {
+            Expr::boxed(ExprKind::Sub(lhs, rhs), span)
+        } else {
+            Expr::boxed(ExprKind::Add(lhs, rhs), span)
+        };
+    }
+}
+
+fn is_comparison(kind: &LatexTokenKind) -> bool {
+    match kind {
+        LatexTokenKind::Eq | LatexTokenKind::Lt | LatexTokenKind::Gt => true,
+        LatexTokenKind::Command(name) => matches!(name.as_str(),
+            "lt" | "gt" | "le" | "leq" | "leqslant" | "ge" | "geq" | "geqslant" | "ne" | "neq"
+        ),
+        _ => false,
+    }
+}
+
+fn make_comparison(operator: &LatexTokenKind, lhs: Box<Expr>, rhs: Box<Expr>) -> Box<Expr> {
+    let span = lhs.span.to(rhs.span);
+    let name = match operator {
+        LatexTokenKind::Eq => "eq",
+        LatexTokenKind::Lt => "lt",
+        LatexTokenKind::Gt => "gt",
+        LatexTokenKind::Command(name) => name.as_str(),
+        _ => unreachable!(),
+    };
+
+    Expr::boxed(
+        match name {
+            "eq" => ExprKind::Eq(lhs, rhs),
+            "lt" => ExprKind::Lt(lhs, rhs),
+            "gt" => ExprKind::Gt(lhs, rhs),
+            "le" | "leq" | "leqslant" => ExprKind::Le(lhs, rhs),
+            "ge" | "geq" | "geqslant" => ExprKind::Ge(lhs, rhs),
+            "ne" | "neq" => ExprKind::Not(Expr::boxed(ExprKind::Eq(lhs, rhs), span)),
+            _ => unreachable!(),
+        },
+        span
+    )
+}
+
+/// comparison ::= additive (comparison_op additive)*
+fn parse_comparison(ctx: &mut LatexParserContext) -> Box<Expr> {
+    let mut lhs = parse_additive(ctx);
+    // Right operand of the last comparison, to continue a comparison chain with.
+    let mut comparison_rhs: Option<Box<Expr>> = None;
+
+    while let Some(operator) = ctx.current_kind().filter(|kind| is_comparison(kind)).cloned() {
+        ctx.next_token(); // eat the operator.
+        let rhs = parse_additive(ctx);
+
+        lhs = match comparison_rhs.take() {
+            Some(middle) => {
+                // `a < b < c` is a chained comparison meaning `a < b \land b < c`.
+                let comparison = make_comparison(&operator, middle, rhs.clone());
+                let span = lhs.span.to(comparison.span);
+                Expr::boxed(ExprKind::And(lhs, comparison), span)
+            },
+            None => make_comparison(&operator, lhs, rhs.clone()),
+        };
+        comparison_rhs = Some(rhs);
+    }
+
+    lhs
+}
+
+/// not ::= ('\lnot' | '\neg') not | comparison
+fn parse_not(ctx: &mut LatexParserContext) -> Box<Expr> {
+    if !is_command(ctx, &["lnot", "neg"]) {
+        return parse_comparison(ctx);
+    }
+
+    let span = ctx.current_span();
+    ctx.next_token();
+    let expr = parse_not(ctx);
+    let span = span.to(expr.span);
+    Expr::boxed(ExprKind::Not(expr), span)
+}
+
+/// and ::= not (('\land' | '\wedge') not)*
+fn parse_and(ctx: &mut LatexParserContext) -> Box<Expr> {
+    let mut lhs = parse_not(ctx);
+
+    while is_command(ctx, &["land", "wedge"]) {
+        ctx.next_token();
+        let rhs = parse_not(ctx);
+        let span = lhs.span.to(rhs.span);
+        lhs = Expr::boxed(ExprKind::And(lhs, rhs), span);
+    }
+
+    lhs
+}
+
+/// expression ::= and (('\lor' | '\vee') and)*
+fn parse_expression(ctx: &mut LatexParserContext) -> Box<Expr> {
+    let mut lhs = parse_and(ctx);
+
+    while is_command(ctx, &["lor", "vee"]) {
+        ctx.next_token();
+        let rhs = parse_and(ctx);
+        let span = lhs.span.to(rhs.span);
+        lhs = Expr::boxed(ExprKind::Or(lhs, rhs), span);
+    }
+
+    lhs
+}
+
+/// Parses LaTeX math input, recovering from syntax errors.
+///
+/// system ::= expression (',' expression)*
+///
+/// Several comma-separated expressions form an `ExprKind::System`.
+/// Always returns an AST; erroneous parts are replaced by `ExprKind::Error` nodes and
+/// every problem found is reported to the context's diagnostic sink.
+pub fn parse_latex_with_recovery(ctx: &mut LatexParserContext) -> Box<Expr> {
+    let mut equations = Vec::new();
+
+    loop {
+        equations.push(parse_expression(ctx));
+
+        if ctx.current_kind().is_some_and(|token| token != &LatexTokenKind::Comma) {
+            let span = ctx.current_span();
+            ctx.push_diagnostic(Diagnostic::new(
+                Level::Error,
+                "unexpected token after top-level expression".to_string(),
+            )
+            .with_code(DiagnosticCode::UnexpectedToken)
+            .with_span(span)
+            .with_label(span, "expected ',' or end of input".to_string()));
+
+            while ctx.current_kind().is_some_and(|token| token != &LatexTokenKind::Comma) {
+                ctx.next_token();
+            }
+        }
+
+        if ctx.current_token().is_none() {
+            break;
+        }
+        ctx.next_token(); // eat ,.
+    }
+
+    if equations.len() == 1 {
+        equations.pop().unwrap()
+    } else {
+        let span = equations[0].span.to(equations[equations.len() - 1].span);
+        Expr::boxed(ExprKind::System(equations), span)
+    }
+}
+
+pub fn parse_latex(mut ctx: LatexParserContext) -> Result<Box<Expr>, ErrorReported> {
+    let result = parse_latex_with_recovery(&mut ctx);
+
+    if ctx.has_errors() {
+        Err(ErrorReported)
+    } else {
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diagnostic::DiagnosticSink;
+
+    /// Parses the LaTeX input and prints the AST in the plain syntax.
+    fn latex(input: &str) -> String {
+        let mut diagnostics = DiagnosticSink::new();
+        let ast = parse_latex(LatexParserContext::new(input, &mut diagnostics))
+            .unwrap_or_else(|_| panic!("failed to parse {}: {:?}", input, diagnostics.diagnostics()));
+        ast.to_string()
+    }
+
+    /// Code and span of each diagnostic reported while parsing the LaTeX input.
+    fn errors(input: &str) -> Vec<(Option<DiagnosticCode>, Option<Span>)> {
+        let mut diagnostics = DiagnosticSink::new();
+        assert!(parse_latex(LatexParserContext::new(input, &mut diagnostics)).is_err());
+        diagnostics.diagnostics().iter().map(|diagnostic| (diagnostic.code(), diagnostic.span())).collect()
+    }
+
+    #[test]
+    fn fractions_and_roots() {
+        assert_eq!(latex(r"\frac{x^2}{4} + y^2"), "x ^ 2 / 4 + y ^ 2");
+        assert_eq!(latex(r"\frac12"), "1 / 2");
+        assert_eq!(latex(r"\sqrt{x}"), "sqrt(x)");
+        assert_eq!(latex(r"\sqrt[2]{x}"), "sqrt(x)");
+        assert_eq!(latex(r"\sqrt[3]{x}"), "cbrt(x)");
+        assert_eq!(latex(r"\sqrt[n]{x+1}"), "(x + 1) ^ (1 / n)");
+    }
+
+    #[test]
+    fn sized_delimiters() {
+        assert_eq!(latex(r"\left( x + 1 \right) \cdot 2"), "(x + 1) * 2");
+        assert_eq!(latex(r"\left| x \right|"), "abs(x)");
+    }
+
+    #[test]
+    fn comparison_commands() {
+        assert_eq!(latex(r"x \le 1"), "x <= 1");
+        assert_eq!(latex(r"x \geq 1"), "x >= 1");
+        assert_eq!(latex(r"x \ne y"), "x != y");
+        assert_eq!(latex(r"x \neq y"), "x != y");
+    }
+
+    #[test]
+    fn greek_letters_are_variables() {
+        assert_eq!(latex(r"\theta + \pi \alpha"), "theta + pi * alpha");
+        assert_eq!(latex(r"\Omega_1"), "Omega_1");
+    }
+
+    #[test]
+    fn functions_take_juxtaposed_arguments() {
+        assert_eq!(latex(r"\sin 2x \cos x"), "sin(2 * x) * cos(x)");
+        assert_eq!(latex(r"\log_2 x"), "log(x, 2)");
+    }
+
+    #[test]
+    fn minus_one_power_of_a_trigonometric_function_is_its_inverse() {
+        assert_eq!(latex(r"\sin^{-1} x"), "asin(x)");
+        assert_eq!(latex(r"\tanh^{-1}(x)"), "atanh(x)");
+        assert_eq!(latex(r"\cos^2 x"), "cos(x) ^ 2");
+        assert_eq!(latex(r"(\ln x)^{-1}"), "ln(x) ^ -1");
+        assert_eq!(errors(r"\ln^{-1} x"), [(Some(DiagnosticCode::UnexpectedToken), Some(Span::new(4, 8)))]);
+        assert_eq!(errors(r"\sec^{-1} x"), [(Some(DiagnosticCode::UnexpectedToken), Some(Span::new(5, 9)))]);
+    }
+
+    #[test]
+    fn bars_nest_where_an_operand_is_expected() {
+        assert_eq!(latex(r"||x|-3|"), "abs(abs(x) - 3)");
+        assert_eq!(latex(r"|x||y|"), "abs(x) * abs(y)");
+        assert_eq!(latex(r"|2-|x||"), "abs(2 - abs(x))");
+    }
+
+    #[test]
+    fn malformed_input_reports_errors() {
+        assert_eq!(errors(r"\frac{x}"), [(Some(DiagnosticCode::UnexpectedEndOfInput), Some(Span::new(8, 8)))]);
+        assert_eq!(errors(r"\left( x"), [(Some(DiagnosticCode::UnclosedDelimiter), Some(Span::new(8, 8)))]);
+        assert_eq!(errors(r"\foo x"), [(Some(DiagnosticCode::UnexpectedToken), Some(Span::new(0, 4)))]);
+    }
+}
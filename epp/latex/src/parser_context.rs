@@ -0,0 +1,110 @@
+use diagnostic::{Diagnostic, DiagnosticSink, Level};
+use span::Span;
+
+use crate::lexer::tokenize;
+use crate::token::{LatexToken, LatexTokenKind};
+
+pub struct LatexParserContext<'a> {
+    tokens: Vec<LatexToken>,
+    position: usize,
+    input_len: usize,
+    diagnostics: &'a mut DiagnosticSink,
+    error_count: usize,
+    /// Number of `|` opening an absolute value that are not closed yet.
+    open_bar_count: usize,
+}
+
+impl<'a> LatexParserContext<'a> {
+    pub fn new(input: &str, diagnostics: &'a mut DiagnosticSink) -> Self {
+        Self {
+            tokens: tokenize(input),
+            position: 0,
+            input_len: input.len(),
+            diagnostics,
+            error_count: 0,
+            open_bar_count: 0,
+        }
+    }
+
+    pub fn next_token(&mut self) -> Option<&LatexToken> {
+        if self.position < self.tokens.len() {
+            self.position += 1;
+        }
+        self.current_token()
+    }
+
+    pub fn peek_kind(&self, n: usize) -> Option<&LatexTokenKind> {
+        self.tokens.get(self.position + n).map(|token| &token.kind)
+    }
+
+    pub fn current_token(&self) -> Option<&LatexToken> {
+        self.tokens.get(self.position)
+    }
+
+    pub fn current_kind(&self) -> Option<&LatexTokenKind> {
+        self.current_token().map(|token| &token.kind)
+    }
+
+    /// Span of the current token, or an empty span at the end of the input.
+    pub fn current_span(&self) -> Span {
+        self.current_token()
+            .map_or(Span::new(self.input_len, self.input_len), |token| token.span)
+    }
+
+    /// Span of the last consumed token.
+    pub fn last_span(&self) -> Span {
+        match self.position {
+            0 => Span::new(0, 0),
+            position => self.tokens[position - 1].span,
+        }
+    }
+
+    /// Splits the current number token after its first character.
+    ///
+    /// A superscript or `\frac` argument without braces is a single character, so `x^23` is `x^2 3`.
+    pub fn split_current_number(&mut self) {
+        let token = match self.tokens.get(self.position) {
+            Some(token) => token.clone(),
+            None => return,
+        };
+
+        if let LatexTokenKind::Number(number) = &token.kind {
+            if number.len() > 1 {
+                let split = token.span.low() + 1;
+                self.tokens[self.position] = LatexToken::new(
+                    LatexTokenKind::Number(number[..1].to_string()),
+                    Span::new(token.span.low(), split),
+                );
+                self.tokens.insert(self.position + 1, LatexToken::new(
+                    LatexTokenKind::Number(number[1..].to_string()),
+                    Span::new(split, token.span.high()),
+                ));
+            }
+        }
+    }
+
+    pub fn open_bar(&mut self) {
+        self.open_bar_count += 1;
+    }
+
+    pub fn close_bar(&mut self) {
+        self.open_bar_count -= 1;
+    }
+
+    /// Whether a `|` at this point closes an absolute value rather than opening one.
+    pub fn is_in_bar(&self) -> bool {
+        self.open_bar_count != 0
+    }
+
+    pub fn push_diagnostic(&mut self, diagnostic: Diagnostic) {
+        if diagnostic.level() == Level::Error {
+            self.error_count += 1;
+        }
+        self.diagnostics.push(diagnostic);
+    }
+
+    /// Whether an error has been reported while parsing with this context.
+    pub fn has_errors(&self) -> bool {
+        self.error_count != 0
+    }
+}
@@ -0,0 +1,41 @@
+use span::Span;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatexToken {
+    pub kind: LatexTokenKind,
+    pub span: Span,
+}
+
+impl LatexToken {
+    pub fn new(kind: LatexTokenKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LatexTokenKind {
+    Unknown(char),
+    Number(String),   // digits with an optional '.'
+    Letter(char),     // a single-letter variable
+    Command(String),  // "\name", without the backslash
+    OpenBrace,        // "{"
+    CloseBrace,       // "}"
+    OpenParen,        // "(" or "\left("
+    CloseParen,       // ")" or "\right)"
+    OpenBracket,      // "[" or "\left["
+    CloseBracket,     // "]" or "\right]"
+    Bar,              // "|", whose meaning depends on the context
+    OpenBar,          // "\left|" or "\lvert"
+    CloseBar,         // "\right|" or "\rvert"
+    Caret,            // "^"
+    Underscore,       // "_"
+    Plus,             // "+"
+    Minus,            // "-"
+    Star,             // "*"
+    Slash,            // "/"
+    Eq,               // "="
+    Lt,               // "<"
+    Gt,               // ">"
+    Comma,            // ","
+    Bang,             // "!"
+}
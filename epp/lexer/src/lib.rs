@@ -4,68 +4,99 @@ mod token;
 pub use token::*;
 
 use cursor::*;
+use span::Span;
 use std::iter::from_fn as iter_from_fn;
 use unicode_xid::UnicodeXID;
 
-pub fn token_iter(mut input: &str) -> impl Iterator<Item = Token> + '_ {
+pub fn token_iter(input: &str) -> impl Iterator<Item = Token> + '_ {
+    let mut offset = 0;
+
     iter_from_fn(move || {
-        let mut cursor = Cursor::new(input);
+        let mut cursor = Cursor::new(&input[offset..]);
         consume_whitespace(&mut cursor);
-        input = &input[cursor.len_consumed()..];
+        offset += cursor.len_consumed();
 
-        if input.is_empty() {
+        if offset == input.len() {
             return None;
         }
 
-        let (token, consume_len) = next(input);
-        input = &input[consume_len..];
-        Some(token)
+        let (kind, consume_len) = next(&input[offset..]);
+        let span = Span::new(offset, offset + consume_len);
+        offset += consume_len;
+        Some(Token::new(kind, span))
     })
 }
 
-fn next(input: &str) -> (Token, usize) {
+fn next(input: &str) -> (TokenKind, usize) {
     let mut cursor = Cursor::new(input);
 
     let kind = match cursor.consume().unwrap() {
         char if is_id_start(char) => {
-            consume_while(&mut cursor, |char| is_id_continue(char));
-            Token::Id(input[..cursor.len_consumed()].to_string())
+            consume_while(&mut cursor, is_id_continue);
+            match &input[..cursor.len_consumed()] {
+                "and" => TokenKind::And,
+                "or" => TokenKind::Or,
+                "not" => TokenKind::Not,
+                "if" => TokenKind::If,
+                "sum" => TokenKind::Sum,
+                "prod" => TokenKind::Prod,
+                "let" => TokenKind::Let,
+                "in" => TokenKind::In,
+                id => TokenKind::Id(id.to_string()),
+            }
+        }
+        first_digit @ '0'..='9' => {
+            consume_number(&mut cursor, first_digit);
+            let suffix_start = cursor.len_consumed();
+
+            TokenKind::NumberLiteral(input[..suffix_start].to_string())
         }
-        '0'..='9' => {
-            consume_number(&mut cursor);
+        '.' if cursor.first().is_ascii_digit() => {
+            consume_number(&mut cursor, '.');
             let suffix_start = cursor.len_consumed();
 
-            Token::NumberLiteral(input[..suffix_start].to_string())
+            TokenKind::NumberLiteral(input[..suffix_start].to_string())
         }
-        '(' => Token::OpenParen,
-        ')' => Token::CloseParen,
-        ',' => Token::Comma,
-        '=' => Token::Eq,
+        '(' => TokenKind::OpenParen,
+        ')' => TokenKind::CloseParen,
+        ',' => TokenKind::Comma,
+        ';' => TokenKind::Semicolon,
+        '=' => TokenKind::Eq,
         '<' => {
             consume_whitespace(&mut cursor);
             if cursor.lookup(0) == '=' {
                 cursor.consume();
-                Token::Le
+                TokenKind::Le
             } else {
-                Token::Lt
+                TokenKind::Lt
             }
         },
         '>' => {
             consume_while(&mut cursor, |char| char.is_whitespace());
             if cursor.lookup(0) == '=' {
                 cursor.consume();
-                Token::Ge
+                TokenKind::Ge
+            } else {
+                TokenKind::Gt
+            }
+        },
+        '+' => TokenKind::Plus,
+        '-' => TokenKind::Minus,
+        '*' => TokenKind::Star,
+        '/' => TokenKind::Slash,
+        '%' => TokenKind::Percent,
+        '^' => TokenKind::Caret,
+        '|' => TokenKind::Pipe,
+        '!' => {
+            // Unlike `< =`, `! =` is a factorial compared for equality.
+            if cursor.first() == '=' {
+                cursor.consume();
+                TokenKind::Ne
             } else {
-                Token::Gt
+                TokenKind::Bang
             }
         },
-        '+' => Token::Plus,
-        '-' => Token::Minus,
-        '*' => Token::Star,
-        '/' => Token::Slash,
-        '%' => Token::Percent,
-        '^' => Token::Caret,
-        _ => Token::Unknown,
+        _ => TokenKind::Unknown,
     };
 
     (kind, cursor.len_consumed())
@@ -82,38 +113,135 @@ fn consume_whitespace(cursor: &mut Cursor) {
 }
 
 fn is_id_start(char: char) -> bool {
-    ('a'..='z').contains(&char)
-        || ('A'..='Z').contains(&char)
+    char.is_ascii_lowercase()
+        || char.is_ascii_uppercase()
         || (char == '_')
         || (char > '\x7f' && UnicodeXID::is_xid_start(char))
 }
 
 fn is_id_continue(char: char) -> bool {
-    ('a'..='z').contains(&char)
-        || ('A'..='Z').contains(&char)
-        || ('0'..='9').contains(&char)
+    char.is_ascii_lowercase()
+        || char.is_ascii_uppercase()
+        || char.is_ascii_digit()
         || (char == '_')
         || (char > '\x7f' && UnicodeXID::is_xid_continue(char))
 }
 
-fn consume_number(cursor: &mut Cursor) {
-    match cursor.first() {
-        '.' if cursor.second().is_digit(10) => {
+/// Consumes the rest of a number literal whose first character has already been consumed.
+///
+/// Accepts `123`, `123.45`, `.5`, exponents like `1e-9` and `6.02E23`, hexadecimal
+/// integers like `0x1F`, and `_` digit separators like `1_000_000`. Literals are only
+/// split into tokens here; malformed ones (e.g. `0x`, `1__0` or `1.2.3`) are reported by the parser.
+fn consume_number(cursor: &mut Cursor, first: char) {
+    if first == '0' && (cursor.first() == 'x' || cursor.first() == 'X') {
+        cursor.consume();
+        consume_while(cursor, |char| char.is_ascii_hexdigit() || char == '_');
+    } else {
+        consume_decimal(cursor, first);
+    }
+
+    // Dots and digits right after a literal belong to it, so that `1.2.3` is one malformed
+    // literal rather than `1.2` and `.3`.
+    consume_while(cursor, |char| char.is_ascii_digit() || char == '_' || char == '.');
+}
+
+fn consume_decimal(cursor: &mut Cursor, first: char) {
+    if first != '.' {
+        consume_while(cursor, |char| char.is_ascii_digit() || char == '_');
+
+        if cursor.first() == '.' {
             cursor.consume();
-            consume_while(cursor, |char| char.is_digit(10));
         }
-        '0'..='9' => {
-            cursor.consume();
-            consume_while(cursor, |char| char.is_digit(10));
+    }
 
-            if cursor.first() == '.' {
-                cursor.consume();
-                consume_while(cursor, |char| char.is_digit(10));
-            }
+    consume_while(cursor, |char| char.is_ascii_digit() || char == '_');
+
+    // The exponent is only consumed if digits follow, so that `2e` still lexes as `2` and `e`.
+    let has_exponent = match (cursor.first(), cursor.second()) {
+        ('e' | 'E', '0'..='9') => true,
+        ('e' | 'E', '+' | '-') => cursor.lookup(2).is_ascii_digit(),
+        _ => false,
+    };
+
+    if has_exponent {
+        cursor.consume();
+
+        if cursor.first() == '+' || cursor.first() == '-' {
+            cursor.consume();
         }
-        _ => {
-            consume_while(cursor, |char| char.is_digit(10));
-            return;
+
+        consume_while(cursor, |char| char.is_ascii_digit() || char == '_');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(input: &str) -> Vec<(TokenKind, usize, usize)> {
+        token_iter(input).map(|token| (token.kind, token.span.low(), token.span.high())).collect()
+    }
+
+    #[test]
+    fn tokens_span_their_text_without_whitespace() {
+        assert_eq!(
+            spans(" foo(12,  x) "),
+            [
+                (TokenKind::Id("foo".to_string()), 1, 4),
+                (TokenKind::OpenParen, 4, 5),
+                (TokenKind::NumberLiteral("12".to_string()), 5, 7),
+                (TokenKind::Comma, 7, 8),
+                (TokenKind::Id("x".to_string()), 10, 11),
+                (TokenKind::CloseParen, 11, 12),
+            ]
+        );
+    }
+
+    #[test]
+    fn spans_are_byte_offsets() {
+        assert_eq!(
+            spans("π + 1"),
+            [
+                (TokenKind::Id("π".to_string()), 0, 2),
+                (TokenKind::Plus, 3, 4),
+                (TokenKind::NumberLiteral("1".to_string()), 5, 6),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_relation_spans_both_characters() {
+        assert_eq!(spans("x < = 1")[1], (TokenKind::Le, 2, 5));
+    }
+
+    fn kinds(input: &str) -> Vec<TokenKind> {
+        token_iter(input).map(|token| token.kind).collect()
+    }
+
+    fn number(text: &str) -> TokenKind {
+        TokenKind::NumberLiteral(text.to_string())
+    }
+
+    #[test]
+    fn number_literals_are_single_tokens() {
+        for literal in ["123", "123.45", ".5", "5.", "1e-9", "6.02E23", "1e+3", "0x1F", "0Xff", "1_000_000", "0x_1"] {
+            assert_eq!(kinds(literal), [number(literal)], "{}", literal);
         }
     }
+
+    #[test]
+    fn exponent_needs_digits() {
+        assert_eq!(kinds("2e"), [number("2"), TokenKind::Id("e".to_string())]);
+        assert_eq!(kinds("2e+x"), [number("2"), TokenKind::Id("e".to_string()), TokenKind::Plus, TokenKind::Id("x".to_string())]);
+        assert_eq!(kinds("2ex"), [number("2"), TokenKind::Id("ex".to_string())]);
+    }
+
+    #[test]
+    fn malformed_literals_are_left_to_the_parser() {
+        assert_eq!(kinds("0x"), [number("0x")]);
+        assert_eq!(kinds("1__0"), [number("1__0")]);
+        assert_eq!(kinds("1.2.3"), [number("1.2.3")]);
+        assert_eq!(kinds("0x1.5"), [number("0x1.5")]);
+        assert_eq!(kinds("1e5.5"), [number("1e5.5")]);
+    }
 }
@@ -1,44 +1,82 @@
+use span::Span;
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+impl Token {
+    pub fn new(kind: TokenKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, PartialOrd, Ord, Hash)]
-pub enum Token {
+pub enum TokenKind {
     Unknown,
     OpenParen,    // "("
     CloseParen,   // ")"
     Comma,        // ","
+    Semicolon,    // ";"
     Eq,           // "="
     Lt,           // "<"
     Gt,           // ">"
     Le,           // "<="
     Ge,           // ">="
+    Ne,           // "!="
     Plus,         // "+"
     Minus,        // "-"
     Star,         // "*"
     Slash,        // "/"
     Percent,      // "%"
     Caret,        // "^"
-    Id(String),   // identifier or keyword
+    Pipe,         // "|"
+    Bang,         // "!"
+    And,          // "and"
+    Or,           // "or"
+    Not,          // "not"
+    If,           // "if"
+    Sum,          // "sum"
+    Prod,         // "prod"
+    Let,          // "let"
+    In,           // "in"
+    Id(String),   // identifier
     NumberLiteral(String),
 }
 
-impl Token {
+impl TokenKind {
     pub fn to_str(&self) -> &'static str {
         match self {
-            Token::Unknown => "unknown",
-            Token::OpenParen => "(",
-            Token::CloseParen => ")",
-            Token::Comma => ",",
-            Token::Eq => "=",
-            Token::Lt => "<",
-            Token::Gt => ">",
-            Token::Le => "<=",
-            Token::Ge => ">=",
-            Token::Plus => "+",
-            Token::Minus => "-",
-            Token::Star => "*",
-            Token::Slash => "/",
-            Token::Percent => "%",
-            Token::Caret => "^",
-            Token::Id(..) => "id",
-            Token::NumberLiteral(..) => "literal",
+            TokenKind::Unknown => "unknown",
+            TokenKind::OpenParen => "(",
+            TokenKind::CloseParen => ")",
+            TokenKind::Comma => ",",
+            TokenKind::Semicolon => ";",
+            TokenKind::Eq => "=",
+            TokenKind::Lt => "<",
+            TokenKind::Gt => ">",
+            TokenKind::Le => "<=",
+            TokenKind::Ge => ">=",
+            TokenKind::Ne => "!=",
+            TokenKind::Plus => "+",
+            TokenKind::Minus => "-",
+            TokenKind::Star => "*",
+            TokenKind::Slash => "/",
+            TokenKind::Percent => "%",
+            TokenKind::Caret => "^",
+            TokenKind::Pipe => "|",
+            TokenKind::Bang => "!",
+            TokenKind::And => "and",
+            TokenKind::Or => "or",
+            TokenKind::Not => "not",
+            TokenKind::If => "if",
+            TokenKind::Sum => "sum",
+            TokenKind::Prod => "prod",
+            TokenKind::Let => "let",
+            TokenKind::In => "in",
+            TokenKind::Id(..) => "id",
+            TokenKind::NumberLiteral(..) => "literal",
         }
     }
 }
@@ -2,71 +2,480 @@ mod parser_context;
 
 pub use parser_context::*;
 
-use ast::Expr;
-use lexer::Token;
-use diagnostic::{Diagnostic, Level};
+use ast::{Expr, ExprKind, ExprList};
+use lexer::TokenKind;
+use diagnostic::{Applicability, Diagnostic, DiagnosticCode, ErrorReported, Level};
+use span::Span;
+
+/// Skips tokens until a `)`, `,` or `in` that is not nested inside skipped parentheses, a `;`,
+/// or the end of input. The delimiter itself is left for the caller to consume.
+fn synchronize(ctx: &mut ParserContext) {
+    let mut depth = 0;
+
+    while let Some(token) = ctx.current_kind() {
+        match token {
+            TokenKind::OpenParen => depth += 1,
+            TokenKind::CloseParen if depth == 0 => return,
+            TokenKind::CloseParen => depth -= 1,
+            TokenKind::Comma | TokenKind::In if depth == 0 => return,
+            TokenKind::Semicolon => return,
+            _ => { }
+        }
+
+        ctx.next_token();
+    }
+}
+
+fn error_expr(span: Span) -> Box<Expr> {
+    Expr::boxed(ExprKind::Error, span)
+}
+
+/// Converts the text of a number literal token into its value.
+fn parse_number_literal(number: &str) -> Result<f64, String> {
+    let (digits, is_hex) = match number.get(..2) {
+        Some("0x") | Some("0X") => (&number[2..], true),
+        _ => (number, false),
+    };
+    let is_digit = |char: &char| if is_hex { char.is_ascii_hexdigit() } else { char.is_ascii_digit() };
+
+    let chars = digits.chars().collect::<Vec<_>>();
+    for (i, char) in chars.iter().enumerate() {
+        if *char == '_' && (i == 0 || i + 1 == chars.len() || !is_digit(&chars[i - 1]) || !is_digit(&chars[i + 1])) {
+            return Err("'_' must be placed between two digits".to_string());
+        }
+    }
+    let digits = digits.replace('_', "");
+
+    let value = if is_hex {
+        if digits.is_empty() {
+            return Err("hexadecimal literal has no digits".to_string());
+        }
+        u64::from_str_radix(&digits, 16)
+            .map_err(|_| "hexadecimal literal is too large".to_string())? as f64
+    } else {
+        digits
+            .parse::<f64>()
+            .map_err(|_| "invalid number literal".to_string())?
+    };
+
+    if value.is_finite() {
+        Ok(value)
+    } else {
+        Err("number literal is out of range".to_string())
+    }
+}
 
 /// number_expr ::= number
 fn parse_number_expr(ctx: &mut ParserContext, number: String) -> Box<Expr> {
+    let span = ctx.current_span();
     ctx.next_token();
-    return Box::new(Expr::Literal(number.parse::<f64>().unwrap()));
+
+    match parse_number_literal(&number) {
+        Ok(value) => Expr::boxed(ExprKind::Literal(value), span),
+        Err(reason) => {
+            ctx.push_diagnostic(Diagnostic::new(
+                Level::Error,
+                format!("malformed number literal '{}'", number),
+            )
+            .with_code(DiagnosticCode::MalformedNumberLiteral)
+            .with_span(span)
+            .with_label(span, reason));
+            error_expr(span)
+        }
+    }
 }
 
 /// paren_expr ::= '(' expression ')'
-fn parse_paren_expr(ctx: &mut ParserContext) -> Result<Box<Expr>, ()> {
+fn parse_paren_expr(ctx: &mut ParserContext) -> Box<Expr> {
+    let open_span = ctx.current_span();
     ctx.next_token(); // eat (.
-    let v = parse_expression(ctx)?;
+    let outer_open_bar_count = ctx.enter_parentheses();
+    let mut v = parse_expression(ctx);
+    ctx.leave_parentheses(outer_open_bar_count);
 
-    if let Some(Token::CloseParen) = ctx.current_token() {    
+    if let Some(TokenKind::CloseParen) = ctx.current_kind() {    
+        v.span = open_span.to(ctx.current_span());
         ctx.next_token(); // eat ).
-        Ok(v)
+        return v;
+    }
+
+    let applicability = if ctx.current_token().is_none() {
+        Applicability::MachineApplicable
     } else {
-        Diagnostic::push_new(Diagnostic::new(
-            Level::Error,
-            "expected ')'".to_string(),
-        ));
-        Err(())
+        Applicability::MaybeIncorrect
+    };
+
+    ctx.push_diagnostic(Diagnostic::new(
+        Level::Error,
+        "expected ')'".to_string(),
+    )
+    .with_code(DiagnosticCode::UnclosedDelimiter)
+    .with_span(ctx.current_span())
+    .with_label(open_span, "unclosed delimiter".to_string())
+    .with_suggestion(
+        ctx.current_span().start(),
+        ")".to_string(),
+        "insert the missing ')'".to_string(),
+        applicability,
+    ));
+
+    synchronize(ctx);
+
+    if let Some(TokenKind::CloseParen) = ctx.current_kind() {
+        ctx.next_token(); // eat ).
     }
-}
 
-/// identifier_expr
-///   ::= identifier
-///   ::= identifier '(' expression* ')'
-fn parse_identifier_expr(ctx: &mut ParserContext, id_name: String) -> Result<Box<Expr>, ()> {
-    ctx.next_token(); // eat identifier.
+    v.span = open_span.to(ctx.last_span());
+    v
+}
 
-    match ctx.current_token() {
-        Some(&Token::OpenParen) => {
-            // Call.
-            ctx.next_token(); // eat (
-            let mut args = Vec::new();
+/// argument_list ::= '(' (expression (',' expression)*)? ')'
+///
+/// Expects the current token to be the '('. Returns the arguments and the span of the closing ')'.
+fn parse_argument_list(ctx: &mut ParserContext, callee_name: &str) -> (ExprList, Span) {
+    let open_span = ctx.current_span();
+    ctx.next_token(); // eat (
+    let outer_open_bar_count = ctx.enter_parentheses();
+    let mut args = Vec::new();
+    
+    if ctx.current_kind().is_none() || ctx.current_kind().unwrap() != &TokenKind::CloseParen {
+        loop {
+            let arg = parse_expression(ctx);
+            let arg_is_error = matches!(arg.kind, ExprKind::Error);
+            args.push(arg);
             
-            if ctx.current_token().is_none() || ctx.current_token().unwrap() != &Token::CloseParen {
-                loop {
-                    let arg = parse_expression(ctx)?;
-                    args.push(arg);
-                    
-                    if let Some(Token::CloseParen) = ctx.current_token() {
-                        break;
-                    } else if ctx.current_token().map_or(true, |tok| tok != &Token::Comma) {
-                        Diagnostic::push_new(Diagnostic::new(
-                            Level::Error,
-                            "Expected ')' or ',' in argument list".to_string(),
-                        ));
-                        return Err(());
-                    }
+            if let Some(TokenKind::CloseParen) = ctx.current_kind() {
+                break;
+            } else if ctx.current_kind() != Some(&TokenKind::Comma) {
+                if arg_is_error && ctx.current_token().is_none() {
+                    // The argument already reported the end of input.
+                    break;
+                }
 
-                    ctx.next_token();
+                ctx.push_diagnostic(Diagnostic::new(
+                    Level::Error,
+                    "Expected ')' or ',' in argument list".to_string(),
+                )
+                .with_code(DiagnosticCode::UnexpectedToken)
+                .with_span(ctx.current_span())
+                .with_label(open_span, format!("argument list of '{}' starts here", callee_name)));
+
+                synchronize(ctx);
+
+                match ctx.current_kind() {
+                    Some(TokenKind::Comma) => { },
+                    _ => break,
                 }
             }
-    
-            // Eat the ')'.
+
             ctx.next_token();
+        }
+    }
+
+    ctx.leave_parentheses(outer_open_bar_count);
+
+    if let Some(TokenKind::CloseParen) = ctx.current_kind() {
+        let close_span = ctx.current_span();
+        ctx.next_token(); // eat ).
+        (args, close_span)
+    } else {
+        (args, ctx.last_span())
+    }
+}
+
+/// abs_expr ::= '|' expression '|'
+///
+/// Parsed as a call of `abs`. See `ParserContext::is_at_implicit_multiplication` for how a
+/// `|` after an operand is told apart from a closing one.
+fn parse_abs_expr(ctx: &mut ParserContext) -> Box<Expr> {
+    let open_span = ctx.current_span();
+    ctx.next_token(); // eat |.
+
+    ctx.open_bar();
+    let v = parse_expression(ctx);
+    ctx.close_bar();
+
+    if let Some(TokenKind::Pipe) = ctx.current_kind() {
+        let span = open_span.to(ctx.current_span());
+        ctx.next_token(); // eat |.
+        return Expr::boxed(ExprKind::Call("abs".to_string(), vec![v]), span);
+    }
+
+    ctx.push_diagnostic(Diagnostic::new(
+        Level::Error,
+        "expected '|'".to_string(),
+    )
+    .with_code(DiagnosticCode::UnclosedDelimiter)
+    .with_span(ctx.current_span())
+    .with_label(open_span, "unclosed absolute value".to_string())
+    .with_suggestion(
+        ctx.current_span().start(),
+        "|".to_string(),
+        "insert the missing '|'".to_string(),
+        Applicability::MaybeIncorrect,
+    ));
+
+    let span = open_span.to(ctx.last_span());
+    Expr::boxed(ExprKind::Call("abs".to_string(), vec![v]), span)
+}
+
+/// identifier_expr
+///   ::= identifier
+///   ::= identifier argument_list
+fn parse_identifier_expr(ctx: &mut ParserContext, id_name: String) -> Box<Expr> {
+    let id_span = ctx.current_span();
+    ctx.next_token(); // eat identifier.
+
+    match ctx.current_kind() {
+        Some(&TokenKind::OpenParen) if ctx.is_callable(&id_name) => {
+            // Call.
+            let (args, close_span) = parse_argument_list(ctx, &id_name);
+            let span = id_span.to(close_span);
         
-            return Ok(Box::new(Expr::Call(id_name.to_owned(), args)));
+            Expr::boxed(ExprKind::Call(id_name.to_owned(), args), span)
         },
         _ => { // Simple variable ref.
-            return Ok(Box::new(Expr::Id(id_name)));
+            Expr::boxed(ExprKind::Id(id_name), id_span)
+        }
+    }
+}
+
+/// Parses the parenthesized arguments of the keyword at the current token, which must be
+/// exactly as many as in `syntax`, e.g. `if(condition, then, otherwise)`.
+///
+/// Returns the arguments and the span of the whole expression, or `None` after reporting an error.
+fn parse_keyword_arguments(ctx: &mut ParserContext, syntax: &str, count: usize) -> Option<(ExprList, Span)> {
+    let keyword_span = ctx.current_span();
+    let keyword = ctx.current_kind().unwrap().to_str();
+    ctx.next_token(); // eat keyword.
+
+    if ctx.current_kind() != Some(&TokenKind::OpenParen) {
+        ctx.push_diagnostic(Diagnostic::new(
+            Level::Error,
+            format!("expected '(' after '{}'", keyword),
+        )
+        .with_code(DiagnosticCode::UnexpectedToken)
+        .with_span(ctx.current_span())
+        .with_help(format!("write it as '{}'", syntax)));
+        synchronize(ctx);
+        return None;
+    }
+
+    let (args, close_span) = parse_argument_list(ctx, keyword);
+    let span = keyword_span.to(close_span);
+
+    if args.len() != count {
+        ctx.push_diagnostic(Diagnostic::new(
+            Level::Error,
+            format!("'{}' takes {} arguments", keyword, count),
+        )
+        .with_code(DiagnosticCode::ArgumentCountMismatch)
+        .with_span(span)
+        .with_label(span, format!("{} argument{} supplied", args.len(), if args.len() == 1 { "" } else { "s" }))
+        .with_help(format!("write it as '{}'", syntax)));
+        return None;
+    }
+
+    Some((args, span))
+}
+
+/// if_expr ::= 'if' '(' expression ',' expression ',' expression ')'
+fn parse_if_expr(ctx: &mut ParserContext) -> Box<Expr> {
+    let if_span = ctx.current_span();
+
+    let (args, span) = match parse_keyword_arguments(ctx, "if(condition, then, otherwise)", 3) {
+        Some(result) => result,
+        None => return error_expr(if_span.to(ctx.last_span())),
+    };
+
+    let mut args = args.into_iter();
+    let condition = args.next().unwrap();
+    let then = args.next().unwrap();
+    let otherwise = args.next().unwrap();
+    Expr::boxed(ExprKind::If(condition, then, otherwise), span)
+}
+
+/// aggregate_expr ::= ('sum' | 'prod') '(' identifier ',' expression ',' expression ',' expression ')'
+fn parse_aggregate_expr(ctx: &mut ParserContext) -> Box<Expr> {
+    let keyword_span = ctx.current_span();
+    let is_sum = ctx.current_kind() == Some(&TokenKind::Sum);
+    let syntax = if is_sum { "sum(index, from, to, term)" } else { "prod(index, from, to, factor)" };
+
+    let (args, span) = match parse_keyword_arguments(ctx, syntax, 4) {
+        Some(result) => result,
+        None => return error_expr(keyword_span.to(ctx.last_span())),
+    };
+
+    let mut args = args.into_iter();
+    let index = args.next().unwrap();
+    let index = match index.kind {
+        ExprKind::Id(name) => name,
+        _ => {
+            ctx.push_diagnostic(Diagnostic::new(
+                Level::Error,
+                "expected the name of the index variable".to_string(),
+            )
+            .with_code(DiagnosticCode::UnexpectedToken)
+            .with_span(index.span)
+            .with_help(format!("write it as '{}'", syntax)));
+            return error_expr(span);
+        },
+    };
+    let from = args.next().unwrap();
+    let to = args.next().unwrap();
+    let term = args.next().unwrap();
+
+    if is_sum {
+        Expr::boxed(ExprKind::Sum(index, from, to, term), span)
+    } else {
+        Expr::boxed(ExprKind::Prod(index, from, to, term), span)
+    }
+}
+
+/// A definition `name = value` or `name(parameters) = value` whose scope is not parsed yet.
+struct Definition {
+    name: String,
+    /// `None` for a variable, the parameter names for a function.
+    parameters: Option<Vec<String>>,
+    value: Box<Expr>,
+    span: Span,
+}
+
+fn report_expected_definition(ctx: &mut ParserContext, message: &str) {
+    ctx.push_diagnostic(Diagnostic::new(
+        Level::Error,
+        message.to_string(),
+    )
+    .with_code(DiagnosticCode::UnexpectedToken)
+    .with_span(ctx.current_span())
+    .with_help("a definition looks like 'r = sqrt(x^2 + y^2)' or 'f(t) = t^2 + 1'".to_string()));
+}
+
+/// definition
+///   ::= identifier '=' expression
+///   ::= identifier '(' (identifier (',' identifier)*)? ')' '=' expression
+///
+/// Reports the first syntax error of the definition header and returns `None` on it.
+fn parse_definition(ctx: &mut ParserContext) -> Option<Definition> {
+    let name_span = ctx.current_span();
+    let name = match ctx.current_kind() {
+        Some(TokenKind::Id(name)) => name.to_owned(),
+        _ => {
+            report_expected_definition(ctx, "expected the name of a definition");
+            return None;
+        },
+    };
+    ctx.next_token(); // eat identifier.
+
+    let parameters = if let Some(TokenKind::OpenParen) = ctx.current_kind() {
+        ctx.next_token(); // eat (
+        let mut parameters = Vec::new();
+
+        if ctx.current_kind() != Some(&TokenKind::CloseParen) {
+            loop {
+                match ctx.current_kind() {
+                    Some(TokenKind::Id(parameter)) => parameters.push(parameter.to_owned()),
+                    _ => {
+                        report_expected_definition(ctx, "expected a parameter name");
+                        return None;
+                    },
+                }
+                ctx.next_token(); // eat identifier.
+
+                match ctx.current_kind() {
+                    Some(TokenKind::Comma) => { ctx.next_token(); },
+                    Some(TokenKind::CloseParen) => break,
+                    _ => {
+                        report_expected_definition(ctx, "expected ')' or ',' in parameter list");
+                        return None;
+                    },
+                }
+            }
+        }
+
+        ctx.next_token(); // eat ).
+        Some(parameters)
+    } else {
+        None
+    };
+
+    if ctx.current_kind() != Some(&TokenKind::Eq) {
+        report_expected_definition(ctx, "expected '=' in definition");
+        return None;
+    }
+    ctx.next_token(); // eat =.
+
+    let value = parse_expression(ctx);
+
+    // The function can only be called after its definition.
+    if parameters.is_some() {
+        ctx.register_function_name(&name);
+    }
+
+    Some(Definition {
+        name,
+        parameters,
+        span: name_span.to(value.span),
+        value,
+    })
+}
+
+/// Wraps `body` into the scope of `definition`.
+fn make_definition_expr(definition: Definition, body: Box<Expr>, start_span: Span) -> Box<Expr> {
+    let span = start_span.to(body.span);
+
+    match definition.parameters {
+        Some(parameters) => Expr::boxed(ExprKind::LetFn(definition.name, parameters, definition.value, body), span),
+        None => Expr::boxed(ExprKind::Let(definition.name, definition.value, body), span),
+    }
+}
+
+/// let_expr ::= 'let' definition 'in' expression
+fn parse_let_expr(ctx: &mut ParserContext) -> Box<Expr> {
+    let let_span = ctx.current_span();
+    ctx.next_token(); // eat let.
+
+    let definition = parse_definition(ctx);
+
+    if ctx.current_kind() != Some(&TokenKind::In) {
+        if definition.is_some() {
+            ctx.push_diagnostic(Diagnostic::new(
+                Level::Error,
+                "expected 'in' after the definition".to_string(),
+            )
+            .with_code(DiagnosticCode::UnexpectedToken)
+            .with_span(ctx.current_span())
+            .with_label(let_span, "'let' starts here".to_string()));
+        }
+
+        synchronize(ctx);
+
+        if ctx.current_kind() != Some(&TokenKind::In) {
+            return error_expr(let_span.to(ctx.last_span()));
+        }
+    }
+    ctx.next_token(); // eat in.
+
+    let body = parse_expression(ctx);
+
+    match definition {
+        Some(definition) => make_definition_expr(definition, body, let_span),
+        None => error_expr(let_span.to(body.span)),
+    }
+}
+
+/// Whether the statement at the current token is a definition, i.e. is terminated by a `;`.
+fn is_at_definition(ctx: &mut ParserContext) -> bool {
+    if ctx.current_kind() == Some(&TokenKind::Semicolon) {
+        return true;
+    }
+
+    let mut n = 0;
+    loop {
+        match ctx.peek_kind(n) {
+            Some(TokenKind::Semicolon) => return true,
+            Some(_) => n += 1,
+            None => return false,
         }
     }
 }
@@ -75,128 +484,699 @@ fn parse_identifier_expr(ctx: &mut ParserContext, id_name: String) -> Result<Box
 ///   ::= identifier_expr
 ///   ::= number_expr
 ///   ::= paren_expr
-fn parse_primary(ctx: &mut ParserContext, unary_check: bool) -> Result<Box<Expr>, ()> {
-    match ctx.current_token() {
+///   ::= '-' primary
+///   ::= 'not' primary
+///   ::= if_expr
+///   ::= aggregate_expr
+///   ::= let_expr
+///   ::= abs_expr
+///   ::= primary '!'
+fn parse_primary(ctx: &mut ParserContext, unary_check: bool) -> Box<Expr> {
+    let span = ctx.current_span();
+
+    let mut primary = match ctx.current_kind() {
         Some(token) => {
             match token {
-                Token::Id(id_name) => {
+                TokenKind::Id(id_name) => {
                     let id_name = id_name.to_owned();
                     parse_identifier_expr(ctx, id_name)
                 },
-                Token::NumberLiteral(number) => {
+                TokenKind::NumberLiteral(number) => {
                     let number = number.to_owned();
-                    Ok(parse_number_expr(ctx, number))
+                    parse_number_expr(ctx, number)
                 },
-                Token::Minus => {
+                TokenKind::Minus => {
                     if !unary_check {
-                        Diagnostic::push_new(Diagnostic::new(
+                        // Report, but keep parsing it as a negation to recover.
+                        ctx.push_diagnostic(Diagnostic::new(
                             Level::Error,
                             "Unexpected '-'".to_string(),
-                        ));
-                        return Err(());
+                        )
+                        .with_code(DiagnosticCode::UnexpectedToken)
+                        .with_span(span)
+                        .with_help("wrap the negated operand in parentheses, e.g. '-(-x)'".to_string()));
                     }
 
                     ctx.next_token();
                     
-                    match parse_primary(ctx, false) {
-                        Ok(expr) => Ok(Box::new(Expr::Unary(expr))),
-                        Err(_) => {
-                            Diagnostic::push_new(Diagnostic::new(
-                                Level::Error,
-                                "Expected number, identifier, or '(' after '-'".to_string(),
-                            ));
-                            Err(())
-                        },
-                    }
+                    // Unary minus binds looser than '^': the operand takes any '^' chain with it.
+                    let operand = parse_primary(ctx, false);
+                    let operand_precedence = ctx.get_unary_operand_precedence();
+                    let expr = parse_bin_op_rhs(ctx, operand_precedence, operand);
+                    let span = span.to(expr.span);
+                    Expr::boxed(ExprKind::Unary(expr), span)
                 },
-                Token::OpenParen => parse_paren_expr(ctx),
+                TokenKind::Not => {
+                    ctx.next_token();
+
+                    // `not` binds looser than comparisons: `not x = y` is `not (x = y)`.
+                    let operand = parse_primary(ctx, true);
+                    let operand_precedence = ctx.get_not_operand_precedence();
+                    let expr = parse_bin_op_rhs(ctx, operand_precedence, operand);
+                    let span = span.to(expr.span);
+                    Expr::boxed(ExprKind::Not(expr), span)
+                },
+                TokenKind::If => parse_if_expr(ctx),
+                TokenKind::Sum | TokenKind::Prod => parse_aggregate_expr(ctx),
+                TokenKind::Let => parse_let_expr(ctx),
+                TokenKind::OpenParen => parse_paren_expr(ctx),
+                TokenKind::Pipe => parse_abs_expr(ctx),
                 _ => {
-                    Diagnostic::push_new(Diagnostic::new(
+                    ctx.push_diagnostic(Diagnostic::new(
                         Level::Error,
                         "unexpected token when parsing primary".to_string(),
-                    ));
-                    Err(())
+                    )
+                    .with_code(DiagnosticCode::UnexpectedToken)
+                    .with_span(span)
+                    .with_label(span, "expected number, identifier, or '('".to_string()));
+
+                    // Leave delimiters and operators in place so that the enclosing
+                    // expression can continue with them; anything else is skipped.
+                    let token = ctx.current_kind().unwrap();
+                    if !matches!(
+                        token,
+                        TokenKind::CloseParen | TokenKind::Comma | TokenKind::Semicolon | TokenKind::In
+                    ) && ctx.get_token_precedence(token) < 0
+                    {
+                        ctx.next_token();
+                    }
+
+                    error_expr(span)
                 },
             }
         },
         None => {
-            Diagnostic::push_new(Diagnostic::new(
+            ctx.push_diagnostic(Diagnostic::new(
                 Level::Error,
                 "unexpected end of input when parsing primary".to_string(),
-            ));
-            Err(())
+            )
+            .with_code(DiagnosticCode::UnexpectedEndOfInput)
+            .with_span(span)
+            .with_label(ctx.last_span(), "expected an operand after this".to_string()));
+            error_expr(span)
         }
+    };
+
+    // Postfix '!' binds tighter than any operator: `-n!^2` is `-((n!)^2)`.
+    while let Some(TokenKind::Bang) = ctx.current_kind() {
+        let span = primary.span.to(ctx.current_span());
+        ctx.next_token(); // eat !.
+        primary = Expr::boxed(ExprKind::Call("factorial".to_string(), vec![primary]), span);
     }
+
+    primary
 }
 
 /// bin_op_rhs
 ///   ::= ('+' primary)*
-fn parse_bin_op_rhs(ctx: &mut ParserContext, expr_precedence: i32, mut lhs: Box<Expr>) -> Result<Box<Expr>, ()> {
+fn parse_bin_op_rhs(ctx: &mut ParserContext, expr_precedence: i32, mut lhs: Box<Expr>) -> Box<Expr> {
+    // Right operand of the comparison merged last, to continue a comparison chain with.
+    let mut comparison_rhs: Option<Box<Expr>> = None;
+
     // If this is a bin_op, find its precedence.
     loop {
-	    let tok_precedence = ctx.current_token().map_or(-1, |tok| ctx.get_token_precedence(tok));
+	    let tok_precedence = ctx.current_precedence();
         
         // If this is a bin_op that binds at least as tightly as the current bin_op,
         // consume it, otherwise we are done.
         if tok_precedence < expr_precedence {
-            return Ok(lhs);
+            return lhs;
         }
 
         // Okay, we know this is a bin_op.
-        let bin_op = ctx.current_token().unwrap().to_owned();
-        ctx.next_token(); // eat bin_op
+        let bin_op = if ctx.is_at_implicit_multiplication() {
+            TokenKind::Star // the operand is not consumed.
+        } else {
+            let bin_op = ctx.current_kind().unwrap().to_owned();
+            ctx.next_token(); // eat bin_op
+            bin_op
+        };
 
         // Parse the primary expression after the binary operator.
-        let mut rhs = parse_primary(ctx, true)?;
+        let mut rhs = parse_primary(ctx, true);
 
-        // If BinOp binds less tightly with RHS than the operator after RHS, let
-        // the pending operator take RHS as its LHS.
-        let next_precedence = ctx.current_token().map_or(-1, |token| ctx.get_token_precedence(token));
+        // If BinOp binds less tightly with RHS than the operator after RHS, or the
+        // operators are equally tight and right-associative, let the pending operator
+        // take RHS as its LHS.
+        let next_precedence = ctx.current_precedence();
+        let right_associative = ctx.get_token_associativity(&bin_op) == Associativity::Right;
         
         if tok_precedence < next_precedence {
-            rhs = parse_bin_op_rhs(ctx, tok_precedence + 1, rhs)?;
+            rhs = parse_bin_op_rhs(ctx, tok_precedence + 1, rhs);
+        } else if right_associative && tok_precedence == next_precedence {
+            rhs = parse_bin_op_rhs(ctx, tok_precedence, rhs);
         }
 
         // Merge LHS/RHS.
-        lhs = Box::new(
-            
-            match bin_op {
-                Token::Eq => Expr::Eq(lhs, rhs),
-                Token::Lt => Expr::Lt(lhs, rhs),
-                Token::Gt => Expr::Gt(lhs, rhs),
-                Token::Le => Expr::Le(lhs, rhs),
-                Token::Ge => Expr::Ge(lhs, rhs),
-                Token::Plus => Expr::Add(lhs, rhs),
-                Token::Minus => Expr::Sub(lhs, rhs),
-                Token::Star => Expr::Mul(lhs, rhs),
-                Token::Slash => Expr::Div(lhs, rhs),
-                Token::Percent => Expr::Mod(lhs, rhs),
-                Token::Caret => Expr::Pow(lhs, rhs),
-                _ => unreachable!(),
-            }
+        let is_comparison = matches!(
+            bin_op,
+            TokenKind::Eq | TokenKind::Ne | TokenKind::Lt | TokenKind::Gt | TokenKind::Le | TokenKind::Ge
         );
+        let previous_comparison_rhs = comparison_rhs.take();
+
+        if is_comparison {
+            comparison_rhs = Some(rhs.clone());
+        }
+
+        lhs = match previous_comparison_rhs {
+            Some(middle) if is_comparison => {
+                // `a < b < c` is a chained comparison meaning `a < b and b < c`.
+                let comparison = make_binary_expr(bin_op, middle, rhs);
+                let span = lhs.span.to(comparison.span);
+                Expr::boxed(ExprKind::And(lhs, comparison), span)
+            },
+            _ => make_binary_expr(bin_op, lhs, rhs),
+        };
     }
 }
 
+fn make_binary_expr(bin_op: TokenKind, lhs: Box<Expr>, rhs: Box<Expr>) -> Box<Expr> {
+    let span = lhs.span.to(rhs.span);
+    Expr::boxed(
+        match bin_op {
+            TokenKind::Or => ExprKind::Or(lhs, rhs),
+            TokenKind::And => ExprKind::And(lhs, rhs),
+            TokenKind::Eq => ExprKind::Eq(lhs, rhs),
+            TokenKind::Ne => ExprKind::Not(Expr::boxed(ExprKind::Eq(lhs, rhs), span)),
+            TokenKind::Lt => ExprKind::Lt(lhs, rhs),
+            TokenKind::Gt => ExprKind::Gt(lhs, rhs),
+            TokenKind::Le => ExprKind::Le(lhs, rhs),
+            TokenKind::Ge => ExprKind::Ge(lhs, rhs),
+            TokenKind::Plus => ExprKind::Add(lhs, rhs),
+            TokenKind::Minus => ExprKind::Sub(lhs, rhs),
+            TokenKind::Star => ExprKind::Mul(lhs, rhs),
+            TokenKind::Slash => ExprKind::Div(lhs, rhs),
+            TokenKind::Percent => ExprKind::Mod(lhs, rhs),
+            TokenKind::Caret => ExprKind::Pow(lhs, rhs),
+            _ => unreachable!(),
+        },
+        span
+    )
+}
+
 /// expression
 ///   ::= primary bin_op_rhs
 ///
-fn parse_expression(ctx: &mut ParserContext) -> Result<Box<Expr>, ()> {
-    let lhs = parse_primary(ctx, true)?;
-    return parse_bin_op_rhs(ctx, 0, lhs);
+fn parse_expression(ctx: &mut ParserContext) -> Box<Expr> {
+    let lhs = parse_primary(ctx, true);
+    parse_bin_op_rhs(ctx, 0, lhs)
 }
 
-pub fn parse_top_level_expression(mut ctx: ParserContext) -> Result<Box<Expr>, ()> {
+/// Parses the whole input, recovering from syntax errors.
+///
+/// program ::= (definition ';')* system
+/// system ::= expression (',' expression)*
+///
+/// The definitions are scoped over the rest of the program, which is returned as
+/// nested `ExprKind::Let`/`ExprKind::LetFn` nodes. Several comma-separated expressions
+/// form an `ExprKind::System`.
+/// Always returns an AST; erroneous parts are replaced by `ExprKind::Error` nodes and
+/// every problem found is reported to the context's diagnostic sink.
+pub fn parse_top_level_expression_with_recovery(ctx: &mut ParserContext) -> Box<Expr> {
     ctx.next_token();
-    let result = parse_expression(&mut ctx);
+    let mut definitions = Vec::new();
 
-    if ctx.current_token().is_some() {
-        Diagnostic::push_new(Diagnostic::new(
-            Level::Error,
-            "unexpected token after top-level expression".to_string(),
-        ));
-        Err(())
+    while is_at_definition(ctx) {
+        let definition = parse_definition(ctx);
+
+        if ctx.current_kind() != Some(&TokenKind::Semicolon) {
+            if definition.is_some() {
+                ctx.push_diagnostic(Diagnostic::new(
+                    Level::Error,
+                    "expected ';' after the definition".to_string(),
+                )
+                .with_code(DiagnosticCode::UnexpectedToken)
+                .with_span(ctx.current_span()));
+            }
+
+            while ctx.current_kind().is_some_and(|token| token != &TokenKind::Semicolon) {
+                ctx.next_token();
+            }
+        }
+        ctx.next_token(); // eat ;.
+
+        definitions.extend(definition);
+    }
+
+    let mut equations = Vec::new();
+
+    loop {
+        let mut equation = parse_expression(ctx);
+
+        while ctx.current_kind().is_some_and(|token| token != &TokenKind::Comma) {
+            ctx.push_diagnostic(Diagnostic::new(
+                Level::Error,
+                "unexpected token after top-level expression".to_string(),
+            )
+            .with_code(DiagnosticCode::UnexpectedToken)
+            .with_span(ctx.current_span())
+            .with_label(ctx.current_span(), "expected ',' or end of input".to_string()));
+
+            // Skip to the next binary operator or ',' and keep parsing from there.
+            ctx.next_token();
+            while ctx.current_kind().is_some_and(|token| token != &TokenKind::Comma)
+                && ctx.current_precedence() < 0
+            {
+                ctx.next_token();
+            }
+
+            equation = parse_bin_op_rhs(ctx, 0, equation);
+        }
+
+        equations.push(equation);
+
+        if ctx.current_token().is_none() {
+            break;
+        }
+        ctx.next_token(); // eat ,.
+    }
+
+    let mut result = if equations.len() == 1 {
+        equations.pop().unwrap()
     } else {
-        result
+        let span = equations[0].span.to(equations[equations.len() - 1].span);
+        Expr::boxed(ExprKind::System(equations), span)
+    };
+
+    for definition in definitions.into_iter().rev() {
+        let start_span = definition.span;
+        result = make_definition_expr(definition, result, start_span);
+    }
+
+    result
+}
+
+pub fn parse_top_level_expression(mut ctx: ParserContext) -> Result<Box<Expr>, ErrorReported> {
+    let result = parse_top_level_expression_with_recovery(&mut ctx);
+
+    if ctx.has_errors() {
+        Err(ErrorReported)
+    } else {
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diagnostic::DiagnosticSink;
+
+    /// Parses the input, returning the AST with every diagnostic reported.
+    fn parse_with_diagnostics(input: &str) -> (Box<Expr>, Vec<Diagnostic>) {
+        let mut diagnostics = DiagnosticSink::new();
+        let mut ctx = ParserContext::new(
+            Box::new(lexer::token_iter(input)),
+            create_binary_op_precedence(),
+            &mut diagnostics,
+        );
+        let ast = parse_top_level_expression_with_recovery(&mut ctx);
+        drop(ctx);
+        (ast, diagnostics.into_diagnostics())
+    }
+
+    fn parse(input: &str) -> Box<Expr> {
+        let (ast, diagnostics) = parse_with_diagnostics(input);
+        assert!(diagnostics.is_empty(), "failed to parse {}: {:?}", input, diagnostics);
+        ast
+    }
+
+    /// Parses the input with implicit multiplication, where `sin` and `f` are functions.
+    fn parse_implicit(input: &str) -> Box<Expr> {
+        let mut diagnostics = DiagnosticSink::new();
+        let mut ctx = ParserContext::new(
+            Box::new(lexer::token_iter(input)),
+            create_binary_op_precedence(),
+            &mut diagnostics,
+        )
+        .with_implicit_multiplication(["sin", "f"].iter().map(|name| name.to_string()).collect());
+        let ast = parse_top_level_expression_with_recovery(&mut ctx);
+        drop(ctx);
+        assert!(!diagnostics.has_errors(), "failed to parse {}: {:?}", input, diagnostics.diagnostics());
+        ast
+    }
+
+    #[test]
+    fn nodes_span_their_source() {
+        let ast = parse("1 + foo(2, x)");
+        assert_eq!(ast.span, Span::new(0, 13));
+
+        let call = match &ast.kind {
+            ExprKind::Add(lhs, rhs) => {
+                assert_eq!(lhs.span, Span::new(0, 1));
+                rhs
+            },
+            kind => panic!("expected an addition, found {:?}", kind),
+        };
+        assert_eq!(call.span, Span::new(4, 13));
+
+        match &call.kind {
+            ExprKind::Call(_, args) => {
+                assert_eq!(args[0].span, Span::new(8, 9));
+                assert_eq!(args[1].span, Span::new(11, 12));
+            },
+            kind => panic!("expected a call, found {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn parenthesized_expression_spans_its_parentheses() {
+        assert_eq!(parse(" (x + 1) * 2").span, Span::new(1, 12));
+        assert_eq!(parse("-(x)").span, Span::new(0, 4));
+    }
+
+    /// Code and span of each diagnostic reported while parsing the input.
+    fn errors(input: &str) -> Vec<(Option<DiagnosticCode>, Option<Span>)> {
+        let (_, diagnostics) = parse_with_diagnostics(input);
+        diagnostics.iter().map(|diagnostic| (diagnostic.code(), diagnostic.span())).collect()
+    }
+
+    #[test]
+    fn recovery_reports_every_error() {
+        assert_eq!(
+            errors("f(1, , 3) + (2"),
+            [
+                (Some(DiagnosticCode::UnexpectedToken), Some(Span::new(5, 6))),
+                (Some(DiagnosticCode::UnclosedDelimiter), Some(Span::new(14, 14))),
+            ]
+        );
+        assert_eq!(
+            errors("0x + 1__0"),
+            [
+                (Some(DiagnosticCode::MalformedNumberLiteral), Some(Span::new(0, 2))),
+                (Some(DiagnosticCode::MalformedNumberLiteral), Some(Span::new(5, 9))),
+            ]
+        );
+        assert_eq!(
+            errors("1.2.3 * 0x1.5"),
+            [
+                (Some(DiagnosticCode::MalformedNumberLiteral), Some(Span::new(0, 5))),
+                (Some(DiagnosticCode::MalformedNumberLiteral), Some(Span::new(8, 13))),
+            ]
+        );
+    }
+
+    #[test]
+    fn recovery_replaces_erroneous_parts_by_error_nodes() {
+        let (ast, _) = parse_with_diagnostics("1 + * 2");
+        assert_eq!(ast.to_string(), "1 + <error> * 2");
+
+        let (ast, _) = parse_with_diagnostics("sin(x");
+        assert_eq!(ast.to_string(), "sin(x)");
+    }
+
+    #[test]
+    fn unclosed_delimiter_points_at_the_opening() {
+        let (_, diagnostics) = parse_with_diagnostics("(1 + 2");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].span(), Some(Span::new(6, 6)));
+        assert_eq!(diagnostics[0].labels()[0].span(), Span::new(0, 1));
+        assert_eq!(diagnostics[0].labels()[0].message(), "unclosed delimiter");
+    }
+
+    #[test]
+    fn missing_operands_and_trailing_tokens_are_reported() {
+        assert_eq!(errors("x +"), [(Some(DiagnosticCode::UnexpectedEndOfInput), Some(Span::new(3, 3)))]);
+        assert_eq!(errors("1 2"), [(Some(DiagnosticCode::UnexpectedToken), Some(Span::new(2, 3)))]);
+    }
+
+    #[test]
+    fn number_literal_values() {
+        assert_eq!(parse_number_literal("123.45"), Ok(123.45));
+        assert_eq!(parse_number_literal(".5"), Ok(0.5));
+        assert_eq!(parse_number_literal("1e-9"), Ok(1e-9));
+        assert_eq!(parse_number_literal("6.02E23"), Ok(6.02e23));
+        assert_eq!(parse_number_literal("0x1F"), Ok(31.0));
+        assert_eq!(parse_number_literal("0xff_ff"), Ok(65535.0));
+        assert_eq!(parse_number_literal("1_000_000"), Ok(1e6));
+    }
+
+    #[test]
+    fn malformed_number_literals() {
+        assert_eq!(parse_number_literal("0x"), Err("hexadecimal literal has no digits".to_string()));
+        assert_eq!(parse_number_literal("1__0"), Err("'_' must be placed between two digits".to_string()));
+        assert_eq!(parse_number_literal("1_"), Err("'_' must be placed between two digits".to_string()));
+        assert_eq!(parse_number_literal("0x_1"), Err("'_' must be placed between two digits".to_string()));
+        assert_eq!(parse_number_literal("1e400"), Err("number literal is out of range".to_string()));
+        assert_eq!(
+            parse_number_literal("0x1_0000_0000_0000_0000"),
+            Err("hexadecimal literal is too large".to_string())
+        );
+    }
+
+    fn expr(kind: ExprKind) -> Box<Expr> {
+        Expr::boxed(kind, Span::default())
+    }
+
+    fn lit(value: f64) -> Box<Expr> {
+        expr(ExprKind::Literal(value))
+    }
+
+    fn id(name: &str) -> Box<Expr> {
+        expr(ExprKind::Id(name.to_string()))
+    }
+
+    #[test]
+    fn exponentiation_is_right_associative() {
+        assert_eq!(
+            parse("2^3^2"),
+            expr(ExprKind::Pow(lit(2.0), expr(ExprKind::Pow(lit(3.0), lit(2.0)))))
+        );
+    }
+
+    #[test]
+    fn unary_minus_binds_below_exponentiation() {
+        assert_eq!(parse("-x^2"), expr(ExprKind::Unary(expr(ExprKind::Pow(id("x"), lit(2.0))))));
+        assert_eq!(parse("2^-x"), expr(ExprKind::Pow(lit(2.0), expr(ExprKind::Unary(id("x"))))));
+        assert_eq!(
+            parse("-2^-x^2"),
+            expr(ExprKind::Unary(expr(ExprKind::Pow(
+                lit(2.0),
+                expr(ExprKind::Unary(expr(ExprKind::Pow(id("x"), lit(2.0))))),
+            ))))
+        );
+    }
+
+    #[test]
+    fn unary_minus_binds_above_multiplication() {
+        assert_eq!(
+            parse("-x * y"),
+            expr(ExprKind::Mul(expr(ExprKind::Unary(id("x"))), id("y")))
+        );
+        assert_eq!(
+            parse("1 - 2 - 3"),
+            expr(ExprKind::Sub(expr(ExprKind::Sub(lit(1.0), lit(2.0))), lit(3.0)))
+        );
+    }
+
+    fn mul(lhs: Box<Expr>, rhs: Box<Expr>) -> Box<Expr> {
+        expr(ExprKind::Mul(lhs, rhs))
+    }
+
+    fn call(name: &str, args: ExprList) -> Box<Expr> {
+        expr(ExprKind::Call(name.to_string(), args))
+    }
+
+    #[test]
+    fn juxtaposed_operands_multiply() {
+        assert_eq!(parse_implicit("2x"), mul(lit(2.0), id("x")));
+        assert_eq!(parse_implicit("2x y"), mul(mul(lit(2.0), id("x")), id("y")));
+        assert_eq!(
+            parse_implicit("(x+1)(x-1)"),
+            mul(expr(ExprKind::Add(id("x"), lit(1.0))), expr(ExprKind::Sub(id("x"), lit(1.0))))
+        );
+        assert_eq!(parse_implicit("2x^2"), mul(lit(2.0), expr(ExprKind::Pow(id("x"), lit(2.0)))));
+    }
+
+    #[test]
+    fn only_functions_are_called() {
+        assert_eq!(parse_implicit("a(x)"), mul(id("a"), id("x")));
+        assert_eq!(parse_implicit("2sin(x)"), mul(lit(2.0), call("sin", vec![id("x")])));
+        assert_eq!(parse_implicit("g(t) = t^2; g(x)"), parse("g(t) = t^2; g(x)"));
+    }
+
+    #[test]
+    fn implicit_multiplication_is_opt_in() {
+        assert_eq!(errors("2x"), [(Some(DiagnosticCode::UnexpectedToken), Some(Span::new(1, 2)))]);
+    }
+
+    #[test]
+    fn comparison_chains_are_conjunctions() {
+        assert_eq!(
+            parse("1 < x <= 2"),
+            expr(ExprKind::And(expr(ExprKind::Lt(lit(1.0), id("x"))), expr(ExprKind::Le(id("x"), lit(2.0)))))
+        );
+        assert_eq!(parse("1 < x < 2 < y"), parse("1 < x and x < 2 and 2 < y"));
+    }
+
+    #[test]
+    fn connectives_bind_looser_than_comparisons() {
+        assert_eq!(
+            parse("x < 1 or y > 2 and not x = y"),
+            expr(ExprKind::Or(
+                expr(ExprKind::Lt(id("x"), lit(1.0))),
+                expr(ExprKind::And(
+                    expr(ExprKind::Gt(id("y"), lit(2.0))),
+                    expr(ExprKind::Not(expr(ExprKind::Eq(id("x"), id("y"))))),
+                )),
+            ))
+        );
+        assert_eq!(parse("x != 1"), expr(ExprKind::Not(expr(ExprKind::Eq(id("x"), lit(1.0))))));
+    }
+
+    #[test]
+    fn conditional_takes_three_arguments() {
+        assert_eq!(
+            parse("if(x < 0, -x, x)"),
+            expr(ExprKind::If(expr(ExprKind::Lt(id("x"), lit(0.0))), expr(ExprKind::Unary(id("x"))), id("x")))
+        );
+        assert_eq!(errors("if(x < 0, 1)"), [(Some(DiagnosticCode::ArgumentCountMismatch), Some(Span::new(0, 12)))]);
+    }
+
+    #[test]
+    fn commas_separate_equations_of_a_system() {
+        assert_eq!(
+            parse("x < 1, y = 2"),
+            expr(ExprKind::System(vec![expr(ExprKind::Lt(id("x"), lit(1.0))), expr(ExprKind::Eq(id("y"), lit(2.0)))]))
+        );
+        assert_eq!(parse("x < 1, y = 2").span, Span::new(0, 12));
+        assert!(matches!(parse("x < 1").kind, ExprKind::Lt(..)));
+    }
+
+    #[test]
+    fn aggregates_bind_their_index() {
+        assert_eq!(
+            parse("sum(k, 1, n, k^2)"),
+            expr(ExprKind::Sum("k".to_string(), lit(1.0), id("n"), expr(ExprKind::Pow(id("k"), lit(2.0)))))
+        );
+        assert!(matches!(parse("prod(i, 1, 3, i)").kind, ExprKind::Prod(..)));
+    }
+
+    #[test]
+    fn malformed_keyword_expressions_report_one_error() {
+        assert_eq!(errors("if x"), [(Some(DiagnosticCode::UnexpectedToken), Some(Span::new(3, 4)))]);
+        assert_eq!(errors("sum k + 1"), [(Some(DiagnosticCode::UnexpectedToken), Some(Span::new(4, 5)))]);
+        assert_eq!(errors("sum(2, 1, 3, k)"), [(Some(DiagnosticCode::UnexpectedToken), Some(Span::new(4, 5)))]);
+        assert_eq!(errors("prod(k, 1, 3)"), [(Some(DiagnosticCode::ArgumentCountMismatch), Some(Span::new(0, 13)))]);
+    }
+
+    fn abs(arg: Box<Expr>) -> Box<Expr> {
+        call("abs", vec![arg])
+    }
+
+    #[test]
+    fn bars_and_bang_are_abs_and_factorial() {
+        assert_eq!(parse("|x|"), abs(id("x")));
+        assert_eq!(parse("|x|!"), call("factorial", vec![abs(id("x"))]));
+        assert_eq!(parse("3!"), call("factorial", vec![lit(3.0)]));
+        assert_eq!(parse("-3!"), expr(ExprKind::Unary(call("factorial", vec![lit(3.0)]))));
+        assert_eq!(parse("|x| + |y|").span, Span::new(0, 9));
+    }
+
+    #[test]
+    fn bars_after_operands_open_while_enough_remain() {
+        assert_eq!(parse_implicit("|a|b"), mul(abs(id("a")), id("b")));
+        assert_eq!(parse_implicit("|a|b|c|"), abs(mul(mul(id("a"), abs(id("b"))), id("c"))));
+        assert_eq!(parse_implicit("|x|y||"), abs(mul(id("x"), abs(id("y")))));
+        assert_eq!(parse_implicit("f(|a|b, |c|)"), call("f", vec![mul(abs(id("a")), id("b")), abs(id("c"))]));
+        assert_eq!(parse_implicit("|a(|b|c)|"), abs(mul(id("a"), mul(abs(id("b")), id("c")))));
+    }
+
+    #[test]
+    fn printing_keeps_associativity_and_prefix_operators() {
+        for input in [
+            "a - (b - c)", "a - b - c", "a / (b * c)", "2 ^ 3 ^ 2", "(2 ^ 3) ^ 2", "-x ^ 2", "(-x) ^ 2",
+            "2 ^ -x", "-(x + 1)", "-(-x)", "(-x)!", "not x = y", "not (x < 1 and y < 2)", "x != y",
+            "1 < x <= 2", "(1 < x) = (x <= 2)", "1 < x and x < 2 or y > 3", "(x < 1 or y < 1) and x > 0",
+            "2 * let t = x + 1 in t ^ 2", "(let t = 1 in t) + 1", "x < 1, y > 2",
+        ] {
+            let ast = parse(input);
+            assert_eq!(parse(&ast.to_string()), ast, "{} printed as {}", input, ast);
+        }
+    }
+
+    mod round_trip {
+        use super::*;
+        use proptest::prelude::*;
+
+        fn boxed(kind: ExprKind) -> Box<Expr> {
+            Expr::boxed(kind, Span::default())
+        }
+
+        fn number_leaf() -> impl Strategy<Value = Box<Expr>> {
+            prop_oneof![
+                (prop::num::f64::POSITIVE | prop::num::f64::NORMAL | prop::num::f64::ZERO)
+                    .prop_map(|value| boxed(ExprKind::Literal(value))),
+                (0..100u32).prop_map(|value| boxed(ExprKind::Literal(value as f64))),
+                prop::sample::select(&["x", "y", "k", "theta_1"][..]).prop_map(|name| boxed(ExprKind::Id(name.to_string()))),
+            ]
+        }
+
+        /// Number-valued trees like the parser builds, with the booleans they can contain.
+        fn number_expr() -> impl Strategy<Value = Box<Expr>> {
+            number_leaf().prop_recursive(5, 48, 3, |number| {
+                let boolean = boolean_expr(number.clone());
+                let pair = (number.clone(), number.clone());
+                prop_oneof![
+                    number.clone().prop_map(|operand| boxed(ExprKind::Unary(operand))),
+                    number.clone().prop_map(|operand| boxed(ExprKind::Call("factorial".to_string(), vec![operand]))),
+                    pair.clone().prop_map(|(lhs, rhs)| boxed(ExprKind::Add(lhs, rhs))),
+                    pair.clone().prop_map(|(lhs, rhs)| boxed(ExprKind::Sub(lhs, rhs))),
+                    pair.clone().prop_map(|(lhs, rhs)| boxed(ExprKind::Mul(lhs, rhs))),
+                    pair.clone().prop_map(|(lhs, rhs)| boxed(ExprKind::Div(lhs, rhs))),
+                    pair.clone().prop_map(|(lhs, rhs)| boxed(ExprKind::Mod(lhs, rhs))),
+                    pair.clone().prop_map(|(lhs, rhs)| boxed(ExprKind::Pow(lhs, rhs))),
+                    prop::collection::vec(number.clone(), 0..3)
+                        .prop_map(|args| boxed(ExprKind::Call("max".to_string(), args))),
+                    (boolean, number.clone(), number.clone())
+                        .prop_map(|(condition, then, otherwise)| boxed(ExprKind::If(condition, then, otherwise))),
+                    (number.clone(), number.clone(), number.clone())
+                        .prop_map(|(from, to, term)| boxed(ExprKind::Sum("k".to_string(), from, to, term))),
+                    pair.clone().prop_map(|(value, body)| boxed(ExprKind::Let("t".to_string(), value, body))),
+                    pair.prop_map(|(value, body)| {
+                        boxed(ExprKind::LetFn("g".to_string(), vec!["s".to_string(), "t".to_string()], value, body))
+                    }),
+                ]
+            })
+        }
+
+        fn boolean_expr(number: BoxedStrategy<Box<Expr>>) -> BoxedStrategy<Box<Expr>> {
+            let pair = (number.clone(), number);
+            let comparison = prop_oneof![
+                pair.clone().prop_map(|(lhs, rhs)| boxed(ExprKind::Eq(lhs, rhs))),
+                pair.clone().prop_map(|(lhs, rhs)| boxed(ExprKind::Lt(lhs, rhs))),
+                pair.clone().prop_map(|(lhs, rhs)| boxed(ExprKind::Gt(lhs, rhs))),
+                pair.clone().prop_map(|(lhs, rhs)| boxed(ExprKind::Le(lhs, rhs))),
+                pair.prop_map(|(lhs, rhs)| boxed(ExprKind::Ge(lhs, rhs))),
+            ];
+            comparison.prop_recursive(3, 12, 2, |boolean| {
+                prop_oneof![
+                    boolean.clone().prop_map(|operand| boxed(ExprKind::Not(operand))),
+                    (boolean.clone(), boolean.clone()).prop_map(|(lhs, rhs)| boxed(ExprKind::And(lhs, rhs))),
+                    (boolean.clone(), boolean).prop_map(|(lhs, rhs)| boxed(ExprKind::Or(lhs, rhs))),
+                ]
+            })
+            .boxed()
+        }
+
+        fn top_level_expr() -> impl Strategy<Value = Box<Expr>> {
+            let boolean = boolean_expr(number_expr().boxed());
+            prop_oneof![
+                number_expr(),
+                boolean.clone(),
+                prop::collection::vec(boolean, 2..4).prop_map(|equations| boxed(ExprKind::System(equations))),
+            ]
+        }
+
+        proptest! {
+            #![proptest_config(ProptestConfig::with_cases(512))]
+
+            #[test]
+            fn parsing_the_printed_tree_yields_it_back(ast in top_level_expr()) {
+                let printed = ast.to_string();
+                let (parsed, diagnostics) = parse_with_diagnostics(&printed);
+                prop_assert!(diagnostics.is_empty(), "failed to parse {}: {:?}", printed, diagnostics);
+                prop_assert_eq!(parsed, ast, "printed as {}", printed);
+            }
+        }
     }
 }
@@ -1,49 +1,258 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 
-use lexer::Token;
+use diagnostic::{Diagnostic, DiagnosticSink, Level};
+use lexer::{Token, TokenKind};
+use span::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Associativity {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BinaryOpPrecedence {
+    pub precedence: i32,
+    pub associativity: Associativity,
+}
+
+impl BinaryOpPrecedence {
+    pub fn new(precedence: i32, associativity: Associativity) -> Self {
+        Self {
+            precedence,
+            associativity,
+        }
+    }
+}
 
 pub struct ParserContext<'a> {
     current_token: Option<Token>,
+    last_span: Span,
     token_iter: Box<dyn Iterator<Item = Token> + 'a>,
-    binary_op_precedence: HashMap<&'static str, i32>,
+    /// Tokens already taken from `token_iter` by `peek_kind`, in order.
+    lookahead: VecDeque<Token>,
+    binary_op_precedence: HashMap<&'static str, BinaryOpPrecedence>,
+    diagnostics: &'a mut DiagnosticSink,
+    error_count: usize,
+    implicit_multiplication: bool,
+    function_names: HashSet<String>,
+    /// Number of `|` opening an absolute value that are not closed yet.
+    open_bar_count: usize,
 }
 
 impl <'a>ParserContext<'_> {
-    pub fn new(token_iter: Box<dyn Iterator<Item = Token> + 'a>, binary_op_precedence: HashMap<&'static str, i32>) -> ParserContext<'a> {
+    pub fn new(
+        token_iter: Box<dyn Iterator<Item = Token> + 'a>,
+        binary_op_precedence: HashMap<&'static str, BinaryOpPrecedence>,
+        diagnostics: &'a mut DiagnosticSink,
+    ) -> ParserContext<'a> {
         ParserContext {
             current_token: None,
+            last_span: Span::default(),
             token_iter,
+            lookahead: VecDeque::new(),
             binary_op_precedence,
+            diagnostics,
+            error_count: 0,
+            implicit_multiplication: false,
+            function_names: HashSet::new(),
+            open_bar_count: 0,
         }
     }
 
+    /// Enables implicit multiplication of juxtaposed operands, e.g. `2x`, `x y` or `(x+1)(x-1)`.
+    ///
+    /// `identifier(...)` is only parsed as a call if the identifier is one of `function_names`;
+    /// otherwise it is a multiplication, so `a(x+1)` means `a*(x+1)`.
+    pub fn with_implicit_multiplication(mut self, function_names: HashSet<String>) -> Self {
+        self.implicit_multiplication = true;
+        self.function_names = function_names;
+        self
+    }
+
+    /// Whether `identifier(...)` should be parsed as a call of `name`.
+    pub fn is_callable(&self, name: &str) -> bool {
+        !self.implicit_multiplication || self.function_names.contains(name)
+    }
+
+    /// Makes `name(...)` parse as a call of a function defined in the input.
+    pub fn register_function_name(&mut self, name: &str) {
+        if self.implicit_multiplication {
+            self.function_names.insert(name.to_string());
+        }
+    }
+
+    /// Whether the current token is an operand directly following another one, to be
+    /// multiplied with it implicitly.
+    pub fn is_at_implicit_multiplication(&mut self) -> bool {
+        if !self.implicit_multiplication {
+            return false;
+        }
+
+        match self.current_kind() {
+            Some(TokenKind::Pipe) => self.is_at_opening_bar_after_operand(),
+            Some(token) => is_operand_start(token),
+            None => false,
+        }
+    }
+
+    /// Whether a `|` directly following an operand opens an absolute value rather than
+    /// closing the innermost open one, as in `|x|y||` meaning `|x * |y||`.
+    ///
+    /// It opens one if nothing is open, or if an operand directly follows it and enough `|`
+    /// remain to close every open absolute value afterwards. So `|a|b` is `|a| * b`, and the
+    /// ambiguous `|a|b|c|` is `|a * |b| * c|`.
+    ///
+    /// Only the `|` up to the end of the enclosing parentheses or argument are counted, since
+    /// an absolute value cannot be closed past them, and the scan stops as soon as enough are found.
+    fn is_at_opening_bar_after_operand(&mut self) -> bool {
+        if self.open_bar_count != 0 && !self.peek_kind(0).is_some_and(is_operand_start) {
+            return false;
+        }
+
+        let mut remaining_bar_count = 0;
+        let mut depth = 0;
+        let mut n = 0;
+        while remaining_bar_count <= self.open_bar_count {
+            match self.peek_kind(n) {
+                Some(TokenKind::Pipe) if depth == 0 => remaining_bar_count += 1,
+                Some(TokenKind::OpenParen) => depth += 1,
+                Some(TokenKind::CloseParen) if depth == 0 => return false,
+                Some(TokenKind::CloseParen) => depth -= 1,
+                Some(TokenKind::Comma | TokenKind::Semicolon) if depth == 0 => return false,
+                Some(_) => { },
+                None => return false,
+            }
+            n += 1;
+        }
+
+        true
+    }
+
+    /// Starts parsing inside parentheses, where the absolute values opened outside cannot be
+    /// closed. Returns the number of them, to be passed to `leave_parentheses`.
+    pub fn enter_parentheses(&mut self) -> usize {
+        std::mem::take(&mut self.open_bar_count)
+    }
+
+    pub fn leave_parentheses(&mut self, outer_open_bar_count: usize) {
+        self.open_bar_count = outer_open_bar_count;
+    }
+
+    pub fn open_bar(&mut self) {
+        self.open_bar_count += 1;
+    }
+
+    pub fn close_bar(&mut self) {
+        self.open_bar_count -= 1;
+    }
+
+    /// Precedence of the binary operator at the current token, including implicit
+    /// multiplication; -1 if the current token does not continue a binary expression.
+    pub fn current_precedence(&mut self) -> i32 {
+        if self.is_at_implicit_multiplication() {
+            return self.get_token_precedence(&TokenKind::Star);
+        }
+
+        self.current_kind().map_or(-1, |token| self.get_token_precedence(token))
+    }
+
     pub fn next_token(&mut self) -> Option<&Token> {
-        self.current_token = self.token_iter.next();
+        if let Some(token) = &self.current_token {
+            self.last_span = token.span;
+        }
+        self.current_token = self.lookahead.pop_front().or_else(|| self.token_iter.next());
         self.current_token.as_ref()
     }
 
+    /// Kind of the `n`-th token after the current one, without consuming anything.
+    pub fn peek_kind(&mut self, n: usize) -> Option<&TokenKind> {
+        while self.lookahead.len() <= n {
+            self.lookahead.push_back(self.token_iter.next()?);
+        }
+        self.lookahead.get(n).map(|token| &token.kind)
+    }
+
     pub fn current_token(&self) -> Option<&Token> {
         self.current_token.as_ref()
     }
 
-    pub fn get_token_precedence(&self, token: &Token) -> i32 {
-        self.binary_op_precedence.get(token.to_str()).unwrap_or(&-1).to_owned()
+    pub fn current_kind(&self) -> Option<&TokenKind> {
+        self.current_token.as_ref().map(|token| &token.kind)
+    }
+
+    /// Span of the current token, or an empty span right after the last token at the end of input.
+    pub fn current_span(&self) -> Span {
+        self.current_token.as_ref().map_or(self.last_span.end(), |token| token.span)
+    }
+
+    /// Span of the most recently consumed token.
+    pub fn last_span(&self) -> Span {
+        self.last_span
     }
+
+    pub fn push_diagnostic(&mut self, diagnostic: Diagnostic) {
+        if diagnostic.level() == Level::Error {
+            self.error_count += 1;
+        }
+        self.diagnostics.push(diagnostic);
+    }
+
+    /// Whether an error has been reported while parsing with this context.
+    pub fn has_errors(&self) -> bool {
+        self.error_count != 0
+    }
+
+    pub fn get_token_precedence(&self, token: &TokenKind) -> i32 {
+        self.binary_op_precedence.get(token.to_str()).map_or(-1, |op| op.precedence)
+    }
+
+    pub fn get_token_associativity(&self, token: &TokenKind) -> Associativity {
+        self.binary_op_precedence.get(token.to_str()).map_or(Associativity::Left, |op| op.associativity)
+    }
+
+    /// Minimum precedence of the binary operators that bind tighter than `not`,
+    /// so that `not x = y` parses as `not (x = y)`.
+    pub fn get_not_operand_precedence(&self) -> i32 {
+        self.get_token_precedence(&TokenKind::Eq)
+    }
+
+    /// Minimum precedence of the binary operators that bind tighter than a unary minus,
+    /// so that `-x^2` parses as `-(x^2)`.
+    pub fn get_unary_operand_precedence(&self) -> i32 {
+        self.get_token_precedence(&TokenKind::Caret)
+    }
+}
+
+/// Whether `token` can start an operand, excluding `|` whose meaning depends on the context.
+fn is_operand_start(token: &TokenKind) -> bool {
+    matches!(
+        token,
+        TokenKind::Id(..)
+            | TokenKind::NumberLiteral(..)
+            | TokenKind::OpenParen
+            | TokenKind::If
+            | TokenKind::Sum
+            | TokenKind::Prod
+    )
 }
 
-pub fn create_binary_op_precedence() -> HashMap<&'static str, i32> {
+pub fn create_binary_op_precedence() -> HashMap<&'static str, BinaryOpPrecedence> {
     let mut map = HashMap::new();
-    map.insert(Token::Lt.to_str(), 10);
-    map.insert(Token::Gt.to_str(), 10);
-    map.insert(Token::Le.to_str(), 10);
-    map.insert(Token::Ge.to_str(), 10);
-    map.insert(Token::Eq.to_str(), 10);
-    map.insert(Token::Plus.to_str(), 20);
-    map.insert(Token::Minus.to_str(), 20);
-    map.insert(Token::Star.to_str(), 40);
-    map.insert(Token::Slash.to_str(), 40);
-    map.insert(Token::Percent.to_str(), 40);
-    map.insert(Token::Caret.to_str(), 50);
+    map.insert(TokenKind::Or.to_str(), BinaryOpPrecedence::new(4, Associativity::Left));
+    map.insert(TokenKind::And.to_str(), BinaryOpPrecedence::new(6, Associativity::Left));
+    map.insert(TokenKind::Lt.to_str(), BinaryOpPrecedence::new(10, Associativity::Left));
+    map.insert(TokenKind::Gt.to_str(), BinaryOpPrecedence::new(10, Associativity::Left));
+    map.insert(TokenKind::Le.to_str(), BinaryOpPrecedence::new(10, Associativity::Left));
+    map.insert(TokenKind::Ge.to_str(), BinaryOpPrecedence::new(10, Associativity::Left));
+    map.insert(TokenKind::Eq.to_str(), BinaryOpPrecedence::new(10, Associativity::Left));
+    map.insert(TokenKind::Ne.to_str(), BinaryOpPrecedence::new(10, Associativity::Left));
+    map.insert(TokenKind::Plus.to_str(), BinaryOpPrecedence::new(20, Associativity::Left));
+    map.insert(TokenKind::Minus.to_str(), BinaryOpPrecedence::new(20, Associativity::Left));
+    map.insert(TokenKind::Star.to_str(), BinaryOpPrecedence::new(40, Associativity::Left));
+    map.insert(TokenKind::Slash.to_str(), BinaryOpPrecedence::new(40, Associativity::Left));
+    map.insert(TokenKind::Percent.to_str(), BinaryOpPrecedence::new(40, Associativity::Left));
+    map.insert(TokenKind::Caret.to_str(), BinaryOpPrecedence::new(50, Associativity::Right));
 
     map
 }
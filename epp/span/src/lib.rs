@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+
+/// Byte range `[low, high)` into the source text.
+///
+/// As JSON, `{"low": 0, "high": 5}`; deserializing fails if `low` is greater than `high`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedSpan")]
+pub struct Span {
+    low: usize,
+    high: usize,
+}
+
+/// The fields of a deserialized `Span`, before checking that they form a range.
+#[derive(Deserialize)]
+struct UncheckedSpan {
+    low: usize,
+    high: usize,
+}
+
+impl TryFrom<UncheckedSpan> for Span {
+    type Error = String;
+
+    fn try_from(span: UncheckedSpan) -> Result<Self, Self::Error> {
+        if span.low > span.high {
+            return Err(format!("span starts at {} after its end at {}", span.low, span.high));
+        }
+
+        Ok(Self { low: span.low, high: span.high })
+    }
+}
+
+impl Span {
+    pub fn new(low: usize, high: usize) -> Self {
+        debug_assert!(low <= high);
+
+        Self { low, high }
+    }
+
+    pub fn low(&self) -> usize {
+        self.low
+    }
+
+    pub fn high(&self) -> usize {
+        self.high
+    }
+
+    pub fn len(&self) -> usize {
+        self.high - self.low
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.low == self.high
+    }
+
+    /// Returns the smallest span covering both `self` and `other`.
+    pub fn to(&self, other: Span) -> Span {
+        Span::new(self.low.min(other.low), self.high.max(other.high))
+    }
+
+    /// Returns an empty span located right before `self`.
+    pub fn start(&self) -> Span {
+        Span::new(self.low, self.low)
+    }
+
+    /// Returns an empty span located right after `self`.
+    pub fn end(&self) -> Span {
+        Span::new(self.high, self.high)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_covers_both_spans() {
+        assert_eq!(Span::new(2, 4).to(Span::new(7, 9)), Span::new(2, 9));
+        assert_eq!(Span::new(7, 9).to(Span::new(2, 4)), Span::new(2, 9));
+        assert_eq!(Span::new(1, 9).to(Span::new(3, 4)), Span::new(1, 9));
+    }
+
+    #[test]
+    fn start_and_end_are_empty() {
+        let span = Span::new(3, 8);
+        assert_eq!(span.len(), 5);
+        assert_eq!(span.start(), Span::new(3, 3));
+        assert_eq!(span.end(), Span::new(8, 8));
+        assert!(span.start().is_empty() && span.end().is_empty());
+    }
+
+    #[test]
+    fn deserializing_checks_the_range() {
+        let span: Span = serde_json::from_str(r#"{"low": 2, "high": 5}"#).unwrap();
+        assert_eq!(span, Span::new(2, 5));
+        assert_eq!(serde_json::to_string(&span).unwrap(), r#"{"low":2,"high":5}"#);
+
+        let error = serde_json::from_str::<Span>(r#"{"low": 5, "high": 2}"#).unwrap_err();
+        assert!(error.to_string().starts_with("span starts at 5 after its end at 2"), "{}", error);
+        assert!(serde_json::from_str::<Span>(r#"{"low": 5}"#).is_err());
+    }
+}
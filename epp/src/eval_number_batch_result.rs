@@ -0,0 +1,7 @@
+use wasm_bindgen::prelude::wasm_bindgen;
+
+#[wasm_bindgen(getter_with_clone)]
+pub struct EvalNumberBatchResult {
+    pub values: Vec<f64>,
+    pub error: String,
+}
@@ -0,0 +1,7 @@
+use wasm_bindgen::prelude::wasm_bindgen;
+
+#[wasm_bindgen(getter_with_clone)]
+pub struct EvalNumberResult {
+    pub value: f64,
+    pub error: String,
+}
@@ -4,3 +4,5 @@ extern crate lazy_static;
 pub mod wasm_driver;
 pub mod parse_result;
 pub mod transform_result;
+pub mod eval_number_result;
+pub mod eval_number_batch_result;
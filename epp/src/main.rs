@@ -1,26 +1,27 @@
 use std::collections::{HashMap, HashSet};
 
-use lexer;
-use parser;
-use diagnostic;
-use evaluator;
-use transpiler;
-use validator;
 use std::f64;
 
 fn main() {
     let mut input = String::new();
     std::io::stdin().read_line(&mut input).unwrap();
 
+    let mut diagnostics = diagnostic::DiagnosticSink::new();
+
     let token_iter = lexer::token_iter(&input);
 
-    let ast = parser::parse_top_level_expression(
-        parser::ParserContext::new(
-            Box::new(token_iter),
-            parser::create_binary_op_precedence()
-        )
+    let mut ctx = parser::ParserContext::new(
+        Box::new(token_iter),
+        parser::create_binary_op_precedence(),
+        &mut diagnostics
     );
 
+    if std::env::args().any(|arg| arg == "--implicit-multiplication") {
+        ctx = ctx.with_implicit_multiplication(validator::function_names().map(str::to_string).collect());
+    }
+
+    let ast = parser::parse_top_level_expression(ctx);
+
     println!("{:?}", ast);
 
     if let Ok(ast) = ast {
@@ -40,11 +41,12 @@ fn main() {
 
         if validator::validate_bool_equation(
             &ast, &constants, &variables,
-            &HashSet::new()
+            &HashSet::new(),
+            &mut diagnostics
         ) {
             let eval_result = evaluator::eval_equation(
                 &ast,
-                &variables.into_iter().chain(constants.into_iter()).collect(),
+                &variables.into_iter().chain(constants).collect(),
                 0.001
             );
 
@@ -58,5 +60,5 @@ fn main() {
         }
     }
 
-    println!("{:?}", diagnostic::Diagnostic::diagnostics());
+    print!("{}", diagnostic::Renderer::for_stdout().render_all(diagnostics.diagnostics(), &input));
 }
@@ -1,14 +1,16 @@
 use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
 
 use ast::Expr;
-use lexer;
-use parser;
-use diagnostic;
-use transpiler;
+use diagnostic::{Diagnostic, DiagnosticCode, DiagnosticSink, ErrorReported, Level};
 use wasm_bindgen::prelude::*;
 use std::f64;
 
-use crate::{parse_result::ParseResult, transform_result::TransformResult};
+use crate::{
+    eval_number_batch_result::EvalNumberBatchResult, eval_number_result::EvalNumberResult, parse_result::ParseResult,
+    transform_result::TransformResult,
+};
 
 lazy_static! {
     pub static ref CONSTANTS: HashMap<String, f64> = HashMap::from([
@@ -26,26 +28,19 @@ lazy_static! {
         ("ln10".to_string(), "Math.LN10".to_string()),
         ("sqrt2".to_string(), "Math.SQRT2".to_string()),
     ]);
+
+    static ref AST_MAP: Mutex<HashMap<i32, Box<Expr>>> = Mutex::new(HashMap::new());
 }
 
-static mut AST_MAP: Option<HashMap<i32, Box<Expr>>> = None;
-static mut NEXT_ID: i32 = 1;
+static NEXT_ID: AtomicI32 = AtomicI32::new(1);
 
-fn ast_map() -> &'static mut HashMap<i32, Box<Expr>> {
-    if let Some(map) = unsafe { AST_MAP.as_mut() } {
-        map
-    } else {
-        unsafe {
-            AST_MAP = Some(HashMap::new());
-            AST_MAP.as_mut().unwrap()
-        }
-    }
+fn ast_map() -> std::sync::MutexGuard<'static, HashMap<i32, Box<Expr>>> {
+    AST_MAP.lock().unwrap()
 }
 
 fn register_ast(ast: Box<Expr>) -> i32 {
-    let id = unsafe { NEXT_ID };
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
     ast_map().insert(id, ast);
-    unsafe { NEXT_ID += 1; }
     id
 }
 
@@ -54,21 +49,27 @@ pub fn dispose_ast(id: i32) {
     ast_map().remove(&id);
 }
 
-#[wasm_bindgen]
-pub fn parse_bool_expr(
-    expr: &str
-) -> ParseResult {
-    diagnostic::Diagnostic::clear();
-    
+fn create_parser_context<'a>(
+    expr: &'a str,
+    implicit_multiplication: bool,
+    diagnostics: &'a mut DiagnosticSink,
+) -> parser::ParserContext<'a> {
     let token_iter = lexer::token_iter(expr);
 
-    let ast = parser::parse_top_level_expression(
-        parser::ParserContext::new(
-            Box::new(token_iter),
-            parser::create_binary_op_precedence()
-        )
+    let ctx = parser::ParserContext::new(
+        Box::new(token_iter),
+        parser::create_binary_op_precedence(),
+        diagnostics
     );
 
+    if implicit_multiplication {
+        ctx.with_implicit_multiplication(validator::function_names().map(str::to_string).collect())
+    } else {
+        ctx
+    }
+}
+
+fn finish_bool_parse(ast: Result<Box<Expr>, ErrorReported>, mut diagnostics: DiagnosticSink) -> ParseResult {
     let result;
 
     if let Ok(ast) = ast {
@@ -79,7 +80,8 @@ pub fn parse_bool_expr(
             &HashSet::from([
                 "x".to_string(),
                 "y".to_string()
-            ])
+            ]),
+            &mut diagnostics
         ) {
             result = -1;
         } else {
@@ -91,37 +93,65 @@ pub fn parse_bool_expr(
 
     ParseResult {
         ast_id: result,
-        diagnostics: serde_json::to_string(&diagnostic::Diagnostic::diagnostics().to_vec()).unwrap()
+        diagnostics: serde_json::to_string(diagnostics.diagnostics()).unwrap()
     }
 }
 
+/// `implicit_multiplication` enables `2x`, `x y` and `(x+1)(x-1)` in this parse.
+#[wasm_bindgen]
+pub fn parse_bool_expr(
+    expr: &str,
+    implicit_multiplication: bool
+) -> ParseResult {
+    let mut diagnostics = DiagnosticSink::new();
+    
+    let ast = parser::parse_top_level_expression(
+        create_parser_context(expr, implicit_multiplication, &mut diagnostics)
+    );
+
+    finish_bool_parse(ast, diagnostics)
+}
+
+/// Same as `parse_bool_expr`, but for LaTeX math input such as `\frac{x^2}{4} + y^2 \le 1`.
+#[wasm_bindgen]
+pub fn parse_latex_bool_expr(
+    expr: &str
+) -> ParseResult {
+    let mut diagnostics = DiagnosticSink::new();
+
+    let ast = latex::parse_latex(
+        latex::LatexParserContext::new(expr, &mut diagnostics)
+    );
+
+    finish_bool_parse(ast, diagnostics)
+}
+
 #[wasm_bindgen]
 pub fn emit_bool_expr(
     ast_id: i32,
     equality_approximate_threshold: f64
 ) -> String {
     transpiler::transplie_to_js(
-        &ast_map().get(&ast_id).unwrap(),
+        ast_map().get(&ast_id).unwrap(),
         &CONSTANTS_NAMES,
         equality_approximate_threshold
     )
 }
 
+/// Emits a JS expression evaluating to an array of the result of each equation of a system.
 #[wasm_bindgen]
-pub fn parse_number_expr(
-    expr: &str
-) -> ParseResult {
-    diagnostic::Diagnostic::clear();
-    
-    let token_iter = lexer::token_iter(expr);
-
-    let ast = parser::parse_top_level_expression(
-        parser::ParserContext::new(
-            Box::new(token_iter),
-            parser::create_binary_op_precedence()
-        )
-    );
+pub fn emit_system_expr(
+    ast_id: i32,
+    equality_approximate_threshold: f64
+) -> String {
+    transpiler::transplie_system_to_js(
+        ast_map().get(&ast_id).unwrap(),
+        &CONSTANTS_NAMES,
+        equality_approximate_threshold
+    )
+}
 
+fn finish_number_parse(ast: Result<Box<Expr>, ErrorReported>, mut diagnostics: DiagnosticSink) -> ParseResult {
     let result;
 
     if let Ok(ast) = ast {
@@ -131,7 +161,8 @@ pub fn parse_number_expr(
             &HashMap::new(),
             &HashSet::from([
                 "x".to_string()
-            ])
+            ]),
+            &mut diagnostics
         ) {
             result = -1;
         } else {
@@ -143,25 +174,101 @@ pub fn parse_number_expr(
 
     ParseResult {
         ast_id: result,
-        diagnostics: serde_json::to_string(&diagnostic::Diagnostic::diagnostics().to_vec()).unwrap()
+        diagnostics: serde_json::to_string(diagnostics.diagnostics()).unwrap()
     }
 }
 
+/// `implicit_multiplication` enables `2x`, `x y` and `(x+1)(x-1)` in this parse.
+#[wasm_bindgen]
+pub fn parse_number_expr(
+    expr: &str,
+    implicit_multiplication: bool
+) -> ParseResult {
+    let mut diagnostics = DiagnosticSink::new();
+    
+    let ast = parser::parse_top_level_expression(
+        create_parser_context(expr, implicit_multiplication, &mut diagnostics)
+    );
+
+    finish_number_parse(ast, diagnostics)
+}
+
+/// Same as `parse_number_expr`, but for LaTeX math input such as `\sqrt{x^2 + 1}`.
+#[wasm_bindgen]
+pub fn parse_latex_number_expr(
+    expr: &str
+) -> ParseResult {
+    let mut diagnostics = DiagnosticSink::new();
+
+    let ast = latex::parse_latex(
+        latex::LatexParserContext::new(expr, &mut diagnostics)
+    );
+
+    finish_number_parse(ast, diagnostics)
+}
+
 #[wasm_bindgen]
 pub fn emit_number_expr(
-    ast_id: i32
+    ast_id: i32,
+    equality_approximate_threshold: f64
 ) -> String {
     transpiler::transplie_to_js(
-        &ast_map().get(&ast_id).unwrap(),
+        ast_map().get(&ast_id).unwrap(),
         &CONSTANTS_NAMES,
-        0.0
+        equality_approximate_threshold
     )
 }
 
+/// Evaluates a number expression at `x` in Rust, without emitting JS. On failure the value
+/// is NaN and `error` describes why. `equality_approximate_threshold` is the one given to
+/// `emit_number_expr`, so that both paths agree on conditions such as `if(x = 1, ...)`.
+#[wasm_bindgen]
+pub fn eval_number_expr(ast_id: i32, x: f64, equality_approximate_threshold: f64) -> EvalNumberResult {
+    let mut variables = CONSTANTS.clone();
+    variables.insert("x".to_string(), x);
+
+    let env = evaluator::Env::new(&variables).with_equality_approximate_threshold(equality_approximate_threshold);
+
+    match evaluator::eval_number(ast_map().get(&ast_id).unwrap(), &env) {
+        Ok(value) => EvalNumberResult {
+            value,
+            error: "".to_string()
+        },
+        Err(error) => EvalNumberResult {
+            value: f64::NAN,
+            error: error.to_string()
+        }
+    }
+}
+
+/// Same as `eval_number_expr`, but at each of `xs` in one call, so that plotting does not
+/// cross into wasm for every sample. On failure every value is NaN.
+#[wasm_bindgen]
+pub fn eval_number_expr_batch(ast_id: i32, xs: &[f64], equality_approximate_threshold: f64) -> EvalNumberBatchResult {
+    let mut values = vec![0.0; xs.len()];
+
+    match evaluator::eval_number_batch(
+        ast_map().get(&ast_id).unwrap(),
+        &[("x", xs)],
+        &CONSTANTS,
+        equality_approximate_threshold,
+        &mut values
+    ) {
+        Ok(()) => EvalNumberBatchResult {
+            values,
+            error: "".to_string()
+        },
+        Err(error) => EvalNumberBatchResult {
+            values: vec![f64::NAN; xs.len()],
+            error: error.to_string()
+        }
+    }
+}
+
 #[wasm_bindgen]
 pub fn differentiate_expr(ast_id: i32) -> TransformResult {
-    let ast = ast_map().get(&ast_id).unwrap();
-    let differentiated = transpiler::differentiate_expr(ast);
+    let mut diagnostics = DiagnosticSink::new();
+    let differentiated = transpiler::differentiate_expr(ast_map().get(&ast_id).unwrap(), &mut diagnostics);
     match differentiated {
         Ok(ast) => {
             let id = register_ast(ast);
@@ -170,24 +277,119 @@ pub fn differentiate_expr(ast_id: i32) -> TransformResult {
                 error: "".to_string()
             }
         },
-        Err(err) => {
+        Err(_) => {
             TransformResult {
                 ast_id: -1,
-                error: err
+                error: diagnostics
+                    .diagnostics()
+                    .iter()
+                    .map(|diagnostic| diagnostic.message())
+                    .collect::<Vec<_>>()
+                    .join("\n")
             }
         }
     }
 }
 
+/// Prints the AST with only the parentheses needed to parse it back.
 #[wasm_bindgen]
 pub fn ast_to_string(ast_id: i32) -> String {
-    let ast = ast_map().get(&ast_id).unwrap();
-    transpiler::ast_to_string(ast)
+    ast_map().get(&ast_id).unwrap().to_string()
+}
+
+#[wasm_bindgen]
+pub fn ast_to_latex(ast_id: i32) -> String {
+    transpiler::ast_to_latex(ast_map().get(&ast_id).unwrap())
+}
+
+#[wasm_bindgen]
+pub fn ast_to_mathml(ast_id: i32) -> String {
+    transpiler::ast_to_mathml(ast_map().get(&ast_id).unwrap())
+}
+
+/// Serializes the AST to JSON, in the schema documented on `ast::Expr`.
+#[wasm_bindgen]
+pub fn export_ast(ast_id: i32) -> String {
+    serde_json::to_string(ast_map().get(&ast_id).unwrap()).unwrap()
+}
+
+fn import_ast(json: &str, diagnostics: &mut DiagnosticSink) -> Result<Box<Expr>, ErrorReported> {
+    serde_json::from_str(json).map_err(|error| {
+        diagnostics.push(
+            Diagnostic::new(Level::Error, format!("malformed AST: {}", error))
+                .with_code(DiagnosticCode::MalformedAst)
+        );
+        ErrorReported
+    })
+}
+
+/// Registers an AST exported by `export_ast`, validated the same way as `parse_bool_expr`.
+#[wasm_bindgen]
+pub fn import_bool_ast(json: &str) -> ParseResult {
+    let mut diagnostics = DiagnosticSink::new();
+    let ast = import_ast(json, &mut diagnostics);
+    finish_bool_parse(ast, diagnostics)
+}
+
+/// Registers an AST exported by `export_ast`, validated the same way as `parse_number_expr`.
+#[wasm_bindgen]
+pub fn import_number_ast(json: &str) -> ParseResult {
+    let mut diagnostics = DiagnosticSink::new();
+    let ast = import_ast(json, &mut diagnostics);
+    finish_number_parse(ast, diagnostics)
 }
 
 #[wasm_bindgen]
 pub fn fold_expr(ast_id: i32) -> i32 {
-    let ast = ast_map().get(&ast_id).unwrap();
-    let folded = evaluator::fold_expr(ast);
+    let folded = evaluator::fold_expr(ast_map().get(&ast_id).unwrap());
     register_ast(folded)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn implicit_multiplication_is_chosen_per_parse() {
+        let implicit = parse_number_expr("2x", true);
+        assert_ne!(implicit.ast_id, -1, "{}", implicit.diagnostics);
+        assert_eq!(ast_to_string(implicit.ast_id), "2 * x");
+
+        assert_eq!(parse_number_expr("2x", false).ast_id, -1);
+        assert_ne!(parse_bool_expr("y = 2x", true).ast_id, -1);
+        assert_eq!(parse_bool_expr("y = 2x", false).ast_id, -1);
+
+        for input in ["1.2.3", "0x1.5"] {
+            let malformed = parse_number_expr(input, true);
+            assert_eq!(malformed.ast_id, -1, "{}", input);
+            assert!(malformed.diagnostics.contains("E0009"), "{}", malformed.diagnostics);
+        }
+    }
+
+    #[test]
+    fn exported_ast_is_imported_back() {
+        let parsed = parse_bool_expr("x ^ 2 + y ^ 2 < 1", false);
+        let json = export_ast(parsed.ast_id);
+
+        let imported = import_bool_ast(&json);
+        assert_ne!(imported.ast_id, -1, "{}", imported.diagnostics);
+        assert_ne!(imported.ast_id, parsed.ast_id);
+        assert_eq!(export_ast(imported.ast_id), json);
+
+        assert_eq!(import_number_ast(&json).ast_id, -1);
+        let malformed = import_bool_ast(r#"{"kind":{"type":"id","args":"x"},"span":{"low":3,"high":1}}"#);
+        assert_eq!(malformed.ast_id, -1);
+        assert!(malformed.diagnostics.contains("span starts at 3 after its end at 1"), "{}", malformed.diagnostics);
+    }
+
+    #[test]
+    fn number_evaluation_uses_the_given_threshold() {
+        let parsed = parse_number_expr("if(x = 1, 2, 3)", false);
+        assert_ne!(parsed.ast_id, -1, "{}", parsed.diagnostics);
+
+        assert_eq!(eval_number_expr(parsed.ast_id, 1.000001, 0.0).value, 3.0);
+        assert_eq!(eval_number_expr(parsed.ast_id, 1.000001, 0.00001).value, 2.0);
+        assert_eq!(eval_number_expr_batch(parsed.ast_id, &[1.000001, 1.1], 0.00001).values, vec![2.0, 3.0]);
+        assert!(emit_number_expr(parsed.ast_id, 0.00001).contains("0.00001"));
+    }
+}
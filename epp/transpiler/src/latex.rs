@@ -0,0 +1,283 @@
+use ast::{Expr, ExprKind};
+
+use crate::typeset::*;
+
+/// Typesets the AST as LaTeX math, e.g. `\frac{x^{2}}{2} + 1`.
+///
+/// Divisions are written as fractions and powers as superscripts, and parentheses are only
+/// emitted where precedence requires them.
+pub fn ast_to_latex(expr: &Expr) -> String {
+    let mut result = String::new();
+    ast_to_latex_internal(expr, &mut result);
+    result
+}
+
+/// Writes `expr`, parenthesized if it binds less tightly than `min_precedence`.
+fn operand_to_latex(expr: &Expr, min_precedence: i32, result: &mut String) {
+    if typeset_precedence(expr) < min_precedence {
+        result.push_str("\\left(");
+        ast_to_latex_internal(expr, result);
+        result.push_str("\\right)");
+    } else {
+        ast_to_latex_internal(expr, result);
+    }
+}
+
+/// Writes a left-associative binary operation of the given precedence.
+fn binary_to_latex(lhs: &Expr, operator: &str, rhs: &Expr, precedence: i32, result: &mut String) {
+    operand_to_latex(lhs, precedence, result);
+    result.push_str(operator);
+    operand_to_latex(rhs, precedence + 1, result);
+}
+
+fn group_to_latex(expr: &Expr, result: &mut String) {
+    result.push('{');
+    ast_to_latex_internal(expr, result);
+    result.push('}');
+}
+
+fn identifier_to_latex(name: &str, result: &mut String) {
+    let (base, subscript) = split_identifier(name);
+
+    if greek_letter(base).is_some() {
+        result.push('\\');
+        result.push_str(base);
+    } else if base.chars().count() == 1 {
+        result.push_str(base);
+    } else {
+        result.push_str("\\mathrm{");
+        result.push_str(base);
+        result.push('}');
+    }
+
+    if let Some(subscript) = subscript {
+        result.push_str("_{");
+        result.push_str(&subscript.replace('_', "\\_"));
+        result.push('}');
+    }
+}
+
+/// Writes the name of a function without a LaTeX command of its own.
+fn operator_name_to_latex(name: &str, result: &mut String) {
+    if name.chars().count() == 1 {
+        result.push_str(name);
+    } else {
+        result.push_str("\\operatorname{");
+        result.push_str(&name.replace('_', "\\_"));
+        result.push('}');
+    }
+}
+
+fn arguments_to_latex(args: &[Box<Expr>], result: &mut String) {
+    result.push_str("\\left(");
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            result.push_str(", ");
+        }
+        ast_to_latex_internal(arg, result);
+    }
+    result.push_str("\\right)");
+}
+
+fn call_to_latex(name: &str, args: &[Box<Expr>], result: &mut String) {
+    match (name, args) {
+        ("sqrt", [arg]) => {
+            result.push_str("\\sqrt");
+            group_to_latex(arg, result);
+        },
+        ("cbrt", [arg]) => {
+            result.push_str("\\sqrt[3]");
+            group_to_latex(arg, result);
+        },
+        ("abs", [arg]) => {
+            result.push_str("\\left|");
+            ast_to_latex_internal(arg, result);
+            result.push_str("\\right|");
+        },
+        ("floor", [arg]) => {
+            result.push_str("\\left\\lfloor ");
+            ast_to_latex_internal(arg, result);
+            result.push_str("\\right\\rfloor");
+        },
+        ("ceil", [arg]) => {
+            result.push_str("\\left\\lceil ");
+            ast_to_latex_internal(arg, result);
+            result.push_str("\\right\\rceil");
+        },
+        ("factorial", [arg]) => {
+            operand_to_latex(arg, FACTORIAL_PRECEDENCE, result);
+            result.push('!');
+        },
+        ("pow", [base, exponent]) => {
+            operand_to_latex(base, POWER_PRECEDENCE + 1, result);
+            result.push('^');
+            group_to_latex(exponent, result);
+        },
+        ("log", [arg, base]) => {
+            result.push_str("\\log_");
+            group_to_latex(base, result);
+            arguments_to_latex(std::slice::from_ref(arg), result);
+        },
+        _ => {
+            match named_function(name) {
+                Some((operator, subscript)) => {
+                    result.push('\\');
+                    result.push_str(operator);
+                    if let Some(subscript) = subscript {
+                        result.push_str("_{");
+                        result.push_str(subscript);
+                        result.push('}');
+                    }
+                },
+                None => operator_name_to_latex(name, result),
+            }
+            arguments_to_latex(args, result);
+        },
+    }
+}
+
+fn ast_to_latex_internal(
+    ast: &Expr,
+    result: &mut String,
+) {
+    match &ast.kind {
+        ExprKind::Id(id) => identifier_to_latex(id, result),
+        ExprKind::Call(id, args) => call_to_latex(id, args, result),
+        ExprKind::Eq(lhs, rhs) => binary_to_latex(lhs, " = ", rhs, COMPARISON_PRECEDENCE + 1, result),
+        ExprKind::Lt(lhs, rhs) => binary_to_latex(lhs, " < ", rhs, COMPARISON_PRECEDENCE + 1, result),
+        ExprKind::Gt(lhs, rhs) => binary_to_latex(lhs, " > ", rhs, COMPARISON_PRECEDENCE + 1, result),
+        ExprKind::Le(lhs, rhs) => binary_to_latex(lhs, " \\le ", rhs, COMPARISON_PRECEDENCE + 1, result),
+        ExprKind::Ge(lhs, rhs) => binary_to_latex(lhs, " \\ge ", rhs, COMPARISON_PRECEDENCE + 1, result),
+        ExprKind::And(lhs, rhs) => binary_to_latex(lhs, " \\land ", rhs, AND_PRECEDENCE, result),
+        ExprKind::Or(lhs, rhs) => binary_to_latex(lhs, " \\lor ", rhs, OR_PRECEDENCE, result),
+        ExprKind::Not(expr) => match not_equal_operands(ast) {
+            Some((lhs, rhs)) => binary_to_latex(lhs, " \\ne ", rhs, COMPARISON_PRECEDENCE + 1, result),
+            None => {
+                result.push_str("\\lnot ");
+                operand_to_latex(expr, COMPARISON_PRECEDENCE + 1, result);
+            },
+        },
+        ExprKind::If(condition, then, otherwise) => {
+            result.push_str("\\begin{cases} ");
+            ast_to_latex_internal(then, result);
+            result.push_str(" & \\text{if } ");
+            ast_to_latex_internal(condition, result);
+            result.push_str(" \\\\ ");
+            ast_to_latex_internal(otherwise, result);
+            result.push_str(" & \\text{otherwise} \\end{cases}");
+        },
+        ExprKind::Let(name, value, body) => {
+            result.push_str("\\text{let } ");
+            identifier_to_latex(name, result);
+            result.push_str(" = ");
+            ast_to_latex_internal(value, result);
+            result.push_str(" \\text{ in } ");
+            ast_to_latex_internal(body, result);
+        },
+        ExprKind::LetFn(name, parameters, value, body) => {
+            result.push_str("\\text{let } ");
+            operator_name_to_latex(name, result);
+            result.push_str("\\left(");
+            for (i, parameter) in parameters.iter().enumerate() {
+                if i > 0 {
+                    result.push_str(", ");
+                }
+                identifier_to_latex(parameter, result);
+            }
+            result.push_str("\\right) = ");
+            ast_to_latex_internal(value, result);
+            result.push_str(" \\text{ in } ");
+            ast_to_latex_internal(body, result);
+        },
+        ExprKind::Sum(index, from, to, term) | ExprKind::Prod(index, from, to, term) => {
+            result.push_str(if matches!(ast.kind, ExprKind::Sum(..)) { "\\sum_{" } else { "\\prod_{" });
+            identifier_to_latex(index, result);
+            result.push('=');
+            ast_to_latex_internal(from, result);
+            result.push_str("}^");
+            group_to_latex(to, result);
+            result.push(' ');
+            operand_to_latex(term, MULTIPLICATIVE_PRECEDENCE, result);
+        },
+        ExprKind::System(equations) => {
+            for (i, equation) in equations.iter().enumerate() {
+                if i > 0 {
+                    result.push_str(",\\quad ");
+                }
+                ast_to_latex_internal(equation, result);
+            }
+        },
+        ExprKind::Add(lhs, rhs) => binary_to_latex(lhs, " + ", rhs, ADDITIVE_PRECEDENCE, result),
+        ExprKind::Sub(lhs, rhs) => binary_to_latex(lhs, " - ", rhs, ADDITIVE_PRECEDENCE, result),
+        ExprKind::Mul(lhs, rhs) => {
+            let operator = if is_implicit_multiplication(lhs, rhs) { "" } else { " \\cdot " };
+            operand_to_latex(lhs, left_factor_precedence(lhs), result);
+            result.push_str(operator);
+            operand_to_latex(rhs, MULTIPLICATIVE_PRECEDENCE + 1, result);
+        },
+        ExprKind::Mod(lhs, rhs) => binary_to_latex(lhs, " \\bmod ", rhs, MULTIPLICATIVE_PRECEDENCE, result),
+        ExprKind::Div(lhs, rhs) => {
+            result.push_str("\\frac");
+            group_to_latex(lhs, result);
+            group_to_latex(rhs, result);
+        },
+        ExprKind::Pow(lhs, rhs) => {
+            operand_to_latex(lhs, POWER_PRECEDENCE + 1, result);
+            result.push('^');
+            group_to_latex(rhs, result);
+        },
+        ExprKind::Unary(expr) => {
+            result.push('-');
+            operand_to_latex(expr, ADDITIVE_PRECEDENCE + 1, result);
+        },
+        ExprKind::Literal(literal) => {
+            if literal.is_nan() {
+                result.push_str("\\mathrm{NaN}");
+            } else if literal.is_infinite() {
+                result.push_str(if *literal < 0.0 { "-\\infty" } else { "\\infty" });
+            } else {
+                result.push_str(&literal.to_string());
+            }
+        },
+        ExprKind::Error => {
+            result.push_str("\\text{<error>}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::parse;
+
+    fn latex(input: &str) -> String {
+        ast_to_latex(&parse(input))
+    }
+
+    #[test]
+    fn structures_are_typeset() {
+        assert_eq!(latex("(x^2 + 1) / (2 * y)"), r"\frac{x^{2} + 1}{2y}");
+        assert_eq!(latex("x^(1/2)"), r"x^{\frac{1}{2}}");
+        assert_eq!(latex("sqrt(x + 1) + |x - 1|"), r"\sqrt{x + 1} + \left|x - 1\right|");
+        assert_eq!(latex("sum(k, 1, n, k^2)"), r"\sum_{k=1}^{n} k^{2}");
+        assert_eq!(
+            latex("if(x < 0, -x, x)"),
+            r"\begin{cases} -x & \text{if } x < 0 \\ x & \text{otherwise} \end{cases}"
+        );
+    }
+
+    #[test]
+    fn only_required_parentheses_are_kept() {
+        assert_eq!(latex("-(x + y)^2"), r"-\left(x + y\right)^{2}");
+        assert_eq!(latex("a - (b - c)"), r"a - \left(b - c\right)");
+        assert_eq!(latex("(a - b) - c"), "a - b - c");
+        assert_eq!(latex("2 * 3"), r"2 \cdot 3");
+    }
+
+    #[test]
+    fn names_are_typeset() {
+        assert_eq!(latex("log10(x) + theta_1"), r"\log_{10}\left(x\right) + \theta_{1}");
+        assert_eq!(latex("f(x, y) + sin(2 * x)"), r"f\left(x, y\right) + \sin\left(2x\right)");
+        assert_eq!(latex("x < 1 and not y = 2"), r"x < 1 \land y \ne 2");
+    }
+}
@@ -1,10 +1,38 @@
-use std::collections::HashMap;
+mod latex;
+mod mathml;
+mod typeset;
 
-use ast::Expr;
+pub use latex::ast_to_latex;
+pub use mathml::ast_to_mathml;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use ast::{walk_expr, Expr, ExprId, ExprKind, ExprNode, ExprPool, Visitor, MAX_AGGREGATE_BOUND, MAX_AGGREGATE_TERM_COUNT};
+use diagnostic::{Diagnostic, DiagnosticCode, DiagnosticSink, ErrorReported, Level};
+use span::Span;
 
 #[macro_use]
 extern crate lazy_static;
 
+/// `n!` for non-negative integers, `gamma(n + 1)` using the Lanczos approximation otherwise.
+const FACTORIAL_JS: &str = "((n) => { \
+if (Number.isInteger(n)) { \
+if (n < 0) return NaN; \
+if (n > 170) return Infinity; \
+let r = 1; for (let i = 2; i <= n; i++) r *= i; return r; \
+} \
+const c = [0.9999999999998099, 676.5203681218851, -1259.1392167224028, 771.3234287776531, \
+-176.6150291621406, 12.507343278686905, -0.13857109526572012, 9.984369578019572e-6, 1.5056327351493116e-7]; \
+const gamma = (z) => { \
+if (z < 0.5) return Math.PI / (Math.sin(Math.PI * z) * gamma(1 - z)); \
+z -= 1; const t = z + 7.5; let s = c[0]; \
+for (let i = 1; i < 9; i++) s += c[i] / (z + i); \
+return Math.sqrt(2 * Math.PI) * Math.pow(t, z + 0.5) * Math.exp(-t) * s; \
+}; \
+return gamma(n + 1); \
+})";
+
 lazy_static! {
     static ref JS_FUNCTION_MAP: HashMap<&'static str, &'static str> = {
         let mut map = HashMap::new();
@@ -23,6 +51,7 @@ lazy_static! {
         map.insert("cosh", "Math.cosh");
         map.insert("exp", "Math.exp");
         map.insert("exp_m1", "Math.expm1");
+        map.insert("factorial", FACTORIAL_JS);
         map.insert("floor", "Math.floor");
         map.insert("hypot", "Math.hypot");
         map.insert("ln", "Math.log");
@@ -48,270 +77,233 @@ pub fn transplie_to_js(
     constant_name_map: &HashMap<String, String>,
     equality_approximate_threshold: f64,
 ) -> String {
-    let mut result = String::new();
-
-    transplie_to_js_internal(ast, constant_name_map, equality_approximate_threshold, &mut result);
-    result
+    let mut emitter = JsEmitter::new(constant_name_map, equality_approximate_threshold);
+    emitter.visit_expr(ast);
+    emitter.result
 }
 
-fn transplie_to_js_internal(
+/// Emits a JS array of the result of each equation of a system; any other equation is
+/// emitted as a system of one. `transplie_to_js` emits a system as the conjunction of its equations.
+pub fn transplie_system_to_js(
     ast: &Expr,
     constant_name_map: &HashMap<String, String>,
     equality_approximate_threshold: f64,
-    result: &mut String, 
-) {
-    match ast {
-        Expr::Id(id) => {
-            if let Some(constant_name) = constant_name_map.get(id) {
-                result.push_str(constant_name);
-            } else {
-                result.push_str(id);
+) -> String {
+    let mut emitter = JsEmitter::new(constant_name_map, equality_approximate_threshold);
+    emitter.emit_system(ast);
+    emitter.result
+}
+
+/// Writes an AST as a JS expression, parenthesizing every operation.
+///
+/// Names defined in the expression are written with a `$` prefix, which cannot appear in a name
+/// of the input, so that they cannot be JS keywords such as `new` or shadow globals such as `Math`.
+/// Names of the emitter itself are written with a `$$` prefix, so that they cannot clash with those.
+struct JsEmitter<'a> {
+    constant_name_map: &'a HashMap<String, String>,
+    equality_approximate_threshold: f64,
+    /// Names defined by the enclosing definitions, innermost last.
+    locals: Vec<String>,
+    result: String,
+}
+
+impl<'a> JsEmitter<'a> {
+    fn new(constant_name_map: &'a HashMap<String, String>, equality_approximate_threshold: f64) -> Self {
+        Self {
+            constant_name_map,
+            equality_approximate_threshold,
+            locals: Vec::new(),
+            result: String::new(),
+        }
+    }
+
+    fn is_local(&self, name: &str) -> bool {
+        self.locals.iter().any(|local| local == name)
+    }
+
+    fn emit_local(&mut self, name: &str) {
+        self.result.push('$');
+        self.result.push_str(name);
+    }
+
+    /// Writes `(lhs operator rhs)`.
+    fn emit_binary(&mut self, lhs: &Expr, operator: &str, rhs: &Expr) {
+        self.result.push('(');
+        self.visit_expr(lhs);
+        self.result.push_str(operator);
+        self.visit_expr(rhs);
+        self.result.push(')');
+    }
+
+    /// Writes the expressions separated by `separator`.
+    fn emit_list<'e>(&mut self, exprs: impl IntoIterator<Item = &'e Expr>, separator: &str) {
+        for (i, expr) in exprs.into_iter().enumerate() {
+            if i > 0 {
+                self.result.push_str(separator);
             }
-        },
-        Expr::Call(id, args) => {
-            if id == "log" {
-                result.push('(');
-
-                result.push_str("Math.log2(");
-                transplie_to_js_internal(
-                    &args[0],
-                    constant_name_map,
-                    equality_approximate_threshold,
-                    result
-                );
-                result.push(')');
-                result.push_str(" / ");
-                result.push_str("Math.log2(");
-                transplie_to_js_internal(
-                    &args[1],
-                    constant_name_map,
-                    equality_approximate_threshold,
-                    result
-                );
-                result.push(')');
-
-                result.push(')');
-            } else {
-                result.push_str(JS_FUNCTION_MAP.get(id.as_str()).expect("function translation not found"));
-                result.push('(');
-                for arg in args {
-                    transplie_to_js_internal(
-                        arg,
-                        constant_name_map,
-                        equality_approximate_threshold,
-                        result
-                    );
-                    result.push_str(", ");
+            self.visit_expr(expr);
+        }
+    }
+
+    /// Writes `((name) => body)(value)`, with `body` written by `emit_body`.
+    fn emit_let(
+        &mut self,
+        name: &str,
+        parameters: Option<&[String]>,
+        value: &Expr,
+        body: &Expr,
+        emit_body: fn(&mut Self, &Expr),
+    ) {
+        self.result.push_str("((");
+        self.emit_local(name);
+        self.result.push_str(") => ");
+        self.locals.push(name.to_owned());
+        emit_body(self, body);
+        self.locals.pop();
+        self.result.push_str(")(");
+
+        let local_count = self.locals.len();
+        if let Some(parameters) = parameters {
+            self.result.push('(');
+            for (i, parameter) in parameters.iter().enumerate() {
+                if i > 0 {
+                    self.result.push_str(", ");
                 }
-                result.pop();
-                result.pop();
-                result.push(')');
+                self.emit_local(parameter);
+            }
+            self.result.push_str(") => ");
+            self.locals.extend(parameters.iter().cloned());
+        }
+        self.visit_expr(value);
+        self.locals.truncate(local_count);
+        self.result.push(')');
+    }
+
+    /// Writes the array of the results of the equations of a system, see `transplie_system_to_js`.
+    fn emit_system(&mut self, ast: &Expr) {
+        match &ast.kind {
+            ExprKind::Let(name, value, body) => self.emit_let(name, None, value, body, Self::emit_system),
+            ExprKind::LetFn(name, parameters, value, body) => {
+                self.emit_let(name, Some(parameters), value, body, Self::emit_system)
+            },
+            ExprKind::System(equations) => {
+                self.result.push('[');
+                self.emit_list(equations.iter().map(|equation| &**equation), ", ");
+                self.result.push(']');
+            },
+            _ => {
+                self.result.push('[');
+                self.visit_expr(ast);
+                self.result.push(']');
+            },
+        }
+    }
+}
+
+impl Visitor for JsEmitter<'_> {
+    fn visit_expr(&mut self, ast: &Expr) {
+        match &ast.kind {
+            ExprKind::Id(id) if self.is_local(id) => self.emit_local(id),
+            ExprKind::Id(id) => {
+                let name = self.constant_name_map.get(id).unwrap_or(id);
+                self.result.push_str(name);
+            },
+            ExprKind::Call(id, args) if self.is_local(id) => {
+                self.emit_local(id);
+                self.result.push('(');
+                self.emit_list(args.iter().map(|arg| &**arg), ", ");
+                self.result.push(')');
+            },
+            ExprKind::Call(id, args) if id == "log" => {
+                self.result.push_str("(Math.log2(");
+                self.visit_expr(&args[0]);
+                self.result.push_str(") / Math.log2(");
+                self.visit_expr(&args[1]);
+                self.result.push_str("))");
+            },
+            ExprKind::Call(id, args) => {
+                self.result.push_str(JS_FUNCTION_MAP.get(id.as_str()).copied().unwrap_or(id));
+                self.result.push('(');
+                self.emit_list(args.iter().map(|arg| &**arg), ", ");
+                self.result.push(')');
+            },
+            ExprKind::Eq(lhs, rhs) => {
+                self.result.push_str("(Math.abs(");
+                self.visit_expr(lhs);
+                self.result.push_str(" - ");
+                self.visit_expr(rhs);
+                self.result.push_str(") < ");
+                self.result.push_str(&self.equality_approximate_threshold.to_string());
+                self.result.push(')');
+            },
+            ExprKind::Lt(lhs, rhs) => self.emit_binary(lhs, " < ", rhs),
+            ExprKind::Gt(lhs, rhs) => self.emit_binary(lhs, " > ", rhs),
+            ExprKind::Le(lhs, rhs) => self.emit_binary(lhs, " <= ", rhs),
+            ExprKind::Ge(lhs, rhs) => self.emit_binary(lhs, " >= ", rhs),
+            ExprKind::And(lhs, rhs) => self.emit_binary(lhs, " && ", rhs),
+            ExprKind::Or(lhs, rhs) => self.emit_binary(lhs, " || ", rhs),
+            ExprKind::Let(name, value, body) => self.emit_let(name, None, value, body, Self::visit_expr),
+            ExprKind::LetFn(name, parameters, value, body) => {
+                self.emit_let(name, Some(parameters), value, body, Self::visit_expr)
+            },
+            ExprKind::Sum(index, from, to, term) | ExprKind::Prod(index, from, to, term) => {
+                // (($$from, $$to) => { ...loop... })(from, to)
+                let (identity, combine) = if let ExprKind::Sum(..) = &ast.kind { ("0", "+=") } else { ("1", "*=") };
+
+                // The index of the `$$i`-th term is `$$from + $$i`, as in the evaluator.
+                self.result.push_str("(($$from, $$to) => { ");
+                self.result.push_str(&format!(
+                    "if (!(Math.abs($$from) <= {0} && Math.abs($$to) <= {0} && $$to - $$from < {1})) return NaN; ",
+                    MAX_AGGREGATE_BOUND, MAX_AGGREGATE_TERM_COUNT
+                ));
+                self.result.push_str("const $$n = $$to < $$from ? 0 : Math.floor($$to - $$from) + 1; ");
+                self.result.push_str(&format!("let $$result = {}; ", identity));
+                self.result.push_str("for (let $$i = 0; $$i < $$n; $$i++) { const ");
+                self.emit_local(index);
+                self.result.push_str(&format!(" = $$from + $$i; $$result {} ", combine));
+                self.locals.push(index.to_owned());
+                self.visit_expr(term);
+                self.locals.pop();
+                self.result.push_str("; } return $$result; })(");
+                self.emit_list([&**from, &**to], ", ");
+                self.result.push(')');
+            },
+            ExprKind::System(equations) => {
+                self.result.push('(');
+                self.emit_list(equations.iter().map(|equation| &**equation), " && ");
+                self.result.push(')');
+            },
+            ExprKind::If(condition, then, otherwise) => {
+                self.result.push('(');
+                self.visit_expr(condition);
+                self.result.push_str(" ? ");
+                self.visit_expr(then);
+                self.result.push_str(" : ");
+                self.visit_expr(otherwise);
+                self.result.push(')');
+            },
+            ExprKind::Not(expr) => {
+                self.result.push_str("(!");
+                self.visit_expr(expr);
+                self.result.push(')');
+            },
+            ExprKind::Add(lhs, rhs) => self.emit_binary(lhs, " + ", rhs),
+            ExprKind::Sub(lhs, rhs) => self.emit_binary(lhs, " - ", rhs),
+            ExprKind::Mul(lhs, rhs) => self.emit_binary(lhs, " * ", rhs),
+            ExprKind::Div(lhs, rhs) => self.emit_binary(lhs, " / ", rhs),
+            ExprKind::Mod(lhs, rhs) => self.emit_binary(lhs, " % ", rhs),
+            ExprKind::Pow(lhs, rhs) => self.emit_binary(lhs, " ** ", rhs),
+            ExprKind::Unary(expr) => {
+                self.result.push_str("(-");
+                self.visit_expr(expr);
+                self.result.push(')');
+            },
+            ExprKind::Literal(literal) => {
+                self.result.push_str(&literal.to_string());
+            },
+            ExprKind::Error => {
+                self.result.push_str("NaN");
             }
-        },
-        Expr::Eq(lhs, rhs) => {
-            result.push('(');
-            result.push_str("Math.abs(");
-            transplie_to_js_internal(
-                lhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push_str(" - ");
-            transplie_to_js_internal(
-                rhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push(')');
-            result.push_str(" < ");
-            result.push_str(&equality_approximate_threshold.to_string());
-            result.push(')');
-        },
-        Expr::Lt(lhs, rhs) => {
-            result.push('(');
-            transplie_to_js_internal(
-                lhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push_str(" < ");
-            transplie_to_js_internal(
-                rhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push(')');
-        },
-        Expr::Gt(lhs, rhs) => {
-            result.push('(');
-            transplie_to_js_internal(
-                lhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push_str(" > ");
-            transplie_to_js_internal(
-                rhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push(')');
-        },
-        Expr::Le(lhs, rhs) => {
-            result.push('(');
-            transplie_to_js_internal(
-                lhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push_str(" <= ");
-            transplie_to_js_internal(
-                rhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push(')');
-        },
-        Expr::Ge(lhs, rhs) => {
-            result.push('(');
-            transplie_to_js_internal(
-                lhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push_str(" >= ");
-            transplie_to_js_internal(
-                rhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push(')');
-        },
-        Expr::Add(lhs, rhs) => {
-            result.push('(');
-            transplie_to_js_internal(
-                lhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push_str(" + ");
-            transplie_to_js_internal(
-                rhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push(')');
-        },
-        Expr::Sub(lhs, rhs) => {
-            result.push('(');
-            transplie_to_js_internal(
-                lhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push_str(" - ");
-            transplie_to_js_internal(
-                rhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push(')');
-        },
-        Expr::Mul(lhs, rhs) => {
-            result.push('(');
-            transplie_to_js_internal(
-                lhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push_str(" * ");
-            transplie_to_js_internal(
-                rhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push(')');
-        },
-        Expr::Div(lhs, rhs) => {
-            result.push('(');
-            transplie_to_js_internal(
-                lhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push_str(" / ");
-            transplie_to_js_internal(
-                rhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push(')');
-        },
-        Expr::Mod(lhs, rhs) => {
-            result.push('(');
-            transplie_to_js_internal(
-                lhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push_str(" % ");
-            transplie_to_js_internal(
-                rhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push(')');
-        },
-        Expr::Pow(lhs, rhs) => {
-            result.push('(');
-            transplie_to_js_internal(
-                lhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push_str(" ** ");
-            transplie_to_js_internal(
-                rhs,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push(')');
-        },
-        Expr::Unary(expr) => {
-            result.push('(');
-            result.push('-');
-            transplie_to_js_internal(
-                expr,
-                constant_name_map,
-                equality_approximate_threshold,
-                result
-            );
-            result.push(')');
-        },
-        Expr::Literal(literal) => {
-            result.push_str(&literal.to_string());
         }
     }
 }
@@ -329,9 +321,9 @@ fn ast_to_string_internal(
     ast: &Expr,
     result: &mut String, 
 ) {
-    match ast {
-        Expr::Id(id) => result.push_str(id),
-        Expr::Call(id, args) => {
+    match &ast.kind {
+        ExprKind::Id(id) => result.push_str(id),
+        ExprKind::Call(id, args) => {
             result.push_str(id);
             result.push('(');
             for (i, arg) in args.iter().enumerate() {
@@ -342,373 +334,585 @@ fn ast_to_string_internal(
             }
             result.push(')');
         },
-        Expr::Eq(lhs, rhs) => {
+        ExprKind::Eq(lhs, rhs) => {
             result.push('(');
             ast_to_string_internal(lhs, result);
             result.push_str(" = ");
             ast_to_string_internal(rhs, result);
             result.push(')');
         },
-        Expr::Lt(lhs, rhs) => {
+        ExprKind::Lt(lhs, rhs) => {
             result.push('(');
             ast_to_string_internal(lhs, result);
             result.push_str(" < ");
             ast_to_string_internal(rhs, result);
             result.push(')');
         },
-        Expr::Gt(lhs, rhs) => {
+        ExprKind::Gt(lhs, rhs) => {
             result.push('(');
             ast_to_string_internal(lhs, result);
             result.push_str(" > ");
             ast_to_string_internal(rhs, result);
             result.push(')');
         },
-        Expr::Le(lhs, rhs) => {
+        ExprKind::Le(lhs, rhs) => {
             result.push('(');
             ast_to_string_internal(lhs, result);
             result.push_str(" <= ");
             ast_to_string_internal(rhs, result);
             result.push(')');
         },
-        Expr::Ge(lhs, rhs) => {
+        ExprKind::Ge(lhs, rhs) => {
             result.push('(');
             ast_to_string_internal(lhs, result);
             result.push_str(" >= ");
             ast_to_string_internal(rhs, result);
             result.push(')');
         },
-        Expr::Add(lhs, rhs) => {
+        ExprKind::And(lhs, rhs) => {
+            result.push('(');
+            ast_to_string_internal(lhs, result);
+            result.push_str(" and ");
+            ast_to_string_internal(rhs, result);
+            result.push(')');
+        },
+        ExprKind::Or(lhs, rhs) => {
+            result.push('(');
+            ast_to_string_internal(lhs, result);
+            result.push_str(" or ");
+            ast_to_string_internal(rhs, result);
+            result.push(')');
+        },
+        ExprKind::Let(name, value, body) => {
+            result.push_str("(let ");
+            result.push_str(name);
+            result.push_str(" = ");
+            ast_to_string_internal(value, result);
+            result.push_str(" in ");
+            ast_to_string_internal(body, result);
+            result.push(')');
+        },
+        ExprKind::LetFn(name, parameters, value, body) => {
+            result.push_str("(let ");
+            result.push_str(name);
+            result.push('(');
+            result.push_str(&parameters.join(", "));
+            result.push_str(") = ");
+            ast_to_string_internal(value, result);
+            result.push_str(" in ");
+            ast_to_string_internal(body, result);
+            result.push(')');
+        },
+        ExprKind::Sum(index, from, to, term) | ExprKind::Prod(index, from, to, term) => {
+            result.push_str(ast.to_str());
+            result.push('(');
+            result.push_str(index);
+            result.push_str(", ");
+            ast_to_string_internal(from, result);
+            result.push_str(", ");
+            ast_to_string_internal(to, result);
+            result.push_str(", ");
+            ast_to_string_internal(term, result);
+            result.push(')');
+        },
+        ExprKind::System(equations) => {
+            for (i, equation) in equations.iter().enumerate() {
+                if i > 0 {
+                    result.push_str(", ");
+                }
+                ast_to_string_internal(equation, result);
+            }
+        },
+        ExprKind::If(condition, then, otherwise) => {
+            result.push_str("if(");
+            ast_to_string_internal(condition, result);
+            result.push_str(", ");
+            ast_to_string_internal(then, result);
+            result.push_str(", ");
+            ast_to_string_internal(otherwise, result);
+            result.push(')');
+        },
+        ExprKind::Not(expr) => {
+            result.push_str("(not ");
+            ast_to_string_internal(expr, result);
+            result.push(')');
+        },
+        ExprKind::Add(lhs, rhs) => {
             result.push('(');
             ast_to_string_internal(lhs, result);
             result.push_str(" + ");
             ast_to_string_internal(rhs, result);
             result.push(')');
         },
-        Expr::Sub(lhs, rhs) => {
+        ExprKind::Sub(lhs, rhs) => {
             result.push('(');
             ast_to_string_internal(lhs, result);
             result.push_str(" - ");
             ast_to_string_internal(rhs, result);
             result.push(')');
         },
-        Expr::Mul(lhs, rhs) => {
+        ExprKind::Mul(lhs, rhs) => {
             result.push('(');
             ast_to_string_internal(lhs, result);
             result.push_str(" * ");
             ast_to_string_internal(rhs, result);
             result.push(')');
         },
-        Expr::Div(lhs, rhs) => {
+        ExprKind::Div(lhs, rhs) => {
             result.push('(');
             ast_to_string_internal(lhs, result);
             result.push_str(" / ");
             ast_to_string_internal(rhs, result);
             result.push(')');
         },
-        Expr::Mod(lhs, rhs) => {
+        ExprKind::Mod(lhs, rhs) => {
             result.push('(');
             ast_to_string_internal(lhs, result);
             result.push_str(" % ");
             ast_to_string_internal(rhs, result);
             result.push(')');
         },
-        Expr::Pow(lhs, rhs) => {
+        ExprKind::Pow(lhs, rhs) => {
             result.push('(');
             ast_to_string_internal(lhs, result);
             result.push_str(" ^ ");
             ast_to_string_internal(rhs, result);
             result.push(')');
         },
-        Expr::Unary(expr) => {
+        ExprKind::Unary(expr) => {
             result.push('(');
             result.push('-');
             ast_to_string_internal(expr, result);
             result.push(')');
         },
-        Expr::Literal(literal) => {
+        ExprKind::Literal(literal) => {
             result.push_str(&literal.to_string());
+        },
+        ExprKind::Error => {
+            result.push_str("<error>");
+        }
+    }
+}
+
+struct TransformContext<'a> {
+    pool: &'a mut ExprPool,
+    top_level_appand_expr_list: Vec<ExprId>,
+    /// Whether the expression being differentiated is inside the term of a `Sum` or `Prod`, where
+    /// a factor appended to `top_level_appand_expr_list` would be outside the scope of the index,
+    /// or inside a branch of an `If`, where it would also multiply the other branch.
+    in_place: bool,
+    /// Derivative of each expression differentiated so far, in place or not, with the
+    /// factors it appended to `top_level_appand_expr_list`, so a shared subexpression is
+    /// differentiated once.
+    derivatives: HashMap<(ExprId, bool), (ExprId, Vec<ExprId>)>,
+    has_x: HashMap<ExprId, bool>,
+    diagnostics: &'a mut DiagnosticSink,
+}
+
+impl TransformContext<'_> {
+    fn report_not_differentiable(&mut self, message: String, span: Span) -> Result<ExprId, ErrorReported> {
+        self.diagnostics.push(Diagnostic::new(
+            Level::Error,
+            message,
+        )
+        .with_code(DiagnosticCode::NotDifferentiable)
+        .with_span(span));
+        Err(ErrorReported)
+    }
+
+    fn add(&mut self, node: ExprNode, span: Span) -> ExprId {
+        self.pool.add(node, span)
+    }
+
+    fn literal(&mut self, literal: f64, span: Span) -> ExprId {
+        self.pool.add(ExprNode::Literal(literal), span)
+    }
+
+    fn call(&mut self, name: &str, args: Vec<ExprId>, span: Span) -> ExprId {
+        self.pool.add(ExprNode::Call(name.to_string(), args), span)
+    }
+
+    /// Whether the expression refers to `x`.
+    fn has_x(&mut self, id: ExprId) -> bool {
+        if let Some(has_x) = self.has_x.get(&id) {
+            return *has_x;
         }
+
+        let node = self.pool.node(id);
+        let has_x = if let ExprNode::Id(name) = node {
+            name == "x"
+        } else {
+            node.children().into_iter().any(|child| self.has_x(child))
+        };
+        self.has_x.insert(id, has_x);
+        has_x
     }
 }
 
-struct TransformContext {
-    top_level_appand_expr_list: Vec<Expr>,
+pub fn differentiate_expr(ast: &Expr, diagnostics: &mut DiagnosticSink) -> Result<Box<Expr>, ErrorReported> {
+    let mut pool = ExprPool::new();
+    let id = pool.add_expr(ast);
+    let result = differentiate_pooled_expr(&mut pool, id, diagnostics)?;
+    Ok(pool.to_expr(result))
 }
 
-pub fn differentiate_expr(ast: &Expr) -> Result<Box<Expr>, String> {
+/// Same as `differentiate_expr`, for an expression of `pool`.
+///
+/// The derivative shares its subexpressions with the expression and with itself, so repeated
+/// differentiation grows the pool polynomially where the AST would grow exponentially.
+pub fn differentiate_pooled_expr(pool: &mut ExprPool, id: ExprId, diagnostics: &mut DiagnosticSink) -> Result<ExprId, ErrorReported> {
     let mut context = TransformContext {
+        pool,
         top_level_appand_expr_list: Vec::new(),
+        in_place: false,
+        derivatives: HashMap::new(),
+        has_x: HashMap::new(),
+        diagnostics,
     };
 
-    let mut result = differentiate_expr_internal(ast, &mut context)?;
+    let mut result = differentiate_expr_internal(id, &mut context)?;
 
-    for expr in context.top_level_appand_expr_list {
-        result = Box::new(Expr::Mul(
-            result,
-            Box::new(expr)
-        ));
+    for expr in std::mem::take(&mut context.top_level_appand_expr_list) {
+        let span = context.pool.span(result);
+        result = context.add(ExprNode::Mul(result, expr), span);
     }
 
     Ok(result)
 }
 
-fn differentiate_expr_internal(ast: &Expr, ctx: &mut TransformContext) -> Result<Box<Expr>, String> {
-    match ast {
-        Expr::Eq(_, _) => Err("Cannot differentiate an equality expression".to_string()),
-        Expr::Lt(_, _) => Err("Cannot differentiate a less than expression".to_string()),
-        Expr::Gt(_, _) => Err("Cannot differentiate a greater than expression".to_string()),
-        Expr::Le(_, _) => Err("Cannot differentiate a less than or equal expression".to_string()),
-        Expr::Ge(_, _) => Err("Cannot differentiate a greater than or equal expression".to_string()),
-        Expr::Unary(expr) => Ok(Box::new(Expr::Unary(differentiate_expr_internal(expr, ctx)?))),
-        Expr::Add(lhs, rhs) => Ok( // (f(x) + g(x))' = f'(x) + g'(x)
-            Box::new(Expr::Add(
-                differentiate_expr_internal(lhs, ctx)?,
-                differentiate_expr_internal(rhs, ctx)?
-            ))
-        ),
-        Expr::Sub(lhs, rhs) => Ok( // (f(x) - g(x))' = f'(x) - g'(x)
-            Box::new(Expr::Sub(
-                differentiate_expr_internal(lhs, ctx)?,
-                differentiate_expr_internal(rhs, ctx)?
-            ))
-        ),
-        Expr::Mul(lhs, rhs) => Ok( // (f(x) * g(x))' = f'(x) * g(x) + f(x) * g'(x)
-            Box::new(Expr::Add(
-                Box::new(Expr::Mul(
-                    differentiate_expr_internal(lhs, ctx)?,
-                    rhs.clone(),
-                )),
-                Box::new(Expr::Mul(
-                    lhs.clone(),
-                    differentiate_expr_internal(rhs, ctx)?,
-                ))
-            ))
-        ),
-        Expr::Div(lhs, rhs) => Ok( // (f(x) / g(x))' = (f'(x) * g(x) - f(x) * g'(x)) / g(x)^2
-            Box::new(Expr::Div(
-                Box::new(Expr::Sub(
-                    Box::new(Expr::Mul(
-                        differentiate_expr_internal(lhs, ctx)?,
-                        rhs.clone(),
-                    )),
-                    Box::new(Expr::Mul(
-                        lhs.clone(),
-                        differentiate_expr_internal(rhs, ctx)?,
-                    )),
-                )),
-                Box::new(Expr::Pow(
-                    rhs.clone(),
-                    Box::new(Expr::Literal(2.0)),
-                ))
-            ))
-        ),
-        Expr::Mod(_, _) => Err("Cannot differentiate a modulo expression".to_string()),
-        Expr::Pow(lhs, rhs) => {
-            let lhs_has_x = check_ast_has_x(lhs);
-            let rhs_has_x = check_ast_has_x(rhs);
-            
+fn differentiate_expr_internal(id: ExprId, ctx: &mut TransformContext) -> Result<ExprId, ErrorReported> {
+    if let Some((derivative, factors)) = ctx.derivatives.get(&(id, ctx.in_place)) {
+        let derivative = *derivative;
+        ctx.top_level_appand_expr_list.extend_from_slice(&factors.clone());
+        return Ok(derivative);
+    }
+
+    let factor_count = ctx.top_level_appand_expr_list.len();
+    let derivative = differentiate_node(id, ctx)?;
+    let factors = ctx.top_level_appand_expr_list[factor_count..].to_vec();
+    ctx.derivatives.insert((id, ctx.in_place), (derivative, factors));
+    Ok(derivative)
+}
+
+fn differentiate_node(id: ExprId, ctx: &mut TransformContext) -> Result<ExprId, ErrorReported> {
+    let span = ctx.pool.span(id);
+
+    match ctx.pool.node(id).clone() {
+        ExprNode::Eq(_, _) => ctx.report_not_differentiable("Cannot differentiate an equality expression".to_string(), span),
+        ExprNode::Lt(_, _) => ctx.report_not_differentiable("Cannot differentiate a less than expression".to_string(), span),
+        ExprNode::Gt(_, _) => ctx.report_not_differentiable("Cannot differentiate a greater than expression".to_string(), span),
+        ExprNode::Le(_, _) => ctx.report_not_differentiable("Cannot differentiate a less than or equal expression".to_string(), span),
+        ExprNode::Ge(_, _) => ctx.report_not_differentiable("Cannot differentiate a greater than or equal expression".to_string(), span),
+        ExprNode::And(_, _) => ctx.report_not_differentiable("Cannot differentiate a logical and expression".to_string(), span),
+        ExprNode::Or(_, _) => ctx.report_not_differentiable("Cannot differentiate a logical or expression".to_string(), span),
+        ExprNode::Not(_) => ctx.report_not_differentiable("Cannot differentiate a logical not expression".to_string(), span),
+        ExprNode::System(_) => ctx.report_not_differentiable("Cannot differentiate a system of equations".to_string(), span),
+        ExprNode::Unary(expr) => {
+            let derivative = differentiate_expr_internal(expr, ctx)?;
+            Ok(ctx.add(ExprNode::Unary(derivative), span))
+        },
+        ExprNode::Let(..) | ExprNode::LetFn(..) => {
+            let expr = ctx.pool.to_expr(id);
+            let mut name_collector = NameCollector::default();
+            name_collector.visit_expr(&expr);
+            let inlined = inline_definitions(&expr, &HashMap::new(), &RefCell::new(name_collector.names));
+            let inlined = ctx.pool.add_expr(&inlined);
+            differentiate_expr_internal(inlined, ctx)
+        },
+        ExprNode::Sum(_, from, to, _) | ExprNode::Prod(_, from, to, _) if ctx.has_x(from) || ctx.has_x(to) => {
+            let kind = if let ExprNode::Sum(..) = ctx.pool.node(id) { ExprKind::sum_str() } else { ExprKind::prod_str() };
+            ctx.report_not_differentiable(format!("Cannot differentiate a {} whose bounds depend on x", kind), span)
+        },
+        ExprNode::Sum(index, from, to, term) => { // sum(k, a, b, f(k, x))' = sum(k, a, b, f'(k, x))
+            let term = differentiate_in_place(term, ctx)?;
+            Ok(ctx.add(ExprNode::Sum(index, from, to, term), span))
+        },
+        ExprNode::Prod(index, from, to, factor) => { // prod(k, a, b, f(k, x))' = prod(k, a, b, f(k, x)) * sum(k, a, b, f'(k, x) / f(k, x))
+            let derivative = differentiate_in_place(factor, ctx)?;
+            let term = ctx.add(ExprNode::Div(derivative, factor), span);
+            let sum = ctx.add(ExprNode::Sum(index, from, to, term), span);
+            Ok(ctx.add(ExprNode::Mul(id, sum), span))
+        },
+        ExprNode::If(condition, then, otherwise) => { // if(c, f(x), g(x))' = if(c, f'(x), g'(x))
+            let then = differentiate_in_place(then, ctx)?;
+            let otherwise = differentiate_in_place(otherwise, ctx)?;
+            Ok(ctx.add(ExprNode::If(condition, then, otherwise), span))
+        },
+        ExprNode::Add(lhs, rhs) => { // (f(x) + g(x))' = f'(x) + g'(x)
+            let lhs = differentiate_expr_internal(lhs, ctx)?;
+            let rhs = differentiate_expr_internal(rhs, ctx)?;
+            Ok(ctx.add(ExprNode::Add(lhs, rhs), span))
+        },
+        ExprNode::Sub(lhs, rhs) => { // (f(x) - g(x))' = f'(x) - g'(x)
+            let lhs = differentiate_expr_internal(lhs, ctx)?;
+            let rhs = differentiate_expr_internal(rhs, ctx)?;
+            Ok(ctx.add(ExprNode::Sub(lhs, rhs), span))
+        },
+        ExprNode::Mul(lhs, rhs) => { // (f(x) * g(x))' = f'(x) * g(x) + f(x) * g'(x)
+            let lhs_derivative = differentiate_expr_internal(lhs, ctx)?;
+            let rhs_derivative = differentiate_expr_internal(rhs, ctx)?;
+            let lhs_term = ctx.add(ExprNode::Mul(lhs_derivative, rhs), span);
+            let rhs_term = ctx.add(ExprNode::Mul(lhs, rhs_derivative), span);
+            Ok(ctx.add(ExprNode::Add(lhs_term, rhs_term), span))
+        },
+        ExprNode::Div(lhs, rhs) => { // (f(x) / g(x))' = (f'(x) * g(x) - f(x) * g'(x)) / g(x)^2
+            let lhs_derivative = differentiate_expr_internal(lhs, ctx)?;
+            let rhs_derivative = differentiate_expr_internal(rhs, ctx)?;
+            let lhs_term = ctx.add(ExprNode::Mul(lhs_derivative, rhs), span);
+            let rhs_term = ctx.add(ExprNode::Mul(lhs, rhs_derivative), span);
+            let numerator = ctx.add(ExprNode::Sub(lhs_term, rhs_term), span);
+            let two = ctx.literal(2.0, span);
+            let denominator = ctx.add(ExprNode::Pow(rhs, two), span);
+            Ok(ctx.add(ExprNode::Div(numerator, denominator), span))
+        },
+        ExprNode::Mod(_, _) => ctx.report_not_differentiable("Cannot differentiate a modulo expression".to_string(), span),
+        ExprNode::Pow(lhs, rhs) => {
+            let lhs_has_x = ctx.has_x(lhs);
+            let rhs_has_x = ctx.has_x(rhs);
+
             if !lhs_has_x && !rhs_has_x { // (a ^ b)' = 0
-                Ok(Box::new(Expr::Literal(0.0)))
+                Ok(ctx.literal(0.0, span))
             } else if lhs_has_x && !rhs_has_x { // (f(x) ^ a)' = a * f(x) ^ (a - 1) * f'(x)
-                Ok(
-                    Box::new(Expr::Mul(
-                        Box::new(Expr::Mul(
-                            rhs.clone(),
-                            Box::new(Expr::Pow(
-                                lhs.clone(),
-                                Box::new(Expr::Sub(
-                                    rhs.clone(),
-                                    Box::new(Expr::Literal(1.0)),
-                                )),
-                            )),
-                        )),
-                        differentiate_expr_internal(lhs, ctx)?
-                    ))
-                )
+                let one = ctx.literal(1.0, span);
+                let exponent = ctx.add(ExprNode::Sub(rhs, one), span);
+                let power = ctx.add(ExprNode::Pow(lhs, exponent), span);
+                let coefficient = ctx.add(ExprNode::Mul(rhs, power), span);
+                let derivative = differentiate_expr_internal(lhs, ctx)?;
+                Ok(ctx.add(ExprNode::Mul(coefficient, derivative), span))
             } else if !lhs_has_x && rhs_has_x { // (a ^ g(x))' = a ^ g(x) * ln(a) * g'(x)
-                Ok(
-                    Box::new(Expr::Mul(
-                        Box::new(Expr::Pow(
-                            lhs.clone(),
-                            rhs.clone(),
-                        )),
-                        Box::new(Expr::Mul(
-                            Box::new(Expr::Call("ln".to_string(), vec![lhs.clone()])),
-                            differentiate_expr_internal(rhs, ctx)?,
-                        )),
-                    ))
-                )
+                let ln = ctx.call("ln", vec![lhs], span);
+                let derivative = differentiate_expr_internal(rhs, ctx)?;
+                let factor = ctx.add(ExprNode::Mul(ln, derivative), span);
+                Ok(ctx.add(ExprNode::Mul(id, factor), span))
             } else { // (f(x) ^ g(x))' = (g'(x) * ln(f(x)) + g(x) * (f'(x) / f(x))) * f(x) ^ g(x)
-                Ok( 
-                    // this mathod is not well defined for negative exponents
-                    Box::new(Expr::Mul(
-                        Box::new(Expr::Add(
-                            Box::new(Expr::Mul(
-                                differentiate_expr_internal(rhs, ctx)?,
-                                Box::new(Expr::Call("ln".to_string(), vec![lhs.clone()])),
-                            )),
-                            Box::new(Expr::Mul(
-                                rhs.clone(),
-                                Box::new(Expr::Div(
-                                    differentiate_expr_internal(lhs, ctx)?,
-                                    lhs.clone(),
-                                )),
-                            ))
-                        )),
-                        Box::new(Expr::Pow(
-                            lhs.clone(),
-                            rhs.clone(),
-                        ))
-                    ))
-                )
+                // this mathod is not well defined for negative exponents
+                let rhs_derivative = differentiate_expr_internal(rhs, ctx)?;
+                let ln = ctx.call("ln", vec![lhs], span);
+                let lhs_term = ctx.add(ExprNode::Mul(rhs_derivative, ln), span);
+                let lhs_derivative = differentiate_expr_internal(lhs, ctx)?;
+                let quotient = ctx.add(ExprNode::Div(lhs_derivative, lhs), span);
+                let rhs_term = ctx.add(ExprNode::Mul(rhs, quotient), span);
+                let sum = ctx.add(ExprNode::Add(lhs_term, rhs_term), span);
+                Ok(ctx.add(ExprNode::Mul(sum, id), span))
             }
         },
-        Expr::Call(function_name, args) => {
+        ExprNode::Call(function_name, args) => {
             match function_name.as_str() {
-                "sin" => Ok( // (sin(f(x)))' = cos(f(x)) * f'(x)
-                    Box::new(Expr::Mul(
-                        Box::new(Expr::Call(
-                            "cos".to_string(),
-                            args.clone(),
-                        )),
-                        differentiate_expr_internal(&args[0], ctx)?,
-                    ))
-                ),
-                "cos" => Ok( // (cos(f(x)))' = -sin(f(x)) * f'(x)
-                    Box::new(Expr::Mul(
-                        Box::new(Expr::Unary(
-                            Box::new(Expr::Call(
-                                "sin".to_string(),
-                                args.clone()
-                            ))
-                        )),
-                        differentiate_expr_internal(&args[0], ctx)?,
-                    ))
-                ),
-                "tan" => Ok( // (tan(f(x)))' = sec^2(f(x)) * f'(x) = 1 / (cos^2(f(x))) * f'(x) = f'(x) / (cos^2(f(x)))
-                    Box::new(Expr::Div(
-                        differentiate_expr_internal(&args[0], ctx)?,
-                        Box::new(Expr::Pow(
-                            Box::new(Expr::Call("cos".to_string(), args.clone())),
-                            Box::new(Expr::Literal(2.0))
-                        ))
-                    ))
-                ),
-                "ln" => Ok( // (ln(f(x)))' = f'(x) / f(x)
-                    Box::new(Expr::Div(
-                        differentiate_expr_internal(&args[0], ctx)?,
-                        args[0].clone(),
-                    ))
-                ),
-                "ln_1p" => Ok( // (ln_1p(f(x)))' = f'(x) / (f(x) + 1)
-                    Box::new(Expr::Div(
-                        differentiate_expr_internal(&args[0], ctx)?,
-                        Box::new(Expr::Add(
-                            args[0].clone(),
-                            Box::new(Expr::Literal(1.0))
-                        ))
-                    ))
-                ),
-                "log2" => Ok( // (log2(f(x)))' = f'(x) / (f(x) * ln(2))
-                    Box::new(Expr::Div(
-                        differentiate_expr_internal(&args[0], ctx)?,
-                        Box::new(Expr::Mul(
-                            args[0].clone(),
-                            Box::new(Expr::Call("ln".to_string(), vec![Box::new(Expr::Literal(2.0))]))
-                        ))
-                    ))
-                ),
-                "log10" => Ok( // (log10(f(x)))' = f'(x) / (f(x) * ln(10))
-                    Box::new(Expr::Div(
-                        differentiate_expr_internal(&args[0], ctx)?,
-                        Box::new(Expr::Mul(
-                            args[0].clone(),
-                            Box::new(Expr::Call("ln".to_string(), vec![Box::new(Expr::Literal(10.0))]))
-                        ))
-                    ))
-                ),
-                "log" => Ok( // log(f(x), g(x)) = ln(f(x)) / ln(g(x))
-                    differentiate_expr_internal(
-                        &Box::new(Expr::Div(
-                            Box::new(Expr::Call("ln".to_string(), vec![args[0].clone()])),
-                            Box::new(Expr::Call("ln".to_string(), vec![args[1].clone()])),
-                        )), ctx
-                    )?
-                ),
-                "sqrt" => Ok( // (sqrt(f(x)))' = f'(x) / (2 * sqrt(f(x)))
-                    Box::new(Expr::Div(
-                        differentiate_expr_internal(&args[0], ctx)?,
-                        Box::new(Expr::Mul(
-                            Box::new(Expr::Literal(2.0)),
-                            Box::new(Expr::Call("sqrt".to_string(), vec![args[0].clone()]))
-                        ))
-                    ))
-                ),
-                "cbrt" => Ok( // (cbrt(f(x)))' = f'(x) / (3 * cbrt(f(x))^2)
-                    Box::new(Expr::Div(
-                        differentiate_expr_internal(&args[0], ctx)?,
-                        Box::new(Expr::Mul(
-                            Box::new(Expr::Literal(3.0)),
-                            Box::new(Expr::Pow(
-                                Box::new(Expr::Call("cbrt".to_string(), vec![args[0].clone()])),
-                                Box::new(Expr::Literal(2.0))
-                            ))
-                        ))
-                    ))
-                ),
-                "exp" => Ok( // (e^f(x))' = e^f(x) * f'(x)
-                    Box::new(Expr::Mul(
-                        Box::new(Expr::Call("exp".to_string(), vec![args[0].clone()])),
-                        differentiate_expr_internal(&args[0], ctx)?,
-                    ))
-                ),
-                "exp_m1" => Ok( // (e^f(x) - 1)' = e^f(x) * f'(x)
-                    Box::new(Expr::Mul(
-                        Box::new(Expr::Call("exp".to_string(), vec![args[0].clone()])),
-                        differentiate_expr_internal(&args[0], ctx)?,
-                    ))
-                ),
+                "sin" => { // (sin(f(x)))' = cos(f(x)) * f'(x)
+                    let cos = ctx.call("cos", args.clone(), span);
+                    let derivative = differentiate_expr_internal(args[0], ctx)?;
+                    Ok(ctx.add(ExprNode::Mul(cos, derivative), span))
+                },
+                "cos" => { // (cos(f(x)))' = -sin(f(x)) * f'(x)
+                    let sin = ctx.call("sin", args.clone(), span);
+                    let negated = ctx.add(ExprNode::Unary(sin), span);
+                    let derivative = differentiate_expr_internal(args[0], ctx)?;
+                    Ok(ctx.add(ExprNode::Mul(negated, derivative), span))
+                },
+                "tan" => { // (tan(f(x)))' = sec^2(f(x)) * f'(x) = 1 / (cos^2(f(x))) * f'(x) = f'(x) / (cos^2(f(x)))
+                    let derivative = differentiate_expr_internal(args[0], ctx)?;
+                    let cos = ctx.call("cos", args.clone(), span);
+                    let two = ctx.literal(2.0, span);
+                    let denominator = ctx.add(ExprNode::Pow(cos, two), span);
+                    Ok(ctx.add(ExprNode::Div(derivative, denominator), span))
+                },
+                "ln" => { // (ln(f(x)))' = f'(x) / f(x)
+                    let derivative = differentiate_expr_internal(args[0], ctx)?;
+                    Ok(ctx.add(ExprNode::Div(derivative, args[0]), span))
+                },
+                "ln_1p" => { // (ln_1p(f(x)))' = f'(x) / (f(x) + 1)
+                    let derivative = differentiate_expr_internal(args[0], ctx)?;
+                    let one = ctx.literal(1.0, span);
+                    let denominator = ctx.add(ExprNode::Add(args[0], one), span);
+                    Ok(ctx.add(ExprNode::Div(derivative, denominator), span))
+                },
+                "log2" | "log10" => { // (log2(f(x)))' = f'(x) / (f(x) * ln(2)), and likewise for log10
+                    let derivative = differentiate_expr_internal(args[0], ctx)?;
+                    let base = ctx.literal(if function_name == "log2" { 2.0 } else { 10.0 }, span);
+                    let ln = ctx.call("ln", vec![base], span);
+                    let denominator = ctx.add(ExprNode::Mul(args[0], ln), span);
+                    Ok(ctx.add(ExprNode::Div(derivative, denominator), span))
+                },
+                "log" => { // log(f(x), g(x)) = ln(f(x)) / ln(g(x))
+                    let numerator = ctx.call("ln", vec![args[0]], span);
+                    let denominator = ctx.call("ln", vec![args[1]], span);
+                    let quotient = ctx.add(ExprNode::Div(numerator, denominator), span);
+                    differentiate_expr_internal(quotient, ctx)
+                },
+                "sqrt" => { // (sqrt(f(x)))' = f'(x) / (2 * sqrt(f(x)))
+                    let derivative = differentiate_expr_internal(args[0], ctx)?;
+                    let two = ctx.literal(2.0, span);
+                    let sqrt = ctx.call("sqrt", vec![args[0]], span);
+                    let denominator = ctx.add(ExprNode::Mul(two, sqrt), span);
+                    Ok(ctx.add(ExprNode::Div(derivative, denominator), span))
+                },
+                "cbrt" => { // (cbrt(f(x)))' = f'(x) / (3 * cbrt(f(x))^2)
+                    let derivative = differentiate_expr_internal(args[0], ctx)?;
+                    let three = ctx.literal(3.0, span);
+                    let cbrt = ctx.call("cbrt", vec![args[0]], span);
+                    let two = ctx.literal(2.0, span);
+                    let square = ctx.add(ExprNode::Pow(cbrt, two), span);
+                    let denominator = ctx.add(ExprNode::Mul(three, square), span);
+                    Ok(ctx.add(ExprNode::Div(derivative, denominator), span))
+                },
+                "exp" | "exp_m1" => { // (e^f(x))' = (e^f(x) - 1)' = e^f(x) * f'(x)
+                    let exp = ctx.call("exp", vec![args[0]], span);
+                    let derivative = differentiate_expr_internal(args[0], ctx)?;
+                    Ok(ctx.add(ExprNode::Mul(exp, derivative), span))
+                },
                 "abs" => { // abs(f(x))' = f'(x) toplevel[ * f(x) / abs(f(x))]
-                    ctx.top_level_appand_expr_list.push(
-                        Expr::Div(
-                            args[0].clone(),
-                            Box::new(Expr::Call("abs".to_string(), vec![args[0].clone()]))
-                        )
-                    );
-                    differentiate_expr_internal(&args[0], ctx)
+                    let abs = ctx.call("abs", vec![args[0]], span);
+                    let sign = ctx.add(ExprNode::Div(args[0], abs), span);
+                    if ctx.in_place { // the sign depends on the index or the branch, so it stays here
+                        let derivative = differentiate_expr_internal(args[0], ctx)?;
+                        return Ok(ctx.add(ExprNode::Mul(derivative, sign), span));
+                    }
+                    ctx.top_level_appand_expr_list.push(sign);
+                    differentiate_expr_internal(args[0], ctx)
                 },
-                _ => Err(format!("Cannot differentiate function {}", function_name))
+                _ => ctx.report_not_differentiable(format!("Cannot differentiate function {}", function_name), span)
             }
         },
-        Expr::Id(_) => Ok(Box::new(Expr::Literal(1.0))),
-        Expr::Literal(_) => Ok(Box::new(Expr::Literal(0.0))),
+        ExprNode::Id(name) => { // x' = 1, and every other name is constant with respect to x
+            Ok(ctx.literal(if name == "x" { 1.0 } else { 0.0 }, span))
+        },
+        ExprNode::Literal(_) => Ok(ctx.literal(0.0, span)),
+        ExprNode::Error => Ok(ctx.add(ExprNode::Error, span)),
     }
 }
 
-fn check_ast_has_x(ast: &Expr) -> bool {
-    match ast {
-        Expr::Eq(lhs, rhs)
-        | Expr::Lt(lhs, rhs)
-        | Expr::Gt(lhs, rhs)
-        | Expr::Le(lhs, rhs)
-        | Expr::Ge(lhs, rhs)
-        | Expr::Add(lhs, rhs)
-        | Expr::Sub(lhs, rhs)
-        | Expr::Mul(lhs, rhs)
-        | Expr::Div(lhs, rhs)
-        | Expr::Mod(lhs, rhs)
-        | Expr::Pow(lhs, rhs) => {
-            check_ast_has_x(lhs) || check_ast_has_x(rhs)
-        },
-        Expr::Call(_, args) => {
-            args.iter().any(|arg| check_ast_has_x(arg))
-        },
-        Expr::Unary(expr) => check_ast_has_x(expr),
-        Expr::Literal(_) => false,
-        Expr::Id(name) => name == "x",
+/// Differentiates an aggregate term or a branch, keeping every factor in its derivative, see `TransformContext::in_place`.
+fn differentiate_in_place(id: ExprId, ctx: &mut TransformContext) -> Result<ExprId, ErrorReported> {
+    let in_place = std::mem::replace(&mut ctx.in_place, true);
+    let derivative = differentiate_expr_internal(id, ctx);
+    ctx.in_place = in_place;
+    derivative
+}
+
+/// Definition in scope while inlining.
+#[derive(Clone)]
+enum Definition<'a> {
+    /// An inlined value, which contains no definitions.
+    Variable(Box<Expr>),
+    /// Parameters and body of a function, with the definitions in scope at its definition.
+    Function(&'a [String], &'a Expr, HashMap<String, Definition<'a>>),
+}
+
+/// Every name an expression refers to or defines.
+#[derive(Default)]
+struct NameCollector {
+    names: HashSet<String>,
+}
+
+impl Visitor for NameCollector {
+    fn visit_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Id(name)
+            | ExprKind::Call(name, _)
+            | ExprKind::Let(name, ..)
+            | ExprKind::Sum(name, ..)
+            | ExprKind::Prod(name, ..) => {
+                self.names.insert(name.to_owned());
+            },
+            ExprKind::LetFn(name, parameters, ..) => {
+                self.names.insert(name.to_owned());
+                self.names.extend(parameters.iter().cloned());
+            },
+            _ => { },
+        }
+        walk_expr(self, expr);
     }
 }
 
+/// Returns a name like `k_1` for `k` which is not in `names`, and adds it.
+fn fresh_name(name: &str, names: &RefCell<HashSet<String>>) -> String {
+    let mut names = names.borrow_mut();
+    let fresh = (1..).map(|n| format!("{}_{}", name, n)).find(|fresh| !names.contains(fresh)).unwrap();
+    names.insert(fresh.clone());
+    fresh
+}
+
+/// Replaces every definition by its value, and every call of a defined function by its body.
+///
+/// Each aggregate index is renamed to a name not in `names`, so that an inlined value
+/// referring to an outer index of the same name is not bound by the aggregate.
+fn inline_definitions<'a>(
+    ast: &'a Expr,
+    definitions: &HashMap<String, Definition<'a>>,
+    names: &RefCell<HashSet<String>>,
+) -> Box<Expr> {
+    let span = ast.span;
+    let inline = |expr: &'a Expr| inline_definitions(expr, definitions, names);
+
+    let kind = match &ast.kind {
+        ExprKind::Id(name) => {
+            if let Some(Definition::Variable(value)) = definitions.get(name) {
+                return value.clone();
+            }
+            ExprKind::Id(name.to_owned())
+        },
+        ExprKind::Call(name, args) => {
+            let args = args.iter().map(|arg| inline(arg)).collect::<Vec<_>>();
+
+            if let Some(Definition::Function(parameters, body, function_definitions)) = definitions.get(name) {
+                let mut function_definitions = function_definitions.clone();
+                for (parameter, arg) in parameters.iter().zip(args) {
+                    function_definitions.insert(parameter.to_owned(), Definition::Variable(arg));
+                }
+                return inline_definitions(body, &function_definitions, names);
+            }
+            ExprKind::Call(name.to_owned(), args)
+        },
+        ExprKind::Let(name, value, body) => {
+            let mut body_definitions = definitions.clone();
+            body_definitions.insert(name.to_owned(), Definition::Variable(inline(value)));
+            return inline_definitions(body, &body_definitions, names);
+        },
+        ExprKind::LetFn(name, parameters, value, body) => {
+            let function = Definition::Function(parameters, value, definitions.clone());
+            let mut body_definitions = definitions.clone();
+            body_definitions.insert(name.to_owned(), function);
+            return inline_definitions(body, &body_definitions, names);
+        },
+        ExprKind::Eq(lhs, rhs) => ExprKind::Eq(inline(lhs), inline(rhs)),
+        ExprKind::Lt(lhs, rhs) => ExprKind::Lt(inline(lhs), inline(rhs)),
+        ExprKind::Gt(lhs, rhs) => ExprKind::Gt(inline(lhs), inline(rhs)),
+        ExprKind::Le(lhs, rhs) => ExprKind::Le(inline(lhs), inline(rhs)),
+        ExprKind::Ge(lhs, rhs) => ExprKind::Ge(inline(lhs), inline(rhs)),
+        ExprKind::And(lhs, rhs) => ExprKind::And(inline(lhs), inline(rhs)),
+        ExprKind::Or(lhs, rhs) => ExprKind::Or(inline(lhs), inline(rhs)),
+        ExprKind::Not(expr) => ExprKind::Not(inline(expr)),
+        ExprKind::If(condition, then, otherwise) => ExprKind::If(inline(condition), inline(then), inline(otherwise)),
+        ExprKind::System(equations) => ExprKind::System(equations.iter().map(|equation| inline(equation)).collect()),
+        ExprKind::Sum(index, from, to, term) | ExprKind::Prod(index, from, to, term) => {
+            let fresh_index = fresh_name(index, names);
+            let mut term_definitions = definitions.clone();
+            term_definitions.insert(
+                index.to_owned(),
+                Definition::Variable(Expr::boxed(ExprKind::Id(fresh_index.clone()), span)),
+            );
+            let term = inline_definitions(term, &term_definitions, names);
+
+            if let ExprKind::Sum(..) = &ast.kind {
+                ExprKind::Sum(fresh_index, inline(from), inline(to), term)
+            } else {
+                ExprKind::Prod(fresh_index, inline(from), inline(to), term)
+            }
+        },
+        ExprKind::Unary(expr) => ExprKind::Unary(inline(expr)),
+        ExprKind::Add(lhs, rhs) => ExprKind::Add(inline(lhs), inline(rhs)),
+        ExprKind::Sub(lhs, rhs) => ExprKind::Sub(inline(lhs), inline(rhs)),
+        ExprKind::Mul(lhs, rhs) => ExprKind::Mul(inline(lhs), inline(rhs)),
+        ExprKind::Div(lhs, rhs) => ExprKind::Div(inline(lhs), inline(rhs)),
+        ExprKind::Mod(lhs, rhs) => ExprKind::Mod(inline(lhs), inline(rhs)),
+        ExprKind::Pow(lhs, rhs) => ExprKind::Pow(inline(lhs), inline(rhs)),
+        ExprKind::Literal(literal) => ExprKind::Literal(*literal),
+        ExprKind::Error => ExprKind::Error,
+    };
+
+    Expr::boxed(kind, span)
+}
+
 // fn composite_x_expr(f_x: &Expr, g_x: &Expr) -> Box<Expr> {
 //     match f_x {
 //         Expr::Eq(lhs, rhs) => Box::new(Expr::Eq(
@@ -770,3 +974,156 @@ fn check_ast_has_x(ast: &Expr) -> bool {
 //         Expr::Literal(_) => Box::new(f_x.clone()),
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(crate) fn parse(input: &str) -> Box<Expr> {
+        let mut diagnostics = DiagnosticSink::new();
+        let ctx = parser::ParserContext::new(
+            Box::new(lexer::token_iter(input)),
+            parser::create_binary_op_precedence(),
+            &mut diagnostics,
+        );
+        parser::parse_top_level_expression(ctx).unwrap_or_else(|_| panic!("failed to parse {}", input))
+    }
+
+    fn to_js(input: &str) -> String {
+        transplie_to_js(&parse(input), &HashMap::new(), 0.0)
+    }
+
+    #[test]
+    fn aggregate_counts_terms_with_an_integer() {
+        assert_eq!(
+            to_js("sum(k, 1, 3, k)"),
+            "(($$from, $$to) => { \
+            if (!(Math.abs($$from) <= 9007199254740992 && Math.abs($$to) <= 9007199254740992 && $$to - $$from < 1000000)) return NaN; \
+            const $$n = $$to < $$from ? 0 : Math.floor($$to - $$from) + 1; \
+            let $$result = 0; \
+            for (let $$i = 0; $$i < $$n; $$i++) { const $k = $$from + $$i; $$result += $k; } \
+            return $$result; })(1, 3)"
+        );
+    }
+
+    #[test]
+    fn aggregate_index_cannot_clash_with_js_names() {
+        let js = to_js("sum(Math, 1, 3, sin(Math))");
+        assert!(js.contains("const $Math = $$from + $$i; $$result += Math.sin($Math); "), "{}", js);
+        let js = to_js("prod(i, 1, 3, let t = i in t)");
+        assert!(js.contains("const $i = $$from + $$i; $$result *= (($t) => $t)($i); "), "{}", js);
+    }
+
+    #[test]
+    fn inlining_does_not_capture_outer_indices() {
+        assert_eq!(derivative_at("let f(t) = sum(k, 1, 3, t * k * x) in sum(k, 1, 2, f(k))", 2.0), 18.0);
+        assert_eq!(derivative_at("sum(k, 1, 2, let r = k in sum(k, 1, 3, r * x))", 2.0), 9.0);
+        assert_eq!(
+            derivative("sum(k, 1, 2, let r = k in sum(k, 1, 3, r * x))"),
+            "sum(k, 1, 2, sum(k_1, 1, 3, ((0 * x) + (k * 1))))"
+        );
+    }
+
+    fn derivative(input: &str) -> String {
+        let mut diagnostics = DiagnosticSink::new();
+        ast_to_string(&differentiate_expr(&parse(input), &mut diagnostics).unwrap())
+    }
+
+    #[test]
+    fn abs_in_aggregate_keeps_its_sign_in_the_term() {
+        assert_eq!(
+            derivative("sum(k, 1, 3, abs(k * x))"),
+            "sum(k, 1, 3, (((0 * x) + (k * 1)) * ((k * x) / abs((k * x)))))"
+        );
+        assert_eq!(
+            derivative("abs(x) + sum(k, 1, 3, abs(x))"),
+            "((1 + sum(k, 1, 3, (1 * (x / abs(x))))) * (x / abs(x)))"
+        );
+    }
+
+    /// Value at `x` of the derivative of the input.
+    fn derivative_at(input: &str, x: f64) -> f64 {
+        let mut diagnostics = DiagnosticSink::new();
+        let derivative = differentiate_expr(&parse(input), &mut diagnostics).unwrap();
+        let variables = HashMap::from([("x".to_string(), x)]);
+        evaluator::eval_number(&derivative, &evaluator::Env::new(&variables)).unwrap()
+    }
+
+    #[test]
+    fn abs_in_a_branch_keeps_its_sign_in_the_branch() {
+        assert_eq!(derivative("if(x < 0, abs(x - 5), x)"), "if((x < 0), ((1 - 0) * ((x - 5) / abs((x - 5)))), 1)");
+        assert_eq!(derivative_at("if(x < 0, abs(x - 5), x)", 2.0), 1.0);
+        assert_eq!(derivative_at("if(x < 0, abs(x - 5), x)", -2.0), -1.0);
+    }
+
+    #[test]
+    fn connectives_emit_js_logical_operators() {
+        assert_eq!(
+            transplie_to_js(&parse("x < 1 and not y = 2 or x >= y"), &HashMap::new(), 0.5),
+            "(((x < 1) && (!(Math.abs(y - 2) < 0.5))) || (x >= y))"
+        );
+    }
+
+    #[test]
+    fn conditional_emits_a_ternary() {
+        assert_eq!(to_js("if(x < 0, -x, x)"), "((x < 0) ? (-x) : x)");
+    }
+
+    #[test]
+    fn definitions_emit_arrow_functions() {
+        assert_eq!(to_js("let f(t) = t^2 in f(x) + 1"), "(($f) => ($f(x) + 1))(($t) => ($t ** 2))");
+        assert_eq!(to_js("let r = x * x in r"), "(($r) => $r)((x * x))");
+    }
+
+    #[test]
+    fn definitions_cannot_clash_with_js_names() {
+        assert_eq!(to_js("let new = 1 in new + x"), "(($new) => ($new + x))(1)");
+        assert_eq!(to_js("let Math(t) = t in sin(Math(x))"), "(($Math) => Math.sin($Math(x)))(($t) => $t)");
+        assert_eq!(to_js("let sin(t) = t in sin(x) + cos(x)"), "(($sin) => ($sin(x) + Math.cos(x)))(($t) => $t)");
+        assert_eq!(to_js("let t = 1 in t + (let t = 2 in t)"), "(($t) => ($t + (($t) => $t)(2)))(1)");
+    }
+
+    #[test]
+    fn system_emits_an_array_of_its_equations() {
+        let ast = parse("r = 2; x < r, y > r");
+        assert_eq!(transplie_system_to_js(&ast, &HashMap::new(), 0.0), "(($r) => [(x < $r), (y > $r)])(2)");
+        assert_eq!(transplie_to_js(&ast, &HashMap::new(), 0.0), "(($r) => ((x < $r) && (y > $r)))(2)");
+        assert_eq!(transplie_system_to_js(&parse("x < 1"), &HashMap::new(), 0.0), "[(x < 1)]");
+    }
+
+    #[test]
+    fn derivatives_follow_the_rules() {
+        assert_eq!(derivative("x^2 + 3 * x"), "(((2 * (x ^ (2 - 1))) * 1) + ((0 * x) + (3 * 1)))");
+        assert_eq!(derivative("sin(x) * exp(x)"), "(((cos(x) * 1) * exp(x)) + (sin(x) * (exp(x) * 1)))");
+        assert_eq!(derivative("ln(x) / x"), "((((1 / x) * x) - (ln(x) * 1)) / (x ^ 2))");
+        assert_eq!(derivative("if(x < 0, -x, x)"), "if((x < 0), (-1), 1)");
+        assert_eq!(derivative("t = x^2; t + 1"), "(((2 * (x ^ (2 - 1))) * 1) + 0)");
+    }
+
+    #[test]
+    fn not_differentiable_expressions_report_an_error() {
+        for input in ["x < 1", "x % 2", "sum(k, 1, x, k)"] {
+            let mut diagnostics = DiagnosticSink::new();
+            assert_eq!(differentiate_expr(&parse(input), &mut diagnostics), Err(ErrorReported), "{}", input);
+            assert_eq!(
+                diagnostics.diagnostics().iter().map(|diagnostic| diagnostic.code()).collect::<Vec<_>>(),
+                [Some(DiagnosticCode::NotDifferentiable)],
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn repeated_derivatives_share_nodes_in_the_pool() {
+        let mut pool = ExprPool::new();
+        let mut diagnostics = DiagnosticSink::new();
+        let mut id = pool.add_expr(&parse("sin(x) * exp(x)"));
+        for _ in 0..12 {
+            id = differentiate_pooled_expr(&mut pool, id, &mut diagnostics).unwrap();
+        }
+
+        assert!(pool.len() < 2000, "{} nodes", pool.len());
+        assert!(diagnostics.diagnostics().is_empty());
+    }
+}
@@ -0,0 +1,346 @@
+use ast::{Expr, ExprKind};
+
+use crate::typeset::*;
+
+/// Typesets the AST as a presentation MathML `<math>` element.
+///
+/// The layout is the same as `ast_to_latex`: fractions, superscripts, and parentheses only
+/// where precedence requires them.
+pub fn ast_to_mathml(expr: &Expr) -> String {
+    let mut result = String::from("<math xmlns=\"http://www.w3.org/1998/Math/MathML\">");
+    ast_to_mathml_internal(expr, &mut result);
+    result.push_str("</math>");
+    result
+}
+
+fn push_element(tag: &str, content: &str, result: &mut String) {
+    result.push('<');
+    result.push_str(tag);
+    result.push('>');
+    result.push_str(content);
+    result.push_str("</");
+    result.push_str(tag);
+    result.push('>');
+}
+
+fn push_operator(operator: &str, result: &mut String) {
+    push_element("mo", operator, result);
+}
+
+/// Writes `expr`, parenthesized if it binds less tightly than `min_precedence`.
+fn operand_to_mathml(expr: &Expr, min_precedence: i32, result: &mut String) {
+    if typeset_precedence(expr) < min_precedence {
+        result.push_str("<mrow>");
+        push_operator("(", result);
+        ast_to_mathml_internal(expr, result);
+        push_operator(")", result);
+        result.push_str("</mrow>");
+    } else {
+        ast_to_mathml_internal(expr, result);
+    }
+}
+
+/// Writes a left-associative binary operation of the given precedence.
+fn binary_to_mathml(lhs: &Expr, operator: &str, rhs: &Expr, precedence: i32, result: &mut String) {
+    result.push_str("<mrow>");
+    operand_to_mathml(lhs, precedence, result);
+    push_operator(operator, result);
+    operand_to_mathml(rhs, precedence + 1, result);
+    result.push_str("</mrow>");
+}
+
+/// Writes `expr` between two delimiters, which stretch to its height.
+fn delimited_to_mathml(open: &str, expr: &Expr, close: &str, result: &mut String) {
+    result.push_str("<mrow>");
+    push_operator(open, result);
+    ast_to_mathml_internal(expr, result);
+    push_operator(close, result);
+    result.push_str("</mrow>");
+}
+
+fn identifier_to_mathml(name: &str, result: &mut String) {
+    let (base, subscript) = split_identifier(name);
+
+    if subscript.is_some() {
+        result.push_str("<msub>");
+    }
+
+    match greek_letter(base) {
+        Some(letter) => push_element("mi", &letter.to_string(), result),
+        None => push_element("mi", base, result),
+    }
+
+    if let Some(subscript) = subscript {
+        let tag = if subscript.chars().all(|char| char.is_ascii_digit()) { "mn" } else { "mi" };
+        push_element(tag, subscript, result);
+        result.push_str("</msub>");
+    }
+}
+
+fn arguments_to_mathml(args: &[Box<Expr>], result: &mut String) {
+    result.push_str("<mrow>");
+    push_operator("(", result);
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            push_operator(",", result);
+        }
+        ast_to_mathml_internal(arg, result);
+    }
+    push_operator(")", result);
+    result.push_str("</mrow>");
+}
+
+fn call_to_mathml(name: &str, args: &[Box<Expr>], result: &mut String) {
+    match (name, args) {
+        ("sqrt", [arg]) => {
+            result.push_str("<msqrt>");
+            ast_to_mathml_internal(arg, result);
+            result.push_str("</msqrt>");
+        },
+        ("cbrt", [arg]) => {
+            result.push_str("<mroot>");
+            ast_to_mathml_internal(arg, result);
+            push_element("mn", "3", result);
+            result.push_str("</mroot>");
+        },
+        ("abs", [arg]) => delimited_to_mathml("|", arg, "|", result),
+        ("floor", [arg]) => delimited_to_mathml("⌊", arg, "⌋", result),
+        ("ceil", [arg]) => delimited_to_mathml("⌈", arg, "⌉", result),
+        ("factorial", [arg]) => {
+            result.push_str("<mrow>");
+            operand_to_mathml(arg, FACTORIAL_PRECEDENCE, result);
+            push_operator("!", result);
+            result.push_str("</mrow>");
+        },
+        ("pow", [base, exponent]) => {
+            result.push_str("<msup>");
+            operand_to_mathml(base, POWER_PRECEDENCE + 1, result);
+            ast_to_mathml_internal(exponent, result);
+            result.push_str("</msup>");
+        },
+        ("log", [arg, base]) => {
+            result.push_str("<mrow><msub>");
+            push_element("mi", "log", result);
+            ast_to_mathml_internal(base, result);
+            result.push_str("</msub>");
+            push_operator("&#x2061;", result);
+            arguments_to_mathml(std::slice::from_ref(arg), result);
+            result.push_str("</mrow>");
+        },
+        _ => {
+            result.push_str("<mrow>");
+            match named_function(name) {
+                Some((operator, Some(subscript))) => {
+                    result.push_str("<msub>");
+                    push_element("mi", operator, result);
+                    push_element("mn", subscript, result);
+                    result.push_str("</msub>");
+                },
+                Some((operator, None)) => push_element("mi", operator, result),
+                None => push_element("mi", name, result),
+            }
+            push_operator("&#x2061;", result);
+            arguments_to_mathml(args, result);
+            result.push_str("</mrow>");
+        },
+    }
+}
+
+fn ast_to_mathml_internal(
+    ast: &Expr,
+    result: &mut String,
+) {
+    match &ast.kind {
+        ExprKind::Id(id) => identifier_to_mathml(id, result),
+        ExprKind::Call(id, args) => call_to_mathml(id, args, result),
+        ExprKind::Eq(lhs, rhs) => binary_to_mathml(lhs, "=", rhs, COMPARISON_PRECEDENCE + 1, result),
+        ExprKind::Lt(lhs, rhs) => binary_to_mathml(lhs, "&lt;", rhs, COMPARISON_PRECEDENCE + 1, result),
+        ExprKind::Gt(lhs, rhs) => binary_to_mathml(lhs, "&gt;", rhs, COMPARISON_PRECEDENCE + 1, result),
+        ExprKind::Le(lhs, rhs) => binary_to_mathml(lhs, "≤", rhs, COMPARISON_PRECEDENCE + 1, result),
+        ExprKind::Ge(lhs, rhs) => binary_to_mathml(lhs, "≥", rhs, COMPARISON_PRECEDENCE + 1, result),
+        ExprKind::And(lhs, rhs) => binary_to_mathml(lhs, "∧", rhs, AND_PRECEDENCE, result),
+        ExprKind::Or(lhs, rhs) => binary_to_mathml(lhs, "∨", rhs, OR_PRECEDENCE, result),
+        ExprKind::Not(expr) => match not_equal_operands(ast) {
+            Some((lhs, rhs)) => binary_to_mathml(lhs, "≠", rhs, COMPARISON_PRECEDENCE + 1, result),
+            None => {
+                result.push_str("<mrow>");
+                push_operator("¬", result);
+                operand_to_mathml(expr, COMPARISON_PRECEDENCE + 1, result);
+                result.push_str("</mrow>");
+            },
+        },
+        ExprKind::If(condition, then, otherwise) => {
+            result.push_str("<mrow>");
+            push_operator("{", result);
+            result.push_str("<mtable columnalign=\"left\"><mtr><mtd>");
+            ast_to_mathml_internal(then, result);
+            result.push_str("</mtd><mtd><mrow>");
+            push_element("mtext", "if&#xA0;", result);
+            ast_to_mathml_internal(condition, result);
+            result.push_str("</mrow></mtd></mtr><mtr><mtd>");
+            ast_to_mathml_internal(otherwise, result);
+            result.push_str("</mtd><mtd>");
+            push_element("mtext", "otherwise", result);
+            result.push_str("</mtd></mtr></mtable></mrow>");
+        },
+        ExprKind::Let(name, value, body) => {
+            result.push_str("<mrow>");
+            push_element("mtext", "let&#xA0;", result);
+            identifier_to_mathml(name, result);
+            push_operator("=", result);
+            ast_to_mathml_internal(value, result);
+            push_element("mtext", "&#xA0;in&#xA0;", result);
+            ast_to_mathml_internal(body, result);
+            result.push_str("</mrow>");
+        },
+        ExprKind::LetFn(name, parameters, value, body) => {
+            result.push_str("<mrow>");
+            push_element("mtext", "let&#xA0;", result);
+            push_element("mi", name, result);
+            push_operator("&#x2061;", result);
+            result.push_str("<mrow>");
+            push_operator("(", result);
+            for (i, parameter) in parameters.iter().enumerate() {
+                if i > 0 {
+                    push_operator(",", result);
+                }
+                identifier_to_mathml(parameter, result);
+            }
+            push_operator(")", result);
+            result.push_str("</mrow>");
+            push_operator("=", result);
+            ast_to_mathml_internal(value, result);
+            push_element("mtext", "&#xA0;in&#xA0;", result);
+            ast_to_mathml_internal(body, result);
+            result.push_str("</mrow>");
+        },
+        ExprKind::Sum(index, from, to, term) | ExprKind::Prod(index, from, to, term) => {
+            result.push_str("<mrow><munderover>");
+            push_operator(if matches!(ast.kind, ExprKind::Sum(..)) { "∑" } else { "∏" }, result);
+            result.push_str("<mrow>");
+            identifier_to_mathml(index, result);
+            push_operator("=", result);
+            ast_to_mathml_internal(from, result);
+            result.push_str("</mrow>");
+            ast_to_mathml_internal(to, result);
+            result.push_str("</munderover>");
+            operand_to_mathml(term, MULTIPLICATIVE_PRECEDENCE, result);
+            result.push_str("</mrow>");
+        },
+        ExprKind::System(equations) => {
+            result.push_str("<mrow>");
+            for (i, equation) in equations.iter().enumerate() {
+                if i > 0 {
+                    push_operator(",", result);
+                }
+                ast_to_mathml_internal(equation, result);
+            }
+            result.push_str("</mrow>");
+        },
+        ExprKind::Add(lhs, rhs) => binary_to_mathml(lhs, "+", rhs, ADDITIVE_PRECEDENCE, result),
+        ExprKind::Sub(lhs, rhs) => binary_to_mathml(lhs, "−", rhs, ADDITIVE_PRECEDENCE, result),
+        ExprKind::Mul(lhs, rhs) => {
+            // U+2062 INVISIBLE TIMES keeps the multiplication for screen readers.
+            let operator = if is_implicit_multiplication(lhs, rhs) { "&#x2062;" } else { "⋅" };
+            result.push_str("<mrow>");
+            operand_to_mathml(lhs, left_factor_precedence(lhs), result);
+            push_operator(operator, result);
+            operand_to_mathml(rhs, MULTIPLICATIVE_PRECEDENCE + 1, result);
+            result.push_str("</mrow>");
+        },
+        ExprKind::Mod(lhs, rhs) => binary_to_mathml(lhs, "mod", rhs, MULTIPLICATIVE_PRECEDENCE, result),
+        ExprKind::Div(lhs, rhs) => {
+            result.push_str("<mfrac>");
+            ast_to_mathml_internal(lhs, result);
+            ast_to_mathml_internal(rhs, result);
+            result.push_str("</mfrac>");
+        },
+        ExprKind::Pow(lhs, rhs) => {
+            result.push_str("<msup>");
+            operand_to_mathml(lhs, POWER_PRECEDENCE + 1, result);
+            ast_to_mathml_internal(rhs, result);
+            result.push_str("</msup>");
+        },
+        ExprKind::Unary(expr) => {
+            result.push_str("<mrow>");
+            push_operator("−", result);
+            operand_to_mathml(expr, ADDITIVE_PRECEDENCE + 1, result);
+            result.push_str("</mrow>");
+        },
+        ExprKind::Literal(literal) => {
+            if literal.is_nan() {
+                push_element("mi", "NaN", result);
+            } else if literal.is_sign_negative() {
+                result.push_str("<mrow>");
+                push_operator("−", result);
+                match -literal {
+                    magnitude if magnitude.is_infinite() => push_element("mi", "∞", result),
+                    magnitude => push_element("mn", &magnitude.to_string(), result),
+                }
+                result.push_str("</mrow>");
+            } else if literal.is_infinite() {
+                push_element("mi", "∞", result);
+            } else {
+                push_element("mn", &literal.to_string(), result);
+            }
+        },
+        ExprKind::Error => {
+            result.push_str("<merror>");
+            push_element("mtext", "error", result);
+            result.push_str("</merror>");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::parse;
+
+    /// MathML of the input, without the enclosing `math` element.
+    fn mathml(input: &str) -> String {
+        let mathml = ast_to_mathml(&parse(input));
+        mathml
+            .strip_prefix(r#"<math xmlns="http://www.w3.org/1998/Math/MathML">"#)
+            .and_then(|mathml| mathml.strip_suffix("</math>"))
+            .unwrap_or_else(|| panic!("not a math element: {}", mathml))
+            .to_string()
+    }
+
+    #[test]
+    fn structures_are_typeset() {
+        assert_eq!(
+            mathml("(x^2 + 1) / (2 * y)"),
+            "<mfrac><mrow><msup><mi>x</mi><mn>2</mn></msup><mo>+</mo><mn>1</mn></mrow>\
+            <mrow><mn>2</mn><mo>&#x2062;</mo><mi>y</mi></mrow></mfrac>"
+        );
+        assert_eq!(
+            mathml("sqrt(x + 1) + |x|"),
+            "<mrow><msqrt><mrow><mi>x</mi><mo>+</mo><mn>1</mn></mrow></msqrt><mo>+</mo>\
+            <mrow><mo>|</mo><mi>x</mi><mo>|</mo></mrow></mrow>"
+        );
+        assert_eq!(
+            mathml("sum(k, 1, n, k)"),
+            "<mrow><munderover><mo>∑</mo><mrow><mi>k</mi><mo>=</mo><mn>1</mn></mrow><mi>n</mi></munderover><mi>k</mi></mrow>"
+        );
+    }
+
+    #[test]
+    fn only_required_parentheses_are_kept() {
+        assert_eq!(
+            mathml("a - (b - c)"),
+            "<mrow><mi>a</mi><mo>−</mo><mrow><mo>(</mo><mrow><mi>b</mi><mo>−</mo><mi>c</mi></mrow><mo>)</mo></mrow></mrow>"
+        );
+        assert_eq!(mathml("(a - b) - c"), "<mrow><mrow><mi>a</mi><mo>−</mo><mi>b</mi></mrow><mo>−</mo><mi>c</mi></mrow>");
+    }
+
+    #[test]
+    fn text_is_escaped() {
+        assert_eq!(mathml("x < 1"), "<mrow><mi>x</mi><mo>&lt;</mo><mn>1</mn></mrow>");
+        assert_eq!(
+            mathml("log10(x) + theta_1"),
+            "<mrow><mrow><msub><mi>log</mi><mn>10</mn></msub><mo>&#x2061;</mo><mrow><mo>(</mo><mi>x</mi><mo>)</mo></mrow></mrow>\
+            <mo>+</mo><msub><mi>θ</mi><mn>1</mn></msub></mrow>"
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use ast::{Expr, ExprKind};
+
+/// Names of Greek letters, which are typeset as the letter itself.
+pub(crate) const GREEK_LETTERS: &[(&str, char)] = &[
+    ("alpha", 'α'), ("beta", 'β'), ("gamma", 'γ'), ("delta", 'δ'), ("epsilon", 'ϵ'),
+    ("varepsilon", 'ε'), ("zeta", 'ζ'), ("eta", 'η'), ("theta", 'θ'), ("vartheta", 'ϑ'),
+    ("iota", 'ι'), ("kappa", 'κ'), ("lambda", 'λ'), ("mu", 'μ'), ("nu", 'ν'), ("xi", 'ξ'),
+    ("pi", 'π'), ("rho", 'ρ'), ("varrho", 'ϱ'), ("sigma", 'σ'), ("tau", 'τ'),
+    ("upsilon", 'υ'), ("phi", 'ϕ'), ("varphi", 'φ'), ("chi", 'χ'), ("psi", 'ψ'),
+    ("omega", 'ω'), ("Gamma", 'Γ'), ("Delta", 'Δ'), ("Theta", 'Θ'), ("Lambda", 'Λ'),
+    ("Xi", 'Ξ'), ("Pi", 'Π'), ("Sigma", 'Σ'), ("Upsilon", 'Υ'), ("Phi", 'Φ'), ("Psi", 'Ψ'),
+    ("Omega", 'Ω'),
+];
+
+/// Built-in functions typeset as a named operator: function, operator name, subscript.
+pub(crate) const NAMED_FUNCTIONS: &[(&str, &str, Option<&str>)] = &[
+    ("sin", "sin", None), ("cos", "cos", None), ("tan", "tan", None),
+    ("asin", "arcsin", None), ("acos", "arccos", None), ("atan", "arctan", None),
+    ("sinh", "sinh", None), ("cosh", "cosh", None), ("tanh", "tanh", None),
+    ("exp", "exp", None), ("ln", "ln", None), ("log10", "log", Some("10")),
+    ("log2", "log", Some("2")), ("max", "max", None), ("min", "min", None),
+];
+
+pub(crate) const SYSTEM_PRECEDENCE: i32 = 0;
+pub(crate) const LET_PRECEDENCE: i32 = 1;
+pub(crate) const OR_PRECEDENCE: i32 = 4;
+pub(crate) const AND_PRECEDENCE: i32 = 6;
+pub(crate) const NOT_PRECEDENCE: i32 = 8;
+pub(crate) const COMPARISON_PRECEDENCE: i32 = 10;
+pub(crate) const ADDITIVE_PRECEDENCE: i32 = 20;
+pub(crate) const AGGREGATE_PRECEDENCE: i32 = 35;
+pub(crate) const MULTIPLICATIVE_PRECEDENCE: i32 = 40;
+pub(crate) const FRACTION_PRECEDENCE: i32 = 45;
+pub(crate) const POWER_PRECEDENCE: i32 = 50;
+pub(crate) const FACTORIAL_PRECEDENCE: i32 = 60;
+pub(crate) const ATOM_PRECEDENCE: i32 = 100;
+
+/// How tightly the typeset form of `expr` binds; an operand is parenthesized if it binds
+/// less tightly than its position requires.
+///
+/// This follows the parser's precedence, except that fractions and superscripts group their
+/// operands by layout and so never need parentheses around them.
+pub(crate) fn typeset_precedence(expr: &Expr) -> i32 {
+    match &expr.kind {
+        ExprKind::System(..) => SYSTEM_PRECEDENCE,
+        ExprKind::Let(..) | ExprKind::LetFn(..) => LET_PRECEDENCE,
+        ExprKind::Or(..) => OR_PRECEDENCE,
+        ExprKind::And(..) => AND_PRECEDENCE,
+        ExprKind::Not(..) if not_equal_operands(expr).is_some() => COMPARISON_PRECEDENCE,
+        ExprKind::Not(..) => NOT_PRECEDENCE,
+        ExprKind::Eq(..) | ExprKind::Lt(..) | ExprKind::Gt(..) | ExprKind::Le(..) | ExprKind::Ge(..) => {
+            COMPARISON_PRECEDENCE
+        },
+        ExprKind::Add(..) | ExprKind::Sub(..) | ExprKind::Unary(..) => ADDITIVE_PRECEDENCE,
+        ExprKind::Literal(literal) if literal.is_sign_negative() && !literal.is_nan() => ADDITIVE_PRECEDENCE,
+        ExprKind::Sum(..) | ExprKind::Prod(..) => AGGREGATE_PRECEDENCE,
+        ExprKind::Mul(..) if is_negation(expr) => ADDITIVE_PRECEDENCE,
+        ExprKind::Mul(..) | ExprKind::Mod(..) => MULTIPLICATIVE_PRECEDENCE,
+        ExprKind::Div(..) => FRACTION_PRECEDENCE,
+        ExprKind::Pow(..) => POWER_PRECEDENCE,
+        ExprKind::Call(name, args) if name == "pow" && args.len() == 2 => POWER_PRECEDENCE,
+        ExprKind::Call(name, args) if name == "factorial" && args.len() == 1 => FACTORIAL_PRECEDENCE,
+        ExprKind::Call(..) | ExprKind::If(..) | ExprKind::Id(..) | ExprKind::Literal(..) | ExprKind::Error => {
+            ATOM_PRECEDENCE
+        },
+    }
+}
+
+/// The operands of `not (a = b)`, which is typeset as `a ≠ b`.
+pub(crate) fn not_equal_operands(expr: &Expr) -> Option<(&Expr, &Expr)> {
+    match &expr.kind {
+        ExprKind::Not(operand) => match &operand.kind {
+            ExprKind::Eq(lhs, rhs) => Some((lhs, rhs)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Whether `expr` is typeset starting with a minus sign, like `-x`, `-3` or `-3x`.
+///
+/// A negation needs no parentheses as the left factor of a product, as `-a b` equals `(-a) b`,
+/// but such a product is then parenthesized like the negation itself.
+pub(crate) fn is_negation(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Unary(..) => true,
+        ExprKind::Mul(lhs, _) => is_negation(lhs),
+        ExprKind::Literal(literal) => literal.is_sign_negative() && !literal.is_nan(),
+        _ => false,
+    }
+}
+
+/// Minimum precedence of the left factor of a product.
+pub(crate) fn left_factor_precedence(lhs: &Expr) -> i32 {
+    if is_negation(lhs) {
+        ADDITIVE_PRECEDENCE
+    } else {
+        MULTIPLICATIVE_PRECEDENCE
+    }
+}
+
+/// Whether `lhs * rhs` reads unambiguously without a multiplication sign, like `2x` or `3 sin(x)`.
+pub(crate) fn is_implicit_multiplication(lhs: &Expr, rhs: &Expr) -> bool {
+    let starts_with_letter = match &rhs.kind {
+        ExprKind::Id(..) => true,
+        ExprKind::Pow(base, _) => matches!(base.kind, ExprKind::Id(..)),
+        ExprKind::Call(name, _) => name != "factorial" && name != "pow",
+        _ => false,
+    };
+
+    let is_number = match &lhs.kind {
+        ExprKind::Literal(literal) => literal.is_finite(),
+        ExprKind::Unary(operand) => matches!(operand.kind, ExprKind::Literal(literal) if literal.is_finite()),
+        _ => false,
+    };
+
+    is_number && starts_with_letter
+}
+
+/// Splits an identifier like `x_1` into its base name and subscript.
+pub(crate) fn split_identifier(name: &str) -> (&str, Option<&str>) {
+    match name.split_once('_') {
+        Some((base, subscript)) if !base.is_empty() && !subscript.is_empty() => (base, Some(subscript)),
+        _ => (name, None),
+    }
+}
+
+pub(crate) fn greek_letter(name: &str) -> Option<char> {
+    GREEK_LETTERS.iter().find(|(greek, _)| *greek == name).map(|(_, letter)| *letter)
+}
+
+pub(crate) fn named_function(name: &str) -> Option<(&'static str, Option<&'static str>)> {
+    NAMED_FUNCTIONS
+        .iter()
+        .find(|(function, _, _)| *function == name)
+        .map(|(_, operator, subscript)| (*operator, *subscript))
+}
@@ -1,7 +1,8 @@
 use std::collections::{HashMap, HashSet};
 
-use diagnostic::{Diagnostic, Level};
-use ast::Expr;
+use diagnostic::{Applicability, Diagnostic, DiagnosticCode, DiagnosticSink, Level};
+use ast::{walk_expr, Expr, ExprKind, Visitor};
+use span::Span;
 
 #[macro_use]
 extern crate lazy_static;
@@ -24,6 +25,7 @@ lazy_static! {
         map.insert("cosh", 1);
         map.insert("exp", 1);
         map.insert("exp_m1", 1);
+        map.insert("factorial", 1);
         map.insert("floor", 1);
         map.insert("hypot", 2);
         map.insert("ln", 1);
@@ -44,108 +46,267 @@ lazy_static! {
     };
 }
 
+/// Names of the built-in functions.
+pub fn function_names() -> impl Iterator<Item = &'static str> {
+    FUNCTION_MAP.keys().copied()
+}
+
 fn validate_equation(
     ast: &Expr,
     constants: &HashMap<String, f64>,
     variables: &HashMap<String, f64>,
     un_evaluated_variables: &HashSet<String>,
-    relational_operation_count: usize,
+    expected_type: ExprType,
+    diagnostics: &mut DiagnosticSink,
 ) -> bool {
     let id_table = make_id_list(ast);
     let expr_count_map = count_expr_count(ast);
+    let mut valid_definitions = true;
+
+    for (name, span, kind) in &id_table.definitions {
+        // Only indices are forbidden to shadow variables, as they are easily confused with `x` or `y`.
+        let shadowed = if FUNCTION_MAP.contains_key(name.as_str()) {
+            "a built-in function"
+        } else if constants.contains_key(name) {
+            "a constant"
+        } else if *kind == "index" && (variables.contains_key(name) || un_evaluated_variables.contains(name)) {
+            "a variable"
+        } else {
+            continue;
+        };
+
+        diagnostics.push(Diagnostic::new(
+            Level::Error,
+            format!("Definition of {} shadows {}", name, shadowed),
+        )
+        .with_code(DiagnosticCode::InvalidDefinition)
+        .with_span(*span)
+        .with_label(*span, format!("{} '{}' is defined here", kind, name))
+        .with_help("choose a different name".to_string()));
+        valid_definitions = false;
+    }
+
+    for (name, span) in &id_table.duplicate_parameters {
+        diagnostics.push(Diagnostic::new(
+            Level::Error,
+            format!("Parameter {} is declared more than once", name),
+        )
+        .with_code(DiagnosticCode::InvalidDefinition)
+        .with_span(*span));
+        valid_definitions = false;
+    }
+
+    if !valid_definitions {
+        return false;
+    }
 
     let mut var_set = variables.keys().chain(un_evaluated_variables.iter()).cloned().collect::<HashSet<_>>();
     
     let ids = id_table.ids;
-    for name in ids {
+    for (name, span) in ids {
         if var_set.contains(&name) {
             var_set.remove(&name);
         } else if !constants.contains_key(&name) {
-            Diagnostic::push_new(Diagnostic::new(
+            let mut diagnostic = Diagnostic::new(
                 Level::Error,
                 format!("Variable or Constant {} is not defined", name),
-            ));
+            )
+            .with_code(DiagnosticCode::UndefinedIdentifier)
+            .with_span(span);
+
+            let candidates = variables.keys().chain(un_evaluated_variables.iter()).chain(constants.keys());
+            if let Some(similar) = find_similar_name(&name, candidates.map(|name| name.as_str())) {
+                diagnostic = diagnostic.with_suggestion(
+                    span,
+                    similar.to_string(),
+                    format!("a variable or constant with a similar name exists: '{}'", similar),
+                    Applicability::MaybeIncorrect,
+                );
+            }
+
+            diagnostics.push(diagnostic);
             return false;
         }
     }
 
     for var_name in var_set {
-        Diagnostic::push_new(Diagnostic::new(
+        diagnostics.push(Diagnostic::new(
             Level::Warning,
             format!("Variable {} is not used", var_name),
-        ));
+        ).with_code(DiagnosticCode::UnusedVariable));
     }
 
     let functions = id_table.called_ids;
-    for function in functions {
+    for (function, span) in functions {
         if !FUNCTION_MAP.contains_key(function.as_str()) {
-            Diagnostic::push_new(Diagnostic::new(
+            let mut diagnostic = Diagnostic::new(
                 Level::Error,
                 format!("Function {} is not defined", function),
-            ));
+            )
+            .with_code(DiagnosticCode::UndefinedFunction)
+            .with_span(span);
+
+            if let Some(similar) = find_similar_name(&function, FUNCTION_MAP.keys().copied()) {
+                diagnostic = diagnostic.with_suggestion(
+                    Span::new(span.low(), span.low() + function.len()),
+                    similar.to_string(),
+                    format!("a function with a similar name exists: '{}'", similar),
+                    Applicability::MaybeIncorrect,
+                );
+            }
+
+            diagnostics.push(diagnostic);
             return false;
         }
     }
 
     let mut function_call_argument_error = false;
-    
-    traverse_ast(
-        ast,
-        &mut |expr| {
-            if let Expr::Call(name, args) = expr {
-                if let Some(param_count) = FUNCTION_MAP.get(name.as_str()) {
-                    if args.len() != param_count.to_owned() {
-                        Diagnostic::push_new(Diagnostic::new(
-                            Level::Error,
-                            format!("Function '{}' takes {} arguments", name, param_count),
-                        ));
-                        function_call_argument_error = true;
-                    }
-                }
+
+    for call in &id_table.calls {
+        if let Some(param_count) = call.parameter_count {
+            if call.argument_count != param_count {
+                diagnostics.push(Diagnostic::new(
+                    Level::Error,
+                    format!("Function '{}' takes {} arguments", call.name, param_count),
+                )
+                .with_code(DiagnosticCode::ArgumentCountMismatch)
+                .with_span(call.span)
+                .with_label(call.span, format!("{} argument{} supplied", call.argument_count, if call.argument_count == 1 { "" } else { "s" })));
+                function_call_argument_error = true;
             }
-        },
-    );
+        }
+    }
 
     if function_call_argument_error {
         return false;
     }
 
-    let mut relation_expr_count = 0;
+    // Syntax errors have already been reported by the parser; the types of a
+    // partially parsed expression are meaningless.
+    if expr_count_map.contains_key(ExprKind::error_str()) {
+        return false;
+    }
+
+    match check_type(ast, diagnostics) {
+        Some(actual_type) if actual_type != expected_type => {
+            let help = match expected_type {
+                ExprType::Number => "a number expression must not contain relations or logical operators",
+                ExprType::Bool => "a boolean equation must contain a relation such as '=', '<', '>', '<=' or '>='",
+            };
 
-    for (expr, count) in expr_count_map {
-        if expr == Expr::eq_str() ||
-            expr == Expr::lt_str() ||
-            expr == Expr::gt_str() ||
-            expr == Expr::le_str() ||
-            expr == Expr::ge_str()
-        {
-            relation_expr_count += count;
+            diagnostics.push(type_mismatch(ast, expected_type, actual_type).with_help(help.to_string()));
+            false
+        },
+        Some(_) => true,
+        None => false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExprType {
+    Number,
+    Bool,
+}
+
+impl ExprType {
+    fn name(&self) -> &'static str {
+        match self {
+            ExprType::Number => "number",
+            ExprType::Bool => "boolean",
         }
     }
+}
 
-    if relational_operation_count != relation_expr_count as usize {
-        Diagnostic::push_new(Diagnostic::new(
-            Level::Error,
-            format!("relation expression must be used once"),
-        ));
-        return false;
+fn type_mismatch(ast: &Expr, expected_type: ExprType, actual_type: ExprType) -> Diagnostic {
+    Diagnostic::new(
+        Level::Error,
+        format!("expected a {} expression, found a {} expression", expected_type.name(), actual_type.name()),
+    )
+    .with_code(DiagnosticCode::TypeMismatch)
+    .with_span(ast.span)
+    .with_label(ast.span, format!("this is a {} expression", actual_type.name()))
+}
+
+/// Infers the type of `ast`, reporting every operand of the wrong type.
+/// Returns `None` if an error was reported.
+fn check_type(ast: &Expr, diagnostics: &mut DiagnosticSink) -> Option<ExprType> {
+    use ExprType::{Bool, Number};
+
+    let (operands, result_type) = match &ast.kind {
+        ExprKind::Literal(_) | ExprKind::Id(_) | ExprKind::Error => return Some(Number),
+        ExprKind::Call(_, args) => (args.iter().map(|arg| (arg, Number)).collect::<Vec<_>>(), Number),
+        ExprKind::Unary(expr) => (vec![(expr, Number)], Number),
+        ExprKind::Add(lhs, rhs)
+        | ExprKind::Sub(lhs, rhs)
+        | ExprKind::Mul(lhs, rhs)
+        | ExprKind::Div(lhs, rhs)
+        | ExprKind::Mod(lhs, rhs)
+        | ExprKind::Pow(lhs, rhs) => (vec![(lhs, Number), (rhs, Number)], Number),
+        ExprKind::Eq(lhs, rhs)
+        | ExprKind::Lt(lhs, rhs)
+        | ExprKind::Gt(lhs, rhs)
+        | ExprKind::Le(lhs, rhs)
+        | ExprKind::Ge(lhs, rhs) => (vec![(lhs, Number), (rhs, Number)], Bool),
+        ExprKind::And(lhs, rhs)
+        | ExprKind::Or(lhs, rhs) => (vec![(lhs, Bool), (rhs, Bool)], Bool),
+        ExprKind::Not(expr) => (vec![(expr, Bool)], Bool),
+        ExprKind::System(equations) => (equations.iter().map(|equation| (equation, Bool)).collect(), Bool),
+        ExprKind::If(condition, then, otherwise) => {
+            (vec![(condition, Bool), (then, Number), (otherwise, Number)], Number)
+        },
+        ExprKind::Sum(_, from, to, term) | ExprKind::Prod(_, from, to, term) => {
+            (vec![(from, Number), (to, Number), (term, Number)], Number)
+        },
+        ExprKind::Let(_, value, body) | ExprKind::LetFn(_, _, value, body) => {
+            // Definitions are numbers; the whole expression has the type of its body.
+            let value_type = check_type(value, diagnostics);
+            let body_type = check_type(body, diagnostics);
+
+            return match value_type {
+                Some(Number) => body_type,
+                Some(actual_type) => {
+                    diagnostics.push(type_mismatch(value, Number, actual_type));
+                    None
+                },
+                None => None,
+            };
+        },
+    };
+
+    let mut valid = true;
+
+    for (operand, operand_type) in operands {
+        match check_type(operand, diagnostics) {
+            Some(actual_type) if actual_type != operand_type => {
+                diagnostics.push(type_mismatch(operand, operand_type, actual_type));
+                valid = false;
+            },
+            Some(_) => { },
+            None => valid = false,
+        }
     }
 
-    true
+    if valid {
+        Some(result_type)
+    } else {
+        None
+    }
 }
 
 pub fn validate_number_equation(
     ast: &Expr,
     constants: &HashMap<String, f64>,
     variables: &HashMap<String, f64>,
-    un_evaluated_variables: &HashSet<String>
+    un_evaluated_variables: &HashSet<String>,
+    diagnostics: &mut DiagnosticSink,
 ) -> bool {
     validate_equation(
         ast,
         constants,
         variables,
         un_evaluated_variables,
-        0,
+        ExprType::Number,
+        diagnostics,
     )
 }
 
@@ -153,89 +314,275 @@ pub fn validate_bool_equation(
     ast: &Expr,
     constants: &HashMap<String, f64>,
     variables: &HashMap<String, f64>,
-    un_evaluated_variables: &HashSet<String>
+    un_evaluated_variables: &HashSet<String>,
+    diagnostics: &mut DiagnosticSink,
 ) -> bool {
     validate_equation(
         ast,
         constants,
         variables,
         un_evaluated_variables,
-        1,
+        ExprType::Bool,
+        diagnostics,
     )
 }
 
+/// Returns the candidate closest to `name` by edit distance, if any is close enough to be a likely typo.
+fn find_similar_name<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let name_length = name.chars().count();
+    let max_distance = usize::max(1, name_length / 3);
+
+    // A candidate that shares no character position with the name is not a typo of it.
+    candidates
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance && *distance < name_length)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(lhs: &str, rhs: &str) -> usize {
+    let rhs = rhs.chars().collect::<Vec<_>>();
+    let mut previous_row = (0..=rhs.len()).collect::<Vec<_>>();
+
+    for (i, lhs_char) in lhs.chars().enumerate() {
+        let mut current_row = vec![i + 1; rhs.len() + 1];
+
+        for (j, rhs_char) in rhs.iter().enumerate() {
+            let substitution_cost = if lhs_char == *rhs_char { 0 } else { 1 };
+            current_row[j + 1] = usize::min(
+                usize::min(previous_row[j + 1] + 1, current_row[j] + 1),
+                previous_row[j] + substitution_cost,
+            );
+        }
+
+        previous_row = current_row;
+    }
+
+    previous_row[rhs.len()]
+}
+
 #[derive(Debug, Clone)]
 struct IdTable {
-    pub(crate) ids: HashSet<String>,
-    pub(crate) called_ids: HashSet<String>,
+    /// Maps each name not bound by a definition to the span of its first occurrence.
+    pub(crate) ids: HashMap<String, Span>,
+    /// Maps each called name that is not a defined function to the span of its first call.
+    pub(crate) called_ids: HashMap<String, Span>,
+    pub(crate) calls: Vec<CallSite>,
+    /// Every defined name, with the span of its definition and what kind of name it is.
+    pub(crate) definitions: Vec<(String, Span, &'static str)>,
+    pub(crate) duplicate_parameters: Vec<(String, Span)>,
+}
+
+#[derive(Debug, Clone)]
+struct CallSite {
+    pub(crate) name: String,
+    pub(crate) span: Span,
+    pub(crate) argument_count: usize,
+    /// `None` if the function is not defined.
+    pub(crate) parameter_count: Option<usize>,
+}
+
+/// Names bound by the enclosing definitions.
+#[derive(Debug, Clone, Default)]
+struct Scope {
+    variables: HashSet<String>,
+    /// Maps each function to its parameter count.
+    functions: HashMap<String, usize>,
 }
 
 fn make_id_list(ast: &Expr) -> IdTable {
-    let mut result = IdTable { ids: HashSet::new(), called_ids: HashSet::new() };
-
-    traverse_ast(
-        &ast, 
-        &mut |ast| {
-            match ast {
-                Expr::Id(id) => {
-                    result.ids.insert(id.to_owned());
-                },
-                Expr::Call(id, _) => {
-                    result.called_ids.insert(id.to_owned());
+    let mut collector = IdCollector {
+        scope: Scope::default(),
+        result: IdTable {
+            ids: HashMap::new(),
+            called_ids: HashMap::new(),
+            calls: Vec::new(),
+            definitions: Vec::new(),
+            duplicate_parameters: Vec::new(),
+        },
+    };
+
+    collector.visit_expr(ast);
+    collector.result
+}
+
+/// Collects the names used and defined in an AST, resolving each against the enclosing definitions.
+struct IdCollector {
+    scope: Scope,
+    result: IdTable,
+}
+
+impl IdCollector {
+    /// Visits `expr` in `scope`, then restores the current scope.
+    fn visit_in_scope(&mut self, expr: &Expr, scope: Scope) {
+        let outer_scope = std::mem::replace(&mut self.scope, scope);
+        self.visit_expr(expr);
+        self.scope = outer_scope;
+    }
+}
+
+impl Visitor for IdCollector {
+    fn visit_expr(&mut self, ast: &Expr) {
+        match &ast.kind {
+            ExprKind::Id(id) => {
+                if !self.scope.variables.contains(id) {
+                    self.result.ids.entry(id.to_owned()).or_insert(ast.span);
                 }
-                _ => { }
-            }
+            },
+            ExprKind::Call(id, args) => {
+                let parameter_count = match self.scope.functions.get(id) {
+                    Some(parameter_count) => Some(*parameter_count),
+                    None => {
+                        self.result.called_ids.entry(id.to_owned()).or_insert(ast.span);
+                        FUNCTION_MAP.get(id.as_str()).copied()
+                    },
+                };
+
+                self.result.calls.push(CallSite {
+                    name: id.to_owned(),
+                    span: ast.span,
+                    argument_count: args.len(),
+                    parameter_count,
+                });
+
+                walk_expr(self, ast);
+            },
+            ExprKind::Let(name, value, body) => {
+                let definition_span = Span::new(ast.span.low(), value.span.high());
+                self.result.definitions.push((name.to_owned(), definition_span, "name"));
+                self.visit_expr(value);
+
+                let mut body_scope = self.scope.clone();
+                body_scope.variables.insert(name.to_owned());
+                self.visit_in_scope(body, body_scope);
+            },
+            ExprKind::LetFn(name, parameters, value, body) => {
+                let definition_span = Span::new(ast.span.low(), value.span.high());
+                self.result.definitions.push((name.to_owned(), definition_span, "function"));
+
+                let mut value_scope = self.scope.clone();
+                let mut declared_parameters = HashSet::new();
+                for parameter in parameters {
+                    if !declared_parameters.insert(parameter) {
+                        self.result.duplicate_parameters.push((parameter.to_owned(), definition_span));
+                    }
+                    self.result.definitions.push((parameter.to_owned(), definition_span, "parameter"));
+                    value_scope.variables.insert(parameter.to_owned());
+                }
+                self.visit_in_scope(value, value_scope);
+
+                let mut body_scope = self.scope.clone();
+                body_scope.functions.insert(name.to_owned(), parameters.len());
+                self.visit_in_scope(body, body_scope);
+            },
+            ExprKind::Sum(index, from, to, term) | ExprKind::Prod(index, from, to, term) => {
+                self.result.definitions.push((index.to_owned(), ast.span, "index"));
+                self.visit_expr(from);
+                self.visit_expr(to);
+
+                let mut term_scope = self.scope.clone();
+                term_scope.variables.insert(index.to_owned());
+                self.visit_in_scope(term, term_scope);
+            },
+            _ => walk_expr(self, ast),
         }
-    );
+    }
+}
+
+/// Counts the nodes of each kind, keyed by `Expr::to_str`.
+#[derive(Default)]
+struct ExprCounter {
+    counts: HashMap<&'static str, i32>,
+}
 
-    result
+impl Visitor for ExprCounter {
+    fn visit_expr(&mut self, expr: &Expr) {
+        self.counts.entry(expr.to_str()).and_modify(|e| *e += 1).or_insert(1);
+        walk_expr(self, expr);
+    }
 }
 
 fn count_expr_count(ast: &Expr) -> HashMap<&'static str, i32> {
-    let mut result = HashMap::new();
+    let mut counter = ExprCounter::default();
+    counter.visit_expr(ast);
+    counter.counts
+}
 
-    traverse_ast(
-        &ast, 
-        &mut |ast| {
-            result.entry(ast.to_str()).and_modify(|e| *e += 1).or_insert(1);
-        }
-    );
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    result
-}
+    fn parse(input: &str) -> Box<Expr> {
+        let mut diagnostics = DiagnosticSink::new();
+        let ctx = parser::ParserContext::new(
+            Box::new(lexer::token_iter(input)),
+            parser::create_binary_op_precedence(),
+            &mut diagnostics,
+        );
+        parser::parse_top_level_expression(ctx).unwrap_or_else(|_| panic!("failed to parse {}", input))
+    }
 
-fn traverse_ast(ast: &Expr, func: &mut impl FnMut(&Expr)) {
-    match ast {
-        Expr::Id(_) => {
-            func(ast);
-        },
-        Expr::Call(_, args) => {
-            func(ast);
-            for arg in args {
-                traverse_ast(&arg, func);
-            }
-        },
-        Expr::Eq(lhs, rhs)
-        | Expr::Lt(lhs, rhs)
-        | Expr::Gt(lhs, rhs)
-        | Expr::Le(lhs, rhs)
-        | Expr::Ge(lhs, rhs)
-        | Expr::Add(lhs, rhs)
-        | Expr::Sub(lhs, rhs)
-        | Expr::Mul(lhs, rhs)
-        | Expr::Div(lhs, rhs)
-        | Expr::Mod(lhs, rhs)
-        | Expr::Pow(lhs, rhs) => {
-            func(ast);
-            traverse_ast(&lhs, func);
-            traverse_ast(&rhs, func);
-        },
-        Expr::Unary(expr) => {
-            func(ast);
-            traverse_ast(&expr, func);
-        },
-        Expr::Literal(_) => {
-            func(ast);
-        }
+    /// Validates the input as a number expression of `x` with the constant `pi`, returning the
+    /// code of each error reported.
+    fn number_errors(input: &str) -> Vec<DiagnosticCode> {
+        let mut diagnostics = DiagnosticSink::new();
+        validate_number_equation(
+            &parse(input),
+            &HashMap::from([("pi".to_string(), std::f64::consts::PI)]),
+            &HashMap::new(),
+            &HashSet::from(["x".to_string()]),
+            &mut diagnostics,
+        );
+        diagnostics
+            .diagnostics()
+            .iter()
+            .filter(|diagnostic| diagnostic.level() == Level::Error)
+            .filter_map(|diagnostic| diagnostic.code())
+            .collect()
+    }
+
+    #[test]
+    fn conditional_needs_a_relation_and_two_numbers() {
+        assert_eq!(number_errors("if(x < 0, -x, x)"), []);
+        assert_eq!(number_errors("if(x, 1, 2)"), [DiagnosticCode::TypeMismatch]);
+        assert_eq!(number_errors("if(x < 0, x > 1, 2)"), [DiagnosticCode::TypeMismatch]);
+    }
+
+    #[test]
+    fn every_invalid_definition_is_reported() {
+        assert_eq!(
+            number_errors("let sin = 1 in let pi = 2 in sin + pi + x"),
+            [DiagnosticCode::InvalidDefinition, DiagnosticCode::InvalidDefinition]
+        );
+        assert_eq!(
+            number_errors("let f(t, t) = t in let g(u, u) = u in f(x, x) + g(x, x)"),
+            [DiagnosticCode::InvalidDefinition, DiagnosticCode::InvalidDefinition]
+        );
+    }
+
+    #[test]
+    fn definitions_bind_names_in_their_body() {
+        assert_eq!(number_errors("let r = x^2 in let f(t) = t * r in f(r)"), []);
+        assert_eq!(number_errors("let r = 1 in r + s"), [DiagnosticCode::UndefinedIdentifier]);
+        assert_eq!(number_errors("let f(t) = t in f(x, 1)"), [DiagnosticCode::ArgumentCountMismatch]);
+        assert_eq!(number_errors("(let f(t) = t in f(x)) + f(x)"), [DiagnosticCode::UndefinedFunction]);
+    }
+
+    #[test]
+    fn ids_are_resolved_against_enclosing_definitions() {
+        let ids = make_id_list(&parse("a = 1; f(t) = t + y; f(a) + g(x) + sum(k, k, 2, k * z) + t"));
+
+        let mut free_names: Vec<_> = ids.ids.keys().map(String::as_str).collect();
+        free_names.sort();
+        assert_eq!(free_names, ["k", "t", "x", "y", "z"]);
+        assert_eq!(ids.called_ids.keys().collect::<Vec<_>>(), ["g"]);
+        assert_eq!(
+            ids.calls.iter().map(|call| (call.name.as_str(), call.parameter_count)).collect::<Vec<_>>(),
+            [("f", Some(1)), ("g", None)]
+        );
+        assert_eq!(
+            ids.definitions.iter().map(|(name, _, kind)| (name.as_str(), *kind)).collect::<Vec<_>>(),
+            [("a", "name"), ("f", "function"), ("t", "parameter"), ("k", "index")]
+        );
     }
 }
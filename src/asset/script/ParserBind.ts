@@ -8,6 +8,9 @@ type GetParametersLengthStringArray<T extends (...args: number[]) => any> =
         ? {[K in keyof U]: string}
         : never;
 
+// How close two numbers must be for `=` to hold, both in emitted JS and when evaluated in wasm.
+const EQUALITY_APPROXIMATE_THRESHOLD = 0.00001;
+
 export class ParserBind {
     private static _epp: typeof import("../../epp")|null = null;
 
@@ -20,9 +23,17 @@ export class ParserBind {
         this._epp.dispose_ast(astId);
     }
 
-    public static parseBoolExpr(expr: string): ParseResult<(x: number, y: number) => boolean> {
+    public static parseBoolExpr(expr: string, implicitMultiplication: boolean = false): ParseResult<(x: number, y: number) => boolean> {
+        if (!this._epp) throw new Error("ParserBind is not initialized");
+        const lowParseResult = this._epp.parse_bool_expr(expr, implicitMultiplication);
+        const parseResult = this.parseResult<(x: number, y: number) => boolean>(lowParseResult, ["x", "y"]);
+        lowParseResult.free();
+        return parseResult;
+    }
+
+    public static parseLatexBoolExpr(expr: string): ParseResult<(x: number, y: number) => boolean> {
         if (!this._epp) throw new Error("ParserBind is not initialized");
-        const lowParseResult = this._epp.parse_bool_expr(expr);
+        const lowParseResult = this._epp.parse_latex_bool_expr(expr);
         const parseResult = this.parseResult<(x: number, y: number) => boolean>(lowParseResult, ["x", "y"]);
         lowParseResult.free();
         return parseResult;
@@ -30,12 +41,25 @@ export class ParserBind {
 
     public static emitBoolExpr(astId: number): string {
         if (!this._epp) throw new Error("ParserBind is not initialized");
-        return this._epp.emit_bool_expr(astId, 0.00001);
+        return this._epp.emit_bool_expr(astId, EQUALITY_APPROXIMATE_THRESHOLD);
+    }
+
+    public static emitSystemExpr(astId: number): string {
+        if (!this._epp) throw new Error("ParserBind is not initialized");
+        return this._epp.emit_system_expr(astId, EQUALITY_APPROXIMATE_THRESHOLD);
+    }
+
+    public static parseNumberExpr(expr: string, implicitMultiplication: boolean = false): ParseResult<(x: number) => number> {
+        if (!this._epp) throw new Error("ParserBind is not initialized");
+        const lowParseResult = this._epp.parse_number_expr(expr, implicitMultiplication);
+        const parseResult = this.parseResult<(x: number) => number>(lowParseResult, ["x"]);
+        lowParseResult.free();
+        return parseResult;
     }
 
-    public static parseNumberExpr(expr: string): ParseResult<(x: number) => number> {
+    public static parseLatexNumberExpr(expr: string): ParseResult<(x: number) => number> {
         if (!this._epp) throw new Error("ParserBind is not initialized");
-        const lowParseResult = this._epp.parse_number_expr(expr);
+        const lowParseResult = this._epp.parse_latex_number_expr(expr);
         const parseResult = this.parseResult<(x: number) => number>(lowParseResult, ["x"]);
         lowParseResult.free();
         return parseResult;
@@ -43,7 +67,7 @@ export class ParserBind {
 
     public static emitNumberExpr(astId: number): string {
         if (!this._epp) throw new Error("ParserBind is not initialized");
-        return this._epp.emit_number_expr(astId);
+        return this._epp.emit_number_expr(astId, EQUALITY_APPROXIMATE_THRESHOLD);
     }
 
     private static parseResult<T extends (...args: number[]) => number|boolean>(
@@ -62,6 +86,22 @@ export class ParserBind {
         return new ParseResult<T>(ast, error);
     }
 
+    public static evalNumberExpr(astId: number, x: number): { value: number, error: string } {
+        if (!this._epp) throw new Error("ParserBind is not initialized");
+        const lowEvalNumberResult = this._epp.eval_number_expr(astId, x, EQUALITY_APPROXIMATE_THRESHOLD);
+        const evalNumberResult = { value: lowEvalNumberResult.value, error: lowEvalNumberResult.error };
+        lowEvalNumberResult.free();
+        return evalNumberResult;
+    }
+
+    public static evalNumberExprBatch(astId: number, xs: Float64Array): { values: Float64Array, error: string } {
+        if (!this._epp) throw new Error("ParserBind is not initialized");
+        const lowEvalNumberBatchResult = this._epp.eval_number_expr_batch(astId, xs, EQUALITY_APPROXIMATE_THRESHOLD);
+        const evalNumberBatchResult = { values: lowEvalNumberBatchResult.values, error: lowEvalNumberBatchResult.error };
+        lowEvalNumberBatchResult.free();
+        return evalNumberBatchResult;
+    }
+
     public static differentiateExpr(astId: number): LowTransformResult {
         if (!this._epp) throw new Error("ParserBind is not initialized");
         return this._epp.differentiate_expr(astId);
@@ -72,6 +112,37 @@ export class ParserBind {
         return this._epp.ast_to_string(astId);
     }
 
+    public static astToLatex(astId: number): string {
+        if (!this._epp) throw new Error("ParserBind is not initialized");
+        return this._epp.ast_to_latex(astId);
+    }
+
+    public static astToMathml(astId: number): string {
+        if (!this._epp) throw new Error("ParserBind is not initialized");
+        return this._epp.ast_to_mathml(astId);
+    }
+
+    public static exportAst(astId: number): string {
+        if (!this._epp) throw new Error("ParserBind is not initialized");
+        return this._epp.export_ast(astId);
+    }
+
+    public static importBoolAst(json: string): ParseResult<(x: number, y: number) => boolean> {
+        if (!this._epp) throw new Error("ParserBind is not initialized");
+        const lowParseResult = this._epp.import_bool_ast(json);
+        const parseResult = this.parseResult<(x: number, y: number) => boolean>(lowParseResult, ["x", "y"]);
+        lowParseResult.free();
+        return parseResult;
+    }
+
+    public static importNumberAst(json: string): ParseResult<(x: number) => number> {
+        if (!this._epp) throw new Error("ParserBind is not initialized");
+        const lowParseResult = this._epp.import_number_ast(json);
+        const parseResult = this.parseResult<(x: number) => number>(lowParseResult, ["x"]);
+        lowParseResult.free();
+        return parseResult;
+    }
+
     public static foldExpr(astId: number): number {
         if (!this._epp) throw new Error("ParserBind is not initialized");
         return this._epp.fold_expr(astId);
@@ -129,6 +200,21 @@ export class Ast<T extends (...args: number[]) => number|boolean> {
         return ParserBind.astToString(this._astId);
     }
 
+    public toLatex(): string {
+        if (this._astId === 0) throw new Error("Ast is disposed");
+        return ParserBind.astToLatex(this._astId);
+    }
+
+    public toMathml(): string {
+        if (this._astId === 0) throw new Error("Ast is disposed");
+        return ParserBind.astToMathml(this._astId);
+    }
+
+    public toJson(): string {
+        if (this._astId === 0) throw new Error("Ast is disposed");
+        return ParserBind.exportAst(this._astId);
+    }
+
     public fold(): Ast<T> {
         if (this._astId === 0) throw new Error("Ast is disposed");
         const astId = ParserBind.foldExpr(this._astId);